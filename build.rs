@@ -0,0 +1,11 @@
+//! Generates the gRPC service from `proto/storage.proto` when the `grpc` feature is on
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        // The vendored compiler, so building needs no protoc on the PATH
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_prost_build::compile_protos("proto/storage.proto")?;
+    }
+    Ok(())
+}
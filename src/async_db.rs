@@ -0,0 +1,145 @@
+use crate::db::Db;
+use std::io;
+use std::path::PathBuf;
+use tokio::task;
+
+/// Async wrapper around [`Db`] for use inside a tokio runtime.
+///
+/// Every call runs the blocking engine operation (including its fsync) on tokio's
+/// blocking thread pool, so the reactor is never stalled. Operations awaited one after
+/// another from the same task apply in that order, exactly as with the sync API.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Db,
+}
+
+impl AsyncDb {
+    pub async fn open<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        let db = blocking(move || Db::open(dir)).await?;
+        Ok(AsyncDb { db })
+    }
+
+    /// Wrap an already-open handle
+    pub fn from_db(db: Db) -> Self {
+        AsyncDb { db }
+    }
+
+    pub async fn put<K, V>(&self, key: K, value: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let db = self.db.clone();
+        let (key, value) = (key.into(), value.into());
+        blocking(move || db.put(key, value)).await
+    }
+
+    pub async fn get<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<String>> {
+        let db = self.db.clone();
+        let key = key.as_ref().to_vec();
+        blocking(move || db.try_get(key)).await
+    }
+
+    pub async fn delete<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<String>> {
+        let db = self.db.clone();
+        let key = key.as_ref().to_vec();
+        blocking(move || db.delete(key)).await
+    }
+
+    pub async fn flush(&self) -> io::Result<()> {
+        let db = self.db.clone();
+        blocking(move || db.flush()).await
+    }
+
+    /// Flush outstanding data and release this handle
+    pub async fn close(self) -> io::Result<()> {
+        self.flush().await
+    }
+
+    /// Access the underlying sync handle
+    pub fn sync_handle(&self) -> &Db {
+        &self.db
+    }
+}
+
+pub(crate) async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::other(format!("blocking task failed: {}", e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_async_puts_and_gets() {
+        let dir = "test_async_db_concurrent";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = AsyncDb::open(dir).await.unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for i in 0..40 {
+                        let key = format!("t{}_key_{}", t, i);
+                        db.put(key.clone(), format!("value_{}", i)).await.unwrap();
+                        // Read-your-writes from the same task
+                        assert_eq!(db.get(&key).await.unwrap(), Some(format!("value_{}", i)));
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        for t in 0..4 {
+            for i in 0..40 {
+                let key = format!("t{}_key_{}", t, i);
+                assert_eq!(db.get(&key).await.unwrap(), Some(format!("value_{}", i)));
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ordering_within_a_task() {
+        let dir = "test_async_db_ordering";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = AsyncDb::open(dir).await.unwrap();
+        db.put("key1", "value1").await.unwrap();
+        db.put("key1", "value2").await.unwrap();
+        assert_eq!(db.delete("key1").await.unwrap(), Some("value2".to_string()));
+        assert_eq!(db.get("key1").await.unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_flushes() {
+        let dir = "test_async_db_close";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = AsyncDb::open(dir).await.unwrap();
+        db.put("key1", "value1").await.unwrap();
+        db.close().await.unwrap();
+
+        assert!(std::path::Path::new(dir).join("sstable_000000.sst").exists());
+        let db = AsyncDb::open(dir).await.unwrap();
+        assert_eq!(db.get("key1").await.unwrap(), Some("value1".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
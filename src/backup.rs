@@ -0,0 +1,658 @@
+use crate::column_family::ColumnFamilies;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::PoisonError;
+
+/// Lists every file in a backup with its size and CRC32, one `path\tsize\tcrc` per line.
+/// It is written last, so a backup without one is incomplete.
+pub(crate) const MANIFEST: &str = "MANIFEST";
+/// First bytes of an archive written by [`export_archive`]
+const ARCHIVE_MAGIC: &[u8; 8] = b"LSMARCH1";
+/// Longest file name an archive entry may carry
+const MAX_ARCHIVE_NAME: usize = 4096;
+
+/// Summary of a completed backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// Files copied, not counting the manifest
+    pub files: usize,
+    pub bytes: u64,
+    /// Highest sequence number of any column family included in the backup
+    pub max_sequence: u64,
+}
+
+/// A file captured while every family was locked: where it goes, an open handle, and
+/// how much of it belongs to the captured state
+struct Pending {
+    dest: PathBuf,
+    source_path: PathBuf,
+    source: File,
+    len: u64,
+    /// The file never changes again, so it may be shared by hard link
+    sealed: bool,
+}
+
+/// Lock every family together, flush its memtable, seal its value log, and open the
+/// files holding the flushed state, then run `f` on them with the highest sequence
+/// number they include. The locks are held until `f` returns.
+fn capture<T, F>(families: &ColumnFamilies, f: F) -> io::Result<T>
+where
+    F: FnOnce(Vec<Pending>, u64) -> io::Result<T>,
+{
+    let families = families.all();
+    let mut memtables: Vec<_> = families
+        .iter()
+        .map(|(_, memtable)| memtable.write().unwrap_or_else(PoisonError::into_inner))
+        .collect();
+
+    let mut pending = Vec::new();
+    let mut max_sequence = 0;
+    for ((dir, _), memtable) in families.iter().zip(memtables.iter_mut()) {
+        memtable.flush()?;
+        memtable.seal_value_log()?;
+        max_sequence = max_sequence.max(memtable.last_sequence());
+        for (path, sealed) in memtable.files()? {
+            let source = File::open(&path)?;
+            let len = source.metadata()?.len();
+            pending.push(Pending {
+                dest: dir.join(path.file_name().unwrap_or_default()),
+                source_path: path,
+                source,
+                len,
+                sealed,
+            });
+        }
+    }
+    f(pending, max_sequence)
+}
+
+/// Copy a consistent point-in-time image of every column family into `dest`.
+///
+/// All families are locked together while their memtables are flushed and their files
+/// opened, so writers are only paused for the flush. The copy itself runs unlocked from
+/// the open handles: SSTables never change once written and the other files are only
+/// appended to, so copying each up to its length at that moment yields the flushed
+/// state even if files are later removed. Each WAL is copied as it stood right after
+/// the flush, which is empty but keeps every family's directory in the backup.
+pub(crate) fn backup(families: &ColumnFamilies, dest: &Path) -> io::Result<BackupInfo> {
+    ensure_empty(dest, "backup destination")?;
+    let (pending, max_sequence) = capture(families, |pending, seq| Ok((pending, seq)))?;
+
+    let mut manifest = String::new();
+    let mut bytes = 0;
+    for file in &pending {
+        let dest_path = dest.join(&file.dest);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let crc = copy_prefix(&file.source, file.len, &dest_path)?;
+        manifest.push_str(&manifest_line(&file.dest, file.len, crc));
+        bytes += file.len;
+    }
+
+    let mut manifest_file = File::create(dest.join(MANIFEST))?;
+    manifest_file.write_all(manifest.as_bytes())?;
+    manifest_file.sync_all()?;
+
+    Ok(BackupInfo {
+        files: pending.len(),
+        bytes,
+        max_sequence,
+    })
+}
+
+/// Create a checkpoint of every column family in `dest` and return the sequence number
+/// it represents.
+///
+/// Sealed files are hard-linked, so the checkpoint costs about the same whatever the
+/// data size and stays valid when the originals are deleted; where linking fails (e.g.
+/// across filesystems) they are copied instead. WALs and active value logs keep being
+/// appended to, so they are copied up to their current length. Writers are paused for
+/// the whole operation.
+pub(crate) fn checkpoint(families: &ColumnFamilies, dest: &Path) -> io::Result<u64> {
+    ensure_empty(dest, "checkpoint destination")?;
+    capture(families, |pending, max_sequence| {
+        for file in &pending {
+            let dest_path = dest.join(&file.dest);
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if !file.sealed || fs::hard_link(&file.source_path, &dest_path).is_err() {
+                copy_prefix(&file.source, file.len, &dest_path)?;
+            }
+        }
+        Ok(max_sequence)
+    })
+}
+
+/// Stream a consistent image of every column family to `writer` as a single archive.
+///
+/// The files are captured as for [`backup`] and written after an 8-byte magic, each as
+/// `[u32 name length][name][u64 length][contents][u32 crc32]`. The WALs go in as they
+/// stood right after the flush, empty, marking that nothing was left unflushed. A
+/// manifest of the files comes last, followed by an entry with an empty name. Files are
+/// copied through a small buffer, never held in memory whole.
+pub(crate) fn export_archive(
+    families: &ColumnFamilies,
+    writer: &mut dyn Write,
+) -> io::Result<BackupInfo> {
+    let (pending, max_sequence) = capture(families, |pending, seq| Ok((pending, seq)))?;
+
+    writer.write_all(ARCHIVE_MAGIC)?;
+    let mut manifest = String::new();
+    let mut bytes = 0;
+    for file in &pending {
+        let name = file.dest.to_string_lossy().replace('\\', "/");
+        write_entry_header(writer, &name, file.len)?;
+        let (copied, crc) = copy_counted(&file.source, file.len, writer)?;
+        if copied != file.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} was truncated during export", file.source_path.display()),
+            ));
+        }
+        writer.write_all(&crc.to_le_bytes())?;
+        manifest.push_str(&manifest_line(&file.dest, file.len, crc));
+        bytes += file.len;
+    }
+    write_entry_header(writer, MANIFEST, manifest.len() as u64)?;
+    writer.write_all(manifest.as_bytes())?;
+    writer.write_all(&crc32fast::hash(manifest.as_bytes()).to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.flush()?;
+
+    Ok(BackupInfo {
+        files: pending.len(),
+        bytes,
+        max_sequence,
+    })
+}
+
+fn write_entry_header(writer: &mut dyn Write, name: &str, len: u64) -> io::Result<()> {
+    writer.write_all(&(name.len() as u32).to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+/// Unpack an archive written by [`export_archive`] into `target_dir`.
+///
+/// Files are written into a staging directory as they are read, each checked against
+/// the CRC32 that follows it, and the manifest at the end must list exactly the files
+/// received. Only then does the staging directory take the target's place. A non-empty
+/// target is refused unless `force` is set, in which case its contents are replaced.
+pub(crate) fn import_archive(
+    reader: &mut dyn Read,
+    target_dir: &Path,
+    force: bool,
+) -> io::Result<()> {
+    if !force {
+        ensure_empty(target_dir, "import target")?;
+    }
+    replace_dir(target_dir, |staging| unpack_archive(reader, staging))
+}
+
+fn unpack_archive(reader: &mut dyn Read, staging: &Path) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let truncated = |err: io::Error| match err.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("archive is truncated".to_string()),
+        _ => err,
+    };
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(truncated)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(invalid("not a database archive".to_string()));
+    }
+
+    let mut unpacked = String::new();
+    let mut manifest = None;
+    loop {
+        let name_len = read_u32(reader).map_err(truncated)? as usize;
+        if name_len == 0 {
+            break;
+        }
+        if name_len > MAX_ARCHIVE_NAME {
+            return Err(invalid(format!("archive entry name of {} bytes", name_len)));
+        }
+        let mut name = vec![0u8; name_len];
+        reader.read_exact(&mut name).map_err(truncated)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| invalid("archive entry name is not UTF-8".to_string()))?;
+        let len = read_u64(reader).map_err(truncated)?;
+
+        if name == MANIFEST {
+            let mut text = Vec::new();
+            reader.take(len).read_to_end(&mut text)?;
+            if text.len() as u64 != len {
+                return Err(invalid("archive is truncated".to_string()));
+            }
+            if read_u32(reader).map_err(truncated)? != crc32fast::hash(&text) {
+                return Err(invalid("archive manifest fails its checksum".to_string()));
+            }
+            let text = String::from_utf8(text)
+                .map_err(|_| invalid("archive manifest is not UTF-8".to_string()))?;
+            manifest = Some(text);
+            continue;
+        }
+
+        let path = relative_path(&name)
+            .ok_or_else(|| invalid(format!("archive entry {:?} is not a relative path", name)))?;
+        let dest = staging.join(path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = File::create(&dest)?;
+        let (copied, crc) = copy_counted(&mut *reader, len, &mut out)?;
+        if copied != len {
+            return Err(invalid(format!("archive is truncated inside {}", name)));
+        }
+        if read_u32(reader).map_err(truncated)? != crc {
+            return Err(invalid(format!("archived file {} fails its checksum", name)));
+        }
+        out.sync_all()?;
+        unpacked.push_str(&manifest_line(path, len, crc));
+    }
+
+    match manifest {
+        Some(manifest) if manifest == unpacked => Ok(()),
+        Some(_) => Err(invalid("archive contents do not match its manifest".to_string())),
+        None => Err(invalid("archive has no manifest (incomplete archive?)".to_string())),
+    }
+}
+
+fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn ensure_empty(dir: &Path, what: &str) -> io::Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} {} is not empty", what, dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Restore the backup in `backup_dir` into `target_dir`.
+///
+/// Every file listed in the manifest is copied into a staging directory next to the
+/// target and checked against its recorded size and CRC32; only when all of them match
+/// does the staging directory take the target's place. A non-empty target is refused
+/// unless `force` is set, in which case its contents are replaced.
+pub(crate) fn restore(backup_dir: &Path, target_dir: &Path, force: bool) -> io::Result<()> {
+    if !force {
+        ensure_empty(target_dir, "restore target")?;
+    }
+
+    let manifest_path = backup_dir.join(MANIFEST);
+    let manifest = fs::read_to_string(&manifest_path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("cannot read {}: {} (incomplete backup?)", manifest_path.display(), err),
+        )
+    })?;
+
+    replace_dir(target_dir, |staging| restore_files(backup_dir, &manifest, staging))
+}
+
+/// Fill a staging directory next to `target_dir` with `fill`, then put it in the
+/// target's place. The target is left alone if `fill` fails.
+fn replace_dir<F>(target_dir: &Path, fill: F) -> io::Result<()>
+where
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    let mut staging = target_dir.as_os_str().to_owned();
+    staging.push(".restoring");
+    let staging = PathBuf::from(staging);
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging)?;
+    if let Err(err) = fill(&staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(err);
+    }
+
+    if target_dir.exists() {
+        fs::remove_dir_all(target_dir)?;
+    }
+    fs::rename(&staging, target_dir)
+}
+
+fn restore_files(backup_dir: &Path, manifest: &str, staging: &Path) -> io::Result<()> {
+    for line in manifest.lines() {
+        let (name, len, crc) = parse_manifest_line(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed manifest line {:?}", line),
+            )
+        })?;
+
+        let source_path = backup_dir.join(name);
+        let source = File::open(&source_path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("backup file {} is unreadable: {}", source_path.display(), err),
+            )
+        })?;
+        let actual_len = source.metadata()?.len();
+        if actual_len != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "backup file {} is {} bytes, expected {}",
+                    source_path.display(),
+                    actual_len,
+                    len
+                ),
+            ));
+        }
+
+        let dest = staging.join(name);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if copy_prefix(&source, len, &dest)? != crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("backup file {} fails its checksum", source_path.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Split a manifest line into a relative path, size, and CRC32. Paths that could escape
+/// the target directory are rejected.
+fn parse_manifest_line(line: &str) -> Option<(&Path, u64, u32)> {
+    let mut fields = line.split('\t');
+    let name = relative_path(fields.next()?)?;
+    let len = fields.next()?.parse().ok()?;
+    let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+    fields.next().is_none().then_some((name, len, crc))
+}
+
+/// `name` as a path, unless it could escape the directory it is joined to
+fn relative_path(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    let relative = path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    relative.then_some(path)
+}
+
+/// The manifest line of the file at `path`, relative to the database directory
+fn manifest_line(path: &Path, len: u64, crc: u32) -> String {
+    let name = path.to_string_lossy().replace('\\', "/");
+    format!("{}\t{}\t{:08x}\n", name, len, crc)
+}
+
+/// Copy at most `len` bytes of `source` to `out`, returning how many there were and
+/// their CRC32
+fn copy_counted(source: impl Read, len: u64, out: &mut dyn Write) -> io::Result<(u64, u32)> {
+    let mut reader = source.take(len);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    Ok((copied, hasher.finalize()))
+}
+
+/// Copy the first `len` bytes of `source` to a new file at `dest` and return their CRC32
+fn copy_prefix(source: &File, len: u64, dest: &Path) -> io::Result<u32> {
+    let mut out = File::create(dest)?;
+    let (copied, crc) = copy_counted(source, len, &mut out)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} was truncated during backup", dest.display()),
+        ));
+    }
+    out.sync_all()?;
+    Ok(crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use std::fs;
+    use std::io;
+    use std::thread;
+
+    #[test]
+    fn test_backup_under_concurrent_writes() {
+        let dir = "test_backup_source";
+        let dest = "test_backup_dest";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(dest);
+
+        let db = Db::open(dir).unwrap();
+        let events = db.create_cf("events").unwrap();
+        for i in 0..250 {
+            db.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        events.put("event", "logged").unwrap();
+        let committed = db.last_sequence();
+
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..250 {
+                    db.put(format!("later{:04}", i), "v").unwrap();
+                }
+            })
+        };
+        let info = db.backup(dest).unwrap();
+        writer.join().unwrap();
+
+        assert!(info.files >= 3);
+        assert!(info.max_sequence >= committed);
+        assert!(db.backup(dest).is_err());
+
+        let restored = Db::open(dest).unwrap();
+        for i in 0..250 {
+            let key = format!("key{:04}", i);
+            assert_eq!(restored.get(&key), Some(format!("value{}", i)));
+        }
+        assert_eq!(restored.cf("events").unwrap().get("event"), Some("logged".to_string()));
+        assert_eq!(restored.last_sequence(), info.max_sequence);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn test_restore_round_trip() {
+        let dir = "test_restore_source";
+        let backup = "test_restore_backup";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(backup);
+
+        let db = Db::open(dir).unwrap();
+        db.put("kept", "before backup").unwrap();
+        db.create_cf("events").unwrap().put("event", "logged").unwrap();
+        db.backup(backup).unwrap();
+        drop(db);
+
+        // Lose the original, then restore over its remains
+        fs::remove_file(format!("{}/sstable_000000.sst", dir)).unwrap();
+        assert!(Db::restore(backup, dir, false).is_err());
+        Db::restore(backup, dir, true).unwrap();
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("kept"), Some("before backup".to_string()));
+        assert_eq!(db.cf("events").unwrap().get("event"), Some("logged".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(backup).unwrap();
+    }
+
+    #[test]
+    fn test_restore_rejects_damaged_backup() {
+        let dir = "test_restore_damaged_source";
+        let backup = "test_restore_damaged_backup";
+        let target = "test_restore_damaged_target";
+        for path in [dir, backup, target] {
+            let _ = fs::remove_dir_all(path);
+        }
+
+        let db = Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+        db.put("b", "2").unwrap();
+        db.backup(backup).unwrap();
+
+        // A flipped byte fails the checksum
+        let table = format!("{}/sstable_000001.sst", backup);
+        let mut bytes = fs::read(&table).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&table, &bytes).unwrap();
+        let err = Db::restore(backup, target, false).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        // A missing file is named in the error
+        fs::remove_file(&table).unwrap();
+        let err = Db::restore(backup, target, false).unwrap_err();
+        assert!(err.to_string().contains("sstable_000001.sst"), "{}", err);
+        assert!(!fs::exists(target).unwrap());
+
+        for path in [dir, backup] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_reflects_state_at_creation() {
+        let dir = "test_checkpoint_source";
+        let checkpoint = "test_checkpoint_dest";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(checkpoint);
+
+        let options = Options {
+            value_log_threshold: Some(16),
+            ..Options::default()
+        };
+        let large = "x".repeat(100);
+        let db = Db::open_with_options(dir, options.clone()).unwrap();
+        db.put("flushed", "v1").unwrap();
+        db.flush().unwrap();
+        db.put("pending", "v1").unwrap();
+        db.put("blob", large.as_str()).unwrap();
+        let seq = db.checkpoint(checkpoint).unwrap();
+        assert_eq!(seq, db.last_sequence());
+
+        db.put("flushed", "v2").unwrap();
+        db.put("later", "v2").unwrap();
+        db.put("blob2", large.as_str()).unwrap();
+        db.flush().unwrap();
+        // Stand-in for compaction removing the original tables
+        fs::remove_file(format!("{}/sstable_000000.sst", dir)).unwrap();
+        fs::remove_file(format!("{}/sstable_000001.sst", dir)).unwrap();
+
+        let restored = Db::open_with_options(checkpoint, options).unwrap();
+        assert_eq!(restored.last_sequence(), seq);
+        assert_eq!(restored.get("flushed"), Some("v1".to_string()));
+        assert_eq!(restored.get("pending"), Some("v1".to_string()));
+        assert_eq!(restored.get("blob"), Some(large.clone()));
+        assert_eq!(restored.get("later"), None);
+
+        // Writing to the checkpoint leaves the original alone
+        restored.put("blob3", "y".repeat(100)).unwrap();
+        assert_eq!(db.get("blob2"), Some(large));
+        assert_eq!(db.get("blob3"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(checkpoint).unwrap();
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = "test_archive_source";
+        let target = "test_archive_target";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(target);
+
+        let options = Options {
+            value_log_threshold: Some(16),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(dir, options.clone()).unwrap();
+        db.put("flushed", "v1").unwrap();
+        db.flush().unwrap();
+        db.put("pending", "v1").unwrap();
+        db.put("blob", "x".repeat(100)).unwrap();
+        db.create_cf("events").unwrap().put("event", "logged").unwrap();
+
+        let mut transfer = Vec::new();
+        let info = db.export_archive(&mut transfer).unwrap();
+        assert_eq!(info.max_sequence, db.last_sequence());
+        assert!(info.files >= 4);
+        assert!(transfer.len() as u64 > info.bytes);
+
+        Db::import_archive(transfer.as_slice(), target, false).unwrap();
+        let err = Db::import_archive(transfer.as_slice(), target, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        Db::import_archive(transfer.as_slice(), target, true).unwrap();
+
+        let imported = Db::open_with_options(target, options).unwrap();
+        assert_eq!(imported.last_sequence(), info.max_sequence);
+        assert_eq!(imported.get("flushed"), Some("v1".to_string()));
+        assert_eq!(imported.get("pending"), Some("v1".to_string()));
+        assert_eq!(imported.get("blob"), Some("x".repeat(100)));
+        assert_eq!(imported.cf("events").unwrap().get("event"), Some("logged".to_string()));
+
+        drop((db, imported));
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_import_detects_corrupt_archive() {
+        let dir = "test_archive_corrupt_source";
+        let target = "test_archive_corrupt_target";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(target);
+
+        let db = Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+        let mut transfer = Vec::new();
+        db.export_archive(&mut transfer).unwrap();
+
+        // Flip a byte inside the first table's contents, past its name and length
+        let name = b"sstable_000000.sst";
+        let at = transfer.windows(name.len()).position(|w| w == name).unwrap();
+        let mut damaged = transfer.clone();
+        damaged[at + name.len() + 8 + 10] ^= 0xff;
+        let err = Db::import_archive(damaged.as_slice(), target, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("sstable_000000.sst fails its checksum"), "{}", err);
+        assert!(!fs::exists(target).unwrap());
+
+        // An archive cut short never gets to its manifest
+        let err = Db::import_archive(&transfer[..transfer.len() / 2], target, false);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(!fs::exists(target).unwrap());
+
+        drop(db);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use crate::entry::Op;
+
+/// A group of puts, deletes, and merges applied atomically by [`crate::Db::write`].
+///
+/// Operations apply in the order they were added, so a later operation on the same key
+/// wins.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, Op)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.ops.push((key.into(), Op::Put(value.into())));
+        self
+    }
+
+    pub fn delete<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut Self {
+        self.ops.push((key.into(), Op::Delete));
+        self
+    }
+
+    pub fn merge<K, V>(&mut self, key: K, operand: V) -> &mut Self
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.ops.push((key.into(), Op::Merge(operand.into())));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, key: Vec<u8>, op: Op) {
+        self.ops.push((key, op));
+    }
+
+    /// Operations in application order
+    pub fn ops(&self) -> &[(Vec<u8>, Op)] {
+        &self.ops
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The arguments after a command name: positionals, `--switch` flags, and
+/// `--option <value>` pairs, which may appear in any order
+pub struct CommandArgs<'a> {
+    positional: Vec<&'a str>,
+    switches: Vec<&'a str>,
+    values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> CommandArgs<'a> {
+    /// Parse `args`, accepting only the flags named in `switches` and `options`
+    pub fn parse(args: &[&'a str], switches: &[&str], options: &[&str]) -> Result<Self, String> {
+        let mut parsed = CommandArgs {
+            positional: Vec::new(),
+            switches: Vec::new(),
+            values: HashMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.positional.push(arg);
+            } else if switches.contains(&arg) {
+                parsed.switches.push(arg);
+            } else if options.contains(&arg) {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                parsed.values.insert(arg, value);
+            } else {
+                return Err(format!("unknown option {}", arg));
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn positional(&self) -> &[&'a str] {
+        &self.positional
+    }
+
+    pub fn has(&self, switch: &str) -> bool {
+        self.switches.contains(&switch)
+    }
+
+    /// The parsed value of `option`, `None` if it was not given
+    pub fn value<T: FromStr>(&self, option: &str) -> Result<Option<T>, String> {
+        match self.values.get(option) {
+            Some(text) => text
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value {:?} for {}", text, option)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! `bench`: drive the engine with a synthetic workload and report throughput and latency
+
+use super::args::CommandArgs;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{self, ExitCode};
+use std::thread;
+use std::time::Instant;
+use storage_engine::{Compression, Db, Options, SyncMode, SyncPolicy, WriteBatch};
+
+/// Rows per batch when loading keys for a read workload
+const PRELOAD_BATCH: u64 = 1000;
+
+pub struct Config {
+    /// Timed puts, split across the threads
+    pub writes: u64,
+    /// Timed gets, split across the threads and interleaved with the puts
+    pub reads: u64,
+    /// Keys are drawn from `0..key_space`; it is loaded before timing when there are reads
+    pub key_space: u64,
+    pub value_size: usize,
+    pub threads: usize,
+    pub random_keys: bool,
+    pub sync_policy: SyncPolicy,
+    pub sync_mode: SyncMode,
+    pub wal_compression: Option<Compression>,
+    /// Shortest value compressed; unset means the engine's default
+    pub compress_above: Option<usize>,
+    /// Leave the database directory in place afterwards
+    pub keep: bool,
+    pub dir: PathBuf,
+}
+
+impl Config {
+    pub const SWITCHES: &'static [&'static str] = &["--keep"];
+    pub const OPTIONS: &'static [&'static str] = &[
+        "--writes",
+        "--reads",
+        "--key-space",
+        "--value-size",
+        "--threads",
+        "--keys",
+        "--sync",
+        "--sync-mode",
+        "--wal-compression",
+        "--compress-above",
+    ];
+
+    pub fn from_args(args: &CommandArgs) -> Result<Self, String> {
+        if !args.positional().is_empty() {
+            return Err("bench takes only options".to_string());
+        }
+        let writes = args.value("--writes")?.unwrap_or(100_000);
+        let reads = args.value("--reads")?.unwrap_or(0);
+        let default_key_space = if writes > 0 { writes } else { 100_000 };
+        let key_space = args.value("--key-space")?.unwrap_or(default_key_space);
+        let threads = args.value("--threads")?.unwrap_or(1);
+        if key_space == 0 || threads == 0 {
+            return Err("--key-space and --threads must be at least 1".to_string());
+        }
+
+        let random_keys = match args.value::<String>("--keys")?.as_deref() {
+            None | Some("sequential") => false,
+            Some("random") => true,
+            Some(other) => {
+                return Err(format!("--keys must be sequential or random, not {}", other))
+            }
+        };
+        let sync_policy = args.value("--sync")?.unwrap_or(SyncPolicy::Always);
+        let sync_mode = args.value("--sync-mode")?.unwrap_or(SyncMode::SyncAll);
+        let wal_compression = match args.value::<String>("--wal-compression")?.as_deref() {
+            None | Some("none") => None,
+            Some(other) => Some(
+                other.parse::<Compression>().map_err(|e| format!("--wal-compression: {}", e))?,
+            ),
+        };
+
+        Ok(Config {
+            writes,
+            reads,
+            key_space,
+            value_size: args.value("--value-size")?.unwrap_or(100),
+            threads,
+            random_keys,
+            sync_policy,
+            sync_mode,
+            wal_compression,
+            compress_above: args.value("--compress-above")?,
+            keep: args.has("--keep"),
+            dir: std::env::temp_dir().join(format!("storage-engine-bench-{}", process::id())),
+        })
+    }
+}
+
+/// Latencies in nanoseconds, per operation type
+#[derive(Default)]
+struct Latencies {
+    puts: Vec<u64>,
+    gets: Vec<u64>,
+}
+
+/// Run the workload in a fresh database and print a summary table to `out`
+pub fn run<W: Write>(config: &Config, out: &mut W) -> io::Result<ExitCode> {
+    let _ = fs::remove_dir_all(&config.dir);
+    let options = Options {
+        sync_policy: config.sync_policy,
+        wal_sync_mode: config.sync_mode,
+        wal_compression: config.wal_compression,
+        wal_compression_threshold: config.compress_above,
+        ..Options::default()
+    };
+    let db = Db::open_with_options(&config.dir, options)?;
+    if config.reads > 0 {
+        preload(&db, config)?;
+        db.reset_stats();
+    }
+
+    let start = Instant::now();
+    let results: Vec<io::Result<Latencies>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.threads)
+            .map(|thread| {
+                let db = &db;
+                scope.spawn(move || worker(db, config, thread))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker.join().unwrap_or_else(|_| Err(io::Error::other("benchmark thread panicked")))
+            })
+            .collect()
+    });
+    let elapsed = start.elapsed();
+    let stats = db.stats()?;
+    drop(db);
+
+    let mut latencies = Latencies::default();
+    for result in results {
+        let thread = result?;
+        latencies.puts.extend(thread.puts);
+        latencies.gets.extend(thread.gets);
+    }
+
+    writeln!(
+        out,
+        "{} threads, {} writes, {} reads, {} keys over {}, {}-byte values, sync {:?} ({:?})",
+        config.threads,
+        config.writes,
+        config.reads,
+        if config.random_keys { "random" } else { "sequential" },
+        config.key_space,
+        config.value_size,
+        config.sync_policy,
+        config.sync_mode
+    )?;
+    writeln!(
+        out,
+        "{:<6}{:>10}{:>12}{:>10}{:>10}{:>10}",
+        "op", "count", "ops/sec", "p50 us", "p99 us", "max us"
+    )?;
+    for (name, samples) in [("put", &mut latencies.puts), ("get", &mut latencies.gets)] {
+        if samples.is_empty() {
+            continue;
+        }
+        samples.sort_unstable();
+        let micros = |nanos: u64| nanos as f64 / 1000.0;
+        writeln!(
+            out,
+            "{:<6}{:>10}{:>12.0}{:>10.1}{:>10.1}{:>10.1}",
+            name,
+            samples.len(),
+            samples.len() as f64 / elapsed.as_secs_f64(),
+            micros(percentile(samples, 50)),
+            micros(percentile(samples, 99)),
+            micros(samples[samples.len() - 1])
+        )?;
+    }
+    writeln!(out, "elapsed {:.3}s", elapsed.as_secs_f64())?;
+    let uncompressed = stats.wal_bytes_written + stats.wal_bytes_saved;
+    writeln!(
+        out,
+        "wal {} bytes written, {} saved by compression ({:.1}% smaller)",
+        stats.wal_bytes_written,
+        stats.wal_bytes_saved,
+        stats.wal_bytes_saved as f64 * 100.0 / uncompressed.max(1) as f64
+    )?;
+
+    if config.keep {
+        writeln!(out, "database kept in {}", config.dir.display())?;
+    } else {
+        fs::remove_dir_all(&config.dir)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Write every key in the key space, untimed, so reads find data
+fn preload(db: &Db, config: &Config) -> io::Result<()> {
+    let value = vec![b'v'; config.value_size];
+    let mut batch = WriteBatch::new();
+    for index in 0..config.key_space {
+        batch.put(key(index), value.clone());
+        if batch.len() as u64 >= PRELOAD_BATCH {
+            db.write(&batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+    db.write(&batch)
+}
+
+fn worker(db: &Db, config: &Config, thread: usize) -> io::Result<Latencies> {
+    let (first_write, writes) = share(config.writes, config.threads, thread);
+    let (first_read, reads) = share(config.reads, config.threads, thread);
+    let mut rng = Rng::new(thread as u64);
+    let value = vec![b'v'; config.value_size];
+    let mut latencies = Latencies::default();
+
+    let (mut written, mut read) = (0, 0);
+    while written < writes || read < reads {
+        // Interleave the two kinds in proportion to how many of each remain
+        let remaining_reads = reads - read;
+        let is_read = rng.below(writes - written + remaining_reads) < remaining_reads;
+        if is_read {
+            let index = if config.random_keys {
+                rng.below(config.key_space)
+            } else {
+                (first_read + read) % config.key_space
+            };
+            let key = key(index);
+            let start = Instant::now();
+            db.get_bytes(&key);
+            latencies.gets.push(start.elapsed().as_nanos() as u64);
+            read += 1;
+        } else {
+            let index = if config.random_keys {
+                rng.below(config.key_space)
+            } else {
+                first_write + written
+            };
+            let key = key(index);
+            let start = Instant::now();
+            db.put(key, value.as_slice())?;
+            latencies.puts.push(start.elapsed().as_nanos() as u64);
+            written += 1;
+        }
+    }
+    Ok(latencies)
+}
+
+/// The first index and count of `thread`'s part of `total` operations
+fn share(total: u64, threads: usize, thread: usize) -> (u64, u64) {
+    let (threads, thread) = (threads as u64, thread as u64);
+    let base = total / threads;
+    let extra = total % threads;
+    let first = thread * base + thread.min(extra);
+    (first, base + u64::from(thread < extra))
+}
+
+fn key(index: u64) -> String {
+    format!("key{:016}", index)
+}
+
+/// `p`th percentile of sorted, non-empty `samples`
+fn percentile(samples: &[u64], p: usize) -> u64 {
+    samples[(samples.len() - 1) * p / 100]
+}
+
+/// xorshift64*, deterministic per thread
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(0x9e37_79b9_7f4a_7c15 ^ (seed + 1))
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_covers_everything() {
+        let parts: Vec<_> = (0..3).map(|t| share(10, 3, t)).collect();
+        assert_eq!(parts, vec![(0, 4), (4, 3), (7, 3)]);
+        assert_eq!("every:8".parse(), Ok(SyncPolicy::EveryN(8)));
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_mixed_run() {
+        let config = Config {
+            writes: 300,
+            reads: 200,
+            key_space: 150,
+            value_size: 16,
+            threads: 2,
+            random_keys: true,
+            sync_policy: SyncPolicy::Never,
+            sync_mode: SyncMode::SyncData,
+            wal_compression: None,
+            compress_above: None,
+            keep: false,
+            dir: PathBuf::from("test_bench_mixed"),
+        };
+        let mut out = Vec::new();
+        run(&config, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("\nput          300"), "{}", out);
+        assert!(out.contains("\nget          200"), "{}", out);
+        assert!(out.contains("sync Never (SyncData)"), "{}", out);
+        assert!(out.contains(" 0 saved by compression (0.0% smaller)"), "{}", out);
+        assert!(!config.dir.exists());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compressed_wal_run() {
+        let args = [
+            "--writes", "200", "--value-size", "2000", "--sync", "never",
+            "--wal-compression", "lz4", "--compress-above", "1000",
+        ];
+        let args = CommandArgs::parse(&args, Config::SWITCHES, Config::OPTIONS).unwrap();
+        let config = Config::from_args(&args).unwrap();
+        let config = Config { dir: PathBuf::from("test_bench_compressed"), ..config };
+        let mut out = Vec::new();
+        run(&config, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let wal = out.lines().find(|line| line.starts_with("wal ")).unwrap();
+        let written: u64 = wal.split(' ').nth(1).unwrap().parse().unwrap();
+        assert!(written < 200 * 2000 / 10, "{}", out);
+        assert!(wal.contains("% smaller") && !wal.contains(" 0 saved"), "{}", out);
+    }
+}
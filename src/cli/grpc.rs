@@ -0,0 +1,80 @@
+//! `serve-grpc`: the gRPC service of `proto/storage.proto`
+
+use super::server::Listen;
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+use storage_engine::Options;
+
+/// Address served when `--listen` is not given
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:50051";
+
+/// Serve the database in `dir` on `listen` until SIGINT or SIGTERM, then flush it.
+///
+/// As with the other servers, the bound address is printed first.
+#[cfg(feature = "grpc")]
+pub fn run(dir: &Path, options: &Options, listen: &Listen) -> io::Result<ExitCode> {
+    let db = storage_engine::Db::open_with_options(dir, options.clone())?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(serve::serve(db.clone(), listen))?;
+    drop(runtime);
+
+    db.flush()?;
+    println!("flushed, shutting down");
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "grpc"))]
+pub fn run(_dir: &Path, _options: &Options, _listen: &Listen) -> io::Result<ExitCode> {
+    let message = "serve-grpc needs the `grpc` feature";
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+#[cfg(feature = "grpc")]
+mod serve {
+    use super::super::server::{Listen, UnixSocket};
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::io;
+    use std::thread;
+    use storage_engine::{Db, GrpcService};
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio::sync::oneshot;
+    use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+    use tonic::transport::Server;
+
+    pub(super) async fn serve(db: Db, listen: &Listen) -> io::Result<()> {
+        let (stop, stopped) = oneshot::channel();
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let signal_handle = signals.handle();
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                let _ = stop.send(());
+            }
+        });
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+
+        let router = Server::builder().add_service(GrpcService::new(db).into_server());
+        let served = match listen {
+            Listen::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                println!("listening on {}", listener.local_addr()?);
+                let incoming = TcpListenerStream::new(listener);
+                router.serve_with_incoming_shutdown(incoming, shutdown).await
+            }
+            Listen::Unix { path, mode } => {
+                // Dropped once serving stops, which removes the socket file
+                let socket = UnixSocket::bind(path, *mode)?;
+                let listener = socket.listener().try_clone()?;
+                listener.set_nonblocking(true)?;
+                println!("listening on unix:{}", path.display());
+                let incoming = UnixListenerStream::new(UnixListener::from_std(listener)?);
+                router.serve_with_incoming_shutdown(incoming, shutdown).await
+            }
+        };
+        signal_handle.close();
+        served.map_err(io::Error::other)
+    }
+}
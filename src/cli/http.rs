@@ -0,0 +1,390 @@
+//! `serve-http`: a small REST API over HTTP/1.1
+//!
+//! - `GET /keys/{key}`: the value, or 404
+//! - `PUT /keys/{key}`: store the request body as the value
+//! - `DELETE /keys/{key}`: 204, or 404 if the key was missing
+//! - `GET /keys?prefix=..&limit=..`: matching pairs as JSON, in key order
+//! - `GET /metrics`: engine statistics for Prometheus
+
+use super::server::{self, Listen, Stream};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use storage_engine::{Db, Options};
+
+/// Address served when `--listen` is not given
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+/// Longest request line or header line accepted
+const MAX_LINE_BYTES: u64 = 8 << 10;
+/// Largest request body accepted
+const MAX_BODY_BYTES: usize = 512 << 20;
+/// Content type of the Prometheus text exposition format
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Pairs listed when the request gives no `limit`
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// Cap on `limit`, so one request can't make the server buffer the whole database
+const MAX_LIST_LIMIT: usize = 10_000;
+
+/// Serve the database in `dir` over HTTP on `listen` until interrupted
+pub fn run(dir: &Path, options: &Options, listen: &Listen) -> io::Result<ExitCode> {
+    server::run(dir, options, listen, serve_connection)
+}
+
+fn serve_connection(db: &Db, stream: Stream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Response::text(400, e.to_string()).write(&mut writer, true)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        route(db, &request).write(&mut writer, request.close)?;
+        writer.flush()?;
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    /// The request target, still percent-encoded
+    target: String,
+    body: Vec<u8>,
+    /// Whether the connection ends after the response
+    close: bool,
+}
+
+/// Read one request with its body, `None` if the client closed the connection first
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid(format!("malformed request line {:?}", line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid(format!("unsupported version {:?}", version)));
+    }
+
+    let mut close = version == "HTTP/1.0";
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(reader)?.ok_or_else(eof)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid(format!("malformed header {:?}", line)));
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let length = value.parse().map_err(|_| invalid("invalid Content-Length"))?;
+                content_length = Some(length);
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+
+    let body = if chunked {
+        read_chunked(reader)?
+    } else {
+        let length = content_length.unwrap_or(0);
+        if length > MAX_BODY_BYTES {
+            return Err(invalid("request body too large"));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        body
+    };
+    Ok(Some(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        body,
+        close,
+    }))
+}
+
+/// A body sent with `Transfer-Encoding: chunked`, as `curl -T -` does
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(eof)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > MAX_BODY_BYTES {
+            return Err(invalid("request body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size + 2, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !body.ends_with(b"\r\n") {
+            return Err(invalid("chunk not terminated by CRLF"));
+        }
+        body.truncate(start + size);
+    }
+    // Trailer fields are ignored
+    while !read_line(reader)?.ok_or_else(eof)?.is_empty() {}
+    Ok(body)
+}
+
+/// A line without its CRLF, `None` at end of stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.by_ref().take(MAX_LINE_BYTES).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long or incomplete"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| invalid("request head is not UTF-8"))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn eof() -> io::Error {
+    io::ErrorKind::UnexpectedEof.into()
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// Methods to list in the `Allow` header of a 405
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response { status, content_type, body, allow: None }
+    }
+
+    fn text(status: u16, message: impl Into<String>) -> Self {
+        let mut body = message.into().into_bytes();
+        body.push(b'\n');
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    fn empty(status: u16) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", Vec::new())
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Response {
+            allow: Some(allow),
+            ..Response::text(405, "method not allowed")
+        }
+    }
+
+    fn write<W: Write>(&self, out: &mut W, close: bool) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason)?;
+        if self.status != 204 {
+            write!(out, "Content-Type: {}\r\n", self.content_type)?;
+            write!(out, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if let Some(allow) = self.allow {
+            write!(out, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            out.write_all(b"Connection: close\r\n")?;
+        }
+        out.write_all(b"\r\n")?;
+        out.write_all(&self.body)
+    }
+}
+
+impl From<io::Error> for Response {
+    fn from(e: io::Error) -> Self {
+        Response::text(500, e.to_string())
+    }
+}
+
+fn route(db: &Db, request: &Request) -> Response {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+    if path == "/metrics" {
+        return match request.method.as_str() {
+            "GET" => match db.metrics_text() {
+                Ok(text) => Response::new(200, PROMETHEUS_TEXT, text.into_bytes()),
+                Err(e) => e.into(),
+            },
+            _ => Response::method_not_allowed("GET"),
+        };
+    }
+    if path == "/keys" {
+        return match request.method.as_str() {
+            "GET" => list(db, query).unwrap_or_else(Response::from),
+            _ => Response::method_not_allowed("GET"),
+        };
+    }
+    let Some(key) = path.strip_prefix("/keys/") else {
+        return Response::text(404, "no such route");
+    };
+    let key = match percent_decode(key, false) {
+        Some(key) if !key.is_empty() => key,
+        _ => return Response::text(400, "invalid key"),
+    };
+
+    let result = match request.method.as_str() {
+        "GET" => db.try_get_bytes(&key).map(|value| match value {
+            Some(value) => Response::new(200, content_type(&value), value),
+            None => Response::text(404, "key not found"),
+        }),
+        "PUT" => db.put(key.as_slice(), request.body.as_slice()).map(|()| Response::empty(204)),
+        "DELETE" => db.delete_bytes(&key).map(|previous| match previous {
+            Some(_) => Response::empty(204),
+            None => Response::text(404, "key not found"),
+        }),
+        _ => Ok(Response::method_not_allowed("GET, PUT, DELETE")),
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+/// Values are stored as sent, whatever the request's Content-Type; they are served as
+/// text when they are UTF-8 and as raw bytes otherwise
+fn content_type(value: &[u8]) -> &'static str {
+    if std::str::from_utf8(value).is_ok() {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// `GET /keys?prefix=..&limit=..`: up to `limit` pairs whose key starts with `prefix`,
+/// and whether more follow. Keys and values that are not UTF-8 are sent as base64 with
+/// a `key_base64` / `value_base64` flag, as in JSON exports.
+fn list(db: &Db, query: &str) -> io::Result<Response> {
+    let mut prefix = Vec::new();
+    let mut limit = DEFAULT_LIST_LIMIT;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let Some(value) = percent_decode(value, true) else {
+            return Ok(Response::text(400, format!("invalid {}", name)));
+        };
+        match name {
+            "prefix" => prefix = value,
+            "limit" => match String::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                Some(value) => limit = MAX_LIST_LIMIT.min(value),
+                None => return Ok(Response::text(400, "invalid limit")),
+            },
+            _ => {}
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut truncated = false;
+    for item in db.iter_from(&prefix) {
+        let (key, value) = item?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if entries.len() == limit {
+            truncated = true;
+            break;
+        }
+        let mut entry = Map::new();
+        insert_bytes(&mut entry, "key", key);
+        insert_bytes(&mut entry, "value", value);
+        entries.push(Value::Object(entry));
+    }
+
+    let body = json!({ "entries": entries, "truncated": truncated });
+    Ok(Response::new(200, "application/json", body.to_string().into_bytes()))
+}
+
+fn insert_bytes(object: &mut Map<String, Value>, field: &str, bytes: Vec<u8>) {
+    match String::from_utf8(bytes) {
+        Ok(text) => {
+            object.insert(field.to_string(), Value::String(text));
+        }
+        Err(err) => {
+            let encoded = BASE64.encode(err.into_bytes());
+            object.insert(field.to_string(), Value::String(encoded));
+            object.insert(format!("{}_base64", field), Value::Bool(true));
+        }
+    }
+}
+
+/// Decode `%XX` escapes (and `+` as a space in query strings); `None` if an escape is
+/// malformed
+fn percent_decode(text: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut bytes = text.bytes();
+    let mut decoded = Vec::with_capacity(text.len());
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let mut input: &[u8] = b"PUT /keys/a%20b HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\n\
+            abc\
+            PUT /keys/c HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+            4\r\nwiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n";
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str()), ("PUT", "/keys/a%20b"));
+        assert_eq!((request.body.as_slice(), request.close), (&b"abc"[..], false));
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!((request.body.as_slice(), request.close), (&b"wikipedia"[..], true));
+        assert!(read_request(&mut input).unwrap().is_none());
+
+        let mut garbage: &[u8] = b"hello\r\n\r\n";
+        assert_eq!(read_request(&mut garbage).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb%00", false), Some(b"a/b\0".to_vec()));
+        assert_eq!(percent_decode("a+b", false), Some(b"a+b".to_vec()));
+        assert_eq!(percent_decode("a+b", true), Some(b"a b".to_vec()));
+        assert_eq!(percent_decode("%zz", false), None);
+        assert_eq!(percent_decode("%4", false), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! One-shot commands that open the database, perform a single operation, and exit.
+//!
+//! Every write is synced to the WAL before it returns, so closing the handle is all
+//! the shutdown these need; flushing would leave one tiny SSTable per invocation.
+
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use storage_engine::{Db, Options};
+
+/// Exit status of `get` when the key does not exist
+pub const EXIT_NOT_FOUND: u8 = 3;
+
+/// Store `value` under `key`; a value of `-` is read from stdin
+pub fn put(dir: &Path, options: &Options, key: &str, value: &str) -> io::Result<ExitCode> {
+    let value = if value == "-" {
+        let mut buf = Vec::new();
+        io::stdin().lock().read_to_end(&mut buf)?;
+        buf
+    } else {
+        value.as_bytes().to_vec()
+    };
+    Db::open_with_options(dir, options.clone())?.put(key, value)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Write the value of `key` to stdout as-is, followed by a newline only on a terminal
+pub fn get(dir: &Path, options: &Options, key: &str) -> io::Result<ExitCode> {
+    let Some(value) = open_read_only(dir, options)?.try_get_bytes(key)? else {
+        eprintln!("key not found: {}", key);
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    };
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&value)?;
+    if stdout.is_terminal() {
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn del(dir: &Path, options: &Options, key: &str) -> io::Result<ExitCode> {
+    Db::open_with_options(dir, options.clone())?.delete(key)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print every pair whose key starts with `prefix` as `key<TAB>value` lines
+pub fn scan(dir: &Path, options: &Options, prefix: &str) -> io::Result<ExitCode> {
+    let entries = open_read_only(dir, options)?.scan_prefix(prefix)?;
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    for (key, value) in entries {
+        writeln!(stdout, "{}\t{}", key, value)?;
+    }
+    stdout.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads take a shared lock so they can run alongside each other
+fn open_read_only(dir: &Path, options: &Options) -> io::Result<Db> {
+    let options = Options {
+        read_only: true,
+        ..options.clone()
+    };
+    Db::open_with_options(dir, options)
+}
@@ -0,0 +1,309 @@
+//! `serve-memcached`: the get, gets, set, delete, and version commands of the memcached
+//! text protocol.
+//!
+//! An item is stored as its flags, four bytes big-endian, followed by its data, so
+//! values written by the other servers read back with their first bytes as flags. An
+//! exptime becomes the write's TTL; one already past deletes the item, as memcached
+//! drops it at once. `gets` gives the sequence number of an item's write as its CAS
+//! value.
+
+use super::resp::read_line;
+use super::server::{self, Listen, Stream};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use storage_engine::{Db, Options};
+
+/// Address served when `--listen` is not given
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:11211";
+/// Longest key the protocol allows
+const MAX_KEY_LEN: usize = 250;
+/// Longest data block accepted in a `set`
+const MAX_DATA_LEN: usize = 512 << 20;
+/// Exptimes up to 30 days are seconds from now; larger ones are Unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// Bytes of an item's flags at the start of its stored value
+const FLAGS_LEN: usize = 4;
+
+/// Serve the database in `dir` over the memcached text protocol on `listen` until
+/// interrupted
+pub fn run(dir: &Path, options: &Options, listen: &Listen) -> io::Result<ExitCode> {
+    server::run(dir, options, listen, serve_connection)
+}
+
+fn serve_connection(db: &Db, stream: Stream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(line) = read_line(&mut reader)? {
+        let words: Vec<&[u8]> = line.split(|&b| b == b' ').filter(|w| !w.is_empty()).collect();
+        let reply = match words.split_first() {
+            Some((&b"quit", [])) => break,
+            Some((command, args)) => execute(db, &mut reader, command, args)?,
+            None => Reply::Status("ERROR"),
+        };
+        reply.write(&mut writer)?;
+        // Pipelined requests are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// What a command sends back
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    /// The items found, each as a `VALUE` line and its data block, then `END`
+    Values(Vec<Item>),
+    ClientError(&'static str),
+    ServerError(String),
+    /// Nothing, for a command sent with `noreply`
+    Silent,
+}
+
+#[derive(Debug, PartialEq)]
+struct Item {
+    key: Vec<u8>,
+    flags: u32,
+    data: Vec<u8>,
+    /// Given by `gets` only
+    cas: Option<u64>,
+}
+
+impl Reply {
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "{}\r\n", status),
+            Reply::Values(items) => {
+                for item in items {
+                    out.write_all(b"VALUE ")?;
+                    out.write_all(&item.key)?;
+                    write!(out, " {} {}", item.flags, item.data.len())?;
+                    if let Some(cas) = item.cas {
+                        write!(out, " {}", cas)?;
+                    }
+                    out.write_all(b"\r\n")?;
+                    out.write_all(&item.data)?;
+                    out.write_all(b"\r\n")?;
+                }
+                out.write_all(b"END\r\n")
+            }
+            Reply::ClientError(message) => write!(out, "CLIENT_ERROR {}\r\n", message),
+            Reply::ServerError(message) => {
+                write!(out, "SERVER_ERROR {}\r\n", message.replace(['\r', '\n'], " "))
+            }
+            Reply::Silent => Ok(()),
+        }
+    }
+}
+
+/// The reply to a malformed command line
+const BAD_FORMAT: Reply = Reply::ClientError("bad command line format");
+
+/// Run one command; `set` reads its data block from `reader`
+fn execute<R: BufRead>(
+    db: &Db,
+    reader: &mut R,
+    command: &[u8],
+    args: &[&[u8]],
+) -> io::Result<Reply> {
+    if args.iter().any(|key| key.len() > MAX_KEY_LEN) {
+        return Ok(BAD_FORMAT);
+    }
+    Ok(match (command, args) {
+        (b"get", keys) if !keys.is_empty() => get(db, keys, false),
+        (b"gets", keys) if !keys.is_empty() => get(db, keys, true),
+        (b"set", [key, flags, exptime, len, rest @ ..]) => {
+            return set(db, reader, key, [flags, exptime, len], rest);
+        }
+        (b"delete", [key, rest @ ..]) => match no_reply(rest) {
+            Some(silent) => delete(db, key, silent),
+            None => BAD_FORMAT,
+        },
+        (b"version", []) => Reply::Status(concat!("VERSION ", env!("CARGO_PKG_VERSION"))),
+        (b"get" | b"gets" | b"set" | b"delete" | b"version", _) => BAD_FORMAT,
+        _ => Reply::Status("ERROR"),
+    })
+}
+
+/// Whether the words after a command's arguments ask for no reply, or `None` if they
+/// are anything other than `noreply`
+fn no_reply(rest: &[&[u8]]) -> Option<bool> {
+    match rest {
+        [] => Some(false),
+        [b"noreply"] => Some(true),
+        _ => None,
+    }
+}
+
+/// `get|gets <key>*`, skipping the keys that have no value
+fn get(db: &Db, keys: &[&[u8]], with_cas: bool) -> Reply {
+    let mut items = Vec::new();
+    for &key in keys {
+        let found = match with_cas {
+            true => db.get_with_metadata(key).map(|meta| (meta.value, Some(meta.seq))),
+            false => match db.try_get_bytes(key) {
+                Ok(value) => value.map(|value| (value, None)),
+                Err(e) => return Reply::ServerError(e.to_string()),
+            },
+        };
+        if let Some((value, cas)) = found {
+            let (flags, data) = unseal(value);
+            items.push(Item { key: key.to_vec(), flags, data, cas });
+        }
+    }
+    Reply::Values(items)
+}
+
+/// `set <key> <flags> <exptime> <bytes> [noreply]`, followed by the data block
+fn set<R: BufRead>(
+    db: &Db,
+    reader: &mut R,
+    key: &[u8],
+    [flags, exptime, len]: [&[u8]; 3],
+    rest: &[&[u8]],
+) -> io::Result<Reply> {
+    let (Some(flags), Some(exptime), Some(len), Some(silent)) =
+        (parse(flags), parse(exptime), parse::<usize>(len), no_reply(rest))
+    else {
+        return Ok(BAD_FORMAT);
+    };
+    if len > MAX_DATA_LEN {
+        // Skipped, so the next command is read from where it starts
+        io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+        return Ok(Reply::ServerError("object too large for cache".to_string()));
+    }
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(Reply::ClientError("bad data chunk"));
+    }
+    data.truncate(len);
+
+    let value = seal(flags, &data);
+    let stored = match ttl(exptime) {
+        Some(ttl) if ttl.is_zero() => db.delete_bytes(key).map(drop),
+        Some(ttl) => db.put_with_ttl(key, value, ttl),
+        None => db.put(key, value),
+    };
+    Ok(match stored {
+        Err(e) => Reply::ServerError(e.to_string()),
+        Ok(()) if silent => Reply::Silent,
+        Ok(()) => Reply::Status("STORED"),
+    })
+}
+
+/// `delete <key> [noreply]`
+fn delete(db: &Db, key: &[u8], silent: bool) -> Reply {
+    match db.delete_bytes(key) {
+        Err(e) => Reply::ServerError(e.to_string()),
+        Ok(_) if silent => Reply::Silent,
+        Ok(Some(_)) => Reply::Status("DELETED"),
+        Ok(None) => Reply::Status("NOT_FOUND"),
+    }
+}
+
+/// How long an item set with `exptime` lives: `None` for as long as it isn't replaced,
+/// and zero if its time has already passed
+fn ttl(exptime: i64) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(Duration::ZERO),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        at => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Some(Duration::from_secs(at as u64).saturating_sub(now))
+        }
+    }
+}
+
+/// The stored form of an item: its flags, then its data
+fn seal(flags: u32, data: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(FLAGS_LEN + data.len());
+    value.extend_from_slice(&flags.to_be_bytes());
+    value.extend_from_slice(data);
+    value
+}
+
+/// The flags and data of a stored item. A value too short to hold flags, which only
+/// another server can have written, is served whole with flags 0.
+fn unseal(mut value: Vec<u8>) -> (u32, Vec<u8>) {
+    let Some(flags) = value.first_chunk::<FLAGS_LEN>() else {
+        return (0, value);
+    };
+    let flags = u32::from_be_bytes(*flags);
+    value.drain(..FLAGS_LEN);
+    (flags, value)
+}
+
+fn parse<T: std::str::FromStr>(word: &[u8]) -> Option<T> {
+    std::str::from_utf8(word).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_conversation() {
+        let dir = "test_cli_memcached";
+        let _ = fs::remove_dir_all(dir);
+        let db = Db::open(dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&db, Stream::Tcp(stream)).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut replies = client.try_clone().unwrap();
+        let mut exchange = |request: &[u8], expected: &[u8]| {
+            client.write_all(request).unwrap();
+            let mut reply = vec![0; expected.len()];
+            replies.read_exact(&mut reply).unwrap();
+            assert_eq!(reply.escape_ascii().to_string(), expected.escape_ascii().to_string());
+        };
+        exchange(b"set greeting 42 0 5\r\nhello\r\n", b"STORED\r\n");
+        exchange(b"set other 0 3600 7 noreply\r\nabc\r\nde\r\n", b"");
+        exchange(
+            b"get greeting missing other\r\n",
+            b"VALUE greeting 42 5\r\nhello\r\nVALUE other 0 7\r\nabc\r\nde\r\nEND\r\n",
+        );
+        exchange(b"gets greeting\r\n", b"VALUE greeting 42 5 1\r\nhello\r\nEND\r\n");
+        exchange(b"delete greeting\r\n", b"DELETED\r\n");
+        exchange(b"delete greeting\r\n", b"NOT_FOUND\r\n");
+        exchange(b"get greeting\r\n", b"END\r\n");
+        // Already expired, so it replaces the item with nothing
+        exchange(b"set other 0 -1 1\r\nx\r\nget other\r\n", b"STORED\r\nEND\r\n");
+
+        exchange(b"set key flags 0 1\r\n", b"CLIENT_ERROR bad command line format\r\n");
+        exchange(b"set key 0 0 1\r\nxyz\r\n", b"CLIENT_ERROR bad data chunk\r\nERROR\r\n");
+        exchange(b"get\r\n", b"CLIENT_ERROR bad command line format\r\n");
+        exchange(b"flush_all\r\n", b"ERROR\r\n");
+        exchange(b"version\r\n", b"VERSION 0.1.0\r\n");
+        exchange(b"quit\r\n", b"");
+        let mut rest = Vec::new();
+        replies.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        server.join().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ttl() {
+        assert_eq!(ttl(0), None);
+        assert_eq!(ttl(-5), Some(Duration::ZERO));
+        assert_eq!(ttl(60), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(MAX_RELATIVE_EXPTIME + 1), Some(Duration::ZERO));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let ttl = ttl(now + 100).unwrap();
+        assert!(ttl > Duration::from_secs(98) && ttl <= Duration::from_secs(100), "{:?}", ttl);
+    }
+}
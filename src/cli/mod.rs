@@ -0,0 +1,374 @@
+//! Command-line front end for the engine
+
+mod args;
+mod bench;
+mod grpc;
+mod http;
+mod kv;
+mod memcached;
+mod repl;
+mod resp;
+mod server;
+mod sst_dump;
+mod stats;
+mod wal_dump;
+
+use args::CommandArgs;
+use server::Listen;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use storage_engine::{Config, Db, Options};
+
+const USAGE: &str = "\
+usage: storage-engine [--dir <path>] [--config <path>] [command]
+
+--config reads engine options, the data directory, and the server address from a
+TOML file; --dir, --listen, and --socket-mode override the file.
+
+The serve commands listen on <host>:<port>, or on a Unix domain socket with
+--listen unix:<path>; --socket-mode <octal> sets the socket file's permissions.
+
+commands:
+  repl                 interactive shell (the default)
+  put <key> <value>    store a value; a value of - is read from stdin
+  get <key>            print a value, exiting with status 3 if it is missing
+  del <key>            delete a key
+  scan [prefix]        print key<TAB>value lines for keys starting with prefix
+  sst-dump <path> [--keys-only] [--limit <n>]
+                       print an SSTable's metadata and entries
+  wal-dump <path> [--values]
+                       print the records of a WAL file
+  compact              merge each column family's SSTables
+  doctor [--fix]       look for structural problems that are not damage, such as leftover
+                       temporary files or unused SSTables, exiting with status 1 if any
+                       warning remains; --fix deletes temporary files and adopts
+                       unused whole SSTables
+  migrate-wal          convert a WAL in the original text format to the binary format,
+                       keeping the original as data.log.v0
+  verify               check every column family's files for damage and inconsistency,
+                       exiting with status 1 if anything is wrong
+  repair               salvage what is readable of damaged SSTables and WALs, moving
+                       the originals to lost/, and report what was kept
+  stats [--json]       summarize tables, WAL, and memtable of each column family
+  bench [--writes <n>] [--reads <n>] [--key-space <n>] [--keys sequential|random]
+        [--value-size <bytes>] [--threads <n>] [--sync <policy>]
+        [--sync-mode all|data] [--wal-compression none|lz4|snappy]
+        [--compress-above <bytes>] [--keep]
+                       time a workload against a temporary database; the sync policy
+                       is always, never, every:<writes>, or interval:<ms>, and data
+                       syncs skip metadata such as modification times
+  serve [--listen <addr>]
+                       serve GET, SET, DEL, EXISTS, and SCAN over the Redis protocol
+                       (default 127.0.0.1:6380) until interrupted, then flush
+  serve-http [--listen <addr>]
+                       serve GET/PUT/DELETE /keys/<key>, GET /keys?prefix=&limit=, and
+                       GET /metrics over HTTP (default 127.0.0.1:8080) until
+                       interrupted, then flush
+  serve-memcached [--listen <addr>]
+                       serve get, gets, set, delete, and version over the memcached
+                       text protocol (default 127.0.0.1:11211) until interrupted, then
+                       flush
+  serve-grpc [--listen <addr>]
+                       serve Get, Put, Delete, Scan, and Stats over gRPC (default
+                       127.0.0.1:50051) until interrupted, then flush; needs the
+                       grpc feature
+  clear                delete the database in the data directory";
+
+/// Names accepted as the first word of a command
+const COMMANDS: &[&str] = &[
+    "repl", "put", "get", "del", "scan", "sst-dump", "wal-dump", "compact", "doctor", "migrate-wal",
+    "verify", "repair", "stats", "bench", "serve", "serve-http", "serve-memcached",
+    "serve-grpc", "clear",
+];
+
+/// Parse `args` (without the program name), run the command, and return the exit status
+pub fn run(args: Vec<String>) -> ExitCode {
+    let mut dir = None;
+    let mut config_path = None;
+    let mut rest = args.into_iter();
+    let mut command = Vec::new();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--dir" | "-d" => match rest.next() {
+                Some(path) => dir = Some(PathBuf::from(path)),
+                None => return usage_error("--dir needs a path"),
+            },
+            "--config" | "-c" => match rest.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => return usage_error("--config needs a path"),
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                command.push(arg);
+                command.extend(rest.by_ref());
+            }
+        }
+    }
+
+    let config = match config_path.map(Config::from_file).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => return failure(e.to_string()),
+    };
+    let dir = dir.or_else(|| config.dir.clone()).unwrap_or_else(|| PathBuf::from("."));
+    let command: Vec<&str> = command.iter().map(String::as_str).collect();
+    match dispatch(&dir, &config, &command) {
+        Ok(code) => code,
+        Err(CliError::Usage(message)) => usage_error(&message),
+        Err(CliError::Io(e)) => failure(e.to_string()),
+    }
+}
+
+/// Why a command could not run
+enum CliError {
+    /// Bad arguments; the usage text is printed and the exit status is 2
+    Usage(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        CliError::Usage(message)
+    }
+}
+
+fn dispatch(dir: &Path, config: &Config, command: &[&str]) -> Result<ExitCode, CliError> {
+    let options = &config.options;
+    match command {
+        [] | ["repl"] => Ok(repl(dir, options)?),
+        ["put", key, value] => Ok(kv::put(dir, options, key, value)?),
+        ["get", key] => Ok(kv::get(dir, options, key)?),
+        ["del", key] => Ok(kv::del(dir, options, key)?),
+        ["scan"] => Ok(kv::scan(dir, options, "")?),
+        ["scan", prefix] => Ok(kv::scan(dir, options, prefix)?),
+        ["sst-dump", rest @ ..] => {
+            let args = CommandArgs::parse(rest, &["--keys-only"], &["--limit"])?;
+            let [path] = args.positional() else {
+                return Err(CliError::Usage("sst-dump needs one table path".to_string()));
+            };
+            let options = sst_dump::DumpOptions {
+                keys_only: args.has("--keys-only"),
+                limit: args.value("--limit")?,
+            };
+            let mut stdout = io::BufWriter::new(io::stdout().lock());
+            Ok(sst_dump::dump(Path::new(path), &options, &mut stdout)?)
+        }
+        ["wal-dump", rest @ ..] => {
+            let args = CommandArgs::parse(rest, &["--values"], &[])?;
+            let [path] = args.positional() else {
+                return Err(CliError::Usage("wal-dump needs one log path".to_string()));
+            };
+            let mut stdout = io::BufWriter::new(io::stdout().lock());
+            Ok(wal_dump::dump(Path::new(path), args.has("--values"), &mut stdout)?)
+        }
+        ["compact"] => Ok(compact(dir, options)?),
+        ["doctor"] => Ok(doctor(dir, false)?),
+        ["doctor", "--fix"] => Ok(doctor(dir, true)?),
+        ["migrate-wal"] => Ok(migrate_wal(dir)?),
+        ["verify"] => Ok(verify(dir, options)?),
+        ["repair"] => Ok(repair(dir)?),
+        ["bench", rest @ ..] => {
+            let args = CommandArgs::parse(rest, bench::Config::SWITCHES, bench::Config::OPTIONS)?;
+            let config = bench::Config::from_args(&args)?;
+            Ok(bench::run(&config, &mut io::stdout().lock())?)
+        }
+        ["serve", rest @ ..] => {
+            let listen = listen("serve", rest, config, resp::DEFAULT_LISTEN)?;
+            Ok(resp::run(dir, options, &listen)?)
+        }
+        ["serve-http", rest @ ..] => {
+            let listen = listen("serve-http", rest, config, http::DEFAULT_LISTEN)?;
+            Ok(http::run(dir, options, &listen)?)
+        }
+        ["serve-memcached", rest @ ..] => {
+            let listen = listen("serve-memcached", rest, config, memcached::DEFAULT_LISTEN)?;
+            Ok(memcached::run(dir, options, &listen)?)
+        }
+        ["serve-grpc", rest @ ..] => {
+            let listen = listen("serve-grpc", rest, config, grpc::DEFAULT_LISTEN)?;
+            Ok(grpc::run(dir, options, &listen)?)
+        }
+        ["stats", rest @ ..] => {
+            let args = CommandArgs::parse(rest, &["--json"], &[])?;
+            if !args.positional().is_empty() {
+                return Err(CliError::Usage("stats takes no arguments".to_string()));
+            }
+            Ok(stats::run(dir, options, args.has("--json"))?)
+        }
+        ["clear"] => match Db::destroy(dir, false) {
+            Ok(()) => {
+                println!("All data cleared");
+                Ok(ExitCode::SUCCESS)
+            }
+            Err(e) => Err(io::Error::new(e.kind(), format!("nothing cleared: {}", e)).into()),
+        },
+        [name, ..] if COMMANDS.contains(name) => {
+            Err(CliError::Usage(format!("wrong number of arguments for {}", name)))
+        }
+        [other, ..] => Err(CliError::Usage(format!("unknown command {:?}", other))),
+    }
+}
+
+fn repl(dir: &Path, options: &Options) -> io::Result<ExitCode> {
+    let db = Db::open_with_options(dir, options.clone())
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot open {}: {}", dir.display(), e)))?;
+    repl::run(&db, io::stdin().lock(), io::stdout())?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Compact every column family, printing table counts and sizes before and after
+fn compact(dir: &Path, options: &Options) -> io::Result<ExitCode> {
+    let db = Db::open_with_options(dir, options.clone())?;
+    for name in db.column_families() {
+        let Some(family) = db.cf(&name) else {
+            continue;
+        };
+        let info = family.compact()?;
+        println!(
+            "{}: {} tables ({} bytes) -> {} tables ({} bytes), {} keys, {} versions and {} \
+             tombstones dropped",
+            name,
+            info.input_tables,
+            info.input_bytes,
+            info.output_tables,
+            info.output_bytes,
+            info.keys,
+            info.versions_dropped,
+            info.tombstones_dropped
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Look the database over, listing each finding with its action
+fn doctor(dir: &Path, fix: bool) -> io::Result<ExitCode> {
+    let report = Db::doctor(dir, fix)?;
+    for finding in &report.findings {
+        let symptom = finding.symptom;
+        println!(
+            "{} {} {}: {}",
+            finding.column_family,
+            symptom.severity(),
+            symptom,
+            finding.description
+        );
+        let note = match (finding.fixed, symptom.is_fixable()) {
+            (true, _) => " (done)",
+            (false, true) => " (--fix does this)",
+            (false, false) => "",
+        };
+        println!("  {}{}", symptom.action(), note);
+    }
+    let fixed = report.findings.iter().filter(|finding| finding.fixed).count();
+    println!("{} findings, {} fixed", report.findings.len(), fixed);
+    if report.needs_attention() {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Convert a text WAL to the binary format
+fn migrate_wal(dir: &Path) -> io::Result<ExitCode> {
+    match Db::migrate_wal(dir)? {
+        Some(records) => {
+            println!("converted {} records; the text log is kept as data.log.v0", records)
+        }
+        None => println!("the WAL is already in the binary format"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Where a serve command listens: `--listen`, else the config file's address, else
+/// `default`. A Unix socket is given the permissions of `--socket-mode` or the config
+/// file, if either sets them.
+fn listen(
+    command: &str,
+    rest: &[&str],
+    config: &Config,
+    default: &str,
+) -> Result<Listen, CliError> {
+    let args = CommandArgs::parse(rest, &[], &["--listen", "--socket-mode"])?;
+    if !args.positional().is_empty() {
+        let message = format!("{} takes only --listen and --socket-mode", command);
+        return Err(CliError::Usage(message));
+    }
+    let listen = args.value::<String>("--listen")?.or_else(|| config.listen.clone());
+    let socket_mode = match args.value::<String>("--socket-mode")? {
+        Some(mode) => match u32::from_str_radix(&mode, 8) {
+            Ok(mode) if mode <= 0o777 => Some(mode),
+            _ => return Err(CliError::Usage(format!("invalid socket mode {:?}", mode))),
+        },
+        None => config.socket_mode,
+    };
+    Ok(Listen::new(listen.as_deref().unwrap_or(default), socket_mode))
+}
+
+/// Verify the database, listing each check with its violations
+fn verify(dir: &Path, options: &Options) -> io::Result<ExitCode> {
+    let report = Db::open_with_options(dir, options.clone())?.verify();
+    for result in &report.checks {
+        let name = format!("{} {}", result.column_family, result.check);
+        match result.violations.len() {
+            0 => println!("{}: ok", name),
+            n => println!("{}: {} violations", name, n),
+        }
+        for violation in &result.violations {
+            println!("  {}", violation);
+        }
+    }
+    if report.is_ok() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+/// Repair the database, listing each file kept, salvaged, or abandoned
+fn repair(dir: &Path) -> io::Result<ExitCode> {
+    let report = Db::repair(dir)?;
+    for path in &report.kept {
+        println!("kept {}", path.display());
+    }
+    for (status, files) in [("salvaged", &report.salvaged), ("abandoned", &report.abandoned)] {
+        for file in files {
+            let table = file.path.extension() == Some("sst".as_ref());
+            let unit = if table { "entries" } else { "records" };
+            let path = file.path.display();
+            println!("{} {}: {} {} kept ({})", status, path, file.kept, unit, file.error);
+        }
+    }
+    println!(
+        "{} kept, {} salvaged, {} abandoned; last sequence {}",
+        report.kept.len(),
+        report.salvaged.len(),
+        report.abandoned.len(),
+        report.last_sequence
+    );
+    if !report.salvaged.is_empty() || !report.abandoned.is_empty() {
+        println!("originals of damaged files are in {}", dir.join("lost").display());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Bytes as a quoted string, with anything but printable ASCII escaped
+fn quoted(bytes: &[u8]) -> String {
+    format!("\"{}\"", bytes.escape_ascii())
+}
+
+fn usage_error(message: &str) -> ExitCode {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    ExitCode::from(2)
+}
+
+fn failure(message: String) -> ExitCode {
+    eprintln!("error: {}", message);
+    ExitCode::FAILURE
+}
@@ -0,0 +1,202 @@
+use std::io::{self, BufRead, Write};
+use storage_engine::Db;
+
+const HELP: &str = "\
+commands:
+  put <key> <value>     store a value
+  get <key>             print a value
+  del <key>             delete a key
+  scan <start> <end>    list keys in [start, end)
+  flush                 write the memtable to an SSTable
+  compact               merge the SSTables
+  stats                 show engine counters
+  quit                  flush and exit
+Quote arguments containing spaces: put greeting \"hello world\"";
+
+/// Run an interactive session against `db`, reading commands from `input` until `quit`
+/// or end of input. Errors are reported and the session continues; the memtable is
+/// flushed on the way out.
+pub fn run<R: BufRead, W: Write>(db: &Db, input: R, mut output: W) -> io::Result<()> {
+    writeln!(output, "storage-engine shell, type `help` for commands")?;
+    write!(output, "> ")?;
+    output.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+        let keep_going = match split_words(&line) {
+            Ok(words) => match execute(db, &words, &mut output) {
+                Ok(keep_going) => keep_going,
+                Err(e) => {
+                    writeln!(output, "error: {}", e)?;
+                    true
+                }
+            },
+            Err(e) => {
+                writeln!(output, "error: {}", e)?;
+                true
+            }
+        };
+        if !keep_going {
+            break;
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+
+    db.flush()?;
+    writeln!(output, "bye")
+}
+
+/// Run one command, returning `false` once the session should end
+fn execute<W: Write>(db: &Db, words: &[String], output: &mut W) -> io::Result<bool> {
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => {}
+        ["put", key, value] => {
+            db.put(*key, *value)?;
+            writeln!(output, "OK")?;
+        }
+        ["get", key] => match db.get(key) {
+            Some(value) => writeln!(output, "{}", value)?,
+            None => writeln!(output, "(not found)")?,
+        },
+        ["del", key] => {
+            db.delete(key)?;
+            writeln!(output, "OK")?;
+        }
+        ["scan", start, end] => {
+            let entries = db.scan(start, end)?;
+            for (key, value) in &entries {
+                writeln!(output, "{:?} => {:?}", key, value)?;
+            }
+            writeln!(output, "({} entries)", entries.len())?;
+        }
+        ["flush"] => {
+            db.flush()?;
+            writeln!(output, "OK")?;
+        }
+        ["compact"] => {
+            let info = db.compact()?;
+            writeln!(
+                output,
+                "{} tables ({} bytes) -> {} tables ({} bytes)",
+                info.input_tables, info.input_bytes, info.output_tables, info.output_bytes
+            )?;
+        }
+        ["stats"] => {
+            let stats = db.stats()?;
+            writeln!(output, "memtable entries: {}", db.size())?;
+            writeln!(output, "last sequence: {}", db.last_sequence())?;
+            writeln!(output, "column families: {}", db.column_families().join(", "))?;
+            writeln!(
+                output,
+                "compactions: {} ({} bytes read, {} written, {} keys, {} versions and {} \
+                 tombstones dropped, {} ms)",
+                stats.compactions,
+                stats.compaction_bytes_read,
+                stats.compaction_bytes_written,
+                stats.compaction_keys,
+                stats.compaction_versions_dropped,
+                stats.compaction_tombstones_dropped,
+                stats.compaction_micros / 1000
+            )?;
+            writeln!(output, "write amplification: {:.2}", stats.write_amplification())?;
+        }
+        ["help"] => writeln!(output, "{}", HELP)?,
+        ["quit"] | ["exit"] => return Ok(false),
+        [command, ..] => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad command or arguments: {} (try `help`)", command),
+            ))
+        }
+    }
+    Ok(true)
+}
+
+/// Split a command line into words. Single or double quotes group words containing
+/// spaces; inside double quotes, `\"` and `\\` escape a quote and a backslash.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(words);
+        };
+
+        let mut word = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '"' => match chars.next() {
+                        Some(escaped) => word.push(escaped),
+                        None => return Err("unterminated quote".to_string()),
+                    },
+                    Some(c) => word.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words("  put  a b "), Ok(vec!["put".into(), "a".into(), "b".into()]));
+        assert_eq!(
+            split_words(r#"put "my key" 'it''s' "say \"hi\"""#),
+            Ok(vec!["put".into(), "my key".into(), "it".into(), "s".into(), "say \"hi\"".into()])
+        );
+        assert_eq!(split_words(r#"put "open"#), Err("unterminated quote".to_string()));
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = "test_repl_session";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let input = "put greeting \"hello world\"\n\
+                     put other x\n\
+                     flush\n\
+                     del other\n\
+                     flush\n\
+                     get greeting\n\
+                     get other\n\
+                     scan a z\n\
+                     compact\n\
+                     stats\n\
+                     bogus\n\
+                     quit\n\
+                     put never run\n";
+        let mut output = Vec::new();
+        run(&db, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("> hello world\n"));
+        assert!(output.contains("> (not found)\n"));
+        assert!(output.contains("\"greeting\" => \"hello world\"\n(1 entries)"));
+        assert!(output.contains("> 2 tables ("), "{}", output);
+        assert!(output.contains("compactions: 1 ("), "{}", output);
+        assert!(output.contains(" 2 keys, 1 versions and 1 tombstones dropped,"), "{}", output);
+        assert!(output.contains("write amplification: "), "{}", output);
+        assert!(output.contains("error: bad command or arguments: bogus"));
+        assert!(output.ends_with("bye\n"));
+        assert_eq!(db.get("never"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,544 @@
+//! `serve`: enough of the Redis protocol (RESP) for redis-cli to use the database
+
+use super::server::{self, Listen, Stream};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+use storage_engine::{Db, Options};
+
+/// Address served when `--listen` is not given
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:6380";
+/// Longest bulk string accepted in a request
+const MAX_BULK_LEN: usize = 512 << 20;
+/// Most of a bulk string's declared length allocated before its bytes arrive
+const BULK_PREALLOCATE: usize = 64 << 10;
+/// Keys examined by one SCAN call unless COUNT says otherwise
+const DEFAULT_SCAN_COUNT: u64 = 10;
+
+/// Serve the database in `dir` over RESP on `listen` until interrupted
+pub fn run(dir: &Path, options: &Options, listen: &Listen) -> io::Result<ExitCode> {
+    server::run(dir, options, listen, serve_connection)
+}
+
+fn serve_connection(db: &Db, stream: Stream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // The stream can't be resynchronised after a framing error
+                Reply::Error(format!("ERR Protocol error: {}", e)).write(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        execute(db, &args).write(&mut writer)?;
+        // Pipelined requests are answered together
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Read one request: a RESP array of bulk strings, or an inline command line.
+/// `None` at a clean end of stream.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let words = line.split(|b| b.is_ascii_whitespace()).filter(|word| !word.is_empty());
+        return Ok(Some(words.map(<[u8]>::to_vec).collect()));
+    };
+
+    let count = parse_len(count)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(eof)?;
+        let Some(len) = header.strip_prefix(b"$") else {
+            return Err(invalid(format!("expected '$', got {:?}", header.escape_ascii())));
+        };
+        let len = parse_len(len)?;
+        if len > MAX_BULK_LEN {
+            return Err(invalid("invalid bulk length".to_string()));
+        }
+        // Grown as the bytes arrive, so a length alone can't make the server allocate
+        let mut arg = Vec::with_capacity((len + 2).min(BULK_PREALLOCATE));
+        if reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)? < len + 2 {
+            return Err(eof());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by CRLF".to_string()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// A line without its CRLF (or bare LF), `None` at end of stream
+pub(super) fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| invalid(format!("invalid length {:?}", digits.escape_ascii())))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn eof() -> io::Error {
+    io::ErrorKind::UnexpectedEof.into()
+}
+
+/// A RESP value sent back to the client
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(message) => write!(out, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(bytes) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Nil => out.write_all(b"$-1\r\n"),
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(out))
+            }
+        }
+    }
+}
+
+/// The error reply for a failed engine call
+fn exists(db: &Db, keys: &[Vec<u8>]) -> Result<Reply, String> {
+    let mut found = 0;
+    for key in keys {
+        if db.try_get_bytes(key).map_err(engine_error)?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Reply::Integer(found))
+}
+
+fn engine_error(e: io::Error) -> String {
+    format!("ERR {}", e)
+}
+
+fn execute(db: &Db, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let result = match (name.as_str(), &args[1..]) {
+        ("PING", []) => Ok(Reply::Status("PONG")),
+        ("PING", [message]) => Ok(Reply::Bulk(message.clone())),
+        ("QUIT", []) => Ok(Reply::Status("OK")),
+        // redis-cli asks for command docs on connect; it copes with none
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("GET", [key]) => match db.try_get_bytes(key) {
+            Ok(value) => Ok(value.map_or(Reply::Nil, Reply::Bulk)),
+            Err(e) => Err(engine_error(e)),
+        },
+        ("SET", [key, value, options @ ..]) => set(db, key, value, options),
+        ("DEL", keys) if !keys.is_empty() => del(db, keys),
+        ("EXISTS", keys) if !keys.is_empty() => exists(db, keys),
+        ("SCAN", [cursor, options @ ..]) => scan(db, cursor, options),
+        ("PING" | "QUIT" | "GET" | "SET" | "DEL" | "EXISTS" | "SCAN", _) => Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )),
+        _ => Err(format!("ERR unknown command '{}'", args[0].escape_ascii())),
+    };
+    result.unwrap_or_else(Reply::Error)
+}
+
+/// `SET key value [EX seconds | PX milliseconds]`
+fn set(db: &Db, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply, String> {
+    let ttl = match options {
+        [] => None,
+        [unit, amount] => {
+            let amount: u64 = parse_number(amount)?;
+            if amount == 0 {
+                return Err("ERR invalid expire time in 'set' command".to_string());
+            }
+            match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => Some(Duration::from_secs(amount)),
+                b"PX" => Some(Duration::from_millis(amount)),
+                _ => return Err("ERR syntax error".to_string()),
+            }
+        }
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    match ttl {
+        Some(ttl) => db.put_with_ttl(key, value, ttl),
+        None => db.put(key, value),
+    }
+    .map_err(engine_error)?;
+    Ok(Reply::Status("OK"))
+}
+
+/// `DEL key [key ...]`, replying with how many of the keys existed
+fn del(db: &Db, keys: &[Vec<u8>]) -> Result<Reply, String> {
+    let mut deleted = 0;
+    for key in keys {
+        if db.delete_bytes(key).map_err(engine_error)?.is_some() {
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+///
+/// The cursor is `0` to start from the first key, and otherwise the next key to examine,
+/// in hex, so each call resumes with a seek. A full iteration sees every key that stays
+/// live throughout, and keys written in between are seen if they sort after the cursor.
+fn scan(db: &Db, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply, String> {
+    let start = match cursor {
+        b"0" => Vec::new(),
+        hex => decode_hex(hex).ok_or_else(|| "ERR invalid cursor".to_string())?,
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(|| "ERR syntax error".to_string())?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(value.as_slice()),
+            b"COUNT" => count = parse_number(value)?.max(1),
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+
+    let mut keys = Vec::new();
+    let mut iter = db.keys_from(start);
+    for key in iter.by_ref().take(count as usize) {
+        let key = key.map_err(engine_error)?;
+        if pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+            keys.push(Reply::Bulk(key));
+        }
+    }
+    let next = match iter.next() {
+        Some(key) => encode_hex(&key.map_err(engine_error)?),
+        None => b"0".to_vec(),
+    };
+    Ok(Reply::Array(vec![Reply::Bulk(next), Reply::Array(keys)]))
+}
+
+fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|byte| format!("{:02x}", byte).into_bytes()).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_number(bytes: &[u8]) -> Result<u64, String> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".to_string())
+}
+
+/// Redis glob matching: `*`, `?`, `[abc]`, `[^a-z]`, and `\` to escape.
+///
+/// A mismatch only ever backtracks to the latest `*`, letting it take one more byte, so
+/// matching takes at most the pattern's length times the text's.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the latest `*`, and where in the text that `*` ends
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(len) = match_byte(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((after_star, end)) = star {
+            p = after_star;
+            t = end + 1;
+            star = Some((after_star, end + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// How much of `pattern` its first element takes up, if that element matches `byte`
+fn match_byte(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern {
+        [] | [b'*', ..] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negated, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => return None,
+                    [b']', after @ ..] => {
+                        class = after;
+                        break;
+                    }
+                    [b'\\', escaped, after @ ..] => {
+                        matched |= *escaped == byte;
+                        class = after;
+                    }
+                    [low, b'-', high, after @ ..] if *high != b']' => {
+                        let (low, high) = ((*low).min(*high), (*low).max(*high));
+                        matched |= (low..=high).contains(&byte);
+                        class = after;
+                    }
+                    [single, after @ ..] => {
+                        matched |= *single == byte;
+                        class = after;
+                    }
+                }
+            }
+            (matched != negated).then_some(pattern.len() - class.len())
+        }
+        [b'\\', escaped, ..] => (*escaped == byte).then_some(2),
+        [literal, ..] => (*literal == byte).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::Listener;
+    use std::fs;
+    use std::net::Shutdown;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use storage_engine::{Client, ClientError};
+
+    /// The server run in this process, one connection at a time
+    struct TestServer {
+        listener: Arc<dyn Listener>,
+        stopped: Arc<AtomicBool>,
+        /// The connection being served
+        current: Arc<Mutex<Option<Stream>>>,
+        thread: JoinHandle<()>,
+    }
+
+    impl TestServer {
+        /// Serve the database in `dir` on `listen`, returning the address to connect to
+        fn start(dir: &str, listen: &Listen) -> (TestServer, String) {
+            let db = Db::open(dir).unwrap();
+            let listener: Arc<dyn Listener> = Arc::from(server::bind(listen).unwrap());
+            let address = listener.address().unwrap();
+            let stopped = Arc::new(AtomicBool::new(false));
+            let current = Arc::new(Mutex::new(None));
+            let (accepting, stop, serving) =
+                (Arc::clone(&listener), Arc::clone(&stopped), Arc::clone(&current));
+            let thread = thread::spawn(move || loop {
+                let stream = accepting.accept().unwrap();
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                *serving.lock().unwrap() = Some(stream.try_clone().unwrap());
+                let _ = serve_connection(&db, stream);
+            });
+            (TestServer { listener, stopped, current, thread }, address)
+        }
+
+        /// Close the listener and the connection being served, and the database
+        fn stop(self) {
+            self.stopped.store(true, Ordering::SeqCst);
+            let _ = match self.current.lock().unwrap().take() {
+                Some(Stream::Tcp(stream)) => stream.shutdown(Shutdown::Both),
+                Some(Stream::Unix(stream)) => stream.shutdown(Shutdown::Both),
+                None => Ok(()),
+            };
+            self.listener.wake();
+            self.thread.join().unwrap();
+        }
+    }
+
+    fn not_found(err: io::Error) -> bool {
+        ClientError::from_io(&err) == Some(&ClientError::NotFound)
+    }
+
+    /// Round trips of every call, then a restart the client reconnects across
+    fn client_conversation(dir: &str, listen: &Listen) {
+        let _ = fs::remove_dir_all(dir);
+        let (server, address) = TestServer::start(dir, listen);
+        let mut client = Client::connect(&address).unwrap();
+        client.put("user:1", "alice").unwrap();
+        client.put("user:2", b"\xff\r\nbob").unwrap();
+        client.put("user*", "glob").unwrap();
+        client.put("order:1", "x").unwrap();
+        assert_eq!(client.get("user:1").unwrap(), b"alice");
+        assert!(not_found(client.get("nobody").unwrap_err()));
+        let users = client.scan_prefix("user:").unwrap();
+        assert_eq!(
+            users,
+            [(b"user:1".to_vec(), b"alice".to_vec()), (b"user:2".to_vec(), b"\xff\r\nbob".to_vec())]
+        );
+        assert_eq!(client.scan_prefix("user*").unwrap().len(), 1);
+        client.delete("user:2").unwrap();
+        assert!(not_found(client.delete("user:2").unwrap_err()));
+        let err = client.put("", "empty key").unwrap_err();
+        assert!(matches!(ClientError::from_io(&err), Some(ClientError::Server { .. })), "{}", err);
+        server.stop();
+
+        // A network failure, not a missing key
+        let err = client.get("user:1").unwrap_err();
+        assert!(ClientError::from_io(&err).is_none(), "{}", err);
+
+        let (server, _) = TestServer::start(dir, listen);
+        assert_eq!(client.get("user:1").unwrap(), b"alice");
+        assert!(not_found(client.get("user:2").unwrap_err()));
+        server.stop();
+
+        // A connection the server closed is replaced on the next call
+        let (server, _) = TestServer::start(dir, listen);
+        client.put("after", "restart").unwrap();
+        server.stop();
+        let (server, _) = TestServer::start(dir, listen);
+        assert_eq!(client.get("after").unwrap(), b"restart");
+        server.stop();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_over_tcp() {
+        // A fixed port, so the restarted server is where the client left it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        client_conversation("test_resp_client_tcp", &Listen::new(&address, None));
+    }
+
+    #[test]
+    fn test_client_over_unix_socket() {
+        let sockets = std::env::temp_dir().join(format!("storage-engine-{}", std::process::id()));
+        fs::create_dir_all(&sockets).unwrap();
+        let listen = format!("unix:{}", sockets.join("client.sock").display());
+        client_conversation("test_resp_client_unix", &Listen::new(&listen, None));
+        fs::remove_dir_all(&sockets).unwrap();
+    }
+
+    #[test]
+    fn test_read_command() {
+        let mut input: &[u8] =
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\na\r\nb!\r\nPING  hi\r\n*1\r\n+x\r\n";
+        let command = read_command(&mut input).unwrap().unwrap();
+        assert_eq!(command, [b"SET".to_vec(), b"k".to_vec(), b"a\r\nb!".to_vec()]);
+        let inline = read_command(&mut input).unwrap().unwrap();
+        assert_eq!(inline, [b"PING".to_vec(), b"hi".to_vec()]);
+        let error = read_command(&mut input).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(read_command(&mut input).unwrap().is_none());
+
+        // A declared length is only trusted as far as the bytes that arrive
+        let mut input: &[u8] = b"*1\r\n$536870912\r\nshort\r\n";
+        let error = read_command(&mut input).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_scan_resumes_after_the_cursor_key() {
+        let dir = "test_resp_scan";
+        let _ = fs::remove_dir_all(dir);
+        let db = Db::open(dir).unwrap();
+        for i in 0..25 {
+            db.put(format!("key{:02}", i), "value").unwrap();
+        }
+        let scan = |cursor: &[u8]| {
+            let args = [b"SCAN".to_vec(), cursor.to_vec(), b"COUNT".to_vec(), b"10".to_vec()];
+            match execute(&db, &args) {
+                Reply::Array(mut reply) => match (reply.pop(), reply.pop()) {
+                    (Some(Reply::Array(keys)), Some(Reply::Bulk(cursor))) => (cursor, keys.len()),
+                    reply => panic!("unexpected reply {:?}", reply),
+                },
+                reply => panic!("unexpected reply {:?}", reply),
+            }
+        };
+
+        let (cursor, found) = scan(b"0");
+        assert_eq!((cursor.as_slice(), found), (encode_hex(b"key10").as_slice(), 10));
+        // Keys written behind the cursor are not seen, and those ahead of it are
+        db.delete("key10").unwrap();
+        db.put("key00a", "value").unwrap();
+        db.put("key99", "value").unwrap();
+        let (cursor, found) = scan(&cursor);
+        assert_eq!((cursor.as_slice(), found), (encode_hex(b"key21").as_slice(), 10));
+        assert_eq!(scan(&cursor), (b"0".to_vec(), 5));
+
+        let args = [b"SCAN".to_vec(), b"xyz".to_vec()];
+        assert_eq!(execute(&db, &args), Reply::Error("ERR invalid cursor".to_string()));
+
+        drop(db);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"key[0-3]", b"key2"));
+        assert!(!glob_match(b"key[0-3]", b"key7"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(!glob_match(b"user:*", b"order:1"));
+        assert!(glob_match(b"*:*:end", b"a:b:c:end"));
+        assert!(!glob_match(b"*a*b", b"bba"));
+        assert!(glob_match(b"a*[xy]*z", b"aqqyqz"));
+
+        // Many stars against a long mismatch finish without backtracking into each one
+        let mut pattern = b"a*".repeat(40);
+        pattern.push(b'b');
+        assert!(!glob_match(&pattern, &[b'a'; 10_000]));
+        assert!(glob_match(&pattern, &[b"a".repeat(40), b"b".to_vec()].concat()));
+    }
+}
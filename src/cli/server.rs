@@ -0,0 +1,254 @@
+//! The accept loop shared by the server commands, over TCP or a Unix domain socket
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs::{self, Permissions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use storage_engine::{Db, Options};
+
+/// Prefix of a `--listen` address naming a Unix domain socket
+const UNIX_PREFIX: &str = "unix:";
+
+/// Handles one client connection until it closes
+pub type Handler = fn(&Db, Stream) -> io::Result<()>;
+
+/// Where a server listens, from `--listen`: `host:port`, or `unix:<path>` for a Unix
+/// domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(String),
+    /// The socket file is given `mode` as its permissions, if set, rather than the ones
+    /// the umask leaves it
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+impl Listen {
+    pub fn new(listen: &str, socket_mode: Option<u32>) -> Self {
+        match listen.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Listen::Unix { path: PathBuf::from(path), mode: socket_mode },
+            None => Listen::Tcp(listen.to_string()),
+        }
+    }
+}
+
+/// A client connection, whichever transport it came over
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Another handle to the same connection, so reads and writes can be buffered apart
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Where the accept loop takes its connections from
+pub trait Listener: Send + Sync {
+    fn accept(&self) -> io::Result<Stream>;
+
+    /// The address to print, in the form `--listen` takes
+    fn address(&self) -> io::Result<String>;
+
+    /// Connect once, so an `accept` blocked in another thread returns
+    fn wake(&self);
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Stream> {
+        Ok(Stream::Tcp(TcpListener::accept(self)?.0))
+    }
+
+    fn address(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+
+    fn wake(&self) {
+        if let Ok(addr) = self.local_addr() {
+            let _ = TcpStream::connect(wake_addr(addr));
+        }
+    }
+}
+
+/// A listening Unix domain socket, whose socket file is removed when it is dropped
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Listen on a socket file created at `path`, given `mode` as its permissions if
+    /// set. A socket file left there by a server that has gone is replaced; one that a
+    /// server still accepts on fails with `AddrInUse`, and any other file with
+    /// `AlreadyExists`.
+    pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<Self> {
+        remove_stale_socket(path)?;
+        let socket = UnixSocket { listener: UnixListener::bind(path)?, path: path.to_path_buf() };
+        if let Some(mode) = mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(socket)
+    }
+
+    /// The socket, for `serve-grpc` to accept on through tokio
+    #[cfg(feature = "grpc")]
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Listener for UnixSocket {
+    fn accept(&self) -> io::Result<Stream> {
+        Ok(Stream::Unix(self.listener.accept()?.0))
+    }
+
+    fn address(&self) -> io::Result<String> {
+        Ok(format!("{}{}", UNIX_PREFIX, self.path.display()))
+    }
+
+    fn wake(&self) {
+        let _ = UnixStream::connect(&self.path);
+    }
+}
+
+/// Remove the socket file at `path` if nothing accepts connections on it any more
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        let message = format!("{} exists and is not a socket", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            let message = format!("a server is already listening on {}", path.display());
+            Err(io::Error::new(io::ErrorKind::AddrInUse, message))
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Start listening where `listen` says
+pub fn bind(listen: &Listen) -> io::Result<Box<dyn Listener>> {
+    Ok(match listen {
+        Listen::Tcp(addr) => Box::new(TcpListener::bind(addr)?),
+        Listen::Unix { path, mode } => Box::new(UnixSocket::bind(path, *mode)?),
+    })
+}
+
+/// Serve the database in `dir` on `listen` until SIGINT or SIGTERM, then flush it.
+///
+/// Each connection gets its own thread running `handler`. The bound address is printed
+/// first, so `--listen 127.0.0.1:0` can be used to pick a free port. A Unix socket's
+/// file is removed on the way out.
+pub fn run(
+    dir: &Path,
+    options: &Options,
+    listen: &Listen,
+    handler: Handler,
+) -> io::Result<ExitCode> {
+    let db = Arc::new(Db::open_with_options(dir, options.clone())?);
+    let listener: Arc<dyn Listener> = Arc::from(bind(listen)?);
+    println!("listening on {}", listener.address()?);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let signal_handle = signals.handle();
+    let stop = Arc::clone(&shutdown);
+    let waker = Arc::clone(&listener);
+    let signal_thread = thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            stop.store(true, Ordering::SeqCst);
+            // Wake the accept loop so it sees the flag
+            waker.wake();
+        }
+    });
+
+    loop {
+        let stream = listener.accept();
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error: accept failed: {}", e);
+                continue;
+            }
+        };
+        let db = Arc::clone(&db);
+        thread::spawn(move || {
+            if let Err(e) = handler(&db, stream) {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    eprintln!("error: connection closed: {}", e);
+                }
+            }
+        });
+    }
+    signal_handle.close();
+    // The signal thread's handle goes first, so this one removes a Unix socket's file
+    let _ = signal_thread.join();
+    drop(listener);
+
+    db.flush()?;
+    println!("flushed, shutting down");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A connectable form of the address the listener is bound to
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
@@ -0,0 +1,240 @@
+//! `sst-dump`: print what an SSTable holds, for debugging
+
+use super::quoted;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use storage_engine::entry::{Entry, Op};
+use storage_engine::sstable::{SSTable, SSTableIterator, DEFAULT_READ_AHEAD};
+use storage_engine::FileStorage;
+
+pub struct DumpOptions {
+    /// Print each entry's key, sequence number, and kind but not its value
+    pub keys_only: bool,
+    /// Stop listing entries after this many
+    pub limit: Option<u64>,
+}
+
+/// Print the table's metadata, its entries, and its range tombstones to `out`.
+///
+/// Entries are streamed, so tables of any size can be dumped. A corrupt table is dumped
+/// up to the point where parsing fails, which is then reported with the file offset the
+/// error gives and a failing exit status; one cut short before its footer has no entry
+/// count to list to, and is read until an entry fails to parse.
+pub fn dump<W: Write>(path: &Path, options: &DumpOptions, out: &mut W) -> io::Result<ExitCode> {
+    let name = path.to_string_lossy();
+    let (size, crc) = file_checksum(path)?;
+    let footer_error = match open(&name, false) {
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Some(e),
+        result => result.map(|_| None)?,
+    };
+    let open = |name: &str| open(name, footer_error.is_some());
+
+    // A first pass over the keys alone finds the key range
+    let mut keys = open(&name)?.keys_only();
+    let header = keys.header();
+    let mut range = None;
+    for (key, _) in keys.by_ref().map_while(Result::ok) {
+        let first = range.map_or_else(|| key.clone(), |(first, _)| first);
+        range = Some((first, key));
+    }
+
+    writeln!(out, "file: {}", name)?;
+    writeln!(out, "size: {} bytes", size)?;
+    writeln!(out, "crc32: {:08x}", crc)?;
+    writeln!(out, "format version: {}", header.version)?;
+    match &footer_error {
+        Some(e) => writeln!(out, "entries: (unknown: {})", e)?,
+        None => writeln!(out, "entries: {}", header.entries)?,
+    }
+    writeln!(out, "sequence range: {} .. {}", header.min_seq, header.max_seq)?;
+    match header.created_at {
+        Some(created_at) => writeln!(out, "created at: {} (unix ms)", created_at)?,
+        None => writeln!(out, "created at: (unknown)")?,
+    }
+    match SSTable::filter_index(&FileStorage, &name) {
+        Ok(Some(index)) => writeln!(out, "filter partitions: {}", index.partitions.len())?,
+        Ok(None) => writeln!(out, "filter partitions: (no filter)")?,
+        Err(e) => writeln!(out, "filter partitions: (unreadable: {})", e)?,
+    }
+    match &range {
+        Some((first, last)) => writeln!(out, "key range: {} .. {}", quoted(first), quoted(last))?,
+        None => writeln!(out, "key range: (none)")?,
+    }
+    writeln!(out)?;
+
+    let mut entries = open(&name)?;
+    if options.keys_only {
+        entries = entries.keys_only();
+    }
+    let limit = options.limit.unwrap_or(u64::MAX);
+    let mut listed = 0u64;
+    while listed < limit {
+        let offset = entries.offset()?;
+        match entries.next() {
+            Some(Ok((key, entry))) => writeln!(out, "{:>8}  {}", offset, describe(&key, &entry))?,
+            Some(Err(e)) => return parse_failure(out, e),
+            None => break,
+        }
+        listed += 1;
+    }
+    if header.entries > listed {
+        writeln!(out, "... {} more entries", header.entries - listed)?;
+    }
+
+    let tombstones = match entries.into_range_tombstones() {
+        Ok(tombstones) => tombstones,
+        Err(e) => return parse_failure(out, e),
+    };
+    writeln!(out)?;
+    writeln!(out, "range tombstones: {}", tombstones.len())?;
+    for tombstone in tombstones {
+        writeln!(
+            out,
+            "  [{}, {}) seq={}",
+            quoted(&tombstone.start),
+            quoted(&tombstone.end),
+            tombstone.seq
+        )?;
+    }
+    out.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Open the table for a front-to-back read, with `salvage` not relying on its footer
+fn open(name: &str, salvage: bool) -> io::Result<SSTableIterator> {
+    match salvage {
+        true => SSTableIterator::open_salvage(&FileStorage, name, DEFAULT_READ_AHEAD),
+        false => SSTableIterator::open_sequential(&FileStorage, name, DEFAULT_READ_AHEAD),
+    }
+}
+
+fn parse_failure<W: Write>(out: &mut W, error: io::Error) -> io::Result<ExitCode> {
+    writeln!(out, "error: {}", error)?;
+    out.flush()?;
+    Ok(ExitCode::FAILURE)
+}
+
+fn describe(key: &[u8], entry: &Entry) -> String {
+    let op = match &entry.op {
+        Op::Put(value) if value.is_empty() => "put".to_string(),
+        Op::Put(value) => format!("put {}", quoted(value)),
+        Op::Delete => "delete".to_string(),
+        Op::Merge(operand) if operand.is_empty() => "merge".to_string(),
+        Op::Merge(operand) => format!("merge {}", quoted(operand)),
+        Op::Blob(pointer) if pointer.len == 0 => "blob".to_string(),
+        Op::Blob(pointer) => format!(
+            "blob file={} offset={} len={}",
+            pointer.file, pointer.offset, pointer.len
+        ),
+    };
+    let mut line = format!("{} seq={} {}", quoted(key), entry.seq, op);
+    if let Some(expires_at) = entry.expires_at {
+        line.push_str(&format!(" expires_at={}", expires_at));
+    }
+    line
+}
+
+/// Size and CRC32 of the whole file, in the form a backup manifest records them
+fn file_checksum(path: &Path) -> io::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok((size, hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use storage_engine::entry::RangeTombstone;
+    use storage_engine::sstable::SSTableWriter;
+
+    fn write_table(path: &str) {
+        let mut writer = SSTableWriter::create(&FileStorage, path).unwrap();
+        writer.add(b"apple", &Entry::put(3, b"red".to_vec())).unwrap();
+        writer.add(b"apple", &Entry::put(1, b"green".to_vec())).unwrap();
+        writer.add(b"bin\xff", &Entry::tombstone(4)).unwrap();
+        writer.add(b"cherry", &Entry::merge(5, b"+1".to_vec())).unwrap();
+        let tombstone = RangeTombstone {
+            start: b"x".to_vec(),
+            end: b"z".to_vec(),
+            seq: 6,
+        };
+        writer.finish(&[tombstone], 6).unwrap();
+    }
+
+    fn run_dump(path: &str, keys_only: bool, limit: Option<u64>) -> (String, ExitCode) {
+        let mut out = Vec::new();
+        let options = DumpOptions { keys_only, limit };
+        let code = dump(Path::new(path), &options, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), code)
+    }
+
+    #[test]
+    fn test_dump_table() {
+        let dir = "test_sst_dump";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{}/sstable_000000.sst", dir);
+        write_table(&path);
+
+        let (output, code) = run_dump(&path, false, None);
+        assert_eq!(code, ExitCode::SUCCESS);
+        let body = output.split_once("\n\n").unwrap().1;
+        assert!(output.contains("format version: 8\nentries: 4\nsequence range: 1 .. 6\n"));
+        assert!(output.contains("\ncreated at: "));
+        assert!(output.contains("\nfilter partitions: 1\n"));
+        assert!(output.contains("key range: \"apple\" .. \"cherry\"\n"));
+        assert_eq!(
+            body,
+            "      32  \"apple\" seq=3 put \"red\"\n\
+             \x20     65  \"apple\" seq=1 put \"green\"\n\
+             \x20    100  \"bin\\xff\" seq=4 delete\n\
+             \x20    129  \"cherry\" seq=5 merge \"+1\"\n\
+             \n\
+             range tombstones: 1\n  [\"x\", \"z\") seq=6\n"
+        );
+
+        let (output, _) = run_dump(&path, true, Some(1));
+        assert!(output.contains("\"apple\" seq=3 put\n... 3 more entries\n"));
+        assert!(output.contains("range tombstones: 1\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dump_truncated_table() {
+        let dir = "test_sst_dump_corrupt";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{}/sstable_000000.sst", dir);
+        write_table(&path);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..111]).unwrap();
+
+        let (output, code) = run_dump(&path, false, None);
+        assert_eq!(code, ExitCode::FAILURE);
+        let footer = format!("entries: (unknown: {}: no valid footer at offset 79;", path);
+        assert!(output.contains(&footer), "{}", output);
+        assert!(output.contains("key range: \"apple\" .. \"apple\"\n"));
+        assert!(output.contains("\"apple\" seq=1 put \"green\"\n"));
+        let error = format!(
+            "error: {}: file truncated: the sequence number of entry 2 at offset 108 runs past \
+             the end of the file at 111 bytes\n",
+            path
+        );
+        assert!(output.ends_with(&error), "{}", output);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! `stats`: a summary of what each column family holds, on disk and in memory, and of
+//! what its compactions have done since it was created
+
+use super::quoted;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use storage_engine::sstable::{SSTable, TableStats};
+use storage_engine::wal::WalIterator;
+use storage_engine::{CompactionTotals, Db, FileStorage, Options};
+
+struct FamilyStats {
+    name: String,
+    tables: Vec<(PathBuf, TableStats)>,
+    wal_path: PathBuf,
+    wal_bytes: u64,
+    wal_records: u64,
+    memtable_entries: usize,
+    live_keys: u64,
+    next_sstable: usize,
+    last_sequence: u64,
+    compaction: CompactionTotals,
+}
+
+/// Print statistics for every column family of the database in `dir`, as a report or
+/// as JSON. The database is opened read-only, so this can run alongside other readers.
+pub fn run(dir: &Path, options: &Options, json: bool) -> io::Result<ExitCode> {
+    let options = Options {
+        read_only: true,
+        ..options.clone()
+    };
+    let db = Db::open_with_options(dir, options)?;
+    let mut families = Vec::new();
+    for name in db.column_families() {
+        if let Some(family) = db.cf(&name) {
+            families.push(collect(name, &family)?);
+        }
+    }
+
+    let mut stdout = io::stdout().lock();
+    if json {
+        let families: Vec<Value> = families.iter().map(FamilyStats::to_json).collect();
+        serde_json::to_writer_pretty(&mut stdout, &json!({ "column_families": families }))?;
+        writeln!(stdout)?;
+    } else {
+        for family in &families {
+            family.report(&mut stdout)?;
+        }
+    }
+    stdout.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn collect(name: String, db: &Db) -> io::Result<FamilyStats> {
+    let mut tables = Vec::new();
+    for path in db.sstable_paths() {
+        let stats = SSTable::stats(&FileStorage, &path.to_string_lossy())?;
+        tables.push((path, stats));
+    }
+
+    // Records before a torn tail are counted; recovery discards the rest
+    let wal_path = db.wal_path();
+    let wal_bytes = fs::metadata(&wal_path)?.len();
+    let mut wal_records = 0;
+    for frame in WalIterator::open(&wal_path.to_string_lossy())?.map_while(Result::ok) {
+        wal_records += frame.records.len() as u64;
+    }
+
+    let mut live_keys = 0;
+    for item in db.iter() {
+        item?;
+        live_keys += 1;
+    }
+
+    Ok(FamilyStats {
+        name,
+        tables,
+        wal_path,
+        wal_bytes,
+        wal_records,
+        memtable_entries: db.size(),
+        live_keys,
+        next_sstable: db.next_sstable_number(),
+        last_sequence: db.last_sequence(),
+        compaction: db.compaction_totals(),
+    })
+}
+
+impl FamilyStats {
+    fn table_bytes(&self) -> u64 {
+        self.tables.iter().map(|(_, stats)| stats.bytes).sum()
+    }
+
+    fn report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "column family {}", self.name)?;
+        writeln!(out, "  sstables: {} ({} bytes)", self.tables.len(), self.table_bytes())?;
+        for (path, stats) in &self.tables {
+            let range = match (&stats.first_key, &stats.last_key) {
+                (Some(first), Some(last)) => format!("{} .. {}", quoted(first), quoted(last)),
+                _ => "empty".to_string(),
+            };
+            writeln!(
+                out,
+                "    {}  {} entries  {} bytes  {}",
+                file_name(path),
+                stats.entries,
+                stats.bytes,
+                range
+            )?;
+        }
+        writeln!(out, "  wal: {} bytes, {} records", self.wal_bytes, self.wal_records)?;
+        writeln!(out, "  memtable entries: {}", self.memtable_entries)?;
+        writeln!(out, "  live keys: {}", self.live_keys)?;
+        writeln!(out, "  next sstable: {}", self.next_sstable)?;
+        writeln!(out, "  last sequence: {}", self.last_sequence)?;
+        let totals = &self.compaction;
+        writeln!(
+            out,
+            "  compactions: {} ({} bytes read, {} written, {} keys, {} versions and {} \
+             tombstones dropped, {} ms)",
+            totals.compactions,
+            totals.bytes_read,
+            totals.bytes_written,
+            totals.keys,
+            totals.versions_dropped,
+            totals.tombstones_dropped,
+            totals.micros / 1000
+        )?;
+        writeln!(out, "  write amplification: {:.2}", totals.write_amplification())
+    }
+
+    fn to_json(&self) -> Value {
+        let tables: Vec<Value> = self
+            .tables
+            .iter()
+            .map(|(path, stats)| {
+                json!({
+                    "file": file_name(path),
+                    "bytes": stats.bytes,
+                    "entries": stats.entries,
+                    "min_sequence": stats.min_seq,
+                    "max_sequence": stats.max_seq,
+                    "created_at": stats.created_at,
+                    "first_key": stats.first_key.as_deref().map(String::from_utf8_lossy),
+                    "last_key": stats.last_key.as_deref().map(String::from_utf8_lossy),
+                    "range_tombstones": stats.range_tombstones,
+                })
+            })
+            .collect();
+        json!({
+            "name": self.name,
+            "sstable_count": self.tables.len(),
+            "sstable_bytes": self.table_bytes(),
+            "sstables": tables,
+            "wal": {
+                "file": self.wal_path.to_string_lossy(),
+                "bytes": self.wal_bytes,
+                "records": self.wal_records,
+            },
+            "memtable_entries": self.memtable_entries,
+            "live_keys": self.live_keys,
+            "next_sstable": self.next_sstable,
+            "last_sequence": self.last_sequence,
+            "compaction": {
+                "compactions": self.compaction.compactions,
+                "bytes_flushed": self.compaction.bytes_flushed,
+                "bytes_read": self.compaction.bytes_read,
+                "bytes_written": self.compaction.bytes_written,
+                "keys": self.compaction.keys,
+                "versions_dropped": self.compaction.versions_dropped,
+                "tombstones_dropped": self.compaction.tombstones_dropped,
+                "micros": self.compaction.micros,
+                "write_amplification": self.compaction.write_amplification(),
+            },
+        })
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+        .into_owned()
+}
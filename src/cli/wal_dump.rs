@@ -0,0 +1,149 @@
+//! `wal-dump`: print the records of a write-ahead log, for debugging recovery
+
+use super::quoted;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use storage_engine::wal::{WalIterator, WalRecord};
+
+/// Print every frame of the log at `path` in order, each prefixed by its file offset.
+/// Values are shown by length unless `values` is set.
+///
+/// A torn or corrupt frame is reported after the good ones, together with how much of
+/// the file recovery would discard, and the exit status is then a failure.
+pub fn dump<W: Write>(path: &Path, values: bool, out: &mut W) -> io::Result<ExitCode> {
+    let file_len = fs::metadata(path)?.len();
+    let mut frames = WalIterator::open(&path.to_string_lossy())?;
+    let mut frame_count = 0u64;
+    let mut record_count = 0u64;
+    let mut failure = None;
+
+    for frame in frames.by_ref() {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        frame_count += 1;
+        record_count += frame.records.len() as u64;
+        if let Some(seq) = frame.sequence {
+            writeln!(out, "{:>8}  sequence {}", frame.offset, seq)?;
+        }
+        if frame.batch {
+            writeln!(out, "{:>8}  batch of {}", frame.offset, frame.records.len())?;
+            for record in &frame.records {
+                writeln!(out, "{:>8}    {}", "", describe(record, values))?;
+            }
+        } else {
+            for record in &frame.records {
+                writeln!(out, "{:>8}  {}", frame.offset, describe(record, values))?;
+            }
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{} records in {} frames, {} bytes", record_count, frame_count, file_len)?;
+    let code = match failure {
+        Some(e) => {
+            let discarded = file_len - frames.offset();
+            writeln!(out, "error: {}; recovery discards the last {} bytes", e, discarded)?;
+            ExitCode::FAILURE
+        }
+        None => ExitCode::SUCCESS,
+    };
+    out.flush()?;
+    Ok(code)
+}
+
+fn describe(record: &WalRecord, values: bool) -> String {
+    let value = |bytes: &[u8]| {
+        if values {
+            quoted(bytes)
+        } else {
+            format!("({} bytes)", bytes.len())
+        }
+    };
+    let expiry = |expires_at: &Option<u64>| {
+        expires_at.map_or_else(String::new, |at| format!(" expires_at={}", at))
+    };
+    match record {
+        WalRecord::Put { key, value: v, expires_at } => {
+            format!("put {} {}{}", quoted(key), value(v), expiry(expires_at))
+        }
+        WalRecord::PutBlob { key, pointer, expires_at } => format!(
+            "blob {} file={} offset={} len={}{}",
+            quoted(key),
+            pointer.file,
+            pointer.offset,
+            pointer.len,
+            expiry(expires_at)
+        ),
+        WalRecord::Delete { key } => format!("delete {}", quoted(key)),
+        WalRecord::Merge { key, operand } => format!("merge {} {}", quoted(key), value(operand)),
+        WalRecord::DeleteRange { start, end } => {
+            format!("delete-range [{}, {})", quoted(start), quoted(end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use storage_engine::wal::WriteAheadLog;
+    use storage_engine::WriteBatch;
+
+    fn run_dump(path: &str, values: bool) -> (String, ExitCode) {
+        let mut out = Vec::new();
+        let code = dump(Path::new(path), values, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), code)
+    }
+
+    #[test]
+    fn test_dump_log() {
+        let path = "test_wal_dump.log";
+        let _ = fs::remove_file(path);
+
+        {
+            let mut wal = WriteAheadLog::new(path).unwrap();
+            wal.log_put(b"user:1", b"alice").unwrap();
+            wal.log_delete(b"user:1").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("a", "1").delete("b");
+            wal.log_batch(&batch).unwrap();
+            wal.log_put_with_expiry(b"tmp", b"x", 99).unwrap();
+        }
+
+        let (output, code) = run_dump(path, false);
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(
+            output,
+            "       4  put \"user:1\" (5 bytes)\n\
+             \x20     41  delete \"user:1\"\n\
+             \x20     69  batch of 2\n\
+             \x20           put \"a\" (1 bytes)\n\
+             \x20           delete \"b\"\n\
+             \x20    108  put \"tmp\" (1 bytes) expires_at=99\n\
+             \n\
+             5 records in 4 frames, 146 bytes\n"
+        );
+        let (output, _) = run_dump(path, true);
+        assert!(output.contains("put \"user:1\" \"alice\"\n"));
+
+        // A torn final frame is reported, not skipped
+        OpenOptions::new().write(true).open(path).unwrap().set_len(143).unwrap();
+        let (output, code) = run_dump(path, false);
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(!output.contains("tmp"));
+        assert!(output.ends_with(
+            "4 records in 3 frames, 143 bytes\n\
+             error: incomplete record (27 of 30 bytes) at offset 108; \
+             recovery discards the last 35 bytes\n"
+        ));
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+//! A client for `storage-engine serve`, speaking its Redis protocol (RESP) over TCP or
+//! a Unix domain socket.
+//!
+//! Calls return `io::Result`. A failure to reach the server or a connection that breaks
+//! is a plain I/O error; a missing key, an error reply, or a reply that isn't RESP
+//! carries a [`ClientError`], recovered with [`ClientError::from_io`].
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Prefix of an address naming a Unix domain socket, as given to `--listen`
+const UNIX_PREFIX: &str = "unix:";
+/// Keys a `SCAN` call examines
+const SCAN_COUNT: usize = 1000;
+/// Values fetched in one round trip by [`Client::scan_prefix`]
+const GET_BATCH: usize = 100;
+/// Longest bulk string a reply may hold, the most the server takes in a command
+const MAX_BULK_LEN: usize = 512 << 20;
+/// Bytes reserved for a bulk string up front; longer ones grow as they arrive
+const BULK_PREALLOCATE: usize = 64 << 10;
+/// Elements reserved for an array up front
+const ARRAY_PREALLOCATE: usize = 1024;
+
+/// Connection settings for a [`Client`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Longest wait to connect over TCP, per address the host resolves to; `None`
+    /// leaves it to the system
+    pub connect_timeout: Option<Duration>,
+    /// Longest wait for each read or write on the connection; `None` waits for ever
+    pub io_timeout: Option<Duration>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            io_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Failures of a [`Client`] call other than the connection's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The key has no value
+    NotFound,
+    /// The server answered with an error reply
+    Server { message: String },
+    /// The server's reply did not follow the protocol or did not fit the request
+    Protocol { reason: String },
+}
+
+impl ClientError {
+    /// Extract the client error carried by `err`, if there is one
+    pub fn from_io(err: &io::Error) -> Option<&ClientError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            ClientError::NotFound => io::ErrorKind::NotFound,
+            ClientError::Server { .. } => io::ErrorKind::Other,
+            ClientError::Protocol { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NotFound => write!(f, "key not found"),
+            ClientError::Server { message } => write!(f, "server error: {}", message),
+            ClientError::Protocol { reason } => write!(f, "protocol error: {}", reason),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<ClientError> for io::Error {
+    fn from(err: ClientError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// A connection to a server, kept open between calls.
+///
+/// A call that finds the connection closed, as it is once the server restarts,
+/// reconnects and sends its request again, so a write may be applied twice if the
+/// server went away after applying it but before answering.
+pub struct Client {
+    address: String,
+    options: ClientOptions,
+    connection: Option<Connection>,
+}
+
+impl Client {
+    /// Connect to `address`: `host:port`, or `unix:<path>` for a Unix domain socket
+    pub fn connect(address: &str) -> io::Result<Self> {
+        Client::connect_with_options(address, ClientOptions::default())
+    }
+
+    pub fn connect_with_options(address: &str, options: ClientOptions) -> io::Result<Self> {
+        let connection = Connection::open(address, &options)?;
+        Ok(Client { address: address.to_string(), options, connection: Some(connection) })
+    }
+
+    /// The value of `key`, or [`ClientError::NotFound`]
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> io::Result<Vec<u8>> {
+        match self.call(&[b"GET", key.as_ref()])? {
+            Reply::Bulk(Some(value)) => Ok(value),
+            Reply::Bulk(None) => Err(ClientError::NotFound.into()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> io::Result<()> {
+        match self.call(&[b"SET", key.as_ref(), value.as_ref()])? {
+            Reply::Status(_) => Ok(()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    /// Delete `key`, or fail with [`ClientError::NotFound`] if it had no value
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> io::Result<()> {
+        match self.call(&[b"DEL", key.as_ref()])? {
+            Reply::Integer(0) => Err(ClientError::NotFound.into()),
+            Reply::Integer(_) => Ok(()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    /// Pairs whose key starts with `prefix`, in key order.
+    ///
+    /// The keys are listed with `SCAN` and their values fetched after, so a key deleted
+    /// in between is left out.
+    pub fn scan_prefix<P: AsRef<[u8]>>(
+        &mut self,
+        prefix: P,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pattern = escape_glob(prefix.as_ref());
+        pattern.push(b'*');
+        let count = SCAN_COUNT.to_string();
+        let mut keys = Vec::new();
+        let mut cursor = b"0".to_vec();
+        loop {
+            let request: [&[u8]; 6] =
+                [b"SCAN", &cursor, b"MATCH", &pattern, b"COUNT", count.as_bytes()];
+            let reply = self.call(&request)?;
+            let Reply::Array(mut reply) = reply else { return Err(unexpected(&reply)) };
+            match (reply.pop(), reply.pop(), reply.is_empty()) {
+                (Some(Reply::Array(found)), Some(Reply::Bulk(Some(next))), true) => {
+                    for key in found {
+                        match key {
+                            Reply::Bulk(Some(key)) => keys.push(key),
+                            key => return Err(unexpected(&key)),
+                        }
+                    }
+                    cursor = next;
+                }
+                _ => return Err(protocol("malformed SCAN reply".to_string())),
+            }
+            if cursor == b"0" {
+                break;
+            }
+        }
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(GET_BATCH) {
+            let requests: Vec<[&[u8]; 2]> = batch.iter().map(|key| [b"GET", &key[..]]).collect();
+            let requests: Vec<&[&[u8]]> = requests.iter().map(|request| &request[..]).collect();
+            for (key, reply) in batch.iter().zip(self.pipeline(&requests)?) {
+                match reply {
+                    Reply::Bulk(Some(value)) => pairs.push((key.clone(), value)),
+                    Reply::Bulk(None) => {}
+                    reply => return Err(unexpected(&reply)),
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Send one request and read its reply, an error reply becoming an error
+    fn call(&mut self, request: &[&[u8]]) -> io::Result<Reply> {
+        let reply = self.pipeline(&[request])?.pop();
+        reply.ok_or_else(|| protocol("no reply".to_string()))
+    }
+
+    /// Send `requests` together and read their replies, reconnecting once if the
+    /// connection turns out to have been closed
+    fn pipeline(&mut self, requests: &[&[&[u8]]]) -> io::Result<Vec<Reply>> {
+        let reused = self.connection.is_some();
+        let replies = match self.exchange(requests) {
+            Err(e) if reused && is_closed(&e) => self.exchange(requests),
+            result => result,
+        }?;
+        for reply in &replies {
+            if let Reply::Error(message) = reply {
+                return Err(ClientError::Server { message: message.clone() }.into());
+            }
+        }
+        Ok(replies)
+    }
+
+    /// One round trip, dropping the connection if it fails part way
+    fn exchange(&mut self, requests: &[&[&[u8]]]) -> io::Result<Vec<Reply>> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(Connection::open(&self.address, &self.options)?),
+        };
+        let replies = connection.exchange(requests);
+        if replies.is_err() {
+            self.connection = None;
+        }
+        replies
+    }
+}
+
+/// The buffered halves of an open connection
+struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl Connection {
+    fn open(address: &str, options: &ClientOptions) -> io::Result<Self> {
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) =
+            match address.strip_prefix(UNIX_PREFIX) {
+                Some(path) => {
+                    let stream = connect_unix(path)?;
+                    stream.set_read_timeout(options.io_timeout)?;
+                    stream.set_write_timeout(options.io_timeout)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+                None => {
+                    let stream = connect_tcp(address, options.connect_timeout)?;
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(options.io_timeout)?;
+                    stream.set_write_timeout(options.io_timeout)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+            };
+        Ok(Connection { reader: BufReader::new(reader), writer: BufWriter::new(writer) })
+    }
+
+    fn exchange(&mut self, requests: &[&[&[u8]]]) -> io::Result<Vec<Reply>> {
+        for request in requests {
+            write!(self.writer, "*{}\r\n", request.len())?;
+            for arg in request.iter() {
+                write!(self.writer, "${}\r\n", arg.len())?;
+                self.writer.write_all(arg)?;
+                self.writer.write_all(b"\r\n")?;
+            }
+        }
+        self.writer.flush()?;
+        requests.iter().map(|_| read_reply(&mut self.reader)).collect()
+    }
+}
+
+fn connect_tcp(address: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else { return TcpStream::connect(address) };
+    let mut last_error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        let message = format!("{} resolves to no addresses", address);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    }))
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<TcpStream> {
+    let message = "Unix domain sockets are not supported on this platform";
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+/// Whether `err` means the server closed the connection, rather than being slow or
+/// unreachable
+fn is_closed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// A RESP reply
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    /// `None` for the nil bulk string
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.truncate(line.len() - 2);
+    let Some((&kind, rest)) = line.split_first() else {
+        return Err(protocol("empty reply line".to_string()));
+    };
+    let text = || String::from_utf8_lossy(rest).into_owned();
+    let number = || {
+        let parsed = std::str::from_utf8(rest).ok().and_then(|text| text.parse::<i64>().ok());
+        parsed.ok_or_else(|| protocol(format!("invalid number {:?}", rest.escape_ascii())))
+    };
+    Ok(match kind {
+        b'+' => Reply::Status(text()),
+        b'-' => Reply::Error(text()),
+        b':' => Reply::Integer(number()?),
+        b'$' => match number()? {
+            -1 => Reply::Bulk(None),
+            len => {
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|&len| len <= MAX_BULK_LEN)
+                    .ok_or_else(|| protocol(format!("bulk string length {} out of range", len)))?;
+                let mut value = Vec::with_capacity((len + 2).min(BULK_PREALLOCATE));
+                reader.by_ref().take(len as u64 + 2).read_to_end(&mut value)?;
+                if value.len() < len + 2 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                if !value.ends_with(b"\r\n") {
+                    return Err(protocol("bulk string not terminated by CRLF".to_string()));
+                }
+                value.truncate(len);
+                Reply::Bulk(Some(value))
+            }
+        },
+        b'*' => match number()? {
+            -1 => Reply::Array(Vec::new()),
+            len => {
+                let len = usize::try_from(len)
+                    .map_err(|_| protocol(format!("array length {} out of range", len)))?;
+                let mut items = Vec::with_capacity(len.min(ARRAY_PREALLOCATE));
+                for _ in 0..len {
+                    items.push(read_reply(reader)?);
+                }
+                Reply::Array(items)
+            }
+        },
+        _ => return Err(protocol(format!("unknown reply type {:?}", kind as char))),
+    })
+}
+
+/// `text` with the characters `SCAN MATCH` treats as wildcards escaped
+fn escape_glob(text: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(text.len());
+    for &b in text {
+        if matches!(b, b'*' | b'?' | b'[' | b']' | b'\\') {
+            escaped.push(b'\\');
+        }
+        escaped.push(b);
+    }
+    escaped
+}
+
+fn protocol(reason: String) -> io::Error {
+    ClientError::Protocol { reason }.into()
+}
+
+fn unexpected(reply: &Reply) -> io::Error {
+    protocol(format!("unexpected reply {:?}", reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_reply() {
+        let mut input: &[u8] =
+            b"+OK\r\n-ERR no\r\n:3\r\n$5\r\na\r\nbc\r\n$-1\r\n*2\r\n$1\r\n0\r\n*0\r\n!x\r\n+cut";
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Status("OK".to_string()));
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Error("ERR no".to_string()));
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Integer(3));
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Bulk(Some(b"a\r\nbc".to_vec())));
+        assert_eq!(read_reply(&mut input).unwrap(), Reply::Bulk(None));
+        let array = Reply::Array(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(vec![])]);
+        assert_eq!(read_reply(&mut input).unwrap(), array);
+        let err = read_reply(&mut input).unwrap_err();
+        assert!(matches!(ClientError::from_io(&err), Some(ClientError::Protocol { .. })));
+        assert_eq!(read_reply(&mut input).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Lengths beyond what a reply may hold fail before anything is allocated
+        for line in ["$9223372036854775807\r\n", "$536870913\r\n", "$-2\r\n", "*-5\r\n"] {
+            let err = read_reply(&mut line.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", line);
+        }
+        let mut short: &[u8] = b"$1000000\r\nabc";
+        assert_eq!(read_reply(&mut short).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut unfinished: &[u8] = b"*4611686018427387903\r\n:1\r\n";
+        assert_eq!(read_reply(&mut unfinished).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(escape_glob(b"a*b?[c]\\"), b"a\\*b\\?\\[c\\]\\\\");
+    }
+}
@@ -0,0 +1,386 @@
+use crate::backup::MANIFEST;
+use crate::lock::{DirLock, LOCK_FILE};
+use crate::memtable::MemTable;
+use crate::options::Options;
+use crate::quota::DiskUsage;
+use crate::rate_limit::RateLimiter;
+use crate::storage::Storage;
+use crate::wal::WAL_FILE;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+/// Name of the column family stored directly in the database directory
+pub const DEFAULT_CF: &str = "default";
+
+/// Why writes fail on a database opened with [`Options::read_only`]
+pub(crate) const READ_ONLY: &str = "database is open read-only";
+
+/// Why direct writes fail on a follower
+const FOLLOWER: &str = "database is a follower; it takes writes only from its primary";
+
+/// Subdirectory prefix of a named column family
+pub(crate) const CF_DIR_PREFIX: &str = "cf_";
+/// Suffix given to a column family's directory while it is being deleted
+pub(crate) const DROPPED_SUFFIX: &str = ".dropped";
+
+/// The column families of one database.
+///
+/// The default family lives in the database directory itself; every named family gets a
+/// `cf_<name>` subdirectory holding its own WAL and SSTables, so families never share
+/// files and dropping one is a directory removal.
+pub(crate) struct ColumnFamilies {
+    dir: PathBuf,
+    options: Options,
+    default: Arc<RwLock<MemTable>>,
+    named: Mutex<BTreeMap<String, Arc<RwLock<MemTable>>>>,
+    /// Shared by every family's memtable
+    disk_usage: Arc<DiskUsage>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Set while the database is a follower that has not been promoted
+    follower: AtomicBool,
+    /// Where the family directories are, as opposed to the files in them
+    storage: Arc<dyn Storage>,
+    _lock: Box<dyn Send + Sync>,
+}
+
+impl ColumnFamilies {
+    /// Recover every named family found in `dir`, finishing any interrupted drop unless
+    /// opening read-only. `lock` is held for as long as the families are open.
+    pub(crate) fn open(
+        dir: &Path,
+        options: &Options,
+        default: Arc<RwLock<MemTable>>,
+        lock: Box<dyn Send + Sync>,
+    ) -> io::Result<Self> {
+        let (disk_usage, rate_limiter) = {
+            let default = default.read().unwrap_or_else(PoisonError::into_inner);
+            (default.disk_usage().clone(), default.rate_limiter().clone())
+        };
+        let storage = options.storage();
+        let mut named = BTreeMap::new();
+        for path in storage.list_subdirs(dir)? {
+            let Some(file_name) = path.file_name() else { continue };
+            let file_name = file_name.to_string_lossy().into_owned();
+            if file_name.ends_with(DROPPED_SUFFIX) {
+                if !options.read_only {
+                    storage.remove_dir_all(&path)?;
+                }
+            } else if let Some(name) = file_name.strip_prefix(CF_DIR_PREFIX) {
+                let mut memtable = open_memtable(&path, options, &disk_usage, &rate_limiter)?;
+                if options.read_only {
+                    memtable.close(READ_ONLY);
+                }
+                named.insert(name.to_string(), Arc::new(RwLock::new(memtable)));
+            }
+        }
+
+        Ok(ColumnFamilies {
+            dir: dir.to_path_buf(),
+            options: options.clone(),
+            default,
+            named: Mutex::new(named),
+            disk_usage,
+            rate_limiter,
+            follower: AtomicBool::new(options.follower),
+            storage,
+            _lock: lock,
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<RwLock<MemTable>>> {
+        if name == DEFAULT_CF {
+            return Some(self.default.clone());
+        }
+        self.lock().get(name).cloned()
+    }
+
+    pub(crate) fn create(&self, name: &str) -> io::Result<Arc<RwLock<MemTable>>> {
+        self.ensure_writable()?;
+        validate_name(name)?;
+        let mut named = self.lock();
+        if name == DEFAULT_CF || named.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("column family {:?} already exists", name),
+            ));
+        }
+
+        let cf_dir = self.cf_dir(name);
+        self.storage.create_dir_all(&cf_dir)?;
+        let memtable =
+            open_memtable(&cf_dir, &self.options, &self.disk_usage, &self.rate_limiter)?;
+        let memtable = Arc::new(RwLock::new(memtable));
+        named.insert(name.to_string(), memtable.clone());
+        Ok(memtable)
+    }
+
+    /// Remove a named family and delete its files. The directory is renamed first, so a
+    /// crash part-way through the deletion never leaves a half-populated family behind.
+    pub(crate) fn remove(&self, name: &str) -> io::Result<()> {
+        self.ensure_writable()?;
+        let mut named = self.lock();
+        let Some(memtable) = named.remove(name) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("column family {:?} does not exist", name),
+            ));
+        };
+        // Handles still held elsewhere must not write into the deleted files
+        let mut memtable = memtable.write().unwrap_or_else(PoisonError::into_inner);
+        memtable.close("column family was dropped");
+        self.disk_usage.shrink(memtable.disk_bytes()?);
+        drop(memtable);
+
+        let cf_dir = self.cf_dir(name);
+        let mut dropped = cf_dir.clone().into_os_string();
+        dropped.push(DROPPED_SUFFIX);
+        let dropped = PathBuf::from(dropped);
+        self.storage.rename_dir(&cf_dir, &dropped)?;
+        self.storage.remove_dir_all(&dropped)
+    }
+
+    /// Names of all families, the default first
+    pub(crate) fn names(&self) -> Vec<String> {
+        let mut names = vec![DEFAULT_CF.to_string()];
+        names.extend(self.lock().keys().cloned());
+        names
+    }
+
+    /// Every family with its directory relative to the database directory, the
+    /// default first
+    pub(crate) fn all(&self) -> Vec<(PathBuf, Arc<RwLock<MemTable>>)> {
+        let mut all = vec![(PathBuf::new(), self.default.clone())];
+        for (name, memtable) in self.lock().iter() {
+            all.push((PathBuf::from(format!("{}{}", CF_DIR_PREFIX, name)), memtable.clone()));
+        }
+        all
+    }
+
+    fn ensure_writable(&self) -> io::Result<()> {
+        if self.options.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, READ_ONLY));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_follower(&self) -> bool {
+        self.follower.load(Ordering::SeqCst)
+    }
+
+    /// Stop being a follower, so direct writes are accepted from now on
+    pub(crate) fn promote(&self) {
+        self.follower.store(false, Ordering::SeqCst);
+    }
+
+    /// Fail direct writes while the database is a follower
+    pub(crate) fn ensure_primary(&self) -> io::Result<()> {
+        if self.is_follower() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, FOLLOWER));
+        }
+        Ok(())
+    }
+
+    fn cf_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", CF_DIR_PREFIX, name))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Arc<RwLock<MemTable>>>> {
+        self.named.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Delete the database in `dir`: exactly the files and family directories the engine
+/// creates, leaving anything else alone. Fails without deleting anything if `dir` holds
+/// neither a WAL nor a backup manifest, or is open elsewhere. With `remove_dir`, `dir`
+/// itself is removed too once nothing else is left in it.
+pub(crate) fn destroy(dir: &Path, remove_dir: bool) -> io::Result<()> {
+    if !dir.join(WAL_FILE).is_file() && !dir.join(MANIFEST).is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not contain a database", dir.display()),
+        ));
+    }
+    let lock = DirLock::acquire(dir, false)?;
+    remove_engine_files(dir)?;
+    drop(lock);
+    fs::remove_file(dir.join(LOCK_FILE))?;
+    if remove_dir && fs::read_dir(dir)?.next().is_none() {
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+fn remove_engine_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            if name.ends_with(DROPPED_SUFFIX) {
+                fs::remove_dir_all(&path)?;
+            } else if name.starts_with(CF_DIR_PREFIX) {
+                remove_engine_files(&path)?;
+                if fs::read_dir(&path)?.next().is_none() {
+                    fs::remove_dir(&path)?;
+                }
+            }
+        } else if name == MANIFEST || MemTable::owns_file(&name) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The column families of the database in `dir`, each with its directory relative to
+/// `dir`: the default family's is empty, and the named ones follow in name order
+pub(crate) fn family_dirs(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut named = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_dir() || name.ends_with(DROPPED_SUFFIX) {
+            continue;
+        }
+        if let Some(family) = name.strip_prefix(CF_DIR_PREFIX) {
+            named.push((family.to_string(), PathBuf::from(&name)));
+        }
+    }
+    named.sort();
+    let mut families = vec![(DEFAULT_CF.to_string(), PathBuf::new())];
+    families.extend(named);
+    Ok(families)
+}
+
+fn open_memtable(
+    dir: &Path,
+    options: &Options,
+    disk_usage: &Arc<DiskUsage>,
+    rate_limiter: &Arc<RateLimiter>,
+) -> io::Result<MemTable> {
+    let wal_path = dir.join(WAL_FILE).to_string_lossy().into_owned();
+    MemTable::with_shared(&wal_path, options, disk_usage.clone(), rate_limiter.clone())
+}
+
+/// Family names become directory names, so only a conservative character set is allowed
+fn validate_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid column family name {:?}", name),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use crate::storage::{MemStorage, Storage};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_same_key_in_two_families() {
+        let dir = "test_cf_isolation";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let events = db.create_cf("events").unwrap();
+        db.put("key", "default").unwrap();
+        events.put("key", "event").unwrap();
+        events.put("only_events", "1").unwrap();
+
+        assert_eq!(db.get("key"), Some("default".to_string()));
+        assert_eq!(db.cf("events").unwrap().get("key"), Some("event".to_string()));
+        assert_eq!(db.get("only_events"), None);
+        assert_eq!(db.scan("a", "z").unwrap().len(), 1);
+        assert!(db.create_cf("events").is_err());
+        assert!(db.create_cf("../escape").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_family() {
+        let dir = "test_cf_drop";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let events = db.create_cf("events").unwrap();
+        let users = db.create_cf("users").unwrap();
+        events.put("key", "event").unwrap();
+        events.flush().unwrap();
+        users.put("key", "user").unwrap();
+
+        db.drop_cf("events").unwrap();
+        assert!(db.cf("events").is_none());
+        assert!(events.put("key", "again").is_err());
+        assert!(!fs::exists(format!("{}/cf_events", dir)).unwrap());
+        assert_eq!(users.get("key"), Some("user".to_string()));
+        assert_eq!(db.column_families(), vec!["default", "users"]);
+
+        // A family of the same name starts out empty
+        let events = db.create_cf("events").unwrap();
+        assert_eq!(events.get("key"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_families_recovered() {
+        let dir = "test_cf_recovery";
+        let _ = fs::remove_dir_all(dir);
+
+        {
+            let db = Db::open(dir).unwrap();
+            let events = db.create_cf("events").unwrap();
+            db.put("key", "default").unwrap();
+            events.put("key", "flushed").unwrap();
+            events.flush().unwrap();
+            events.put("pending", "in wal").unwrap();
+        }
+
+        let db = Db::open(dir).unwrap();
+        let events = db.cf("events").unwrap();
+        assert_eq!(db.get("key"), Some("default".to_string()));
+        assert_eq!(events.get("key"), Some("flushed".to_string()));
+        assert_eq!(events.get("pending"), Some("in wal".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_families_in_mem_storage() {
+        let dir = "test_cf_mem_storage";
+        let storage = MemStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            ..Options::default()
+        };
+
+        {
+            let db = Db::open_with_options(dir, options.clone()).unwrap();
+            db.create_cf("events").unwrap().put("key", "event").unwrap();
+            db.create_cf("users").unwrap().put("key", "user").unwrap();
+        }
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.column_families(), vec!["default", "events", "users"]);
+        assert_eq!(db.cf("events").unwrap().get("key"), Some("event".to_string()));
+
+        db.drop_cf("events").unwrap();
+        let families = storage.list_subdirs(Path::new(dir)).unwrap();
+        assert_eq!(families, vec![Path::new(dir).join("cf_users")]);
+        assert!(!fs::exists(dir).unwrap());
+    }
+}
@@ -0,0 +1,1103 @@
+use crate::entry::{Entry, Op, RangeTombstone};
+use crate::options::SyncMode;
+use crate::sstable::{SSTable, SSTableIterator, SSTableWriter};
+use crate::storage::Storage;
+use std::cmp::Reverse;
+use std::io;
+use log::warn;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Suffix of a compaction output (or commit marker) that has not been installed yet
+pub(crate) const COMPACT_SUFFIX: &str = ".compact";
+/// Lists the input tables while compaction outputs are being installed
+pub(crate) const COMPACT_MARKER: &str = "COMPACT_COMMIT";
+/// Names the tables that compaction replaced but that may still be open for reading.
+/// Whatever it lists is deleted when the database is next opened.
+pub(crate) const OBSOLETE_LIST: &str = "OBSOLETE";
+/// Size at which compaction starts a new output table
+const MAX_OUTPUT_TABLE_BYTES: u64 = 64 << 20;
+
+/// What a compaction did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionInfo {
+    pub input_tables: usize,
+    pub input_bytes: u64,
+    pub output_tables: usize,
+    /// Size of the outputs. A compaction that merged anything wrote them on top of what
+    /// flushes wrote: its share of
+    /// [`Stats::write_amplification`](crate::Stats::write_amplification).
+    pub output_bytes: u64,
+    /// Distinct keys read from the inputs
+    pub keys: u64,
+    /// Versions of keys left out of the outputs, overwritten or deleted by newer ones
+    pub versions_dropped: u64,
+    /// Point tombstones left out of the outputs, with nothing left for them to delete
+    pub tombstones_dropped: u64,
+    pub duration: Duration,
+}
+
+/// The tables a merge wrote and what it found in its inputs
+#[derive(Debug, Default)]
+pub(crate) struct Merged {
+    pub(crate) outputs: Vec<String>,
+    pub(crate) keys: u64,
+    pub(crate) versions_dropped: u64,
+    pub(crate) tombstones_dropped: u64,
+}
+
+/// What the compactions of a column family have done since it was created. They are
+/// kept in its table list so they outlast the process, and unlike
+/// [`Stats`](crate::Stats) they are never reset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionTotals {
+    pub compactions: u64,
+    /// Size of the SSTables flushes wrote, which compactions rewrite
+    pub bytes_flushed: u64,
+    /// Size of the tables compactions merged
+    pub bytes_read: u64,
+    /// Size of the tables compactions wrote
+    pub bytes_written: u64,
+    /// Distinct keys compactions read, counted once per compaction
+    pub keys: u64,
+    /// Versions of keys compactions dropped, overwritten or deleted by newer ones
+    pub versions_dropped: u64,
+    /// Point tombstones compactions dropped
+    pub tombstones_dropped: u64,
+    /// Time spent compacting, in microseconds
+    pub micros: u64,
+}
+
+/// Starts the line of the table list that holds the [`CompactionTotals`]
+pub(crate) const TOTALS_PREFIX: &str = "# ";
+
+impl CompactionTotals {
+    /// Bytes flushes and compactions wrote to SSTables per byte flushed, or 0 before
+    /// anything was flushed
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_flushed == 0 {
+            return 0.0;
+        }
+        (self.bytes_flushed + self.bytes_written) as f64 / self.bytes_flushed as f64
+    }
+
+    /// Count a compaction that merged tables
+    pub(crate) fn add(&mut self, info: &CompactionInfo) {
+        self.compactions += 1;
+        self.bytes_read += info.input_bytes;
+        self.bytes_written += info.output_bytes;
+        self.keys += info.keys;
+        self.versions_dropped += info.versions_dropped;
+        self.tombstones_dropped += info.tombstones_dropped;
+        self.micros += info.duration.as_micros() as u64;
+    }
+
+    fn fields(&mut self) -> [(&'static str, &mut u64); 8] {
+        [
+            ("compactions", &mut self.compactions),
+            ("bytes_flushed", &mut self.bytes_flushed),
+            ("bytes_read", &mut self.bytes_read),
+            ("bytes_written", &mut self.bytes_written),
+            ("keys", &mut self.keys),
+            ("versions_dropped", &mut self.versions_dropped),
+            ("tombstones_dropped", &mut self.tombstones_dropped),
+            ("micros", &mut self.micros),
+        ]
+    }
+
+    /// The line of the table list that holds them, `name=value` pairs after
+    /// [`TOTALS_PREFIX`]
+    pub(crate) fn to_line(&self) -> String {
+        let pairs: Vec<String> = self
+            .clone()
+            .fields()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        format!("{}{}", TOTALS_PREFIX, pairs.join(" "))
+    }
+
+    /// Read them from the table list written by [`CompactionTotals::to_line`]. Names
+    /// this version does not know are skipped, and missing ones left at 0.
+    pub(crate) fn from_list(list: &str) -> Self {
+        let mut totals = Self::default();
+        let Some(line) = list.lines().find_map(|line| line.strip_prefix(TOTALS_PREFIX)) else {
+            return totals;
+        };
+        for (name, value) in line.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+            let value = value.parse().unwrap_or(0);
+            if let Some((_, field)) = totals.fields().into_iter().find(|(n, _)| *n == name) {
+                *field = value;
+            }
+        }
+        totals
+    }
+}
+
+/// A live SSTable. Readers that stream it hold a clone, so once compaction retires the
+/// table its file is only deleted when the last of them is done.
+pub(crate) struct TableFile {
+    path: String,
+    storage: Arc<dyn Storage>,
+    retired: AtomicBool,
+}
+
+impl TableFile {
+    pub(crate) fn new(path: String, storage: Arc<dyn Storage>) -> Arc<Self> {
+        Arc::new(TableFile {
+            path,
+            storage,
+            retired: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Delete the file when the last reference to it is dropped
+    pub(crate) fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for TableFile {
+    fn drop(&mut self) {
+        if !self.retired.load(Ordering::Relaxed) {
+            return;
+        }
+        match self.storage.remove(Path::new(&self.path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn!("could not remove obsolete SSTable {}: {}", self.path, err)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Which versions a compaction must keep
+pub(crate) struct Retention {
+    /// Sequence numbers pinned by live snapshots and transactions; each keeps the
+    /// versions it reads
+    pub(crate) snapshots: Vec<u64>,
+    /// Whether the inputs include the oldest table. Only then can a tombstone with
+    /// nothing left beneath it go, as no older table can hold what it deletes.
+    pub(crate) bottommost: bool,
+}
+
+impl Retention {
+    /// The versions of one key, newest first, that some reader can still see
+    fn retain(&self, versions: Vec<Entry>) -> Vec<Entry> {
+        let mut needed = vec![false; versions.len()];
+        for seq in std::iter::once(u64::MAX).chain(self.snapshots.iter().copied()) {
+            let Some(first) = versions.iter().position(|entry| entry.seq <= seq) else {
+                continue;
+            };
+            // A merge operand needs everything beneath it down to its base
+            for (i, entry) in versions.iter().enumerate().skip(first) {
+                needed[i] = true;
+                if !matches!(entry.op, Op::Merge(_)) {
+                    break;
+                }
+            }
+        }
+
+        let mut kept: Vec<Entry> = versions
+            .into_iter()
+            .zip(needed)
+            .filter_map(|(entry, needed)| needed.then_some(entry))
+            .collect();
+        if self.bottommost {
+            while kept.last().is_some_and(|entry| entry.op == Op::Delete) {
+                kept.pop();
+            }
+        }
+        kept
+    }
+}
+
+/// One input table and its next unmerged entry
+struct Input {
+    table: SSTableIterator,
+    head: Option<(Vec<u8>, Entry)>,
+}
+
+impl Input {
+    fn advance(&mut self) -> io::Result<()> {
+        self.head = self.table.next().transpose()?;
+        Ok(())
+    }
+}
+
+/// Merge the tables at `inputs` (oldest first) into new tables, returning their paths
+/// along with counts of the keys merged and the versions and tombstones dropped.
+///
+/// Versions are dropped as `retention` allows; range tombstones are all carried over.
+/// Each output is written to its path plus [`COMPACT_SUFFIX`] and must be put in place
+/// with [`install`]; `output(i)` names the `i`th one. Outputs are split only between
+/// keys, so all versions of a key stay in one table, and there is always at least one
+/// output so the newest table still records the highest sequence number.
+///
+/// With `threads` above 1 the key space is split at keys sampled from the inputs' block
+/// indexes, and the ranges are merged side by side, each into outputs of its own. If
+/// any range fails the others stop, and every output is removed.
+pub(crate) fn merge_tables<F>(
+    storage: &dyn Storage,
+    inputs: &[String],
+    read_ahead: usize,
+    sync_mode: SyncMode,
+    retention: &Retention,
+    threads: usize,
+    mut output: F,
+) -> io::Result<Merged>
+where
+    F: FnMut(usize) -> String,
+{
+    let merge = Merge {
+        storage,
+        inputs,
+        read_ahead,
+        sync_mode,
+        retention,
+        failed: AtomicBool::new(false),
+        keys: AtomicU64::new(0),
+        versions_dropped: AtomicU64::new(0),
+        tombstones_dropped: AtomicU64::new(0),
+    };
+    let mut outputs = Vec::new();
+    let result = split_keys(storage, inputs, threads).and_then(|bounds| {
+        if bounds.is_empty() {
+            merge.write_range(None, None, &mut output, &mut outputs)
+        } else {
+            merge.write_ranges(&bounds, &mut output, &mut outputs)
+        }
+    });
+    if result.is_err() {
+        for table in &outputs {
+            let path = format!("{}{}", table, COMPACT_SUFFIX);
+            if let Err(err) = storage.remove(Path::new(&path)) {
+                warn!("could not remove unfinished compaction output {}: {}", path, err);
+            }
+        }
+    }
+    result.map(|()| Merged {
+        outputs,
+        keys: merge.keys.into_inner(),
+        versions_dropped: merge.versions_dropped.into_inner(),
+        tombstones_dropped: merge.tombstones_dropped.into_inner(),
+    })
+}
+
+/// Keys splitting the inputs into at most `threads` ranges of about the same size, from
+/// the last keys of the blocks their block indexes point to. None for a single range.
+fn split_keys(
+    storage: &dyn Storage,
+    inputs: &[String],
+    threads: usize,
+) -> io::Result<Vec<Vec<u8>>> {
+    if threads < 2 {
+        return Ok(Vec::new());
+    }
+    let mut keys = Vec::new();
+    for path in inputs {
+        keys.extend(SSTable::index_keys(storage, path)?);
+    }
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    keys.sort_unstable();
+    let mut bounds: Vec<Vec<u8>> =
+        (1..threads).map(|i| keys[keys.len() * i / threads].clone()).collect();
+    bounds.dedup();
+    Ok(bounds)
+}
+
+/// One compaction, shared by the threads merging its key ranges
+struct Merge<'a> {
+    storage: &'a dyn Storage,
+    inputs: &'a [String],
+    read_ahead: usize,
+    sync_mode: SyncMode,
+    retention: &'a Retention,
+    /// Set once any range fails, so the others give up
+    failed: AtomicBool,
+    keys: AtomicU64,
+    versions_dropped: AtomicU64,
+    tombstones_dropped: AtomicU64,
+}
+
+impl Merge<'_> {
+    /// Merge the ranges between `bounds` on a thread each, then name their outputs with
+    /// `output` in key order. Until then each range's outputs are named after the first
+    /// output and the range.
+    fn write_ranges<F>(
+        &self,
+        bounds: &[Vec<u8>],
+        mut output: F,
+        outputs: &mut Vec<String>,
+    ) -> io::Result<()>
+    where
+        F: FnMut(usize) -> String,
+    {
+        let first = output(0);
+        let starts = std::iter::once(None).chain(bounds.iter().map(|key| Some(key.as_slice())));
+        let ends = bounds.iter().map(|key| Some(key.as_slice())).chain(std::iter::once(None));
+        let ranges: Vec<_> = starts.zip(ends).collect();
+        let results: Vec<(io::Result<()>, Vec<String>)> = thread::scope(|scope| {
+            let workers: Vec<_> = ranges
+                .iter()
+                .enumerate()
+                .map(|(range, &(start, end))| {
+                    let first = &first;
+                    scope.spawn(move || {
+                        let mut parts = Vec::new();
+                        let name = |i| format!("{}.part{}-{}", first, range, i);
+                        let result = self.write_range(start, end, name, &mut parts);
+                        if result.is_err() {
+                            self.failed.store(true, Ordering::Relaxed);
+                        }
+                        (result, parts)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        self.failed.store(true, Ordering::Relaxed);
+                        (Err(io::Error::other("a compaction thread panicked")), Vec::new())
+                    })
+                })
+                .collect()
+        });
+
+        let mut parts = Vec::new();
+        let mut failure = None;
+        for (result, range_parts) in results {
+            parts.extend(range_parts);
+            if let Err(err) = result {
+                // Report the range that failed, not one it stopped
+                let stopped = |err: &io::Error| err.kind() == io::ErrorKind::Interrupted;
+                if failure.as_ref().is_none_or(|first| stopped(first) && !stopped(&err)) {
+                    failure = Some(err);
+                }
+            }
+        }
+        if let Some(err) = failure {
+            outputs.extend(parts);
+            return Err(err);
+        }
+        for (i, part) in parts.iter().enumerate() {
+            let table = output(i);
+            let from = format!("{}{}", part, COMPACT_SUFFIX);
+            let to = format!("{}{}", table, COMPACT_SUFFIX);
+            if let Err(err) = self.storage.rename(Path::new(&from), Path::new(&to)) {
+                outputs.extend(parts[i..].iter().cloned());
+                return Err(err);
+            }
+            outputs.push(table);
+        }
+        Ok(())
+    }
+
+    /// Merge the keys in `[start, end)`, a missing bound being open, into outputs named
+    /// by `output` and added to `outputs`. Only the range that runs to the end carries
+    /// the range tombstones, and only it always writes an output.
+    fn write_range<F>(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        mut output: F,
+        outputs: &mut Vec<String>,
+    ) -> io::Result<()>
+    where
+        F: FnMut(usize) -> String,
+    {
+        let mut max_seq = 0;
+        let mut sources = Vec::with_capacity(self.inputs.len());
+        // Newest first, so versions with equal sequence numbers (legacy tables) keep their order
+        for path in self.inputs.iter().rev() {
+            let mut table = SSTableIterator::open_sequential(self.storage, path, self.read_ahead)?;
+            max_seq = max_seq.max(table.header().max_seq);
+            if let Some(start) = start {
+                table.skip_to(start)?;
+            }
+            let mut input = Input { table, head: None };
+            input.advance()?;
+            let before_start = |key: &[u8]| start.is_some_and(|start| key < start);
+            while input.head.as_ref().is_some_and(|(key, _)| before_start(key)) {
+                input.advance()?;
+            }
+            sources.push(input);
+        }
+
+        let (storage, sync_mode) = (self.storage, self.sync_mode);
+        let mut create = |outputs: &mut Vec<String>| {
+            let table = output(outputs.len());
+            let writer = SSTableWriter::create(storage, &format!("{}{}", table, COMPACT_SUFFIX));
+            outputs.push(table);
+            writer.map(|mut writer| {
+                writer.set_sync_mode(sync_mode);
+                writer
+            })
+        };
+
+        let mut writer: Option<SSTableWriter> = None;
+        loop {
+            let next_key =
+                sources.iter().filter_map(|s| s.head.as_ref().map(|(key, _)| key)).min();
+            let Some(key) = next_key.cloned() else {
+                break;
+            };
+            if end.is_some_and(|end| key.as_slice() >= end) {
+                break;
+            }
+            if self.failed.load(Ordering::Relaxed) {
+                let message = "another part of the compaction failed";
+                return Err(io::Error::new(io::ErrorKind::Interrupted, message));
+            }
+
+            let mut versions = Vec::new();
+            for source in &mut sources {
+                while source.head.as_ref().is_some_and(|(head, _)| *head == key) {
+                    if let Some((_, entry)) = source.head.take() {
+                        versions.push(entry);
+                    }
+                    source.advance()?;
+                }
+            }
+            versions.sort_by_key(|entry| Reverse(entry.seq));
+            let tombstones = |versions: &[Entry]| {
+                versions.iter().filter(|entry| entry.op == Op::Delete).count() as u64
+            };
+            let (read, read_tombstones) = (versions.len() as u64, tombstones(&versions));
+            let versions = self.retention.retain(versions);
+            self.keys.fetch_add(1, Ordering::Relaxed);
+            let dropped = read_tombstones - tombstones(&versions);
+            self.tombstones_dropped.fetch_add(dropped, Ordering::Relaxed);
+            let versions_dropped = read - versions.len() as u64 - dropped;
+            self.versions_dropped.fetch_add(versions_dropped, Ordering::Relaxed);
+            if versions.is_empty() {
+                continue;
+            }
+
+            if writer.as_ref().is_some_and(|w| w.len() >= MAX_OUTPUT_TABLE_BYTES) {
+                if let Some(full) = writer.take() {
+                    full.finish(&[], max_seq)?;
+                }
+            }
+            let writer = match &mut writer {
+                Some(writer) => writer,
+                None => writer.insert(create(outputs)?),
+            };
+            for entry in &versions {
+                writer.add(&key, entry)?;
+            }
+        }
+
+        if end.is_some() {
+            return writer.map_or(Ok(()), |writer| writer.finish(&[], max_seq));
+        }
+        let mut range_tombstones: Vec<RangeTombstone> = Vec::new();
+        for source in sources {
+            range_tombstones.extend(source.table.into_range_tombstones()?);
+        }
+        let last = match writer {
+            Some(writer) => writer,
+            None => create(outputs)?,
+        };
+        last.finish(&range_tombstones, max_seq)
+    }
+}
+
+/// Replace the tables at `inputs` with the merged tables at `outputs`.
+///
+/// A marker naming the inputs is made durable first; from then on [`recover`] rolls the
+/// compaction forward if it is interrupted, so either the inputs or the outputs are
+/// live after a crash, never both. The inputs are not deleted here: they are added to
+/// the [`OBSOLETE_LIST`] before the marker goes, and removed once no reader has them
+/// open (or by [`sweep_obsolete`] on the next open).
+pub(crate) fn install(
+    storage: &dyn Storage,
+    dir: &Path,
+    inputs: &[String],
+    outputs: &[String],
+) -> io::Result<()> {
+    let marker = dir.join(COMPACT_MARKER);
+    let pending = dir.join(format!("{}{}", COMPACT_MARKER, COMPACT_SUFFIX));
+    let mut names = String::new();
+    for input in inputs {
+        if let Some(name) = Path::new(input).file_name() {
+            names.push_str(&name.to_string_lossy());
+            names.push('\n');
+        }
+    }
+    storage.write(&pending, names.as_bytes())?;
+    storage.rename(&pending, &marker)?;
+    storage.sync_dir(dir)?;
+
+    for output in outputs {
+        let pending = format!("{}{}", output, COMPACT_SUFFIX);
+        storage.rename(Path::new(&pending), Path::new(output))?;
+    }
+    storage.sync_dir(dir)?;
+    let mut obsolete = read_obsolete(storage, dir)?;
+    obsolete.extend(names.lines().map(str::to_string));
+    write_obsolete(storage, dir, &obsolete)?;
+    storage.remove(&marker)
+}
+
+/// Drop names from the [`OBSOLETE_LIST`] whose files are gone, removing the list once
+/// it is empty
+pub(crate) fn prune_obsolete(storage: &dyn Storage, dir: &Path) -> io::Result<()> {
+    let listed = read_obsolete(storage, dir)?;
+    let remaining: Vec<String> =
+        listed.iter().filter(|name| storage.exists(&dir.join(name))).cloned().collect();
+    if remaining.len() < listed.len() {
+        write_obsolete(storage, dir, &remaining)?;
+    }
+    Ok(())
+}
+
+/// Delete every table named in the [`OBSOLETE_LIST`], and then the list. Only safe
+/// while nothing is reading, i.e. when opening.
+pub(crate) fn sweep_obsolete(storage: &dyn Storage, dir: &Path) -> io::Result<()> {
+    for name in read_obsolete(storage, dir)? {
+        match storage.remove(&dir.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    write_obsolete(storage, dir, &[])
+}
+
+pub(crate) fn read_obsolete(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<String>> {
+    match storage.read(&dir.join(OBSOLETE_LIST)) {
+        Ok(names) => Ok(String::from_utf8_lossy(&names).lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the list durably, or remove it when `names` is empty
+fn write_obsolete(storage: &dyn Storage, dir: &Path, names: &[String]) -> io::Result<()> {
+    let list = dir.join(OBSOLETE_LIST);
+    if names.is_empty() {
+        return match storage.remove(&list) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let pending = dir.join(format!("{}{}", OBSOLETE_LIST, COMPACT_SUFFIX));
+    let contents: String = names.iter().map(|name| format!("{}\n", name)).collect();
+    storage.write(&pending, contents.as_bytes())?;
+    storage.rename(&pending, &list)?;
+    storage.sync_dir(dir)
+}
+
+/// Names of the inputs of a compaction interrupted while installing its outputs, which
+/// [`recover`] deletes
+pub(crate) fn interrupted_inputs(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<String>> {
+    match storage.read(&dir.join(COMPACT_MARKER)) {
+        Ok(names) => Ok(String::from_utf8_lossy(&names).lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Finish a compaction interrupted by a crash: with the commit marker present the
+/// outputs are installed and the inputs it names removed; otherwise the outputs are
+/// discarded.
+pub(crate) fn recover(storage: &dyn Storage, dir: &Path) -> io::Result<()> {
+    let marker = dir.join(COMPACT_MARKER);
+    let inputs = match storage.read(&marker) {
+        Ok(names) => Some(String::from_utf8_lossy(&names).into_owned()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    for path in storage.list_dir(dir)? {
+        let name = path.to_string_lossy();
+        match name.strip_suffix(COMPACT_SUFFIX) {
+            Some(table)
+                if inputs.is_some()
+                    && !table.ends_with(COMPACT_MARKER)
+                    && !table.ends_with(OBSOLETE_LIST) =>
+            {
+                storage.rename(&path, Path::new(table))?
+            }
+            Some(_) => storage.remove(&path)?,
+            None => {}
+        }
+    }
+
+    if let Some(names) = inputs {
+        for name in names.lines() {
+            match storage.remove(&dir.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        storage.remove(&marker)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactionInfo, Retention, COMPACT_SUFFIX, OBSOLETE_LIST};
+    use crate::entry::{Entry, Op, RangeTombstone};
+    use crate::fault::{Fault, FaultOp, FaultStorage};
+    use crate::memtable::MemTable;
+    use crate::options::{Options, SyncMode};
+    use crate::sstable::SSTable;
+    use crate::storage::{FileStorage, MemStorage, Storage};
+    use crate::db::Db;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// Every version of every key, oldest first, and every range tombstone
+    type Contents = (BTreeMap<Vec<u8>, Vec<Entry>>, Vec<RangeTombstone>);
+
+    fn table_count(dir: &str) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sst"))
+            .count()
+    }
+
+    /// A memtable over `storage` that compacts on `threads` threads
+    fn open_memtable(storage: Arc<dyn Storage>, threads: usize) -> MemTable {
+        let options = Options {
+            storage: Some(storage),
+            compaction_threads: Some(threads),
+            ..Options::default()
+        };
+        MemTable::with_options("test_parallel_compaction.log", &options).unwrap()
+    }
+
+    /// Six overlapping tables of 3000 keys, then one with a deletion and a range tombstone
+    fn write_overlapping_tables(memtable: &mut MemTable) {
+        for round in 0..6 {
+            let rows = (0..3000).map(|i| {
+                (format!("key{:05}", i * 2 + round % 2), format!("value {} of round {}", i, round))
+            });
+            memtable.ingest(rows).unwrap();
+        }
+        memtable.delete("key00002").unwrap();
+        memtable.delete_range("key01000", "key01100").unwrap();
+        memtable.flush().unwrap();
+    }
+
+    fn table_contents(storage: &dyn Storage, memtable: &MemTable) -> Contents {
+        let mut contents = Contents::default();
+        for path in memtable.sstable_paths() {
+            let path = path.to_string_lossy();
+            for (key, versions) in SSTable::read_versions(storage, &path).unwrap() {
+                contents.0.entry(key).or_default().extend(versions);
+            }
+            contents.1.extend(SSTable::read_range_tombstones(storage, &path).unwrap());
+        }
+        contents
+    }
+
+    fn copy_files(from: &dyn Storage, to: &dyn Storage) {
+        for path in from.list_dir(Path::new("")).unwrap() {
+            to.write(&path, &from.read(&path).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parallel_compaction_matches_serial() {
+        let serial = MemStorage::new();
+        let mut memtable = open_memtable(Arc::new(serial.clone()), 1);
+        write_overlapping_tables(&mut memtable);
+        let parallel = MemStorage::new();
+        copy_files(&serial, &parallel);
+        let mut parallel_memtable = open_memtable(Arc::new(parallel.clone()), 4);
+
+        let info = memtable.compact().unwrap();
+        assert_eq!((info.input_tables, info.output_tables), (7, 1));
+        let info = parallel_memtable.compact().unwrap();
+        assert_eq!((info.input_tables, info.output_tables), (7, 4));
+        let contents = table_contents(&serial, &memtable);
+        assert_eq!(table_contents(&parallel, &parallel_memtable), contents);
+        assert!(contents.0.values().all(|versions| versions.len() == 1));
+        assert_eq!(parallel_memtable.scan("a", "z").unwrap(), memtable.scan("a", "z").unwrap());
+
+        // The outputs are numbered in key order, and only the last has range tombstones
+        let stats: Vec<_> = parallel_memtable
+            .sstable_paths()
+            .iter()
+            .map(|path| SSTable::stats(&parallel, &path.to_string_lossy()).unwrap())
+            .collect();
+        assert!(stats.windows(2).all(|pair| pair[0].last_key < pair[1].first_key));
+        let range_tombstones: Vec<usize> = stats.iter().map(|s| s.range_tombstones).collect();
+        assert_eq!(range_tombstones, [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_failed_parallel_compaction_leaves_inputs_or_every_output() {
+        let original = MemStorage::new();
+        let mut memtable = open_memtable(Arc::new(original.clone()), 1);
+        write_overlapping_tables(&mut memtable);
+        drop(memtable);
+        let files = MemStorage::new();
+        copy_files(&original, &files);
+        let mut memtable = open_memtable(Arc::new(files.clone()), 1);
+        let before = table_contents(&files, &memtable);
+        memtable.compact().unwrap();
+        let compacted = table_contents(&files, &memtable);
+
+        // Renames go to the four outputs, then the commit marker, then the installs
+        let failpoints = [
+            (FaultOp::Write, 1),
+            (FaultOp::Write, 40),
+            (FaultOp::Rename, 1),
+            (FaultOp::Rename, 4),
+            (FaultOp::Rename, 5),
+            (FaultOp::Rename, 7),
+            (FaultOp::SyncDir, 1),
+        ];
+        for (op, nth) in failpoints {
+            let storage = FaultStorage::new();
+            copy_files(&original, &storage);
+            let mut memtable = open_memtable(Arc::new(storage.clone()), 4);
+            storage.arm(op, nth, Fault::Error);
+            assert!(memtable.compact().is_err(), "{:?} {}", op, nth);
+            assert!(storage.fired());
+
+            let after = table_contents(&storage, &memtable);
+            let partial = after != before && after != compacted;
+            assert!(!partial, "{:?} {} left a partial result", op, nth);
+            let leftovers: Vec<PathBuf> = storage
+                .list_dir(Path::new(""))
+                .unwrap()
+                .into_iter()
+                .filter(|path| path.to_string_lossy().contains(".sst."))
+                .collect();
+            assert!(leftovers.is_empty(), "{:?} {} left {:?}", op, nth, leftovers);
+            drop(memtable);
+            let reopened = open_memtable(Arc::new(storage.files()), 1);
+            assert_eq!(table_contents(&storage.files(), &reopened), after);
+        }
+    }
+
+    #[test]
+    fn test_compact_keeps_data_and_snapshots() {
+        let dir = "test_compact_merge";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for round in 0..5 {
+            for i in 0..20 {
+                db.put(format!("key{:02}", i), format!("v{}", round)).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        db.delete("key03").unwrap();
+        db.delete_range("key10", "key15").unwrap();
+        db.flush().unwrap();
+        let snapshot = db.snapshot();
+        db.put("key00", "latest").unwrap();
+        db.flush().unwrap();
+        let before = db.scan("a", "z").unwrap();
+        assert_eq!(table_count(dir), 7);
+
+        let info = db.compact().unwrap();
+        assert_eq!((info.input_tables, info.output_tables), (7, 1));
+        assert_eq!(table_count(dir), 1);
+        assert_eq!(db.scan("a", "z").unwrap(), before);
+        assert_eq!(snapshot.get("key00"), Some("v4".to_string()));
+        drop(snapshot);
+
+        // Numbering continues after the compacted table, and so do sequence numbers
+        db.put("key99", "new").unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("key00"), Some("latest".to_string()));
+        assert_eq!(db.get("key03"), None);
+        assert_eq!(db.get("key12"), None);
+        assert_eq!(db.get("key99"), Some("new".to_string()));
+        assert_eq!(db.scan("a", "z").unwrap().len(), before.len() + 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_drops_shadowed_versions_and_tombstones() {
+        let dir = "test_compact_gc";
+        let _ = fs::remove_dir_all(dir);
+        let versions = |path: &PathBuf| {
+            SSTable::read_versions(&FileStorage, &path.to_string_lossy()).unwrap()
+        };
+        let total_size = |db: &Db| -> u64 {
+            db.sstable_paths().iter().map(|path| fs::metadata(path).unwrap().len()).sum()
+        };
+
+        let db = Db::open(dir).unwrap();
+        for round in 0..2 {
+            for i in 0..50 {
+                db.put(format!("key{:02}", i), format!("value from round {}", round)).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let snapshot = db.snapshot();
+        db.delete("key07").unwrap();
+        db.put("key08", "latest").unwrap();
+        db.flush().unwrap();
+
+        // The oldest table still holds key07, so merging the newer two keeps its tombstone
+        let newer_size = total_size(&db) - fs::metadata(&db.sstable_paths()[0]).unwrap().len();
+        let info = db.compact_newest(2).unwrap();
+        let tables = db.sstable_paths();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(info.input_bytes, newer_size);
+        assert_eq!(info.output_bytes, fs::metadata(&tables[1]).unwrap().len());
+        assert_eq!((info.keys, info.versions_dropped, info.tombstones_dropped), (50, 0, 0));
+        let newest = versions(&tables[1]);
+        let ops: Vec<&Op> = newest[b"key07".as_slice()].iter().map(|e| &e.op).collect();
+        assert_eq!(ops, [&Op::Put(b"value from round 1".to_vec()), &Op::Delete]);
+        assert_eq!(db.get("key07"), None);
+
+        // A full compaction keeps only what the snapshot still reads
+        db.compact().unwrap();
+        let table = versions(&db.sstable_paths()[0]);
+        assert_eq!(table[b"key07".as_slice()].len(), 2);
+        assert_eq!(table[b"key08".as_slice()].len(), 2);
+        assert_eq!(table[b"key09".as_slice()].len(), 1);
+        assert_eq!(snapshot.get("key07"), Some("value from round 1".to_string()));
+        assert_eq!(db.get("key07"), None);
+        let pinned_size = total_size(&db);
+        drop(snapshot);
+
+        // Then the tombstone and the value under it are gone altogether
+        db.put("key49", "latest").unwrap();
+        db.flush().unwrap();
+        let input_bytes = total_size(&db);
+        let info = db.compact().unwrap();
+        assert_eq!((info.input_bytes, info.output_bytes), (input_bytes, total_size(&db)));
+        // key07's value under the tombstone and the values key08 and key49 replaced
+        assert_eq!((info.keys, info.versions_dropped, info.tombstones_dropped), (50, 3, 1));
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.compaction_tombstones_dropped, 1);
+        assert!(stats.write_amplification() > 1.0);
+        let table = versions(&db.sstable_paths()[0]);
+        assert!(!table.contains_key(b"key07".as_slice()));
+        assert!(table.values().all(|versions| versions.len() == 1));
+        assert!(total_size(&db) < pinned_size);
+        assert_eq!(db.get("key07"), None);
+        assert_eq!(db.get("key08"), Some("latest".to_string()));
+        assert_eq!(db.scan("a", "z").unwrap().len(), 49);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_range_reclaims_only_tables_in_range() {
+        let dir = "test_compact_range";
+        let _ = fs::remove_dir_all(dir);
+        let sizes = |db: &Db| -> Vec<(PathBuf, u64)> {
+            let paths = db.sstable_paths().into_iter();
+            paths.map(|path| (path.clone(), fs::metadata(&path).unwrap().len())).collect()
+        };
+
+        let db = Db::open(dir).unwrap();
+        for prefix in ["a", "m_", "z"] {
+            for i in 0..40 {
+                db.put(format!("{}{:02}", prefix, i), "some value to reclaim").unwrap();
+            }
+            db.flush().unwrap();
+        }
+        for i in 0..40 {
+            db.delete(format!("m_{:02}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        let tables = sizes(&db);
+        let disk_bytes = db.stats().unwrap().disk_bytes;
+
+        // Only the puts and deletes of the prefix are merged, and both go
+        let info = db.compact_range(Some("m_"), Some("m`")).unwrap();
+        assert_eq!((info.input_tables, info.output_tables), (2, 1));
+        let after = sizes(&db);
+        assert_eq!(after[..2], [tables[0].clone(), tables[2].clone()]);
+        assert!(info.output_bytes < tables[1].1 / 10);
+        assert!(db.stats().unwrap().disk_bytes < disk_bytes);
+        assert_eq!(db.scan("m", "n").unwrap(), []);
+        assert_eq!(db.scan("a", "b").unwrap().len(), 40);
+        assert_eq!(db.scan("z", "{").unwrap().len(), 40);
+
+        // A newer table sharing keys with one in range is merged too, so the newest
+        // version stays in the newest table
+        db.put("b", "old").unwrap();
+        db.put("m_00", "in range").unwrap();
+        db.flush().unwrap();
+        db.put("b", "new").unwrap();
+        db.flush().unwrap();
+        let info = db.compact_range(Some("m_"), Some("m`")).unwrap();
+        assert_eq!(info.input_tables, 2);
+        assert_eq!(sizes(&db)[..2], after[..2]);
+        assert_eq!(db.get("b"), Some("new".to_string()));
+
+        // The oldest table now goes last, yet sequence numbers carry on from the highest
+        db.compact_range(None, Some("a99")).unwrap();
+        assert_eq!(db.compact_range(Some("n"), Some("o")).unwrap(), CompactionInfo::default());
+        let last = db.last_sequence();
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.last_sequence(), last);
+        assert_eq!(db.get("b"), Some("new".to_string()));
+        assert_eq!(db.scan("a", "b").unwrap().len(), 40);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_keeps_merge_operands_and_pinned_reads() {
+        let dir = "test_compact_gc_merge";
+        let _ = fs::remove_dir_all(dir);
+        let options = Options {
+            merge_operator: Some(Arc::new(|_key, existing, operand| {
+                let mut value = existing.unwrap_or_default().to_vec();
+                value.extend_from_slice(operand);
+                value
+            })),
+            ..Options::default()
+        };
+
+        let db = Db::open_with_options(dir, options).unwrap();
+        db.put("log", "a").unwrap();
+        db.flush().unwrap();
+        db.merge("log", "b").unwrap();
+        db.flush().unwrap();
+        let mut transaction = db.begin();
+        db.merge("log", "c").unwrap();
+        db.flush().unwrap();
+
+        db.compact().unwrap();
+        assert_eq!(db.get("log"), Some("abc".to_string()));
+        // The transaction reads as of its start, and still notices the later write
+        assert_eq!(transaction.get("log"), Some("ab".to_string()));
+        transaction.put("log", "replaced");
+        assert!(transaction.commit().is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_iterator_keeps_compacted_tables() {
+        let dir = "test_compact_pinned";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for round in 0..3 {
+            for i in 0..10 {
+                db.put(format!("key{:02}", i), format!("v{}", round)).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let inputs = db.sstable_paths();
+        let expected = db.scan_bytes("a", "z").unwrap();
+        let mut iter = db.iter();
+        let first = iter.next().unwrap().unwrap();
+
+        // The inputs outlive the compaction while the iterator reads them
+        db.compact().unwrap();
+        assert!(inputs.iter().all(|path| path.exists()));
+        assert!(fs::exists(format!("{}/{}", dir, OBSOLETE_LIST)).unwrap());
+        let mut rows = vec![first];
+        rows.extend(iter.by_ref().map(Result::unwrap));
+        assert_eq!(rows, expected);
+        drop(iter);
+        assert!(inputs.iter().all(|path| !path.exists()));
+
+        // Without readers, inputs go at once and the list is emptied
+        db.put("key99", "new").unwrap();
+        db.flush().unwrap();
+        let inputs = db.sstable_paths();
+        db.compact().unwrap();
+        assert!(inputs.iter().all(|path| !path.exists()));
+        assert!(!fs::exists(format!("{}/{}", dir, OBSOLETE_LIST)).unwrap());
+        assert_eq!(table_count(dir), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_obsolete_tables_swept_on_open() {
+        let dir = "test_compact_sweep";
+        let _ = fs::remove_dir_all(dir);
+
+        let inputs = {
+            let db = Db::open(dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.flush().unwrap();
+            db.put("b", "2").unwrap();
+            db.flush().unwrap();
+            let inputs = db.sstable_paths();
+            // A reader that never finishes stands in for a crash before the inputs go
+            std::mem::forget(db.iter());
+            db.compact().unwrap();
+            inputs
+        };
+        assert!(inputs.iter().all(|path| path.exists()));
+        assert_eq!(table_count(dir), 3);
+
+        let db = Db::open(dir).unwrap();
+        assert!(inputs.iter().all(|path| !path.exists()));
+        assert!(!fs::exists(format!("{}/{}", dir, OBSOLETE_LIST)).unwrap());
+        assert_eq!(table_count(dir), 1);
+        assert_eq!(db.scan("a", "z").unwrap().len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_compaction_recovers() {
+        let dir = "test_compact_recovery";
+        let _ = fs::remove_dir_all(dir);
+
+        {
+            let db = Db::open(dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.flush().unwrap();
+            db.put("b", "2").unwrap();
+            db.flush().unwrap();
+        }
+        let inputs = [0, 1].map(|i| format!("{}/sstable_{:06}.sst", dir, i));
+        let output = format!("{}/sstable_000002.sst", dir);
+
+        // Outputs written but never committed are discarded
+        let merge = || {
+            let retention = Retention { snapshots: Vec::new(), bottommost: true };
+            let output = |_| output.clone();
+            let sync = SyncMode::SyncAll;
+            super::merge_tables(&FileStorage, &inputs, 0, sync, &retention, 1, output)
+        };
+        merge().unwrap();
+        {
+            let db = Db::open(dir).unwrap();
+            assert!(!fs::exists(format!("{}{}", output, COMPACT_SUFFIX)).unwrap());
+            assert_eq!(db.scan("a", "z").unwrap().len(), 2);
+        }
+
+        // Once the marker is in place, a crash part-way through rolls forward
+        merge().unwrap();
+        let names = "sstable_000000.sst\nsstable_000001.sst\n";
+        fs::write(format!("{}/COMPACT_COMMIT", dir), names).unwrap();
+        fs::remove_file(&inputs[0]).unwrap();
+        let db = Db::open(dir).unwrap();
+        assert_eq!(table_count(dir), 1);
+        assert!(fs::exists(&output).unwrap());
+        assert_eq!(db.get("a"), Some("1".to_string()));
+        assert_eq!(db.get("b"), Some("2".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
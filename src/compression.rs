@@ -0,0 +1,105 @@
+//! The codecs behind [`Compression`], each compiled in with its cargo feature.
+//!
+//! A compressed value is stored as `[codec u8][length u32][compressed bytes]`, the
+//! length being that of the original value, so a reader knows how to decompress it
+//! without being told how it was written.
+
+use crate::options::Compression;
+use std::io;
+
+const CODEC_LZ4: u8 = 1;
+const CODEC_SNAPPY: u8 = 2;
+
+/// Neither codec expands data by more than this factor, so a claimed length past it
+/// marks a corrupt value rather than an allocation to attempt
+const MAX_RATIO: usize = 256;
+
+impl Compression {
+    /// Fail unless the codec was compiled in
+    pub(crate) fn ensure_available(self) -> io::Result<()> {
+        let (available, feature) = match self {
+            Compression::Lz4 => (cfg!(feature = "lz4"), "lz4"),
+            Compression::Snappy => (cfg!(feature = "snappy"), "snappy"),
+        };
+        if available {
+            return Ok(());
+        }
+        let message = format!("{:?} compression needs the `{}` feature", self, feature);
+        Err(io::Error::new(io::ErrorKind::Unsupported, message))
+    }
+}
+
+/// `value` compressed with `compression`, or `None` if that does not make it shorter
+pub(crate) fn compress(compression: Compression, value: &[u8]) -> Option<Vec<u8>> {
+    let (codec, compressed) = match compression {
+        Compression::Lz4 => (CODEC_LZ4, codecs::lz4_compress(value)?),
+        Compression::Snappy => (CODEC_SNAPPY, codecs::snappy_compress(value)?),
+    };
+    let mut out = vec![codec];
+    out.extend_from_slice(&u32::try_from(value.len()).ok()?.to_le_bytes());
+    out.extend_from_slice(&compressed);
+    (out.len() < value.len()).then_some(out)
+}
+
+/// The value [`compress`] turned into `stored`. `Ok(None)` if it is malformed, and an
+/// error if its codec is not compiled in.
+pub(crate) fn decompress(stored: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let Some((&codec, rest)) = stored.split_first() else { return Ok(None) };
+    let Some((len, data)) = rest.split_first_chunk() else { return Ok(None) };
+    let len = u32::from_le_bytes(*len) as usize;
+    if len > data.len().saturating_mul(MAX_RATIO) {
+        return Ok(None);
+    }
+    let value = match codec {
+        CODEC_LZ4 => codecs::lz4_decompress(data, len)?,
+        CODEC_SNAPPY => codecs::snappy_decompress(data)?,
+        _ => None,
+    };
+    Ok(value.filter(|value| value.len() == len))
+}
+
+/// Each codec, or for one not compiled in, a compressor that declines and a
+/// decompressor that fails
+mod codecs {
+    use std::io;
+
+    #[cfg(feature = "lz4")]
+    pub(super) fn lz4_compress(value: &[u8]) -> Option<Vec<u8>> {
+        Some(lz4_flex::block::compress(value))
+    }
+
+    #[cfg(feature = "lz4")]
+    pub(super) fn lz4_decompress(data: &[u8], len: usize) -> io::Result<Option<Vec<u8>>> {
+        Ok(lz4_flex::block::decompress(data, len).ok())
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    pub(super) fn lz4_compress(_value: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    pub(super) fn lz4_decompress(_data: &[u8], _len: usize) -> io::Result<Option<Vec<u8>>> {
+        crate::Compression::Lz4.ensure_available().map(|()| None)
+    }
+
+    #[cfg(feature = "snappy")]
+    pub(super) fn snappy_compress(value: &[u8]) -> Option<Vec<u8>> {
+        snap::raw::Encoder::new().compress_vec(value).ok()
+    }
+
+    #[cfg(feature = "snappy")]
+    pub(super) fn snappy_decompress(data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(snap::raw::Decoder::new().decompress_vec(data).ok())
+    }
+
+    #[cfg(not(feature = "snappy"))]
+    pub(super) fn snappy_compress(_value: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    #[cfg(not(feature = "snappy"))]
+    pub(super) fn snappy_decompress(_data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        crate::Compression::Snappy.ensure_available().map(|()| None)
+    }
+}
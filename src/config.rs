@@ -0,0 +1,408 @@
+//! Engine and server settings read from a TOML file, for [`Options::from_file`] and the
+//! binary's `--config` flag
+
+use crate::options::{Compression, Options};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::{Table, Value};
+
+/// Sections of a config file and the keys each accepts
+const SECTIONS: &[(&str, &[&str])] = &[
+    (
+        "storage",
+        &[
+            "dir",
+            "max_disk_bytes",
+            "value_log_threshold",
+            "max_key_size",
+            "max_value_size",
+            "lookup_threads",
+            "memtable_shards",
+            "memtable_entries",
+            "read_only",
+            "flush_on_recovery",
+            "verify_on_open",
+            "allow_missing_tables",
+            "disable_latency_histograms",
+        ],
+    ),
+    (
+        "wal",
+        &[
+            "sync_policy",
+            "sync_mode",
+            "preallocate_bytes",
+            "compression",
+            "compression_threshold",
+            "retention_ms",
+        ],
+    ),
+    (
+        "compaction",
+        &[
+            "threads",
+            "rate_limit_bytes_per_sec",
+            "slowdown_writes_sstables",
+            "stop_writes_sstables",
+        ],
+    ),
+    (
+        "cache",
+        &[
+            "read_ahead_bytes",
+            "filter_cache_bytes",
+            "row_cache_bytes",
+            "negative_cache_keys",
+            "max_open_files",
+        ],
+    ),
+    ("server", &["listen", "socket_mode"]),
+];
+
+/// The settings of a config file.
+///
+/// Every section and key is optional, and anything left out keeps its default. Most
+/// keys set the [`Options`] field of the same name, with the section's name dropped
+/// from `wal` keys (`wal.sync_mode` sets `wal_sync_mode`) and added to `compaction`
+/// ones (`compaction.threads` sets `compaction_threads`):
+///
+/// ```toml
+/// [storage]
+/// dir = "./data"
+/// max_disk_bytes = 10_000_000_000
+/// value_log_threshold = 4096
+/// max_key_size = 1024
+/// max_value_size = 1048576
+/// lookup_threads = 4
+/// memtable_shards = 8
+/// memtable_entries = 1000
+/// read_only = false
+/// flush_on_recovery = true
+/// verify_on_open = false
+/// allow_missing_tables = false
+/// disable_latency_histograms = false
+///
+/// [wal]
+/// sync_policy = "interval:100"   # always, never, every:<writes>, or interval:<ms>
+/// sync_mode = "data"             # all or data
+/// preallocate_bytes = 67108864
+/// compression = "lz4"            # none, lz4, or snappy
+/// compression_threshold = 512
+/// retention_ms = 3600000         # sets wal_retention
+///
+/// [compaction]
+/// threads = 4
+/// rate_limit_bytes_per_sec = 50000000
+/// slowdown_writes_sstables = 20
+/// stop_writes_sstables = 36
+///
+/// [cache]
+/// read_ahead_bytes = 262144
+/// filter_cache_bytes = 8388608
+/// row_cache_bytes = 67108864
+/// negative_cache_keys = 10000
+/// max_open_files = 1000
+///
+/// [server]
+/// listen = "0.0.0.0:6380"
+/// socket_mode = 0o660
+/// ```
+///
+/// Unknown sections and keys are rejected rather than ignored, so a misspelt key is not
+/// silently left at its default.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// `storage.dir`, the database directory
+    pub dir: Option<PathBuf>,
+    /// `server.listen`, the address the server commands listen on
+    pub listen: Option<String>,
+    /// `server.socket_mode`, the permissions of the socket file when `listen` is
+    /// `unix:<path>`
+    pub socket_mode: Option<u32>,
+    pub options: Options,
+}
+
+impl Config {
+    /// Read the config file at `path`. A file that does not parse, or has an unknown
+    /// key or a value of the wrong type, fails with [`io::ErrorKind::InvalidData`] and
+    /// a message naming the key and what it expects.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        text.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        })
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let document: Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        let mut config = Config::default();
+        for (name, value) in &document {
+            let Some((_, keys)) = SECTIONS.iter().find(|(section, _)| *section == name) else {
+                let names: Vec<&str> = SECTIONS.iter().map(|(section, _)| *section).collect();
+                return Err(format!("unknown section [{}]; expected {}", name, names.join(", ")));
+            };
+            let Value::Table(table) = value else {
+                return Err(format!("{}: expected a section, found {}", name, value.type_str()));
+            };
+            let section = Section { name, table };
+            for key in table.keys() {
+                if !keys.contains(&key.as_str()) {
+                    return Err(format!(
+                        "unknown key {}.{}; [{}] accepts {}",
+                        name,
+                        key,
+                        name,
+                        keys.join(", ")
+                    ));
+                }
+            }
+            section.apply(&mut config)?;
+        }
+        Ok(config)
+    }
+}
+
+/// One section of a config file, with its keys already checked
+struct Section<'a> {
+    name: &'a str,
+    table: &'a Table,
+}
+
+impl Section<'_> {
+    fn apply(&self, config: &mut Config) -> Result<(), String> {
+        let options = &mut config.options;
+        match self.name {
+            "storage" => {
+                config.dir = self.string("dir")?.map(PathBuf::from);
+                options.max_disk_bytes = self.integer("max_disk_bytes")?;
+                options.value_log_threshold = self.integer("value_log_threshold")?;
+                options.max_key_size = self.integer("max_key_size")?;
+                options.max_value_size = self.integer("max_value_size")?;
+                options.lookup_threads = self.integer("lookup_threads")?;
+                options.memtable_shards = self.integer("memtable_shards")?;
+                options.memtable_entries = self.integer("memtable_entries")?;
+                options.read_only = self.boolean("read_only")?;
+                options.flush_on_recovery = self.boolean("flush_on_recovery")?;
+                options.verify_on_open = self.boolean("verify_on_open")?;
+                options.allow_missing_tables = self.boolean("allow_missing_tables")?;
+                options.disable_latency_histograms = self.boolean("disable_latency_histograms")?;
+            }
+            "wal" => {
+                options.sync_policy = self.parsed("sync_policy")?.unwrap_or_default();
+                options.wal_sync_mode = self.parsed("sync_mode")?.unwrap_or_default();
+                options.wal_preallocate_bytes = self.integer("preallocate_bytes")?;
+                options.wal_compression = match self.string("compression")? {
+                    None | Some("none") => None,
+                    Some(_) => self.parsed::<Compression>("compression")?,
+                };
+                options.wal_compression_threshold = self.integer("compression_threshold")?;
+                options.wal_retention = self.integer("retention_ms")?.map(Duration::from_millis);
+            }
+            "compaction" => {
+                options.compaction_threads = self.integer("threads")?;
+                options.rate_limit_bytes_per_sec = self.integer("rate_limit_bytes_per_sec")?;
+                options.slowdown_writes_sstables = self.integer("slowdown_writes_sstables")?;
+                options.stop_writes_sstables = self.integer("stop_writes_sstables")?;
+            }
+            "cache" => {
+                options.read_ahead_bytes = self.integer("read_ahead_bytes")?;
+                options.filter_cache_bytes = self.integer("filter_cache_bytes")?;
+                options.row_cache_bytes = self.integer("row_cache_bytes")?;
+                options.negative_cache_keys = self.integer("negative_cache_keys")?;
+                options.max_open_files = self.integer("max_open_files")?;
+            }
+            _ => {
+                config.listen = self.string("listen")?.map(str::to_string);
+                config.socket_mode = self.integer("socket_mode")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Why `key` holds `value` rather than the `expected` kind of value
+    fn mismatch(&self, key: &str, expected: &str, value: &Value) -> String {
+        format!("{}.{}: expected {}, found {}", self.name, key, expected, value.type_str())
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&str>, String> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(Value::String(text)) => Ok(Some(text)),
+            Some(value) => Err(self.mismatch(key, "a string", value)),
+        }
+    }
+
+    fn boolean(&self, key: &str) -> Result<bool, String> {
+        match self.table.get(key) {
+            None => Ok(false),
+            Some(Value::Boolean(value)) => Ok(*value),
+            Some(value) => Err(self.mismatch(key, "true or false", value)),
+        }
+    }
+
+    fn integer<T: TryFrom<i64>>(&self, key: &str) -> Result<Option<T>, String> {
+        let expected = "a non-negative integer";
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) => {
+                let found = || format!("{}.{}: expected {}, found {}", self.name, key, expected, n);
+                T::try_from(*n).ok().filter(|_| *n >= 0).map(Some).ok_or_else(found)
+            }
+            Some(value) => Err(self.mismatch(key, expected, value)),
+        }
+    }
+
+    /// A string value parsed into `T`, whose error says what it expects
+    fn parsed<T: FromStr<Err = String>>(&self, key: &str) -> Result<Option<T>, String> {
+        let Some(text) = self.string(key)? else { return Ok(None) };
+        text.parse().map(Some).map_err(|e| format!("{}.{}: {}", self.name, key, e))
+    }
+}
+
+impl Options {
+    /// Read options from the TOML config file at `path`, laid out as described for
+    /// [`Config`]. Its database directory and server address are not options, and are
+    /// ignored here.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Options> {
+        Ok(Config::from_file(path)?.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{SyncMode, SyncPolicy};
+
+    #[test]
+    fn test_full_config() {
+        let text = "
+            [storage]
+            dir = './data'
+            max_disk_bytes = 10_000_000_000
+            value_log_threshold = 4096
+            max_key_size = 1024
+            max_value_size = 1048576
+            lookup_threads = 4
+            memtable_shards = 8
+            memtable_entries = 1000
+            read_only = true
+            flush_on_recovery = true
+            verify_on_open = true
+            disable_latency_histograms = true
+
+            [wal]
+            sync_policy = 'interval:100'
+            sync_mode = 'data'
+            preallocate_bytes = 65536
+            compression = 'snappy'
+            compression_threshold = 128
+            retention_ms = 60000
+
+            [compaction]
+            threads = 4
+            rate_limit_bytes_per_sec = 50000000
+            slowdown_writes_sstables = 20
+            stop_writes_sstables = 36
+
+            [cache]
+            read_ahead_bytes = 0
+            filter_cache_bytes = 1024
+            row_cache_bytes = 2048
+            negative_cache_keys = 100
+            max_open_files = 64
+
+            [server]
+            listen = '0.0.0.0:6380'
+            socket_mode = 0o660
+        ";
+        let config: Config = text.parse().unwrap();
+        assert_eq!(config.dir, Some(PathBuf::from("./data")));
+        assert_eq!(config.listen.as_deref(), Some("0.0.0.0:6380"));
+        assert_eq!(config.socket_mode, Some(0o660));
+        let options = &config.options;
+        assert_eq!(options.max_disk_bytes, Some(10_000_000_000));
+        assert_eq!(options.value_log_threshold, Some(4096));
+        assert_eq!((options.max_key_size, options.max_value_size), (Some(1024), Some(1048576)));
+        assert_eq!((options.lookup_threads, options.memtable_shards), (Some(4), Some(8)));
+        assert_eq!(options.memtable_entries, Some(1000));
+        assert!(options.read_only && options.flush_on_recovery && options.verify_on_open);
+        assert!(options.disable_latency_histograms);
+        assert_eq!(options.sync_policy, SyncPolicy::Interval(Duration::from_millis(100)));
+        assert_eq!(options.wal_sync_mode, SyncMode::SyncData);
+        assert_eq!(options.wal_preallocate_bytes, Some(65536));
+        assert_eq!(options.wal_compression, Some(Compression::Snappy));
+        assert_eq!(options.wal_compression_threshold, Some(128));
+        assert_eq!(options.wal_retention, Some(Duration::from_secs(60)));
+        assert_eq!(options.compaction_threads, Some(4));
+        assert_eq!(options.rate_limit_bytes_per_sec, Some(50_000_000));
+        assert_eq!(options.slowdown_writes_sstables, Some(20));
+        assert_eq!(options.stop_writes_sstables, Some(36));
+        assert_eq!(options.read_ahead_bytes, Some(0));
+        assert_eq!(options.filter_cache_bytes, Some(1024));
+        assert_eq!(options.row_cache_bytes, Some(2048));
+        assert_eq!(options.negative_cache_keys, Some(100));
+        assert_eq!(options.max_open_files, Some(64));
+    }
+
+    #[test]
+    fn test_minimal_config() {
+        let config: Config = "[wal]\nsync_policy = 'every:8'\n".parse().unwrap();
+        assert_eq!(config.options.sync_policy, SyncPolicy::EveryN(8));
+        assert_eq!(config.options.wal_sync_mode, SyncMode::SyncAll);
+        assert_eq!(config.options.wal_compression, None);
+        assert_eq!(config.options.max_open_files, None);
+        assert!(!config.options.read_only);
+        assert_eq!((config.dir, config.listen), (None, None));
+        assert!("".parse::<Config>().is_ok());
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let error = |text: &str| text.parse::<Config>().err().unwrap();
+        assert_eq!(
+            error("[cache]\nrow_cache_byte = 10\n"),
+            "unknown key cache.row_cache_byte; [cache] accepts read_ahead_bytes, \
+             filter_cache_bytes, row_cache_bytes, negative_cache_keys, max_open_files"
+        );
+        assert!(error("[memtable]\n").starts_with("unknown section [memtable]; expected storage"));
+        assert_eq!(
+            error("[cache]\nrow_cache_bytes = '64MB'\n"),
+            "cache.row_cache_bytes: expected a non-negative integer, found string"
+        );
+        assert_eq!(
+            error("[compaction]\nthreads = -1\n"),
+            "compaction.threads: expected a non-negative integer, found -1"
+        );
+        assert_eq!(
+            error("[storage]\nread_only = 'yes'\n"),
+            "storage.read_only: expected true or false, found string"
+        );
+        assert_eq!(
+            error("[wal]\nsync_policy = 'sometimes'\n"),
+            "wal.sync_policy: expected always, never, every:<writes>, or interval:<ms>, \
+             not \"sometimes\""
+        );
+        assert_eq!(error("storage = 1\n"), "storage: expected a section, found integer");
+        assert!(error("[wal\n").contains("TOML parse error"));
+
+        let name = format!("storage-engine-config-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test_config_errors.toml");
+        fs::write(&path, "[server]\nlisten = 6380\n").unwrap();
+        let err = Options::from_file(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message =
+            format!("{}: server.listen: expected a string, found integer", path.display());
+        assert_eq!(err.to_string(), message);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,2164 @@
+use crate::backup::{self, BackupInfo};
+use crate::batch::WriteBatch;
+use crate::column_family::{self, ColumnFamilies, READ_ONLY};
+use crate::compaction::{CompactionInfo, CompactionTotals};
+use crate::doctor::{self, DoctorReport};
+use crate::entry::{now_millis, Op, ValueMeta};
+use crate::error::EngineError;
+use crate::event;
+use crate::export::{self, CsvImportOptions, CsvImportSummary};
+use crate::iterator::{DbIterator, KeyIterator};
+use crate::memtable::MemTable;
+use crate::migrate;
+use crate::options::Options;
+use crate::repair::{self, RepairReport};
+use crate::replication::{Changes, WalRecords};
+use crate::snapshot::Snapshot;
+use crate::stats::{LatencyTotals, Stats};
+use crate::transaction::Transaction;
+use crate::verify::{CheckResult, VerifyReport};
+use crate::wal::{WalRecord, WAL_FILE};
+use crate::write_stall::WriteStall;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+/// Thread-safe handle to the storage engine.
+///
+/// `Db` is cheap to clone and every clone refers to the same engine. Writes take an
+/// exclusive lock around the memtable and WAL; reads take a shared lock, so any number of
+/// threads can call `get` at once and SSTable lookups run concurrently.
+///
+/// Consistency: once `put` or `delete` has returned, the change is visible to every
+/// subsequent `get` on any thread.
+///
+/// Keys and values are arbitrary bytes, ordered bytewise. Methods accept string literals,
+/// `String`s, and byte slices alike. Reads such as `get` and `scan` return `String`s for
+/// convenience, replacing any invalid UTF-8; binary data should be read back through the
+/// `_bytes` methods and [`Db::iter`].
+///
+/// A database holds one or more column families: independent keyspaces with their own
+/// memtable, WAL, and SSTables. `Db::open` returns a handle to the default family, and
+/// [`Db::cf`] returns a handle to a named one with the same API.
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<RwLock<MemTable>>,
+    families: Arc<ColumnFamilies>,
+}
+
+/// Keys directly under a prefix, from [`Db::list`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listing {
+    /// Live keys with no delimiter after the prefix, in key order
+    pub keys: Vec<String>,
+    /// Prefix up to and including the first delimiter after it, once for each distinct
+    /// one among the remaining keys, in key order
+    pub common_prefixes: Vec<String>,
+}
+
+/// One page of a paginated scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub entries: Vec<(String, String)>,
+    /// Where the next page starts, `None` after the last page
+    pub cursor: Option<String>,
+}
+
+impl Db {
+    /// Open (or create) a database in `dir`.
+    ///
+    /// Fails if another process (or another `Db` in this one) has it open, unless both
+    /// open it with [`Options::read_only`]. A read-only open creates nothing, not even
+    /// the directory or its lock file.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        Self::open_with_options(dir, Options::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: Options) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let storage = options.storage();
+        // A read-only open leaves the directory as it finds it
+        if !options.read_only {
+            storage.create_dir_all(dir)?;
+        }
+        let lock = storage.lock_dir(dir, options.read_only)?;
+        let wal_path = dir.join(WAL_FILE);
+        let mut memtable = MemTable::with_options(&wal_path.to_string_lossy(), &options)?;
+        if options.read_only {
+            memtable.close(READ_ONLY);
+        }
+        let inner = Arc::new(RwLock::new(memtable));
+        let families = ColumnFamilies::open(dir, &options, inner.clone(), lock)?;
+
+        let db = Db {
+            inner,
+            families: Arc::new(families),
+        };
+        if options.verify_on_open {
+            let report = db.verify();
+            if !report.is_ok() {
+                let violations = report.violations();
+                return Err(EngineError::VerificationFailed { violations }.into());
+            }
+        }
+        Ok(db)
+    }
+
+    /// Delete the database in `dir`, every column family included. Only files the engine
+    /// creates are removed; anything else in `dir` is left alone. Fails if `dir` does not
+    /// look like a database. With `remove_dir`, `dir` is removed as well if nothing else
+    /// remains in it.
+    pub fn destroy<P: AsRef<Path>>(dir: P, remove_dir: bool) -> io::Result<()> {
+        column_family::destroy(dir.as_ref(), remove_dir)
+    }
+
+    /// Check every column family's files against what it has in use, running every
+    /// check to the end and reporting each violation rather than stopping at the first.
+    /// Every SSTable is read in full. Tables are not arranged in levels, so there are no
+    /// key-range invariants between them to check.
+    pub fn verify(&self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        for name in self.column_families() {
+            let Some(family) = self.families.get(&name) else { continue };
+            let memtable = family.read().unwrap_or_else(PoisonError::into_inner);
+            for (check, violations) in memtable.verify() {
+                let column_family = name.clone();
+                report.checks.push(CheckResult { column_family, check, violations });
+            }
+        }
+        report
+    }
+
+    /// Create a named column family and return a handle to it
+    pub fn create_cf(&self, name: &str) -> io::Result<Db> {
+        let inner = self.families.create(name)?;
+        Ok(self.with_family(inner))
+    }
+
+    /// Handle to the column family `name`, or `None` if it does not exist.
+    /// `"default"` names the family that `Db::open` returns.
+    pub fn cf(&self, name: &str) -> Option<Db> {
+        self.families.get(name).map(|inner| self.with_family(inner))
+    }
+
+    /// Drop a named column family and delete its files. Handles to it that are still
+    /// held fail on every later write.
+    pub fn drop_cf(&self, name: &str) -> io::Result<()> {
+        self.families.remove(name)
+    }
+
+    /// Counters and table counts for the whole engine, every column family included
+    pub fn stats(&self) -> io::Result<Stats> {
+        let mut stats = Stats::default();
+        let mut latencies = LatencyTotals::default();
+        for (_, family) in self.families.all() {
+            let memtable = family.read().unwrap_or_else(PoisonError::into_inner);
+            memtable.counters().add_to(&mut stats, &mut latencies);
+            let filters = memtable.filters().stats();
+            stats.filter_cache_hits += filters.hits;
+            stats.filter_cache_misses += filters.misses;
+            stats.filter_cache_bytes += filters.bytes;
+            let rows = memtable.row_cache().stats();
+            stats.row_cache_hits += rows.hits;
+            stats.row_cache_misses += rows.misses;
+            stats.row_cache_bytes += rows.bytes;
+            stats.negative_cache_hits += memtable.absent_keys().hits();
+            let stalls = memtable.write_controller().stats();
+            stats.writes_slowed += stalls.slowed;
+            stats.writes_stopped += stalls.stopped;
+            stats.write_stall_micros += stalls.waited_micros;
+            match stalls.current {
+                WriteStall::Normal => {}
+                WriteStall::Slowed => stats.slowed_column_families += 1,
+                WriteStall::Stopped => stats.stopped_column_families += 1,
+            }
+            for path in memtable.sstable_paths() {
+                stats.sstable_count += 1;
+                stats.sstable_bytes += memtable.storage().file_len(&path)?;
+            }
+            stats.wal_bytes += memtable.wal_len()?;
+            stats.memtable_entries += memtable.size() as u64;
+            stats.file_count += memtable.files()?.len() as u64;
+        }
+        stats.latency = latencies.summarize();
+        let default = self.read_lock();
+        stats.disk_bytes = default.disk_usage().bytes();
+        let throttle = default.rate_limiter().stats();
+        stats.throttled_bytes = throttle.bytes;
+        stats.throttle_wait_micros = throttle.waited_micros;
+        Ok(stats)
+    }
+
+    /// [`Db::stats`] in the Prometheus text exposition format, for a `/metrics` endpoint
+    pub fn metrics_text(&self) -> io::Result<String> {
+        Ok(self.stats()?.to_prometheus())
+    }
+
+    /// Zero every counter in [`Db::stats`]
+    pub fn reset_stats(&self) {
+        for (_, family) in self.families.all() {
+            let memtable = family.read().unwrap_or_else(PoisonError::into_inner);
+            memtable.counters().reset();
+            memtable.filters().reset();
+            memtable.row_cache().reset();
+            memtable.absent_keys().reset();
+            memtable.write_controller().reset();
+        }
+        self.read_lock().rate_limiter().reset();
+    }
+
+    /// Names of all column families, starting with `"default"`
+    pub fn column_families(&self) -> Vec<String> {
+        self.families.names()
+    }
+
+    fn with_family(&self, inner: Arc<RwLock<MemTable>>) -> Db {
+        Db {
+            inner,
+            families: self.families.clone(),
+        }
+    }
+
+    pub fn put<K, V>(&self, key: K, value: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.write_key(key.into(), Op::Put(value.into()), None)
+    }
+
+    /// [`Db::put`], except that where it would wait for writes to resume at
+    /// [`Options::stop_writes_sstables`](crate::Options#structfield.stop_writes_sstables)
+    /// this fails at once with [`EngineError::WriteStopped`], of kind `WouldBlock`
+    pub fn try_put<K, V>(&self, key: K, value: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.families.ensure_primary()?;
+        self.read_lock().write_controller().clone().admit(false)?;
+        self.with_write_lock(|memtable| memtable.put(key, value))
+    }
+
+    /// Write `value` so that it is no longer visible to reads once `ttl` has elapsed.
+    /// The expiry is persisted with the entry, so it also holds across restarts.
+    pub fn put_with_ttl<K, V>(&self, key: K, value: V, ttl: Duration) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_key(key.into(), Op::Put(value.into()), Some(expires_at))
+    }
+
+    /// The value of `key`. A read that fails, as when an SSTable in use has gone
+    /// missing, is logged and answers `None`; [`Db::try_get`] reports it instead.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<String> {
+        self.get_bytes(key).map(into_string)
+    }
+
+    pub fn get_bytes<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
+        self.read_lock().get(key)
+    }
+
+    /// [`Db::get`], except that a table the value may be in having gone missing fails
+    /// with [`EngineError::MissingTables`] rather than reading as an absent key
+    pub fn try_get<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<String>> {
+        Ok(self.try_get_bytes(key)?.map(into_string))
+    }
+
+    pub fn try_get_bytes<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<Vec<u8>>> {
+        self.read_lock().try_get(key)
+    }
+
+    /// Write the value of `key` to `out` a chunk at a time, returning its length, or
+    /// `None` without writing anything if the key has no value. A value in an SSTable
+    /// or the value log streams from its file, so it is never held in memory whole,
+    /// and writes go on while it is copied.
+    pub fn get_to_writer<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        out: &mut impl Write,
+    ) -> io::Result<Option<u64>> {
+        let Some((mut value, len)) = self.read_lock().value_reader(key)? else {
+            return Ok(None);
+        };
+        let copied = io::copy(&mut value, out)?;
+        if copied < len {
+            let message = format!("value ended after {} of its {} bytes", copied, len);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+        }
+        Ok(Some(copied))
+    }
+
+    /// The value of `key` along with the sequence number and write time of its newest
+    /// version, and whether the memtable or an SSTable (and which) answered
+    pub fn get_with_metadata<K: AsRef<[u8]>>(&self, key: K) -> Option<ValueMeta> {
+        self.read_lock().get_with_metadata(key)
+    }
+
+    /// Delete `key`, returning the value it held, read through SSTables too
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<String>> {
+        Ok(self.delete_bytes(key)?.map(into_string))
+    }
+
+    pub fn delete_bytes<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<Vec<u8>>> {
+        self.with_admitted_write(|memtable| memtable.delete(key))
+    }
+
+    /// Atomically replace the value of `key` with `new` (deleting it for `None`) if its
+    /// current value is `expected` (`None` meaning absent). The check and the write
+    /// happen under the same lock, and the current value is read through SSTables too.
+    /// Returns `false` without writing anything on a mismatch.
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<&str>,
+        new: Option<&str>,
+    ) -> io::Result<bool> {
+        self.with_admitted_write(|memtable| {
+            memtable.compare_and_swap(key, expected.map(str::as_bytes), new.map(str::as_bytes))
+        })
+    }
+
+    /// Write `value` only if `key` has no live value anywhere (a deleted key counts as
+    /// absent). The first writer wins; returns whether this call inserted.
+    pub fn put_if_absent<K, V>(&self, key: K, value: V) -> io::Result<bool>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.with_admitted_write(|memtable| memtable.put_if_absent(key, value))
+    }
+
+    /// The current value of `key`, read through SSTables too, or else the result of `f`,
+    /// which is written through the WAL and returned. The read and the write happen
+    /// under one lock, so concurrent callers agree on a single value and `f` runs only
+    /// when the key is absent or deleted. `f` runs with the lock held and must not call
+    /// back into this column family.
+    pub fn get_or_insert_with<K, F>(&self, key: K, f: F) -> io::Result<String>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce() -> String,
+    {
+        let value = self.with_admitted_write(|memtable| {
+            memtable.get_or_insert_with(key, || f().into_bytes())
+        })?;
+        Ok(into_string(value))
+    }
+
+    /// Atomically replace the value of `key` (read through SSTables too, `None` if absent
+    /// or deleted) with the result of `f`: a put for `Some`, a delete for `None`. Returns
+    /// the new value. The read and the write happen under one lock, so no other write to
+    /// the column family comes between them. `f` is called exactly once, with the lock
+    /// held, and must not call back into this column family. Its result is written even
+    /// when unchanged.
+    pub fn update<K, F>(&self, key: K, f: F) -> io::Result<Option<String>>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce(Option<&str>) -> Option<String>,
+    {
+        let value = self.with_admitted_write(|memtable| {
+            memtable.update(key, |current| {
+                let current = current.map(String::from_utf8_lossy);
+                f(current.as_deref()).map(String::into_bytes)
+            })
+        })?;
+        Ok(value.map(into_string))
+    }
+
+    /// Atomically add `delta` to the decimal integer stored at `key`, treating a missing
+    /// key as 0, and return the new value. Fails with
+    /// [`EngineError::InvalidValue`] if the stored value isn't an integer or the result
+    /// would overflow.
+    pub fn increment<K: AsRef<[u8]>>(&self, key: K, delta: i64) -> io::Result<i64> {
+        self.with_admitted_write(|memtable| memtable.increment(key, delta))
+    }
+
+    /// Record `operand` against `key` without reading it. Reads combine the base value
+    /// with pending operands using `Options::merge_operator`; fails with
+    /// `InvalidInput` if none is configured. Operands are flushed to SSTables as-is.
+    pub fn merge<K, V>(&self, key: K, operand: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.write_key(key.into(), Op::Merge(operand.into()), None)
+    }
+
+    /// Delete every key in `[start, end)` with a single WAL record. Keys written
+    /// after the call are visible again; an empty range does nothing.
+    pub fn delete_range<K: AsRef<[u8]>>(&self, start: K, end: K) -> io::Result<()> {
+        self.with_admitted_write(|memtable| memtable.delete_range(start, end))
+    }
+
+    /// Apply every operation in `batch` atomically
+    pub fn write(&self, batch: &WriteBatch) -> io::Result<()> {
+        self.with_admitted_write(|memtable| memtable.write_batch(batch))
+    }
+
+    /// Start an optimistic transaction reading from the current state
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(self.clone(), self.snapshot())
+    }
+
+    /// Apply `batch` only if none of `reads` has been written since `start_seq`
+    pub(crate) fn commit_if_unchanged(
+        &self,
+        start_seq: u64,
+        reads: &HashSet<Vec<u8>>,
+        batch: &WriteBatch,
+    ) -> io::Result<()> {
+        self.with_admitted_write(|memtable| {
+            for key in reads {
+                if memtable.latest_sequence(key).is_some_and(|seq| seq > start_seq) {
+                    let key = String::from_utf8_lossy(key).into_owned();
+                    return Err(EngineError::Conflict { key }.into());
+                }
+            }
+            memtable.write_batch(batch)
+        })
+    }
+
+    /// Value of `key` as of sequence number `seq`, along with the sequence it was
+    /// written at. Returns `None` if the key was absent or deleted at that point.
+    ///
+    /// For optimistic concurrency: read at `last_sequence()`, then later compare the
+    /// returned sequence against a fresh `get_at` to detect an intervening write.
+    /// Compaction drops overwritten versions unless a [`Snapshot`] pins them, so reads
+    /// at an older sequence are only reliable through one.
+    pub fn get_at<K: AsRef<[u8]>>(&self, key: K, seq: u64) -> Option<(String, u64)> {
+        let (value, seq) = self.read_lock().get_at(key, seq)?;
+        Some((into_string(value), seq))
+    }
+
+    /// Sequence number of the most recent write
+    pub fn last_sequence(&self) -> u64 {
+        self.read_lock().last_sequence()
+    }
+
+    /// Every write to this column family after sequence number `seq`, in order, for a
+    /// replica to apply. Reaching back past the live WAL takes
+    /// [`Options::wal_retention`](crate::Options#structfield.wal_retention); records no
+    /// longer kept end the iteration with [`EngineError::WalGap`].
+    pub fn wal_records_since(&self, seq: u64) -> io::Result<WalRecords> {
+        self.read_lock().wal_records_since(seq)
+    }
+
+    /// Every committed write to this column family after sequence number `seq`, in
+    /// order, one key (or key range) at a time. A consumer that records the sequence
+    /// number of the last change it processed resumes from it after a restart; changes
+    /// older than the live WAL are kept for
+    /// [`Options::wal_retention`](crate::Options#structfield.wal_retention), and any
+    /// no longer kept end the iteration with [`EngineError::WalGap`].
+    pub fn changes_since(&self, seq: u64) -> io::Result<Changes> {
+        let records = self.wal_records_since(seq)?;
+        Ok(Changes::new(records, self.inner.clone()))
+    }
+
+    /// Apply a write shipped from the primary of this follower, as yielded by its
+    /// [`Db::wal_records_since`]. The write is logged to this database's own WAL and keeps
+    /// the primary's sequence number `seq`, which must follow [`Db::last_sequence`];
+    /// anything else fails with [`EngineError::ReplicationOutOfOrder`]. A restarted
+    /// follower resumes shipping from its last sequence number. Fails once promoted.
+    pub fn apply_replicated(&self, seq: u64, record: WalRecord) -> io::Result<()> {
+        if !self.families.is_follower() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "only a follower applies replicated writes",
+            ));
+        }
+        self.read_lock().write_controller().clone().admit(true)?;
+        self.with_write_lock(|memtable| memtable.apply_replicated(seq, record))
+    }
+
+    /// Whether this is a follower, opened with
+    /// [`Options::follower`](crate::Options#structfield.follower) and not yet promoted
+    pub fn is_follower(&self) -> bool {
+        self.families.is_follower()
+    }
+
+    /// Turn a follower into a primary: direct writes are accepted from now on and
+    /// replicated ones refused. Every column family is promoted at once.
+    pub fn promote(&self) {
+        self.families.promote();
+    }
+
+    /// Live key-value pairs in `[start, end)`, in key order
+    pub fn scan<K: AsRef<[u8]>>(&self, start: K, end: K) -> io::Result<Vec<(String, String)>> {
+        Ok(into_strings(self.scan_bytes(start, end)?))
+    }
+
+    pub fn scan_bytes<K: AsRef<[u8]>>(
+        &self,
+        start: K,
+        end: K,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.read_lock().scan(start, end)
+    }
+
+    /// Iterate over every live key-value pair in byte order of the keys.
+    ///
+    /// The iterator streams SSTables rather than loading them, and does not hold the
+    /// database lock: writes made after this call are not seen.
+    pub fn iter(&self) -> DbIterator {
+        let memtable = self.read_lock();
+        memtable.iter_range(None, None, memtable.last_sequence())
+    }
+
+    /// Like [`Db::iter`], but starting at the first key at or after `start`
+    pub fn iter_from<K: AsRef<[u8]>>(&self, start: K) -> DbIterator {
+        let memtable = self.read_lock();
+        memtable.iter_range(Some(start.as_ref()), None, memtable.last_sequence())
+    }
+
+    /// Stream every live key-value pair to `writer` as JSON Lines, in key order, and
+    /// return how many were written. Keys and values that are not UTF-8 are written as
+    /// base64 with a `key_base64` / `value_base64` flag.
+    pub fn export_json<W: Write>(&self, writer: W) -> io::Result<u64> {
+        export::export_json(self, writer)
+    }
+
+    /// Write every pair from JSON Lines produced by [`Db::export_json`] and return how
+    /// many were imported
+    pub fn import_json<R: Read>(&self, reader: R) -> io::Result<u64> {
+        export::import_json(self, reader)
+    }
+
+    /// Import `key,value` rows from CSV, quoted fields included, through `put` or
+    /// bulk ingest as `options` choose. Malformed rows abort the import or are skipped
+    /// and reported with their line numbers.
+    pub fn import_csv<R: Read>(
+        &self,
+        reader: R,
+        options: &CsvImportOptions,
+    ) -> io::Result<CsvImportSummary> {
+        export::import_csv(self, reader, options)
+    }
+
+    /// Live key-value pairs whose key starts with `prefix`, in key order
+    pub fn scan_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> io::Result<Vec<(String, String)>> {
+        let memtable = self.read_lock();
+        let prefix = prefix.as_ref();
+        let iter = memtable.iter_range(Some(prefix), None, memtable.last_sequence());
+        drop(memtable);
+        let entries = iter
+            .take_while(|item| item.as_ref().map_or(true, |(key, _)| key.starts_with(prefix)))
+            .collect::<io::Result<_>>()?;
+        Ok(into_strings(entries))
+    }
+
+    /// List the keys under `prefix` one level deep, the way S3 lists a "directory":
+    /// keys with no `delimiter` after the prefix are returned as they are, and the rest
+    /// are rolled up into their common prefixes. Keys under a common prefix are skipped
+    /// rather than visited, so a prefix holding a few subdirectories of a million keys
+    /// each lists cheaply. Values are never read.
+    pub fn list(&self, prefix: &str, delimiter: &str) -> io::Result<Listing> {
+        let memtable = self.read_lock();
+        let seq = memtable.last_sequence();
+        let (keys, common_prefixes) = memtable.list(prefix.as_bytes(), delimiter.as_bytes(), seq)?;
+        Ok(Listing {
+            keys: keys.into_iter().map(into_string).collect(),
+            common_prefixes: common_prefixes.into_iter().map(into_string).collect(),
+        })
+    }
+
+    /// Number of live keys in `[start, end)`, without reading or cloning values
+    pub fn count_range<K: AsRef<[u8]>>(&self, start: K, end: K) -> io::Result<u64> {
+        let memtable = self.read_lock();
+        memtable.count_range(start.as_ref(), end.as_ref(), memtable.last_sequence())
+    }
+
+    /// Exact number of live keys, after deletions, range deletions, expiry, and
+    /// overwrites are resolved. This is O(n): every key in the memtable and SSTables is
+    /// visited, though values are not read. The lock is not held while counting.
+    pub fn len(&self) -> io::Result<u64> {
+        self.live_keys().count_live()
+    }
+
+    /// Whether no key is live, stopping at the first live key found
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(!self.live_keys().has_live()?)
+    }
+
+    /// Iterate over the live keys in byte order without reading values. Like
+    /// [`Db::iter`], it does not hold the lock: writes made after this call are not seen.
+    pub fn keys(&self) -> KeyIterator {
+        KeyIterator::new(self.live_keys())
+    }
+
+    /// Like [`Db::keys`], but starting at the first key at or after `start`
+    pub fn keys_from<K: AsRef<[u8]>>(&self, start: K) -> KeyIterator {
+        let memtable = self.read_lock();
+        KeyIterator::new(memtable.live_keys_from(start.as_ref(), memtable.last_sequence()))
+    }
+
+    /// Smallest live key, or `None` when no key is live
+    pub fn first_key(&self) -> io::Result<Option<String>> {
+        let memtable = self.read_lock();
+        Ok(memtable.first_key(memtable.last_sequence())?.map(into_string))
+    }
+
+    /// Largest live key, or `None` when no key is live. Tables are read backwards a
+    /// block or so at a time, so this is cheap unless the top of the key space is
+    /// mostly deleted.
+    pub fn last_key(&self) -> io::Result<Option<String>> {
+        let memtable = self.read_lock();
+        Ok(memtable.last_key(memtable.last_sequence())?.map(into_string))
+    }
+
+    /// Up to `n` live keys picked at random, in key order, without scanning the whole
+    /// database. Fewer come back when fewer are live, or when picks keep landing on
+    /// deleted keys.
+    ///
+    /// Each pick chooses the memtable or an SSTable in proportion to the versions it
+    /// holds, then a key within it by way of the table's block index. The sample is only
+    /// roughly uniform: keys with more versions, keys in sparsely filled blocks, and keys
+    /// at the start of large blocks are favoured. A database holding few versions
+    /// compared to `n` is listed in full and sampled exactly.
+    pub fn sample_keys(&self, n: usize) -> io::Result<Vec<String>> {
+        let memtable = self.read_lock();
+        let keys = memtable.sample_keys(n, memtable.last_sequence())?;
+        Ok(keys.into_iter().map(into_string).collect())
+    }
+
+    fn live_keys(&self) -> DbIterator {
+        let memtable = self.read_lock();
+        memtable.live_keys(memtable.last_sequence())
+    }
+
+    /// Up to `limit` live key-value pairs starting at `start` (or the first key), plus
+    /// the cursor to pass as `start` for the next page.
+    ///
+    /// No iterator is held between calls; each page reads the current state. Keys that
+    /// stay live for the whole pagination are returned exactly once, while keys written
+    /// or deleted in the meantime may or may not appear depending on where they fall
+    /// relative to the cursor.
+    pub fn scan_page(&self, start: Option<&str>, limit: usize) -> io::Result<Page> {
+        let memtable = self.read_lock();
+        let start = start.map(str::as_bytes);
+        let mut iter = memtable.iter_range(start, None, memtable.last_sequence());
+        drop(memtable);
+
+        let entries = iter.by_ref().take(limit).collect::<io::Result<Vec<_>>>()?;
+        let cursor = iter.next().transpose()?.map(|(key, _)| into_string(key));
+        Ok(Page { entries: into_strings(entries), cursor })
+    }
+
+    /// Live key-value pairs in `[start, end)` in descending key order, e.g. for
+    /// "latest N" queries over timestamped keys
+    pub fn scan_rev<K: AsRef<[u8]>>(
+        &self,
+        start: K,
+        end: K,
+    ) -> io::Result<Vec<(String, String)>> {
+        let entries = self.iter_rev(start, end).collect::<io::Result<Vec<_>>>()?;
+        Ok(into_strings(entries))
+    }
+
+    /// Iterate over the live key-value pairs in `[start, end)` from the highest key down.
+    ///
+    /// SSTables are read a block at a time down from `end`, so taking the first few
+    /// pairs reads little more than the blocks they are in. Like [`Db::iter`], writes
+    /// made after this call are not seen.
+    pub fn iter_rev<K: AsRef<[u8]>>(&self, start: K, end: K) -> DbIterator {
+        let memtable = self.read_lock();
+        let (start, end) = (start.as_ref(), end.as_ref());
+        memtable.iter_range_rev(Some(start), Some(end), memtable.last_sequence())
+    }
+
+    /// Take a consistent read-only view of the current state.
+    ///
+    /// Writes made after this call are invisible through the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let memtable = self.read_lock();
+        let seq = memtable.last_sequence();
+        // Registered under the lock so the pin is taken before any further write lands
+        memtable.snapshots().acquire(seq);
+        Snapshot::new(self.clone(), seq)
+    }
+
+    pub(crate) fn scan_at_sequence(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        seq: u64,
+    ) -> io::Result<Vec<(String, String)>> {
+        Ok(into_strings(self.read_lock().scan_at(start, end, seq)?))
+    }
+
+    pub(crate) fn iter_at_sequence(&self, seq: u64) -> DbIterator {
+        self.read_lock().iter_range(None, None, seq)
+    }
+
+    pub(crate) fn scan_rev_at_sequence(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        seq: u64,
+    ) -> io::Result<Vec<(String, String)>> {
+        Ok(into_strings(self.read_lock().scan_rev_at(start, end, seq)?))
+    }
+
+    pub(crate) fn release_snapshot(&self, seq: u64) {
+        self.read_lock().snapshots().release(seq);
+    }
+
+    /// Make every write acknowledged so far crash-durable, in every column family,
+    /// however relaxed [`Options::sync_policy`](crate::Options::sync_policy) is: a
+    /// barrier to call before acknowledging a client while still batching most
+    /// syncs. Does no IO when nothing is waiting for a sync.
+    pub fn sync(&self) -> io::Result<()> {
+        for (_, family) in self.families.all() {
+            family.write().unwrap_or_else(PoisonError::into_inner).sync()?;
+        }
+        Ok(())
+    }
+
+    /// Force the memtable out to an SSTable
+    pub fn flush(&self) -> io::Result<()> {
+        self.with_write_lock(|memtable| memtable.flush())
+    }
+
+    /// Why the last flush a write triggered failed, as an
+    /// [`EngineError::BackgroundError`], if no flush has succeeded since. The write
+    /// itself succeeded, being in the WAL, but later writes are refused until then.
+    pub fn background_error(&self) -> Option<EngineError> {
+        self.read_lock().background_error()
+    }
+
+    /// Retry the flush behind [`Db::background_error`], so that writes resume if it
+    /// succeeds. Does nothing when there is no background error.
+    pub fn try_flush(&self) -> io::Result<()> {
+        self.with_write_lock(|memtable| memtable.try_flush())
+    }
+
+    /// Merge this column family's SSTables into as few tables as possible, so reads
+    /// search fewer tables. Overwritten versions are dropped unless a snapshot still
+    /// reads them, and so are deletes once nothing they hide is left.
+    pub fn compact(&self) -> io::Result<CompactionInfo> {
+        self.with_write_lock(|memtable| memtable.compact())
+    }
+
+    /// Merge only the `count` newest SSTables, which is cheaper than [`Db::compact`] but
+    /// keeps every delete, as older tables may still hold what it hides
+    pub fn compact_newest(&self, count: usize) -> io::Result<CompactionInfo> {
+        self.with_write_lock(|memtable| memtable.compact_newest(count))
+    }
+
+    /// Merge only the SSTables holding keys in `[start, end)`, `None` leaving that end
+    /// open, to reclaim the space of a region full of deletes without a full
+    /// compaction. Newer tables sharing keys with merged ones are merged too; see
+    /// [`MemTable::compact_range`].
+    pub fn compact_range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+    ) -> io::Result<CompactionInfo> {
+        let (start, end) = (start.map(str::as_bytes), end.map(str::as_bytes));
+        self.with_write_lock(|memtable| memtable.compact_range(start, end))
+    }
+
+    /// Copy a consistent point-in-time image of the whole database, every column family
+    /// included, into the empty or missing directory `dest`. Writes are paused only
+    /// while memtables are flushed; `dest` can then be opened as a database.
+    pub fn backup<P: AsRef<Path>>(&self, dest: P) -> io::Result<BackupInfo> {
+        let result = backup::backup(&self.families, dest.as_ref());
+        self.deliver_all_events();
+        result
+    }
+
+    /// Create a checkpoint of the whole database in the empty or missing directory `dir`
+    /// and return the sequence number it reflects. Immutable files are hard-linked
+    /// rather than copied where the filesystem allows, so this is cheap at any size;
+    /// `dir` can then be opened as a database.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> io::Result<u64> {
+        let result = backup::checkpoint(&self.families, dir.as_ref());
+        self.deliver_all_events();
+        result
+    }
+
+    /// Stream a consistent image of the whole database, every column family included, to
+    /// `writer` as a single self-contained archive for [`Db::import_archive`] to unpack
+    /// elsewhere. Writes are paused only while memtables are flushed.
+    pub fn export_archive<W: Write>(&self, mut writer: W) -> io::Result<BackupInfo> {
+        let result = backup::export_archive(&self.families, &mut writer);
+        self.deliver_all_events();
+        result
+    }
+
+    /// Unpack an archive made by [`Db::export_archive`] into `target_dir`, verifying
+    /// every file's checksum before the directory appears; it can then be opened as a
+    /// database. A non-empty target is refused unless `force` is set, in which case its
+    /// contents are replaced.
+    pub fn import_archive<R, P>(mut reader: R, target_dir: P, force: bool) -> io::Result<()>
+    where
+        R: Read,
+        P: AsRef<Path>,
+    {
+        backup::import_archive(&mut reader, target_dir.as_ref(), force)
+    }
+
+    /// Restore a backup made by [`Db::backup`] into `target_dir`, verifying every
+    /// file's size and checksum first. A non-empty target is refused unless `force`
+    /// is set, in which case its contents are replaced.
+    pub fn restore<P, Q>(backup_dir: P, target_dir: Q, force: bool) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        backup::restore(backup_dir.as_ref(), target_dir.as_ref(), force)
+    }
+
+    /// Look over the files of the database in `dir` for structural problems that are not
+    /// damage, such as leftover temporary files, SSTables under names the engine does
+    /// not read, or a WAL flushes are not keeping up with, each with what to do about it.
+    /// With `fix`, temporary files are deleted and whole SSTables the engine does not
+    /// read are adopted under the next table number. The database must not be open.
+    pub fn doctor<P: AsRef<Path>>(dir: P, fix: bool) -> io::Result<DoctorReport> {
+        doctor::doctor(dir.as_ref(), fix)
+    }
+
+    /// Convert the WAL of the database in `dir` from the original text format to the
+    /// binary one, which opening requires. Returns the number of records converted, or
+    /// `None` if the WAL is not in the text format. The original is kept as
+    /// `data.log.v0`. The database must not be open.
+    pub fn migrate_wal<P: AsRef<Path>>(dir: P) -> io::Result<Option<u64>> {
+        migrate::migrate_wal(dir.as_ref())
+    }
+
+    /// Rebuild the database in `dir` from whatever of its files can still be read, then
+    /// reopen it to check the result. Damaged SSTables are rewritten from their readable
+    /// prefix and a damaged WAL is cut short at its damage; every original replaced or
+    /// cut short is first moved or copied into a `lost/` subdirectory, and nothing is
+    /// deleted. The database must not be open.
+    pub fn repair<P: AsRef<Path>>(dir: P) -> io::Result<RepairReport> {
+        repair::repair(dir.as_ref())
+    }
+
+    /// Load pre-sorted rows directly into new SSTables without writing them to the WAL.
+    /// Keys must be strictly increasing; unsorted input is rejected and nothing is
+    /// ingested. Returns the number of rows ingested.
+    pub fn bulk_ingest<I, K, V>(&self, rows: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.families.ensure_primary()?;
+        self.with_write_lock(|memtable| memtable.ingest(rows))
+    }
+
+    pub(crate) fn try_bulk_ingest<I, K, V>(&self, rows: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = io::Result<(K, V)>>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.families.ensure_primary()?;
+        self.with_write_lock(|memtable| memtable.try_ingest(rows))
+    }
+
+    /// Paths of this column family's live SSTables, oldest first
+    pub fn sstable_paths(&self) -> Vec<PathBuf> {
+        self.read_lock().sstable_paths()
+    }
+
+    /// Number the next SSTable written by this column family will get
+    pub fn next_sstable_number(&self) -> usize {
+        self.read_lock().next_sstable_number()
+    }
+
+    /// What this column family's compactions have done since it was created, unlike
+    /// [`Db::stats`] kept across opens
+    pub fn compaction_totals(&self) -> CompactionTotals {
+        self.read_lock().compaction_totals().clone()
+    }
+
+    /// Path of this column family's write-ahead log
+    pub fn wal_path(&self) -> PathBuf {
+        self.read_lock().wal_path().to_path_buf()
+    }
+
+    /// Number of entries currently held in the memtable
+    pub fn size(&self) -> usize {
+        self.read_lock().size()
+    }
+
+    /// Roughly how many keys there are, from the entry counts in SSTable headers plus
+    /// the keys in the memtable; no table is read past its header. Every version is
+    /// counted until compaction drops it, so overwritten and deleted keys make this an
+    /// upper bound; it is exact only when each key was written once. [`Db::len`]
+    /// gives the exact count at the cost of a full scan.
+    pub fn estimated_key_count(&self) -> io::Result<u64> {
+        self.read_lock().estimated_key_count()
+    }
+
+    /// Estimated bytes held in memory by the memtable; see
+    /// [`MemTable::approximate_memory_usage`]
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.read_lock().approximate_memory_usage()
+    }
+
+    // A panic inside an engine call can't leave the map half-updated (every
+    // mutation is a single insert/remove), so a poisoned lock is still usable.
+    fn read_lock(&self) -> RwLockReadGuard<'_, MemTable> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hold a write back for as long as the column family's write stall requires, then
+    /// run it like `with_write_lock`. The wait happens without the lock, so
+    /// compactions on other threads can end it.
+    fn with_admitted_write<T>(
+        &self,
+        f: impl FnOnce(&mut MemTable) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.families.ensure_primary()?;
+        let controller = self.read_lock().write_controller().clone();
+        controller.admit(true)?;
+        self.with_write_lock(f)
+    }
+
+    /// Write `op`, a put or merge, to `key` once the write stall admits it. With a
+    /// sharded memtable it goes through the key's shard under the read lock, alongside
+    /// writes to other shards, and a write that fills the memtable then flushes it
+    /// under the write lock; otherwise it is written under the write lock.
+    fn write_key(&self, key: Vec<u8>, op: Op, expires_at: Option<u64>) -> io::Result<()> {
+        self.families.ensure_primary()?;
+        let controller = self.read_lock().write_controller().clone();
+        controller.admit(true)?;
+        let full = {
+            let memtable = self.read_lock();
+            if !memtable.writes_shared(&op) {
+                drop(memtable);
+                return self.with_write_lock(|memtable| memtable.write_key(key, op, expires_at));
+            }
+            memtable.write_shared(key, op, expires_at)?
+        };
+        if full {
+            self.with_write_lock(MemTable::flush_when_full)?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` under the write lock, then deliver the events it raised once the lock is
+    /// released, so listeners may call back into the database
+    fn with_write_lock<T>(&self, f: impl FnOnce(&mut MemTable) -> T) -> T {
+        let (result, events) = {
+            let mut memtable = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+            let result = f(&mut memtable);
+            memtable.update_write_stall();
+            (result, memtable.take_events())
+        };
+        if let Some((listener, events)) = events {
+            event::deliver(&*listener, events);
+        }
+        result
+    }
+
+    /// Deliver the events of every column family, for operations that lock them all
+    fn deliver_all_events(&self) {
+        for (_, memtable) in self.families.all() {
+            let mut memtable = memtable.write().unwrap_or_else(PoisonError::into_inner);
+            memtable.update_write_stall();
+            let events = memtable.take_events();
+            drop(memtable);
+            if let Some((listener, events)) = events {
+                event::deliver(&*listener, events);
+            }
+        }
+    }
+}
+
+/// Decode a value for the `&str` API, replacing invalid UTF-8 rather than failing
+fn into_string(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+fn into_strings(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(String, String)> {
+    entries
+        .into_iter()
+        .map(|(key, value)| (into_string(key), into_string(value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::ValueSource;
+    use crate::fault::FaultStorage;
+    use crate::storage::Storage;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn test_db_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<Db>();
+    }
+
+    #[test]
+    fn test_put_visible_from_other_thread() {
+        let dir = "test_db_visibility";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("key1", "value1").unwrap();
+
+        let reader = db.clone();
+        let value = thread::spawn(move || reader.get("key1")).join().unwrap();
+        assert_eq!(value, Some("value1".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writer() {
+        let dir = "test_db_concurrent";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let keys: Vec<String> = (0..10).map(|i| format!("key_{}", i)).collect();
+
+        // Writer: overwrite the same keys repeatedly, crossing several flushes
+        let writer = {
+            let db = db.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                for round in 0..50 {
+                    for key in &keys {
+                        db.put(key.clone(), format!("{}:{}", key, round)).unwrap();
+                    }
+                }
+            })
+        };
+
+        // Readers: every value seen must be a complete value written for that key
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                let keys = keys.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        for key in &keys {
+                            if let Some(value) = db.get(key) {
+                                let (prefix, round) = value.split_once(':').unwrap();
+                                assert_eq!(prefix, key);
+                                assert!(round.parse::<u32>().unwrap() < 50);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        for key in &keys {
+            assert_eq!(db.get(key), Some(format!("{}:49", key)));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_memtable_takes_concurrent_writers() {
+        let dir = "test_db_sharded_writers";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { memtable_shards: Some(4), ..counter_options() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        // Each writer has its own keys, and together they cross many flushes
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..150 {
+                        db.put(format!("w{}_{:03}", writer, i), i.to_string()).unwrap();
+                        db.merge(format!("w{}_count", writer), "1").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut expected = Vec::new();
+        for writer in 0..8 {
+            for i in 0..150 {
+                expected.push((format!("w{}_{:03}", writer, i), i.to_string()));
+            }
+            expected.push((format!("w{}_count", writer), "150".to_string()));
+        }
+        expected.sort();
+        // Every write took a number of its own, and scans merge the shards in key order
+        assert_eq!(db.last_sequence(), 8 * 300);
+        assert_eq!(db.scan("a", "z").unwrap(), expected);
+        for (key, value) in &expected {
+            assert_eq!(db.get(key).as_ref(), Some(value));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots_of_a_sharded_memtable_are_a_point_in_time() {
+        let dir = "test_db_sharded_snapshots";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { memtable_shards: Some(4), ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..300 {
+                        db.put(format!("w{}_{:03}", writer, i), "value").unwrap();
+                    }
+                })
+            })
+            .collect();
+        // Every write puts a key of its own, so a snapshot holds one key per number up
+        // to its own, and keeps holding them while writes under way complete
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            let snapshot = db.snapshot();
+            let seen = snapshot.scan("w", "x").unwrap();
+            assert_eq!(seen.len() as u64, snapshot.sequence());
+            thread::yield_now();
+            assert_eq!(snapshot.scan("w", "x").unwrap(), seen);
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(db.snapshot().scan("w", "x").unwrap().len(), 8 * 300);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_memtable_recovers_every_segment() {
+        let dir = "test_db_sharded_recovery";
+        let _ = fs::remove_dir_all(dir);
+        let shards = |shards| Options { memtable_shards: Some(shards), ..Options::default() };
+        let segments = || {
+            let names = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name());
+            names.filter(|name| name.to_string_lossy().starts_with("data.log.shard")).count()
+        };
+
+        {
+            let db = Db::open_with_options(dir, shards(4)).unwrap();
+            for i in 0..20 {
+                db.put(format!("key{:02}", i), "shard").unwrap();
+            }
+            // Batches and range deletes go to the WAL, between the shards' writes
+            let mut batch = WriteBatch::new();
+            batch.put("key03", "batch");
+            db.write(&batch).unwrap();
+            db.delete_range("key10", "key15").unwrap();
+            db.put("key12", "after").unwrap();
+        }
+        assert_eq!(segments(), 4);
+
+        // Replayed in sequence order whichever log holds each write, with any number of
+        // shards; a flush then retires every segment along with the WAL
+        for count in [4, 1] {
+            let db = Db::open_with_options(dir, shards(count)).unwrap();
+            assert_eq!(db.last_sequence(), 23);
+            assert_eq!(db.get("key03"), Some("batch".to_string()));
+            assert_eq!(db.get("key04"), Some("shard".to_string()));
+            assert_eq!(db.get("key11"), None);
+            assert_eq!(db.get("key12"), Some("after".to_string()));
+            assert_eq!(db.scan("key09", "key16").unwrap().len(), 3);
+            if count == 1 {
+                db.flush().unwrap();
+            }
+        }
+        assert_eq!(segments(), 0);
+        assert_eq!(Db::open(dir).unwrap().get("key12"), Some("after".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_at_intermediate_sequences() {
+        let dir = "test_db_get_at";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let mut seqs = Vec::new();
+        for version in 1..=3 {
+            db.put("key1", format!("v{}", version)).unwrap();
+            seqs.push(db.last_sequence());
+        }
+
+        let check = |db: &Db| {
+            assert_eq!(db.get_at("key1", seqs[0] - 1), None);
+            for (i, &seq) in seqs.iter().enumerate() {
+                assert_eq!(db.get_at("key1", seq), Some((format!("v{}", i + 1), seq)));
+            }
+            assert_eq!(db.get_at("key1", u64::MAX), Some(("v3".to_string(), seqs[2])));
+        };
+
+        check(&db);
+        db.flush().unwrap();
+        check(&db);
+
+        // Sequence numbering continues after reopening
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        check(&db);
+        db.delete("key1").unwrap();
+        assert!(db.last_sequence() > seqs[2]);
+        assert_eq!(db.get_at("key1", db.last_sequence()), None);
+        assert_eq!(db.get_at("key1", seqs[2]), Some(("v3".to_string(), seqs[2])));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_keys_match_scan() {
+        let dir = "test_db_keys";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { value_log_threshold: Some(8), ..counter_options() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        for i in 0..20 {
+            db.put(format!("key{:02}", i), format!("first value {}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..20).step_by(3) {
+            db.put(format!("key{:02}", i), "short").unwrap();
+        }
+        db.delete("key01").unwrap();
+        db.delete_range("key10", "key13").unwrap();
+        db.merge("counter", "5").unwrap();
+        db.flush().unwrap();
+        db.delete("key02").unwrap();
+        db.put("key11", "back").unwrap();
+        db.put_with_ttl("key15", "expired", Duration::ZERO).unwrap();
+
+        let keys: Vec<Vec<u8>> = db.keys().collect::<io::Result<_>>().unwrap();
+        let scanned: Vec<Vec<u8>> =
+            db.scan_bytes("", "~").unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, scanned);
+        assert_eq!(keys.len(), 16);
+        assert_eq!(keys.len() as u64, db.len().unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_len_and_is_empty() {
+        let dir = "test_db_len";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!((db.len().unwrap(), db.is_empty().unwrap()), (0, true));
+
+        // Data that is all deleted, some of it after a flush
+        db.put("a", "1").unwrap();
+        db.put("b", "2").unwrap();
+        db.put("c", "3").unwrap();
+        db.flush().unwrap();
+        db.delete("a").unwrap();
+        db.delete_range("b", "d").unwrap();
+        assert_eq!((db.len().unwrap(), db.is_empty().unwrap()), (0, true));
+        db.flush().unwrap();
+        assert_eq!((db.len().unwrap(), db.is_empty().unwrap()), (0, true));
+
+        // Live keys split across tables and the memtable, with overwrites
+        db.put("b", "again").unwrap();
+        db.put("d", "4").unwrap();
+        db.flush().unwrap();
+        db.put("d", "overwritten").unwrap();
+        db.put("e", "5").unwrap();
+        db.put_with_ttl("gone", "x", Duration::ZERO).unwrap();
+        assert_eq!((db.len().unwrap(), db.is_empty().unwrap()), (3, false));
+        assert_eq!(db.len().unwrap(), db.scan("", "~").unwrap().len() as u64);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_and_last_key() {
+        let dir = "test_db_first_last_key";
+        let _ = fs::remove_dir_all(dir);
+        let ends = |db: &Db| (db.first_key().unwrap(), db.last_key().unwrap());
+        let some = |first: &str, last: &str| (Some(first.to_string()), Some(last.to_string()));
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(ends(&db), (None, None));
+
+        // Only in the memtable, then only in SSTables
+        db.put("m", "1").unwrap();
+        db.put("p", "1").unwrap();
+        assert_eq!(ends(&db), some("m", "p"));
+        db.flush().unwrap();
+        assert_eq!(ends(&db), some("m", "p"));
+
+        // Split across both
+        db.put("c", "1").unwrap();
+        db.put("x", "1").unwrap();
+        assert_eq!(ends(&db), some("c", "x"));
+
+        // The physical ends are tombstoned
+        db.flush().unwrap();
+        db.delete("c").unwrap();
+        db.delete("x").unwrap();
+        assert_eq!(ends(&db), some("m", "p"));
+        db.delete_range("a", "z").unwrap();
+        assert_eq!(ends(&db), (None, None));
+
+        // A deleted top spanning many blocks is searched down window by window
+        let key = |i: usize| format!("key{:05}", i);
+        db.bulk_ingest((0..5000).map(|i| (key(i), "value"))).unwrap();
+        db.delete_range(key(1200), key(5000)).unwrap();
+        db.delete(key(0)).unwrap();
+        assert_eq!(ends(&db), some(&key(1), &key(1199)));
+        db.flush().unwrap();
+        assert_eq!(ends(&db), some(&key(1), &key(1199)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = "test_db_list";
+        let _ = fs::remove_dir_all(dir);
+        let db = Db::open(dir).unwrap();
+        let listing = |keys: &[&str], common_prefixes: &[&str]| Listing {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            common_prefixes: common_prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        };
+        assert_eq!(db.list("", "/").unwrap(), Listing::default());
+
+        for key in ["acme/app/1", "acme/app/2", "acme/db/1", "acme/readme", "zeta/app/1"] {
+            db.put(key, "1").unwrap();
+        }
+        db.flush().unwrap();
+        db.put("acme/web/1", "1").unwrap();
+        db.put("acme", "1").unwrap();
+        db.put("acme/", "1").unwrap();
+
+        assert_eq!(db.list("", "/").unwrap(), listing(&["acme"], &["acme/", "zeta/"]));
+        assert_eq!(
+            db.list("acme/", "/").unwrap(),
+            listing(&["acme/", "acme/readme"], &["acme/app/", "acme/db/", "acme/web/"])
+        );
+        assert_eq!(db.list("acme/app/", "/").unwrap(), listing(&["acme/app/1", "acme/app/2"], &[]));
+        assert_eq!(db.list("acme/a", "/").unwrap(), listing(&[], &["acme/app/"]));
+        assert_eq!(db.list("zeta/", "").unwrap(), listing(&["zeta/app/1"], &[]));
+        assert_eq!(db.list("nothing/", "/").unwrap(), Listing::default());
+
+        // A subdirectory whose keys are all deleted disappears
+        db.delete("acme/db/1").unwrap();
+        db.delete_range("acme/web/", "acme/web0").unwrap();
+        assert_eq!(
+            db.list("acme/", "/").unwrap(),
+            listing(&["acme/", "acme/readme"], &["acme/app/"])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let dir = "test_db_sample_keys";
+        let _ = fs::remove_dir_all(dir);
+        let db = Db::open(dir).unwrap();
+        assert!(db.sample_keys(10).unwrap().is_empty());
+
+        // Fewer live keys than asked for: all of them
+        for key in ["a", "b", "c", "d"] {
+            db.put(key, "1").unwrap();
+        }
+        db.flush().unwrap();
+        db.delete("b").unwrap();
+        assert_eq!(db.sample_keys(10).unwrap(), ["a", "c", "d"]);
+        assert_eq!(db.sample_keys(2).unwrap().len(), 2);
+        assert!(db.sample_keys(0).unwrap().is_empty());
+
+        // Even keys live, odd ones deleted in later tables and the memtable
+        let key = |i: usize| format!("key{:05}", i);
+        db.bulk_ingest((0..5000).map(|i| (key(i), "value"))).unwrap();
+        for i in (1..5000).step_by(2) {
+            db.delete(key(i)).unwrap();
+        }
+        db.delete_range(key(4000), key(5000)).unwrap();
+        let sample = db.sample_keys(50).unwrap();
+        assert!(!sample.is_empty() && sample.len() <= 50, "{}", sample.len());
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        for key in &sample {
+            assert!(db.get(key).is_some(), "{key} is not live");
+            assert!(key.as_str() < "key04000", "{key} is range deleted");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_estimated_key_count() {
+        let dir = "test_db_estimated_key_count";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.estimated_key_count().unwrap(), 0);
+        for table in 0..3 {
+            for i in 0..10 {
+                db.put(format!("key{}_{}", table, i), "v").unwrap();
+            }
+            db.flush().unwrap();
+        }
+        db.put("unflushed", "v").unwrap();
+        assert_eq!(db.estimated_key_count().unwrap(), 31);
+        db.compact().unwrap();
+        assert_eq!(db.estimated_key_count().unwrap(), 31);
+
+        // Overwrites and deletes in later tables are counted again, until compaction
+        // drops what they shadow
+        for i in 0..10 {
+            db.put(format!("key0_{}", i), "new").unwrap();
+        }
+        db.delete("key1_0").unwrap();
+        db.flush().unwrap();
+        let estimate = db.estimated_key_count().unwrap();
+        assert_eq!(db.scan("a", "z").unwrap().len(), 30);
+        assert!(estimate > 30, "{}", estimate);
+        db.compact().unwrap();
+        assert_eq!(db.estimated_key_count().unwrap(), 30);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_with_metadata() {
+        let dir = "test_db_get_with_metadata";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let started = crate::entry::now_millis();
+        db.put("key1", "v1").unwrap();
+        let first = db.get_with_metadata("key1").unwrap();
+        assert_eq!(first.value, b"v1");
+        assert_eq!(first.seq, db.last_sequence());
+        assert_eq!(first.source, ValueSource::MemTable);
+        assert!(first.written_at.is_some_and(|t| t >= started), "{:?}", first);
+
+        db.put("key1", "v2").unwrap();
+        let second = db.get_with_metadata("key1").unwrap();
+        assert!(second.seq > first.seq);
+        assert!(second.written_at >= first.written_at);
+
+        // After a flush the same version is answered by the table, write time intact
+        db.flush().unwrap();
+        let flushed = db.get_with_metadata("key1").unwrap();
+        assert_eq!(flushed.source, ValueSource::SSTable(db.sstable_paths()[0].clone()));
+        assert_ne!(flushed, second);
+        assert_eq!((flushed.value, flushed.seq), (second.value, second.seq));
+        assert_eq!(flushed.written_at, second.written_at);
+
+        // Write times survive WAL replay too
+        db.put("key2", "v").unwrap();
+        let logged = db.get_with_metadata("key2").unwrap();
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get_with_metadata("key2"), Some(logged));
+        db.delete("key2").unwrap();
+        assert_eq!(db.get_with_metadata("key2"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_to_writer_streams_values() {
+        let dir = "test_db_get_to_writer";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { value_log_threshold: Some(4 << 20), ..counter_options() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        let value = |len: u32| -> Vec<u8> { (0..len).map(|i| (i % 251) as u8).collect() };
+        let (inline, separated) = (value(3 << 20), value(5 << 20));
+        db.put("inline", inline.clone()).unwrap();
+        db.put("separated", separated.clone()).unwrap();
+        db.merge("count", "2").unwrap();
+        db.merge("count", "3").unwrap();
+
+        let streamed = |key: &str| -> Option<Vec<u8>> {
+            let mut out = Vec::new();
+            let len = db.get_to_writer(key, &mut out).unwrap()?;
+            assert_eq!(len, out.len() as u64);
+            Some(out)
+        };
+        // From the memtable, then from the SSTable and value log
+        for _ in 0..2 {
+            assert!(streamed("inline") == Some(inline.clone()));
+            assert!(streamed("separated") == Some(separated.clone()));
+            assert_eq!(streamed("count"), Some(b"5".to_vec()));
+            db.flush().unwrap();
+        }
+
+        db.delete("inline").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(db.get_to_writer("inline", &mut out).unwrap(), None);
+        assert_eq!(db.get_to_writer("missing", &mut out).unwrap(), None);
+        assert!(out.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn counter_options() -> Options {
+        let add: crate::options::MergeOperator = Arc::new(|_key, existing, operand| {
+            let parse = |bytes: &[u8]| std::str::from_utf8(bytes).unwrap().parse::<i64>().unwrap();
+            (existing.map_or(0, parse) + parse(operand)).to_string().into_bytes()
+        });
+        Options {
+            merge_operator: Some(add),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_without_base_value() {
+        let dir = "test_db_merge_no_base";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open_with_options(dir, counter_options()).unwrap();
+        for _ in 0..150 {
+            db.merge("hits", "1").unwrap();
+        }
+        assert_eq!(db.get("hits"), Some("150".to_string()));
+        assert_eq!(db.scan("a", "z").unwrap(), vec![("hits".to_string(), "150".to_string())]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_over_flushed_base_and_recovery() {
+        let dir = "test_db_merge_flushed";
+        let _ = fs::remove_dir_all(dir);
+
+        {
+            let db = Db::open_with_options(dir, counter_options()).unwrap();
+            db.put("hits", "100").unwrap();
+            db.flush().unwrap();
+            db.merge("hits", "5").unwrap();
+            db.flush().unwrap();
+            db.merge("hits", "-2").unwrap();
+            assert_eq!(db.get("hits"), Some("103".to_string()));
+        }
+
+        // The last operand is only in the WAL
+        let db = Db::open_with_options(dir, counter_options()).unwrap();
+        assert_eq!(db.get("hits"), Some("103".to_string()));
+
+        // A delete resets the base
+        db.delete("hits").unwrap();
+        db.merge("hits", "7").unwrap();
+        assert_eq!(db.get("hits"), Some("7".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_requires_operator() {
+        let dir = "test_db_merge_no_operator";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let err = db.merge("hits", "1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(db.last_sequence(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let dir = "test_db_compare_and_swap";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+
+        // Swap from absent
+        assert!(db.compare_and_swap("key1", None, Some("v1")).unwrap());
+        assert!(!db.compare_and_swap("key1", None, Some("other")).unwrap());
+        assert_eq!(db.get("key1"), Some("v1".to_string()));
+
+        // Mismatch writes nothing, even when the value lives only in an SSTable
+        db.flush().unwrap();
+        let seq = db.last_sequence();
+        assert!(!db.compare_and_swap("key1", Some("wrong"), Some("v2")).unwrap());
+        assert_eq!(db.last_sequence(), seq);
+
+        // Successful swap against the flushed value
+        assert!(db.compare_and_swap("key1", Some("v1"), Some("v2")).unwrap());
+        assert_eq!(db.get("key1"), Some("v2".to_string()));
+
+        // Delete via CAS
+        assert!(db.compare_and_swap("key1", Some("v2"), None).unwrap());
+        assert_eq!(db.get("key1"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        let dir = "test_db_put_if_absent";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert!(db.put_if_absent("key1", "first").unwrap());
+        assert!(!db.put_if_absent("key1", "second").unwrap());
+        assert_eq!(db.get("key1"), Some("first".to_string()));
+
+        db.delete("key1").unwrap();
+        assert!(db.put_if_absent("key1", "third").unwrap());
+        assert_eq!(db.get("key1"), Some("third".to_string()));
+
+        db.put("key2", "flushed").unwrap();
+        db.flush().unwrap();
+        assert!(!db.put_if_absent("key2", "other").unwrap());
+        assert_eq!(db.get("key2"), Some("flushed".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let dir = "test_db_get_or_insert_with";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get_or_insert_with("key1", || "made".to_string()).unwrap(), "made");
+        assert_eq!(db.get("key1"), Some("made".to_string()));
+        let hit = db.get_or_insert_with("key1", || panic!("called on a hit")).unwrap();
+        assert_eq!(hit, "made");
+
+        db.put("key2", "flushed").unwrap();
+        db.flush().unwrap();
+        let hit = db.get_or_insert_with("key2", || panic!("called on a hit")).unwrap();
+        assert_eq!(hit, "flushed");
+
+        db.delete("key2").unwrap();
+        assert_eq!(db.get_or_insert_with("key2", || "again".to_string()).unwrap(), "again");
+
+        // Racing callers agree on one value, made once
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let (db, calls) = (db.clone(), calls.clone());
+                thread::spawn(move || {
+                    db.get_or_insert_with("shared", || {
+                        calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        format!("from {}", i)
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        let values: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(values.iter().all(|value| *value == values[0]));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // The inserted value went through the WAL
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("key2"), Some("again".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = "test_db_update";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("list", "a").unwrap();
+        db.put("gone", "soon").unwrap();
+        db.flush().unwrap();
+
+        // The old value is read from the SSTable
+        let appended = db.update("list", |current| current.map(|list| format!("{},b", list)));
+        assert_eq!(appended.unwrap(), Some("a,b".to_string()));
+        assert_eq!(db.get("list"), Some("a,b".to_string()));
+
+        assert_eq!(db.update("gone", |_| None).unwrap(), None);
+        assert_eq!(db.get("gone"), None);
+
+        let mut seen = Vec::new();
+        let created = db.update("new", |current| {
+            seen.push(current.map(str::to_string));
+            Some("made".to_string())
+        });
+        assert_eq!(created.unwrap(), Some("made".to_string()));
+        assert_eq!(seen, [None]);
+
+        // Racing updates each see the one before
+        let bump = |n: Option<&str>| {
+            let n: u32 = n.map_or(0, |n| n.parse().unwrap());
+            Some((n + 1).to_string())
+        };
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || db.update("count", bump).unwrap())
+            })
+            .collect();
+        handles.into_iter().for_each(|h| drop(h.join().unwrap()));
+        assert_eq!(db.get("count"), Some("8".to_string()));
+
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("list"), Some("a,b".to_string()));
+        assert_eq!(db.get("gone"), None);
+        assert_eq!(db.get("new"), Some("made".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_increment() {
+        let dir = "test_db_increment";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.increment("counter", 5).unwrap(), 5);
+        assert_eq!(db.increment("counter", 2).unwrap(), 7);
+        assert_eq!(db.increment("counter", -10).unwrap(), -3);
+        assert_eq!(db.get("counter"), Some("-3".to_string()));
+
+        // Works when the old value lives only in an SSTable
+        db.flush().unwrap();
+        assert_eq!(db.increment("counter", 3).unwrap(), 0);
+
+        db.put("name", "alice").unwrap();
+        let err = db.increment("name", 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::InvalidValue { key, .. }) if key == "name"
+        ));
+        assert_eq!(db.get("name"), Some("alice".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let dir = "test_db_ttl";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("old", "permanent").unwrap();
+        db.flush().unwrap();
+        db.put_with_ttl("old", "short", Duration::from_millis(100))
+            .unwrap();
+        db.put_with_ttl("flushed", "short", Duration::from_millis(100))
+            .unwrap();
+        db.put_with_ttl("long", "lived", Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(db.get("old"), Some("short".to_string()));
+        assert_eq!(db.scan("a", "z").unwrap().len(), 3);
+
+        // Half the entries go through an SSTable, the rest stay in the WAL
+        db.flush().unwrap();
+        db.put_with_ttl("wal", "short", Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(db.get("flushed"), Some("short".to_string()));
+        assert_eq!(db.get("wal"), Some("short".to_string()));
+
+        std::thread::sleep(Duration::from_millis(150));
+
+        let check = |db: &Db| {
+            // An expired value doesn't reveal the older version underneath
+            assert_eq!(db.get("old"), None);
+            assert_eq!(db.get("flushed"), None);
+            assert_eq!(db.get("wal"), None);
+            assert_eq!(db.get("long"), Some("lived".to_string()));
+            assert_eq!(db.scan("a", "z").unwrap(), vec![("long".to_string(), "lived".to_string())]);
+        };
+        check(&db);
+
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        check(&db);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range_across_sources() {
+        let dir = "test_db_delete_range";
+        let _ = fs::remove_dir_all(dir);
+
+        {
+            let db = Db::open(dir).unwrap();
+            for i in 0..10 {
+                db.put(format!("key_{}", i), format!("old_{}", i)).unwrap();
+            }
+            db.flush().unwrap();
+            for i in 5..10 {
+                db.put(format!("key_{}", i), format!("mid_{}", i)).unwrap();
+            }
+            db.flush().unwrap();
+            db.put("key_3", "memtable").unwrap();
+
+            db.delete_range("key_2", "key_8").unwrap();
+            db.put("key_4", "after").unwrap();
+            // Empty ranges don't write anything
+            let seq = db.last_sequence();
+            db.delete_range("key_9", "key_1").unwrap();
+            assert_eq!(db.last_sequence(), seq);
+        }
+
+        // The range delete is recovered from the WAL
+        let db = Db::open(dir).unwrap();
+        let check = |db: &Db| {
+            assert_eq!(db.get("key_1"), Some("old_1".to_string()));
+            assert_eq!(db.get("key_3"), None);
+            assert_eq!(db.get("key_4"), Some("after".to_string()));
+            assert_eq!(db.get("key_7"), None);
+            assert_eq!(db.get("key_8"), Some("mid_8".to_string()));
+
+            let keys: Vec<String> =
+                db.scan("key_", "key_~").unwrap().into_iter().map(|(k, _)| k).collect();
+            assert_eq!(
+                keys,
+                vec!["key_0", "key_1", "key_4", "key_8", "key_9"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            );
+        };
+        check(&db);
+
+        // And survives being flushed into an SSTable
+        db.flush().unwrap();
+        check(&db);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_keys_and_values_round_trip() {
+        let dir = "test_db_binary";
+        let _ = fs::remove_dir_all(dir);
+
+        let key: &[u8] = &[0x00, 0xff, b'k'];
+        let value: &[u8] = &[0x00, 0xc3, 0x28, b',', b'\n', 0xff];
+        let db = Db::open(dir).unwrap();
+        db.put(key, value).unwrap();
+        db.put(b"\xffhigh", b"\x00").unwrap();
+        db.put("text", "plain").unwrap();
+        assert_eq!(db.get_bytes(key), Some(value.to_vec()));
+
+        // Recovered from the WAL
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get_bytes(key), Some(value.to_vec()));
+
+        // And read back from an SSTable
+        db.flush().unwrap();
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get_bytes(key), Some(value.to_vec()));
+        assert_eq!(db.get("text"), Some("plain".to_string()));
+
+        // Plain byte order: 0x00 first, 0xff after every ASCII key
+        let keys: Vec<_> = db.iter().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, vec![key.to_vec(), b"text".to_vec(), b"\xffhigh".to_vec()]);
+
+        db.delete_bytes(key).unwrap();
+        assert_eq!(db.get_bytes(key), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_page_covers_full_scan() {
+        let dir = "test_db_scan_page";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for i in 0..30 {
+            db.put(format!("key_{:03}", i), format!("value_{}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 30..45 {
+            db.put(format!("key_{:03}", i), format!("value_{}", i)).unwrap();
+        }
+        db.delete("key_007").unwrap();
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.scan_page(cursor.as_deref(), 7).unwrap();
+            pages.push(page.entries);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        // 44 live keys: six full pages and a final short one
+        assert_eq!(pages.len(), 7);
+        assert_eq!(pages.last().unwrap().len(), 2);
+        assert_eq!(pages.concat(), db.scan("", "~").unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_count_range_matches_scan() {
+        let dir = "test_db_count_range";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for i in 0..40 {
+            db.put(format!("key_{:03}", i), format!("v1_{}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..60).step_by(2) {
+            db.put(format!("key_{:03}", i), format!("v2_{}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..60).step_by(5) {
+            db.delete(format!("key_{:03}", i)).unwrap();
+        }
+        db.delete_range("key_030", "key_035").unwrap();
+        db.put("key_032", "back").unwrap();
+
+        for (start, end) in [("key_", "key_~"), ("key_010", "key_045"), ("key_5", "key_6")] {
+            let expected = db.scan(start, end).unwrap().len() as u64;
+            assert_eq!(db.count_range(start, end).unwrap(), expected);
+        }
+        assert_eq!(db.count_range("b", "a").unwrap(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_rev_matches_reversed_scan() {
+        let dir = "test_db_scan_rev";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for i in 0..20 {
+            db.put(format!("event_{:03}", i), format!("v1_{}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..20).step_by(3) {
+            db.put(format!("event_{:03}", i), format!("v2_{}", i)).unwrap();
+        }
+        db.delete("event_010").unwrap();
+        db.delete("event_019").unwrap();
+
+        let forward = db.scan("event_", "event_~").unwrap();
+        let mut reversed = db.scan_rev("event_", "event_~").unwrap();
+        assert_eq!(reversed.first(), Some(&("event_018".to_string(), "v2_18".to_string())));
+        assert_eq!(reversed.len(), 18);
+        reversed.reverse();
+        assert_eq!(reversed, forward);
+
+        let snapshot = db.snapshot();
+        db.put("event_999", "later").unwrap();
+        assert_eq!(snapshot.scan_rev("event_", "event_~").unwrap()[0].0, "event_018");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_iter_rev_reads_only_what_it_takes() {
+        let storage = FaultStorage::new();
+        let options = Options { storage: Some(Arc::new(storage.clone())), ..Options::default() };
+        let db = Db::open_with_options("test_db_iter_rev", options).unwrap();
+        let rows = (0..20_000).map(|i| (format!("event_{:05}", i), format!("value_{}", i)));
+        db.bulk_ingest(rows).unwrap();
+        db.put("event_19999", "newer").unwrap();
+
+        let before = storage.reads();
+        assert_eq!(db.scan_rev("event_", "event_~").unwrap().len(), 20_000);
+        let scanned = storage.reads() - before;
+
+        let before = storage.reads();
+        let latest = db.iter_rev("event_", "event_~").take(3);
+        let latest = latest.collect::<io::Result<Vec<_>>>().unwrap();
+        let taken = storage.reads() - before;
+        assert_eq!(latest[0], (b"event_19999".to_vec(), b"newer".to_vec()));
+        assert_eq!(latest[2], (b"event_19997".to_vec(), b"value_19997".to_vec()));
+        assert!(taken * 10 < scanned, "taking 3 read {} times, scanning {}", taken, scanned);
+    }
+
+    #[test]
+    fn test_scan_reports_table_read_errors() {
+        let storage = FaultStorage::new();
+        let options = Options { storage: Some(Arc::new(storage.clone())), ..Options::default() };
+        let db = Db::open_with_options("test_db_scan_error", options).unwrap();
+        for i in 0..100 {
+            db.put(format!("key_{:03}", i), "value").unwrap();
+        }
+        db.flush().unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(db.scan("key_", "key_~").unwrap().len(), 100);
+
+        // The rows before the cut are not passed off as the whole range
+        let table = Path::new("test_db_scan_error/sstable_000000.sst");
+        storage.set_len(table, 30).unwrap();
+        assert!(db.scan("key_", "key_~").is_err());
+        assert!(db.scan_rev("key_", "key_~").is_err());
+        assert!(snapshot.scan("key_", "key_~").is_err());
+        assert!(snapshot.scan_rev("key_", "key_~").is_err());
+    }
+
+    #[test]
+    fn test_bulk_ingest() {
+        let dir = "test_db_bulk_ingest";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("row_0000050", "from memtable").unwrap();
+        let rows = (0..100_000).map(|i| (format!("row_{:07}", i), format!("value_{}", i)));
+        assert_eq!(db.bulk_ingest(rows).unwrap(), 100_000);
+        assert_eq!(fs::metadata(format!("{}/data.log", dir)).unwrap().len(), 4);
+
+        assert_eq!(db.get("row_0000050"), Some("value_50".to_string()));
+        assert_eq!(db.get("row_0099999"), Some("value_99999".to_string()));
+        assert_eq!(db.scan("row_0001000", "row_0001010").unwrap().len(), 10);
+        assert_eq!(db.count_range("row_", "row_~").unwrap(), 100_000);
+
+        db.put("row_0000001", "newer").unwrap();
+        db.delete("row_0000002").unwrap();
+        assert_eq!(db.get("row_0000001"), Some("newer".to_string()));
+        assert_eq!(db.get("row_0000002"), None);
+
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("row_0000001"), Some("newer".to_string()));
+        assert_eq!(db.get("row_0000003"), Some("value_3".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_ingest_rejects_unsorted_input() {
+        let dir = "test_db_bulk_ingest_unsorted";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let err = db.bulk_ingest([("b", "1"), ("a", "2")]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(db.bulk_ingest([("a", "1"), ("a", "2")]).is_err());
+        assert_eq!(db.get("b"), None);
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        // No table or ingest file is left behind; the table list is written on open
+        assert_eq!(names, ["LOCK", "TABLES", "data.log"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_destroy_removes_only_engine_files() {
+        let dir = "test_db_destroy";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("key", "value").unwrap();
+        db.flush().unwrap();
+        db.put("other", "value").unwrap();
+        db.create_cf("events").unwrap().put("event", "value").unwrap();
+        drop(db);
+        fs::write(format!("{}/notes.txt", dir), "keep me").unwrap();
+        fs::write(format!("{}/sstable_notes.txt", dir), "keep me too").unwrap();
+
+        Db::destroy(dir, true).unwrap();
+        let mut left: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["notes.txt", "sstable_notes.txt"]);
+
+        // The directory is no longer a database
+        assert!(Db::destroy(dir, true).is_err());
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+
+        let db = Db::open(dir).unwrap();
+        db.put("key", "value").unwrap();
+        drop(db);
+        Db::destroy(dir, true).unwrap();
+        assert!(!fs::exists(dir).unwrap());
+    }
+
+    #[test]
+    fn test_second_open_is_locked_out() {
+        let dir = "test_db_lock";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("key", "value").unwrap();
+        let err = Db::open(dir).err().unwrap();
+        assert!(err.to_string().contains("locked by another process"), "{}", err);
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        assert!(Db::open_with_options(dir, read_only.clone()).is_err());
+
+        // Clones share the lock; it is released once the last one is dropped
+        let clone = db.clone();
+        drop(db);
+        assert!(Db::open(dir).is_err());
+        drop(clone);
+
+        let reader = Db::open_with_options(dir, read_only.clone()).unwrap();
+        let second_reader = Db::open_with_options(dir, read_only).unwrap();
+        assert_eq!(second_reader.get("key"), Some("value".to_string()));
+        assert!(reader.put("key", "changed").is_err());
+        assert!(reader.create_cf("events").is_err());
+        assert!(Db::open(dir).is_err());
+        drop((reader, second_reader));
+
+        Db::open(dir).unwrap().put("key", "changed").unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_open_creates_nothing() {
+        let dir = "test_db_read_only_creates_nothing";
+        let _ = fs::remove_dir_all(dir);
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+
+        let err = Db::open_with_options(dir, read_only.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!fs::exists(dir).unwrap());
+
+        // A copy without the lock file is read without one
+        Db::open(dir).unwrap().put("key", "value").unwrap();
+        fs::remove_file(format!("{}/LOCK", dir)).unwrap();
+        let reader = Db::open_with_options(dir, read_only).unwrap();
+        assert_eq!(reader.get("key"), Some("value".to_string()));
+        assert!(!fs::exists(format!("{}/LOCK", dir)).unwrap());
+        drop(reader);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
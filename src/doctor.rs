@@ -0,0 +1,447 @@
+//! Structural checks for [`Db::doctor`](crate::Db::doctor): things in a database
+//! directory that are not damage but point at a crash, a stalled flush, or files moved
+//! by hand, each with what to do about it.
+//!
+//! Tables are found by listing; the table list only records which were in use, so that
+//! ones gone missing can be told from the gaps compaction leaves. An orphan here is a
+//! file the engine does not use that reads back as a whole SSTable, and adopting it
+//! renames it to the next table number. Nor is there a counter file: the next table and
+//! sequence numbers are derived from the files on open, so the counter checked is the
+//! WAL's sequence numbering against the tables'.
+
+use crate::backup::MANIFEST;
+use crate::column_family;
+use crate::compaction;
+use crate::lock::{DirLock, LOCK_FILE};
+use crate::memtable::{table_number, MemTable};
+use crate::options::DEFAULT_MEMTABLE_ENTRIES;
+use crate::migrate::{MIGRATING_SUFFIX, TEXT_LOG_SUFFIX};
+use crate::repair::SALVAGE_SUFFIX;
+use crate::sstable::{SSTable, SSTableIterator, DEFAULT_READ_AHEAD};
+use crate::storage::{FileStorage, Storage};
+use crate::verify::{check_table, TableCheck};
+use crate::wal::{WalIterator, WAL_FILE};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Memtables' worth of records a WAL may hold before it counts as large
+const LARGE_WAL_FLUSHES: usize = 4;
+/// Suffix of temporary files, which nothing reads back
+const TMP_SUFFIX: &str = ".tmp";
+
+/// A kind of structural problem [`Db::doctor`](crate::Db::doctor) looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symptom {
+    /// Table numbers missing between ones present. Compaction leaves these, so they only
+    /// matter if tables were removed by hand.
+    TableNumberGap,
+    /// A table the database was using is gone, so opening it fails
+    MissingTable,
+    /// A temporary file nothing will read again
+    TempFile,
+    /// A file of a flush, ingest, or compaction interrupted by a crash
+    UnfinishedWork,
+    /// A whole SSTable under a name the engine does not use, so none of it is read
+    OrphanTable,
+    /// A file that is neither an engine file nor an SSTable
+    UnknownFile,
+    /// A WAL holding several memtables' worth of records, so flushes have not been
+    /// keeping up
+    LargeWal,
+    /// An SSTable with no entries or range tombstones
+    EmptyTable,
+    /// WAL records numbered at or below the highest sequence number in a table, which
+    /// replay skips
+    WalBehindTables,
+}
+
+impl Symptom {
+    pub fn severity(self) -> Severity {
+        match self {
+            Symptom::TableNumberGap | Symptom::TempFile | Symptom::UnknownFile => Severity::Info,
+            Symptom::EmptyTable => Severity::Info,
+            Symptom::UnfinishedWork | Symptom::OrphanTable => Severity::Warning,
+            Symptom::MissingTable => Severity::Warning,
+            Symptom::LargeWal | Symptom::WalBehindTables => Severity::Warning,
+        }
+    }
+
+    /// What to do about it
+    pub fn action(self) -> &'static str {
+        match self {
+            Symptom::TableNumberGap => {
+                "nothing if compaction removed them; otherwise restore them from a backup"
+            }
+            Symptom::MissingTable => {
+                "restore it from a backup, or open with allow_missing_tables to go on without \
+                 its data"
+            }
+            Symptom::TempFile => "delete it",
+            Symptom::UnfinishedWork => "open the database, which finishes or discards it",
+            Symptom::OrphanTable => "adopt it under the next table number",
+            Symptom::UnknownFile => "move it out of the database directory",
+            Symptom::LargeWal => {
+                "open the database, which flushes it, and find what stopped flushes, such as \
+                 a full disk quota"
+            }
+            Symptom::EmptyTable => "compact, which drops it",
+            Symptom::WalBehindTables => "open the database and flush, which retires the WAL",
+        }
+    }
+
+    /// Whether [`Db::doctor`](crate::Db::doctor) takes the action itself when asked to
+    /// fix what it finds
+    pub fn is_fixable(self) -> bool {
+        matches!(self, Symptom::TempFile | Symptom::OrphanTable)
+    }
+}
+
+impl fmt::Display for Symptom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Symptom::TableNumberGap => "table number gap",
+            Symptom::MissingTable => "missing table",
+            Symptom::TempFile => "temporary file",
+            Symptom::UnfinishedWork => "unfinished work",
+            Symptom::OrphanTable => "orphan table",
+            Symptom::UnknownFile => "unknown file",
+            Symptom::LargeWal => "large wal",
+            Symptom::EmptyTable => "empty table",
+            Symptom::WalBehindTables => "wal behind tables",
+        })
+    }
+}
+
+/// How much a [`Symptom`] matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Harmless, though worth knowing about
+    Info,
+    /// Data is not being read, or space not reclaimed, until something is done
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// One problem [`Db::doctor`](crate::Db::doctor) found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub column_family: String,
+    pub symptom: Symptom,
+    /// The file concerned, relative to the database directory
+    pub path: Option<PathBuf>,
+    pub description: String,
+    /// Whether the symptom's action was taken
+    pub fixed: bool,
+}
+
+/// Everything [`Db::doctor`](crate::Db::doctor) found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DoctorReport {
+    pub findings: Vec<Finding>,
+}
+
+impl DoctorReport {
+    /// Whether any warning was found and not fixed
+    pub fn needs_attention(&self) -> bool {
+        let open = |finding: &&Finding| !finding.fixed;
+        self.findings.iter().filter(open).any(|f| f.symptom.severity() == Severity::Warning)
+    }
+}
+
+pub(crate) fn doctor(dir: &Path, fix: bool) -> io::Result<DoctorReport> {
+    if !dir.join(WAL_FILE).is_file() && !dir.join(MANIFEST).is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not contain a database", dir.display()),
+        ));
+    }
+    let _lock = DirLock::acquire(dir, !fix)?;
+    let mut report = DoctorReport::default();
+    for (name, family) in column_family::family_dirs(dir)? {
+        let start = report.findings.len();
+        examine_family(dir, &name, &family, &mut report.findings)?;
+        if !fix {
+            continue;
+        }
+        for finding in &mut report.findings[start..] {
+            if let (true, Some(path)) = (finding.symptom.is_fixable(), &finding.path) {
+                treat(dir, &family, finding.symptom, path)?;
+                finding.fixed = true;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Look over the files of the column family `name` in `family`, relative to `dir`
+fn examine_family(
+    dir: &Path,
+    name: &str,
+    family: &Path,
+    findings: &mut Vec<Finding>,
+) -> io::Result<()> {
+    let mut found = |symptom, file: Option<&str>, description: String| {
+        findings.push(Finding {
+            column_family: name.to_string(),
+            symptom,
+            path: file.map(|file| family.join(file)),
+            description,
+            fixed: false,
+        })
+    };
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir.join(family))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    files.sort();
+
+    let mut tables = Vec::new();
+    let mut max_seq = 0;
+    for file in &files {
+        let path = dir.join(family).join(file);
+        let path = path.to_string_lossy();
+        if [TMP_SUFFIX, SALVAGE_SUFFIX, MIGRATING_SUFFIX].iter().any(|s| file.ends_with(s)) {
+            found(Symptom::TempFile, Some(file), format!("{} is a leftover temporary file", file));
+        } else if MemTable::is_unfinished_file(file) {
+            let description =
+                format!("{} is left from an interrupted flush, ingest, or compaction", file);
+            found(Symptom::UnfinishedWork, Some(file), description);
+        } else if let Some(number) = table_number(file) {
+            tables.push(number);
+            // Unreadable tables are for verify to report
+            let Ok(header) = SSTable::header(&FileStorage, &path) else { continue };
+            max_seq = max_seq.max(header.max_seq);
+            if header.entries == 0 && is_empty_table(&path) {
+                let description = format!("{} holds no entries or range tombstones", file);
+                found(Symptom::EmptyTable, Some(file), description);
+            }
+        } else if MemTable::owns_file(file)
+            || file == LOCK_FILE
+            || file == MANIFEST
+            || file.strip_suffix(TEXT_LOG_SUFFIX) == Some(WAL_FILE)
+        {
+            continue;
+        } else if let Ok(TableCheck { header: Some(header), damage: None, entries, .. }) =
+            check_table(&FileStorage, &path)
+        {
+            let description = format!(
+                "{} is a whole SSTable of {} entries up to sequence {} that is not in use",
+                file, entries, header.max_seq
+            );
+            found(Symptom::OrphanTable, Some(file), description);
+        } else {
+            found(Symptom::UnknownFile, Some(file), format!("{} is not an engine file", file));
+        }
+    }
+
+    for file in MemTable::missing_tables(&FileStorage, &dir.join(family))? {
+        tables.extend(table_number(&file));
+        found(Symptom::MissingTable, Some(&file), format!("{} is in use but missing", file));
+    }
+    tables.sort_unstable();
+
+    for pair in tables.windows(2) {
+        let (first, last) = (pair[0] + 1, pair[1] - 1);
+        let description = match last.cmp(&first) {
+            std::cmp::Ordering::Less => continue,
+            std::cmp::Ordering::Equal => format!("{} is missing", table_name(first)),
+            std::cmp::Ordering::Greater => {
+                format!("{} to {} are missing", table_name(first), table_name(last))
+            }
+        };
+        found(Symptom::TableNumberGap, None, description);
+    }
+
+    let wal = dir.join(family).join(WAL_FILE);
+    // A WAL that cannot be read at all is for verify to report
+    let Ok(frames) = WalIterator::open(&wal.to_string_lossy()) else { return Ok(()) };
+    let (mut records, mut behind) = (0, 0);
+    let mut next_seq = None;
+    for frame in frames {
+        let Ok(frame) = frame else { break };
+        next_seq = frame.sequence.or(next_seq);
+        for _ in &frame.records {
+            records += 1;
+            if let Some(seq) = next_seq {
+                if seq <= max_seq {
+                    behind += 1;
+                }
+                next_seq = Some(seq + 1);
+            }
+        }
+    }
+    if records > LARGE_WAL_FLUSHES * DEFAULT_MEMTABLE_ENTRIES {
+        let description = format!(
+            "{} holds {} records, more than {} times the {} a memtable is flushed at by \
+             default",
+            WAL_FILE, records, LARGE_WAL_FLUSHES, DEFAULT_MEMTABLE_ENTRIES
+        );
+        found(Symptom::LargeWal, Some(WAL_FILE), description);
+    }
+    if behind > 0 {
+        let description = format!(
+            "{} of the {} records in {} are numbered at or below sequence {}, the highest \
+             in any table",
+            behind, records, WAL_FILE, max_seq
+        );
+        found(Symptom::WalBehindTables, Some(WAL_FILE), description);
+    }
+    Ok(())
+}
+
+/// Whether the table at `path`, which has no entries, has no range tombstones either
+fn is_empty_table(path: &str) -> bool {
+    SSTableIterator::open_sequential(&FileStorage, path, DEFAULT_READ_AHEAD)
+        .and_then(SSTableIterator::into_range_tombstones)
+        .is_ok_and(|tombstones| tombstones.is_empty())
+}
+
+fn table_name(number: usize) -> String {
+    format!("sstable_{:06}.sst", number)
+}
+
+/// Take the action for `symptom` on the file at `path`, relative to `dir`, in the
+/// column family directory `family`
+fn treat(dir: &Path, family: &Path, symptom: Symptom, path: &Path) -> io::Result<()> {
+    let family_dir = dir.join(family);
+    match symptom {
+        Symptom::TempFile => fs::remove_file(dir.join(path))?,
+        Symptom::OrphanTable => {
+            // Past every table present, pending, or named by the obsolete list, so
+            // neither an open's recovery nor its sweep touches the adopted table
+            let mut names = compaction::read_obsolete(&FileStorage, &family_dir)?;
+            for entry in fs::read_dir(&family_dir)? {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+            let mut newest = None;
+            for name in &names {
+                let table = name.find(".sst").map_or(name.as_str(), |end| &name[..end + 4]);
+                newest = newest.max(table_number(table));
+            }
+            let next = newest.map_or(0, |newest| newest + 1);
+            FileStorage.rename(&dir.join(path), &family_dir.join(table_name(next)))?;
+        }
+        _ => return Ok(()),
+    }
+    FileStorage.sync_dir(&family_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::SSTableWriter;
+    use crate::wal::WriteAheadLog;
+    use crate::{Db, Options};
+
+    fn symptoms(report: &DoctorReport) -> Vec<(Symptom, Option<PathBuf>, bool)> {
+        let finding = |f: &Finding| (f.symptom, f.path.clone(), f.fixed);
+        report.findings.iter().map(finding).collect()
+    }
+
+    #[test]
+    fn test_doctor_diagnoses_and_fixes() {
+        let dir = Path::new("test_doctor");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let db = Db::open(dir).unwrap();
+            for table in 0..4 {
+                db.put(format!("key{}", table), "1").unwrap();
+                db.flush().unwrap();
+            }
+        }
+        assert_eq!(Db::doctor(dir, false).unwrap(), DoctorReport::default());
+
+        // A gap, an orphan, an empty table, leftovers, and a stranger
+        fs::rename(dir.join(table_name(1)), dir.join("moved.sst")).unwrap();
+        fs::remove_file(dir.join(table_name(2))).unwrap();
+        let empty = dir.join(table_name(7));
+        SSTableWriter::create(&FileStorage, &empty.to_string_lossy())
+            .unwrap()
+            .finish(&[], 2)
+            .unwrap();
+        fs::write(dir.join("export.tmp"), "partial").unwrap();
+        fs::write(dir.join("sstable_000008.sst.flush"), "partial").unwrap();
+        fs::write(dir.join("notes.txt"), "mine").unwrap();
+
+        let report = Db::doctor(dir, false).unwrap();
+        let path = |name: &str| Some(PathBuf::from(name));
+        assert_eq!(
+            symptoms(&report),
+            [
+                (Symptom::TempFile, path("export.tmp"), false),
+                (Symptom::OrphanTable, path("moved.sst"), false),
+                (Symptom::UnknownFile, path("notes.txt"), false),
+                (Symptom::EmptyTable, path("sstable_000007.sst"), false),
+                (Symptom::UnfinishedWork, path("sstable_000008.sst.flush"), false),
+                (Symptom::MissingTable, path("sstable_000001.sst"), false),
+                (Symptom::MissingTable, path("sstable_000002.sst"), false),
+                (Symptom::TableNumberGap, None, false),
+            ]
+        );
+        let gap = &report.findings[7].description;
+        assert_eq!(gap, "sstable_000004.sst to sstable_000006.sst are missing");
+        assert!(report.needs_attention());
+
+        let report = Db::doctor(dir, true).unwrap();
+        assert!(report.findings[0].fixed && report.findings[1].fixed);
+        assert!(report.findings[2..].iter().all(|finding| !finding.fixed));
+        assert!(!dir.join("export.tmp").exists());
+        assert!(dir.join(table_name(9)).exists());
+        // The unfinished flush is still there until the database is opened
+        assert!(report.needs_attention());
+
+        // Going on without the missing table settles it
+        let options = Options { allow_missing_tables: true, ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.get("key1"), Some("1".to_string()));
+        assert_eq!(db.get("key2"), None);
+        drop(db);
+        let report = Db::doctor(dir, false).unwrap();
+        assert!(!report.needs_attention(), "{:?}", report);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_doctor_checks_wal_numbering() {
+        let dir = Path::new("test_doctor_wal");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let db = Db::open(dir).unwrap();
+            db.put("a", "1").unwrap();
+            db.flush().unwrap();
+        }
+        // A WAL of records from before the flush, too many of them
+        let wal_path = dir.join(WAL_FILE);
+        fs::remove_file(&wal_path).unwrap();
+        let mut wal = WriteAheadLog::new(&wal_path.to_string_lossy()).unwrap();
+        wal.set_sequence(1);
+        for i in 0..=LARGE_WAL_FLUSHES * DEFAULT_MEMTABLE_ENTRIES {
+            wal.log_put(format!("key{}", i).as_bytes(), b"1").unwrap();
+        }
+        drop(wal);
+
+        let report = Db::doctor(dir, false).unwrap();
+        let wal = Some(PathBuf::from(WAL_FILE));
+        assert_eq!(
+            symptoms(&report),
+            [(Symptom::LargeWal, wal.clone(), false), (Symptom::WalBehindTables, wal, false)]
+        );
+        assert!(report.findings[1].description.starts_with("1 of the 401 records"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
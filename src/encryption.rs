@@ -0,0 +1,677 @@
+//! At-rest encryption of SSTables with XChaCha20-Poly1305, under
+//! [`Options::encryption`](crate::Options#structfield.encryption).
+//!
+//! Every column family reaches its files through an [`EncryptedStorage`], which
+//! encrypts the SSTables it creates and passes every other file through untouched. An
+//! encrypted table starts with a plaintext header naming the key it was encrypted with,
+//! followed by the table in blocks of 4 KiB, each sealed with its own random nonce and
+//! stored as `[nonce][ciphertext][tag]`. Readers see the plain table and seek in it as
+//! usual; the AEAD tag makes a wrong key or a damaged block fail the read rather than
+//! return garbage. Tables are recognised by their header, so plaintext tables written
+//! before encryption was turned on stay readable.
+//!
+//! The WAL seals each record on its own with [`seal`], as described in [`crate::wal`].
+
+use crate::error::EngineError;
+use crate::sstable::ENCRYPTED_MAGIC;
+use crate::storage::{Storage, StorageFile};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// `[magic][key_id u32][block_size u32][reserved u32]`
+const HEADER_LEN: u64 = 16;
+/// Plaintext bytes sealed together
+const BLOCK_SIZE: u32 = 4 << 10;
+const NONCE_LEN: u64 = 24;
+const TAG_LEN: u64 = 16;
+/// Bytes each block takes on disk beyond its plaintext
+const BLOCK_OVERHEAD: u64 = NONCE_LEN + TAG_LEN;
+
+/// Supplies the keys SSTables are encrypted with, so keys can be rotated: new tables
+/// use the current key while older ones are read with the key they name
+pub trait KeyProvider: Send + Sync {
+    /// Id and 32-byte key that new tables are encrypted with
+    fn current(&self) -> (u32, [u8; 32]);
+
+    /// The key with id `id`, or `None` if it is not known
+    fn key(&self, id: u32) -> Option<[u8; 32]>;
+}
+
+/// A single fixed key, for databases that never rotate
+#[derive(Clone)]
+pub struct EncryptionKey {
+    /// Recorded in every table, to tell keys apart
+    pub id: u32,
+    pub key: [u8; 32],
+}
+
+impl KeyProvider for EncryptionKey {
+    fn current(&self) -> (u32, [u8; 32]) {
+        (self.id, self.key)
+    }
+
+    fn key(&self, id: u32) -> Option<[u8; 32]> {
+        (id == self.id).then_some(self.key)
+    }
+}
+
+/// Whether files at `path` are SSTables, under their final or a temporary name
+fn is_sstable(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().contains(".sst"))
+}
+
+/// Storage that encrypts the SSTables it creates when it has keys, and decrypts
+/// encrypted ones it opens
+pub(crate) struct EncryptedStorage {
+    storage: Arc<dyn Storage>,
+    keys: Option<Arc<dyn KeyProvider>>,
+}
+
+/// What the header of an encrypted table says
+struct Header {
+    key_id: u32,
+    block_size: u32,
+}
+
+impl EncryptedStorage {
+    pub(crate) fn wrap(
+        storage: Arc<dyn Storage>,
+        keys: Option<Arc<dyn KeyProvider>>,
+    ) -> Arc<dyn Storage> {
+        Arc::new(EncryptedStorage { storage, keys })
+    }
+
+    fn cipher(&self, key_id: u32) -> io::Result<XChaCha20Poly1305> {
+        let key = self.keys.as_ref().and_then(|keys| keys.key(key_id));
+        let key = key.ok_or(EngineError::UnknownEncryptionKey { key_id })?;
+        Ok(cipher(&key))
+    }
+
+    /// The header of the table open in `file`, or `None` if it is not encrypted. The
+    /// file is left just past the header.
+    fn read_header(file: &mut dyn StorageFile) -> io::Result<Option<Header>> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        let mut read = 0;
+        while read < header.len() {
+            match file.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read < 4 || header[..4] != ENCRYPTED_MAGIC {
+            file.seek(SeekFrom::Start(0))?;
+            return Ok(None);
+        }
+        if read < header.len() {
+            let message = "encrypted table header is incomplete";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        Ok(Some(Header { key_id: field(4), block_size: field(8) }))
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.storage.open_append(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut file = self.storage.create(path)?;
+        let Some(keys) = self.keys.as_ref().filter(|_| is_sstable(path)) else {
+            return Ok(file);
+        };
+        let (key_id, key) = keys.current();
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(&ENCRYPTED_MAGIC);
+        header.extend_from_slice(&key_id.to_le_bytes());
+        header.extend_from_slice(&BLOCK_SIZE.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Box::new(EncryptedWriter {
+            file,
+            cipher: cipher(&key),
+            key_id,
+            block_size: u64::from(BLOCK_SIZE),
+            sealed: 0,
+            tail: Vec::new(),
+            first: None,
+            first_dirty: false,
+            pos: 0,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut file = self.storage.open(path)?;
+        if !is_sstable(path) {
+            return Ok(file);
+        }
+        let Some(header) = Self::read_header(&mut *file)? else { return Ok(file) };
+        let cipher = self.cipher(header.key_id)?;
+        let block_size = u64::from(header.block_size);
+        let len = plaintext_len(self.storage.file_len(path)?, block_size)?;
+        Ok(Box::new(EncryptedReader {
+            file,
+            path: path.to_path_buf(),
+            cipher,
+            key_id: header.key_id,
+            block_size,
+            len,
+            pos: 0,
+            block: None,
+        }))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.storage.open_write(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.storage.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.storage.remove(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage.list_dir(dir)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.storage.set_len(path, len)
+    }
+
+    /// The length of the plain table for encrypted tables, which is what readers seek in
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        let len = self.storage.file_len(path)?;
+        if !is_sstable(path) {
+            return Ok(len);
+        }
+        match Self::read_header(&mut *self.storage.open(path)?)? {
+            Some(header) => plaintext_len(len, u64::from(header.block_size)),
+            None => Ok(len),
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.storage.sync_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.storage.create_dir_all(dir)
+    }
+
+    fn list_subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage.list_subdirs(dir)
+    }
+
+    fn rename_dir(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.storage.rename_dir(from, to)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.storage.remove_dir_all(dir)
+    }
+
+    fn lock_dir(&self, dir: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        self.storage.lock_dir(dir, shared)
+    }
+}
+
+/// Length of the plain table stored encrypted in `disk_len` bytes
+fn plaintext_len(disk_len: u64, block_size: u64) -> io::Result<u64> {
+    let body = disk_len.saturating_sub(HEADER_LEN);
+    let stride = block_size + BLOCK_OVERHEAD;
+    let (full, partial) = (body / stride, body % stride);
+    if block_size == 0 || (partial > 0 && partial <= BLOCK_OVERHEAD) {
+        let message = "encrypted table ends inside a block";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(full * block_size + partial.saturating_sub(BLOCK_OVERHEAD))
+}
+
+/// The cipher for `key`
+pub(crate) fn cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(key.into())
+}
+
+/// `plaintext` sealed under a fresh random nonce, as `[nonce][ciphertext][tag]`; `aad`
+/// is authenticated but not stored
+pub(crate) fn seal(
+    cipher: &XChaCha20Poly1305,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> io::Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    let mut out = Vec::with_capacity(nonce.len() + sealed.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// The plaintext [`seal`] made `sealed` from, or `None` if it fails authentication
+pub(crate) fn open(cipher: &XChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, sealed) = sealed.split_at_checked(NONCE_LEN as usize)?;
+    cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad }).ok()
+}
+
+/// Where block `number` starts on disk
+fn block_offset(number: u64, block_size: u64) -> u64 {
+    HEADER_LEN + number * (block_size + BLOCK_OVERHEAD)
+}
+
+/// Associated data of block `number`, so blocks cannot be swapped or moved between keys
+fn block_aad(key_id: u32, number: u64) -> [u8; 12] {
+    let mut aad = [0u8; 12];
+    aad[..4].copy_from_slice(&key_id.to_le_bytes());
+    aad[4..].copy_from_slice(&number.to_le_bytes());
+    aad
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("encrypted tables {}", what))
+}
+
+/// Writes a new encrypted table front to back. Blocks are sealed as they fill up,
+/// except that the first block is kept in memory so the table header in it can still be
+/// filled in; seeking back anywhere else is not supported.
+struct EncryptedWriter {
+    file: Box<dyn StorageFile>,
+    cipher: XChaCha20Poly1305,
+    key_id: u32,
+    block_size: u64,
+    /// Full blocks written
+    sealed: u64,
+    /// Plaintext after the full blocks
+    tail: Vec<u8>,
+    /// Plaintext of block 0, once it is full
+    first: Option<Vec<u8>>,
+    first_dirty: bool,
+    pos: u64,
+}
+
+impl EncryptedWriter {
+    fn len(&self) -> u64 {
+        self.sealed * self.block_size + self.tail.len() as u64
+    }
+
+    fn seal(&mut self, number: u64, plaintext: &[u8]) -> io::Result<()> {
+        let sealed = seal(&self.cipher, &block_aad(self.key_id, number), plaintext)?;
+        self.file.seek(SeekFrom::Start(block_offset(number, self.block_size)))?;
+        self.file.write_all(&sealed)
+    }
+}
+
+impl Write for EncryptedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let block_size = self.block_size as usize;
+        let tail_start = self.sealed * self.block_size;
+        if self.pos >= tail_start {
+            let at = (self.pos - tail_start) as usize;
+            let n = buf.len().min(block_size - at);
+            let overlap = n.min(self.tail.len().saturating_sub(at));
+            self.tail[at..at + overlap].copy_from_slice(&buf[..overlap]);
+            self.tail.extend_from_slice(&buf[overlap..n]);
+            self.pos += n as u64;
+            if self.tail.len() == block_size {
+                let block = std::mem::take(&mut self.tail);
+                self.seal(self.sealed, &block)?;
+                if self.sealed == 0 {
+                    self.first = Some(block);
+                }
+                self.sealed += 1;
+            }
+            return Ok(n);
+        }
+        match &mut self.first {
+            Some(first) if self.pos < self.block_size => {
+                let at = self.pos as usize;
+                let n = buf.len().min(block_size - at);
+                first[at..at + n].copy_from_slice(&buf[..n]);
+                self.first_dirty = true;
+                self.pos += n as u64;
+                Ok(n)
+            }
+            _ => Err(unsupported("are written front to back")),
+        }
+    }
+
+    /// Seal the partial last block and any change to the first; the last block is
+    /// sealed again if it grows
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.tail.is_empty() {
+            let tail = std::mem::take(&mut self.tail);
+            let sealed = self.seal(self.sealed, &tail);
+            self.tail = tail;
+            sealed?;
+        }
+        if self.first_dirty {
+            let first = self.first.take().unwrap_or_default();
+            let sealed = self.seal(0, &first);
+            self.first = Some(first);
+            sealed?;
+            self.first_dirty = false;
+        }
+        self.file.flush()
+    }
+}
+
+impl Read for EncryptedWriter {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(unsupported("being written cannot be read"))
+    }
+}
+
+impl Seek for EncryptedWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = resolve_seek(pos, self.pos, self.len())?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for EncryptedWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync_data()
+    }
+}
+
+/// Reads an encrypted table, decrypting one block at a time
+struct EncryptedReader {
+    file: Box<dyn StorageFile>,
+    path: PathBuf,
+    cipher: XChaCha20Poly1305,
+    key_id: u32,
+    block_size: u64,
+    /// Length of the plain table
+    len: u64,
+    pos: u64,
+    /// Number and plaintext of the block last read
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl EncryptedReader {
+    fn load(&mut self, number: u64) -> io::Result<()> {
+        let plain_len = self.block_size.min(self.len - number * self.block_size);
+        let mut sealed = vec![0u8; (plain_len + BLOCK_OVERHEAD) as usize];
+        self.file.seek(SeekFrom::Start(block_offset(number, self.block_size)))?;
+        self.file.read_exact(&mut sealed)?;
+        let aad = block_aad(self.key_id, number);
+        let plaintext = open(&self.cipher, &aad, &sealed).ok_or_else(|| {
+            let path = self.path.display().to_string();
+            EngineError::DecryptionFailed { path, key_id: self.key_id }
+        })?;
+        self.block = Some((number, plaintext));
+        Ok(())
+    }
+}
+
+impl Read for EncryptedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let number = self.pos / self.block_size;
+        if self.block.as_ref().is_none_or(|(loaded, _)| *loaded != number) {
+            self.load(number)?;
+        }
+        let Some((_, block)) = &self.block else { unreachable!("just loaded") };
+        let at = (self.pos - number * self.block_size) as usize;
+        let n = buf.len().min(block.len() - at);
+        buf[..n].copy_from_slice(&block[at..at + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for EncryptedReader {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(unsupported("are written only when created"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for EncryptedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = resolve_seek(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for EncryptedReader {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The position `pos` leads to from `current` in a file of `len` bytes
+fn resolve_seek(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let target = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(delta) => len.checked_add_signed(delta),
+        SeekFrom::Current(delta) => current.checked_add_signed(delta),
+    };
+    target.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncryptionKey;
+    use crate::sstable::ENCRYPTED_MAGIC;
+    use crate::{Db, EngineError, MemStorage, Options, Storage};
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn key(id: u32, byte: u8) -> Option<Arc<dyn super::KeyProvider>> {
+        Some(Arc::new(EncryptionKey { id, key: [byte; 32] }))
+    }
+
+    fn open(
+        dir: &str,
+        storage: &Arc<MemStorage>,
+        key: Option<Arc<dyn super::KeyProvider>>,
+    ) -> io::Result<Db> {
+        let options = Options {
+            storage: Some(storage.clone()),
+            encryption: key,
+            ..Options::default()
+        };
+        Db::open_with_options(dir, options)
+    }
+
+    /// Put 100 keys from `first` with values long enough to span several blocks, flushing
+    /// them into one table
+    fn fill(db: &Db, first: usize) {
+        for i in first..first + 100 {
+            let value = format!("secret-{:04}-{}", i, "x".repeat(80));
+            db.put(format!("key{:04}", i), value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    fn assert_filled(db: &Db, count: usize) {
+        for i in 0..count {
+            let value = db.get(format!("key{:04}", i)).unwrap();
+            assert!(value.starts_with(&format!("secret-{:04}-", i)), "{}", value);
+        }
+        assert_eq!(db.scan("key", "kez").unwrap().len(), count);
+    }
+
+    /// Contents of every file under `dir` named with `suffix`
+    fn files(storage: &MemStorage, dir: &str, suffix: &str) -> Vec<Vec<u8>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![Path::new(dir).to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for path in storage.list_dir(&dir).unwrap_or_default() {
+                if path.to_string_lossy().ends_with(suffix) {
+                    files.push(storage.read(&path).unwrap());
+                } else {
+                    dirs.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    fn sstables(storage: &MemStorage, dir: &str) -> Vec<Vec<u8>> {
+        files(storage, dir, ".sst")
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_encrypted_tables_round_trip() {
+        let dir = "test_encryption_round_trip";
+        let storage = Arc::new(MemStorage::new());
+
+        let db = open(dir, &storage, key(7, 1)).unwrap();
+        for first in [0, 100, 200] {
+            fill(&db, first);
+        }
+        assert_filled(&db, 300);
+        db.compact().unwrap();
+        assert_filled(&db, 300);
+        drop(db);
+
+        let tables = sstables(&storage, dir);
+        assert!(!tables.is_empty());
+        for table in &tables {
+            assert_eq!(table[..4], ENCRYPTED_MAGIC);
+            assert_eq!(table[4..8], 7u32.to_le_bytes());
+            assert!(!contains(table, b"secret-"));
+        }
+
+        let db = open(dir, &storage, key(7, 1)).unwrap();
+        assert_filled(&db, 300);
+    }
+
+    #[test]
+    fn test_wrong_key_fails_to_open() {
+        let dir = "test_encryption_wrong_key";
+        let storage = Arc::new(MemStorage::new());
+
+        let db = open(dir, &storage, key(1, 1)).unwrap();
+        fill(&db, 0);
+        drop(db);
+
+        let Err(err) = open(dir, &storage, key(1, 2)) else { panic!("opened with wrong key") };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::DecryptionFailed { key_id: 1, .. })
+        ));
+        for key in [key(2, 1), None] {
+            let Err(err) = open(dir, &storage, key) else { panic!("opened without the key") };
+            let unknown = EngineError::UnknownEncryptionKey { key_id: 1 };
+            assert_eq!(EngineError::from_io(&err), Some(&unknown));
+        }
+
+        let db = open(dir, &storage, key(1, 1)).unwrap();
+        assert_filled(&db, 100);
+    }
+
+    #[test]
+    fn test_plaintext_and_encrypted_tables_mix() {
+        let dir = "test_encryption_mixed";
+        let storage = Arc::new(MemStorage::new());
+
+        let db = open(dir, &storage, None).unwrap();
+        fill(&db, 0);
+        drop(db);
+
+        let db = open(dir, &storage, key(3, 9)).unwrap();
+        assert_filled(&db, 100);
+        fill(&db, 100);
+        assert_filled(&db, 200);
+        drop(db);
+
+        let tables = sstables(&storage, dir);
+        let encrypted = tables.iter().filter(|table| table[..4] == ENCRYPTED_MAGIC).count();
+        assert_eq!((tables.len(), encrypted), (2, 1));
+
+        // Compaction rewrites the plaintext table under the key
+        let db = open(dir, &storage, key(3, 9)).unwrap();
+        assert_filled(&db, 200);
+        db.compact().unwrap();
+        assert_filled(&db, 200);
+        drop(db);
+        let tables = sstables(&storage, dir);
+        assert!(tables.iter().all(|table| table[..4] == ENCRYPTED_MAGIC));
+    }
+
+    /// Put keys `range` without flushing them
+    fn log(db: &Db, range: std::ops::Range<usize>) {
+        for i in range {
+            db.put(format!("key{:04}", i), format!("secret-{:04}-", i)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_encrypted_wal_recovers_only_with_its_key() {
+        let dir = "test_encryption_wal";
+        let storage = Arc::new(MemStorage::new());
+
+        let db = open(dir, &storage, key(5, 1)).unwrap();
+        log(&db, 0..20);
+        drop(db);
+        let [wal] = &files(&storage, dir, "data.log")[..] else { panic!("one WAL expected") };
+        assert!(!contains(wal, b"secret-"));
+
+        let Err(err) = open(dir, &storage, key(5, 2)) else { panic!("opened with wrong key") };
+        assert!(matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::DecryptionFailed { key_id: 5, .. })
+        ));
+        let Err(err) = open(dir, &storage, None) else { panic!("opened without the key") };
+        let unknown = EngineError::UnknownEncryptionKey { key_id: 5 };
+        assert_eq!(EngineError::from_io(&err), Some(&unknown));
+
+        // Failed opens leave the log as it was
+        let db = open(dir, &storage, key(5, 1)).unwrap();
+        assert_filled(&db, 20);
+    }
+
+    #[test]
+    fn test_plaintext_wal_stays_readable() {
+        let dir = "test_encryption_plain_wal";
+        let storage = Arc::new(MemStorage::new());
+
+        let db = open(dir, &storage, None).unwrap();
+        log(&db, 0..10);
+        drop(db);
+        let db = open(dir, &storage, None).unwrap();
+        assert_filled(&db, 10);
+        drop(db);
+
+        // Turning encryption on replays the plain records and seals only new ones
+        let db = open(dir, &storage, key(5, 1)).unwrap();
+        assert_filled(&db, 10);
+        log(&db, 10..20);
+        drop(db);
+        let wal = &files(&storage, dir, "data.log")[0];
+        assert!(contains(wal, b"secret-0009-") && !contains(wal, b"secret-0010-"));
+
+        let db = open(dir, &storage, key(5, 1)).unwrap();
+        assert_filled(&db, 20);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::value_log::ValuePointer;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a single version of a key does
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Put(Vec<u8>),
+    /// A deletion (tombstone)
+    Delete,
+    /// An operand combined with the older value by the merge operator on read
+    Merge(Vec<u8>),
+    /// A put whose value was separated into the value log
+    Blob(ValuePointer),
+}
+
+/// One version of a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub seq: u64,
+    pub op: Op,
+    /// Unix time in milliseconds from which a put is no longer visible
+    pub expires_at: Option<u64>,
+    /// Unix time in milliseconds the version was written, `None` for versions written
+    /// before write times were recorded
+    pub written_at: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(seq: u64, op: Op) -> Self {
+        Entry { seq, op, expires_at: None, written_at: None }
+    }
+
+    pub fn put(seq: u64, value: Vec<u8>) -> Self {
+        Entry::new(seq, Op::Put(value))
+    }
+
+    pub fn tombstone(seq: u64) -> Self {
+        Entry::new(seq, Op::Delete)
+    }
+
+    pub fn merge(seq: u64, operand: Vec<u8>) -> Self {
+        Entry::new(seq, Op::Merge(operand))
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.op == Op::Delete
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// A copy with the value left out, for reads that only decide whether a key is live
+    pub(crate) fn without_value(&self) -> Entry {
+        let op = match &self.op {
+            Op::Put(_) => Op::Put(Vec::new()),
+            Op::Merge(_) => Op::Merge(Vec::new()),
+            op => op.clone(),
+        };
+        Entry { op, ..*self }
+    }
+
+    /// The stored value of an unexpired inline put, `None` for anything else
+    pub fn value(&self) -> Option<&[u8]> {
+        match &self.op {
+            Op::Put(value) if !self.is_expired(now_millis()) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// A value together with where and when it was written, from
+/// [`Db::get_with_metadata`](crate::Db::get_with_metadata)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueMeta {
+    pub value: Vec<u8>,
+    /// Sequence number of the newest version of the key
+    pub seq: u64,
+    /// Unix time in milliseconds of that version, `None` if it predates write times
+    /// being recorded
+    pub written_at: Option<u64>,
+    pub source: ValueSource,
+}
+
+/// Which component held the newest version of a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    MemTable,
+    SSTable(PathBuf),
+}
+
+/// Current Unix time in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Deletes every key in `[start, end)` written before `seq`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn covers(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && key < self.end.as_slice()
+    }
+}
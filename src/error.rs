@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Engine-level failures that aren't plain I/O problems.
+///
+/// The public API stays on `io::Result`; these travel inside the `io::Error` and can be
+/// recovered with [`EngineError::from_io`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    /// A key read by a transaction was changed by another commit before it committed
+    Conflict { key: String },
+    /// A stored value couldn't be interpreted the way the operation requires
+    InvalidValue { key: String, reason: String },
+    /// A write would take the database past
+    /// [`Options::max_disk_bytes`](crate::Options::max_disk_bytes)
+    DiskQuotaExceeded { limit: u64, usage: u64, needed: u64 },
+    /// A write that may not wait found writes stopped, `sstables` having reached
+    /// [`Options::stop_writes_sstables`](crate::Options#structfield.stop_writes_sstables)
+    WriteStopped { sstables: usize, limit: usize },
+    /// The WAL record numbered `sequence` is no longer kept for shipping, so a replica
+    /// that still needs it has to be rebuilt from a backup
+    WalGap { sequence: u64 },
+    /// A follower was handed a replicated write numbered `received` when the next one it
+    /// can apply is `expected`
+    ReplicationOutOfOrder { expected: u64, received: u64 },
+    /// An SSTable is encrypted with key `key_id`, which the configured key provider
+    /// does not have
+    UnknownEncryptionKey { key_id: u32 },
+    /// A block of the SSTable at `path` failed authentication under key `key_id`: the
+    /// key is wrong or the file is damaged
+    DecryptionFailed { path: String, key_id: u32 },
+    /// An open with
+    /// [`Options::verify_on_open`](crate::Options#structfield.verify_on_open) found the
+    /// database inconsistent, as listed by [`crate::VerifyReport::violations`]
+    VerificationFailed { violations: Vec<String> },
+    /// SSTables the database was using are gone, so opening it would serve partial data;
+    /// [`Options::allow_missing_tables`](crate::Options#structfield.allow_missing_tables)
+    /// opens it anyway
+    MissingTables { tables: Vec<String> },
+    /// A flush a write triggered failed with `error` after the write itself was logged;
+    /// writes are refused until a flush succeeds, as by [`crate::Db::try_flush`]
+    BackgroundError { error: String },
+}
+
+impl EngineError {
+    /// Extract the engine error carried by `err`, if there is one
+    pub fn from_io(err: &io::Error) -> Option<&EngineError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            EngineError::Conflict { .. } => io::ErrorKind::Other,
+            EngineError::InvalidValue { .. } => io::ErrorKind::InvalidData,
+            EngineError::DiskQuotaExceeded { .. } => io::ErrorKind::StorageFull,
+            EngineError::WriteStopped { .. } => io::ErrorKind::WouldBlock,
+            EngineError::WalGap { .. } => io::ErrorKind::NotFound,
+            EngineError::ReplicationOutOfOrder { .. } => io::ErrorKind::InvalidInput,
+            EngineError::UnknownEncryptionKey { .. } => io::ErrorKind::PermissionDenied,
+            EngineError::DecryptionFailed { .. } => io::ErrorKind::InvalidData,
+            EngineError::VerificationFailed { .. } => io::ErrorKind::InvalidData,
+            EngineError::MissingTables { .. } => io::ErrorKind::NotFound,
+            EngineError::BackgroundError { .. } => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Conflict { key } => {
+                write!(f, "transaction conflict: key {:?} was modified concurrently", key)
+            }
+            EngineError::InvalidValue { key, reason } => {
+                write!(f, "invalid value for key {:?}: {}", key, reason)
+            }
+            EngineError::DiskQuotaExceeded { limit, usage, needed } => write!(
+                f,
+                "disk quota exceeded: {} bytes in use, {} more needed, limit is {}",
+                usage, needed, limit
+            ),
+            EngineError::WriteStopped { sstables, limit } => write!(
+                f,
+                "writes stopped: {} SSTables awaiting compaction, limit is {}",
+                sstables, limit
+            ),
+            EngineError::WalGap { sequence } => write!(
+                f,
+                "WAL records from sequence {} on are no longer kept; rebuild the replica \
+                 from a backup",
+                sequence
+            ),
+            EngineError::ReplicationOutOfOrder { expected, received } => write!(
+                f,
+                "replicated write {} is out of order: expected sequence {}",
+                received, expected
+            ),
+            EngineError::UnknownEncryptionKey { key_id } => {
+                write!(f, "no encryption key with id {} is configured", key_id)
+            }
+            EngineError::DecryptionFailed { path, key_id } => write!(
+                f,
+                "cannot decrypt {} with key {}: wrong key or corrupt data",
+                path, key_id
+            ),
+            EngineError::VerificationFailed { violations } => write!(
+                f,
+                "database failed verification with {} violations: {}",
+                violations.len(),
+                violations.join("; ")
+            ),
+            EngineError::MissingTables { tables } => write!(
+                f,
+                "SSTables in use are missing: {}; restore them from a backup, or open with \
+                 allow_missing_tables to go on without their data",
+                tables.join(", ")
+            ),
+            EngineError::BackgroundError { error } => write!(
+                f,
+                "writes refused until a flush succeeds: the last one failed: {}",
+                error
+            ),
+        }
+    }
+}
+
+impl Error for EngineError {}
+
+impl From<EngineError> for io::Error {
+    fn from(err: EngineError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
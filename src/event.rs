@@ -0,0 +1,205 @@
+//! Callbacks for engine lifecycle events, registered through [`Options::event_listener`]
+//!
+//! [`Options::event_listener`]: crate::Options::event_listener
+
+use crate::compaction::CompactionInfo;
+use crate::write_stall::WriteStall;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Observes flushes, compactions, WAL rotations, write stalls, and background errors. Every
+/// method defaults to doing nothing.
+///
+/// Events are raised while the engine holds a column family's lock but delivered only
+/// once it is released, in the order they happened: a listener may call back into the
+/// [`Db`](crate::Db) without deadlocking, and `on_flush_begin` arrives just before the
+/// matching `on_flush_complete` rather than while the flush runs. A panic in a listener
+/// is caught and does not affect the engine.
+pub trait EventListener: Send + Sync {
+    /// A memtable holding `entries` versions is being written to the SSTable at `path`
+    fn on_flush_begin(&self, _path: &Path, _entries: usize) {}
+
+    fn on_flush_complete(&self, _info: &FlushInfo) {}
+
+    fn on_compaction_complete(&self, _info: &CompactionInfo) {}
+
+    /// A flush retired the WAL and started an empty one
+    fn on_wal_rotate(&self, _info: &WalRotateInfo) {}
+
+    /// Writes to a column family started or stopped being held back
+    fn on_write_stall_change(&self, _info: &WriteStallInfo) {}
+
+    /// A flush a write triggered failed with `error`. The write itself is durable;
+    /// later writes are refused until a flush succeeds.
+    fn on_background_error(&self, _error: &io::Error) {}
+}
+
+/// What a flush wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushInfo {
+    pub path: PathBuf,
+    /// Versions written, deletions included
+    pub entries: usize,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRotateInfo {
+    /// Path of the new, empty log
+    pub path: PathBuf,
+    /// Size of the log that was retired
+    pub retired_bytes: u64,
+}
+
+/// A change in whether writes are held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStallInfo {
+    pub previous: WriteStall,
+    pub current: WriteStall,
+    /// SSTables in the column family, the count the limits apply to
+    pub sstables: usize,
+}
+
+/// An event waiting to be delivered
+pub(crate) enum Event {
+    FlushBegin { path: PathBuf, entries: usize },
+    FlushComplete(FlushInfo),
+    CompactionComplete(CompactionInfo),
+    WalRotate(WalRotateInfo),
+    WriteStallChange(WriteStallInfo),
+    BackgroundError(io::Error),
+}
+
+/// Deliver `events` in order, containing any panic to the callback that raised it
+pub(crate) fn deliver(listener: &dyn EventListener, events: Vec<Event>) {
+    for event in events {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| match &event {
+            Event::FlushBegin { path, entries } => listener.on_flush_begin(path, *entries),
+            Event::FlushComplete(info) => listener.on_flush_complete(info),
+            Event::CompactionComplete(info) => listener.on_compaction_complete(info),
+            Event::WalRotate(info) => listener.on_wal_rotate(info),
+            Event::WriteStallChange(info) => listener.on_write_stall_change(info),
+            Event::BackgroundError(error) => listener.on_background_error(error),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventListener, FlushInfo, WalRotateInfo};
+    use crate::compaction::CompactionInfo;
+    use crate::{Db, Options};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+        flushes: Mutex<Vec<FlushInfo>>,
+        compactions: Mutex<Vec<CompactionInfo>>,
+        db: Mutex<Option<Db>>,
+    }
+
+    impl Recorder {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl EventListener for Recorder {
+        fn on_flush_begin(&self, path: &Path, entries: usize) {
+            self.record(format!("flush_begin {} {}", path.display(), entries));
+        }
+
+        fn on_flush_complete(&self, info: &FlushInfo) {
+            self.record(format!("flush_complete {}", info.path.display()));
+            self.flushes.lock().unwrap().push(info.clone());
+            // Delivered outside the lock, so calling back in doesn't deadlock
+            if let Some(db) = self.db.lock().unwrap().as_ref() {
+                self.record(format!("read {:?}", db.get("a")));
+            }
+        }
+
+        fn on_compaction_complete(&self, info: &CompactionInfo) {
+            self.record("compaction_complete".to_string());
+            self.compactions.lock().unwrap().push(info.clone());
+        }
+
+        fn on_wal_rotate(&self, info: &WalRotateInfo) {
+            assert!(info.retired_bytes > 0);
+            self.record(format!("wal_rotate {}", info.path.display()));
+        }
+    }
+
+    #[test]
+    fn test_listener_sees_flush_and_compaction() {
+        let dir = "test_event_listener";
+        let _ = fs::remove_dir_all(dir);
+
+        let recorder = Arc::new(Recorder::default());
+        let options = Options { event_listener: Some(recorder.clone()), ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        *recorder.db.lock().unwrap() = Some(db.clone());
+
+        db.put("a", "1").unwrap();
+        db.put("b", "2").unwrap();
+        db.delete("c").unwrap();
+        db.flush().unwrap();
+        let first = db.sstable_paths()[0].clone();
+        let wal = db.wal_path();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                format!("flush_begin {} 3", first.display()),
+                format!("flush_complete {}", first.display()),
+                format!("read {:?}", Some("1")),
+                format!("wal_rotate {}", wal.display()),
+            ]
+        );
+        let flush = recorder.flushes.lock().unwrap()[0].clone();
+        assert_eq!((flush.path, flush.entries), (first.clone(), 3));
+        assert_eq!(flush.bytes, fs::metadata(&first).unwrap().len());
+
+        db.put("a", "3").unwrap();
+        db.flush().unwrap();
+        let info = db.compact().unwrap();
+        assert_eq!((info.input_tables, info.output_tables), (2, 1));
+        assert_eq!((info.keys, info.tombstones_dropped), (3, 1));
+        assert_eq!(*recorder.compactions.lock().unwrap(), [info]);
+        assert_eq!(recorder.events.lock().unwrap().last().unwrap(), "compaction_complete");
+
+        // Break the cycle through the listener so the database closes
+        recorder.db.lock().unwrap().take();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    struct Panicking;
+
+    impl EventListener for Panicking {
+        fn on_flush_complete(&self, _info: &FlushInfo) {
+            panic!("listener failed");
+        }
+    }
+
+    #[test]
+    fn test_panicking_listener_is_contained() {
+        let dir = "test_event_listener_panic";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { event_listener: Some(Arc::new(Panicking)), ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+        db.put("b", "2").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.get("a"), Some("1".to_string()));
+        assert_eq!(db.get("b"), Some("2".to_string()));
+        assert_eq!(db.sstable_paths().len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use crate::batch::WriteBatch;
+use crate::db::Db;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{Map, Value};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// Rows written per batch when importing
+const IMPORT_BATCH_ROWS: usize = 1000;
+
+/// What a CSV import does with a row that is not exactly `key,value`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnMalformed {
+    /// Fail the import at the first malformed row
+    #[default]
+    Abort,
+    /// Record the row in [`CsvImportSummary::malformed`] and continue
+    Skip,
+}
+
+/// Settings for [`crate::Db::import_csv`]
+#[derive(Debug, Clone, Default)]
+pub struct CsvImportOptions {
+    /// Treat the first row as a header and ignore it
+    pub has_headers: bool,
+    /// Write through [`crate::Db::bulk_ingest`] instead of `put`. Rows must then be
+    /// sorted by key, and an abort leaves nothing imported.
+    pub bulk: bool,
+    pub on_malformed: OnMalformed,
+}
+
+/// Outcome of a CSV import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CsvImportSummary {
+    pub imported: u64,
+    pub skipped: u64,
+    /// Line number (1-based, where the row starts) and problem for each skipped row
+    pub malformed: Vec<(u64, String)>,
+}
+
+/// Stream every live pair of `db` to `writer` as JSON Lines, in key order.
+///
+/// Each line is `{"key": ..., "value": ...}`. A key or value that is not valid UTF-8 is
+/// written as base64 and flagged with `"key_base64": true` or `"value_base64": true`.
+pub(crate) fn export_json<W: Write>(db: &Db, writer: W) -> io::Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    let mut count = 0;
+    for item in db.iter() {
+        let (key, value) = item?;
+        let mut object = Map::new();
+        insert_bytes(&mut object, "key", key);
+        insert_bytes(&mut object, "value", value);
+        serde_json::to_writer(&mut writer, &object)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Load lines written by [`export_json`] into `db`, returning how many pairs were written.
+/// Blank lines are ignored; any other line that does not parse fails the import with
+/// its line number, after the lines before it have been written.
+pub(crate) fn import_json<R: Read>(db: &Db, reader: R) -> io::Result<u64> {
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for (index, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = parse_line(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected a JSON object with key and value", index + 1),
+            )
+        })?;
+
+        batch.put(key, value);
+        count += 1;
+        if batch.len() >= IMPORT_BATCH_ROWS {
+            db.write(&batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+    db.write(&batch)?;
+    Ok(count)
+}
+
+/// Import `key,value` rows from CSV with standard quoting: fields may be quoted to hold
+/// commas, newlines, and doubled quotes.
+///
+/// With the `put` path, rows before an aborting malformed row stay written.
+pub(crate) fn import_csv<R: Read>(
+    db: &Db,
+    reader: R,
+    options: &CsvImportOptions,
+) -> io::Result<CsvImportSummary> {
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(options.has_headers)
+        .flexible(true)
+        .from_reader(reader)
+        .into_byte_records();
+
+    let mut summary = CsvImportSummary::default();
+    let mut rows = std::iter::from_fn(|| loop {
+        let record = match records.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(io::Error::from(err))),
+        };
+        if record.len() == 2 {
+            summary.imported += 1;
+            return Some(Ok((record[0].to_vec(), record[1].to_vec())));
+        }
+
+        let line = record.position().map_or(0, csv::Position::line);
+        let problem = format!("expected 2 fields, found {}", record.len());
+        match options.on_malformed {
+            OnMalformed::Abort => {
+                let message = format!("line {}: {}", line, problem);
+                return Some(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+            }
+            OnMalformed::Skip => {
+                summary.skipped += 1;
+                summary.malformed.push((line, problem));
+            }
+        }
+    });
+
+    if options.bulk {
+        db.try_bulk_ingest(rows)?;
+    } else {
+        let mut batch = WriteBatch::new();
+        for row in rows.by_ref() {
+            let (key, value) = row?;
+            batch.put(key, value);
+            if batch.len() >= IMPORT_BATCH_ROWS {
+                db.write(&batch)?;
+                batch = WriteBatch::new();
+            }
+        }
+        db.write(&batch)?;
+    }
+    Ok(summary)
+}
+
+fn insert_bytes(object: &mut Map<String, Value>, field: &str, bytes: Vec<u8>) {
+    match String::from_utf8(bytes) {
+        Ok(text) => {
+            object.insert(field.to_string(), Value::String(text));
+        }
+        Err(err) => {
+            let encoded = BASE64.encode(err.into_bytes());
+            object.insert(field.to_string(), Value::String(encoded));
+            object.insert(format!("{}_base64", field), Value::Bool(true));
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let object = match serde_json::from_str(line).ok()? {
+        Value::Object(object) => object,
+        _ => return None,
+    };
+    let field = |name: &str| -> Option<Vec<u8>> {
+        let text = object.get(name)?.as_str()?;
+        let base64 = object
+            .get(&format!("{}_base64", name))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if base64 {
+            BASE64.decode(text).ok()
+        } else {
+            Some(text.as_bytes().to_vec())
+        }
+    };
+    Some((field("key")?, field("value")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvImportOptions, OnMalformed};
+    use crate::db::Db;
+    use std::fs;
+
+    #[test]
+    fn test_export_and_reimport() {
+        let dir = "test_export_source";
+        let copy = "test_export_copy";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(copy);
+
+        let db = Db::open(dir).unwrap();
+        for i in 0..120 {
+            db.put(format!("key{:03}", i), format!("value \"{}\"\n", i)).unwrap();
+        }
+        db.put("key005", "overwritten").unwrap();
+        db.delete("key006").unwrap();
+        db.put(vec![0xff, 0x00], vec![0xc3, 0x28]).unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(db.export_json(&mut exported).unwrap(), 120);
+        let text = String::from_utf8(exported.clone()).unwrap();
+        assert_eq!(text.lines().count(), 120);
+        assert!(text.contains(r#"{"key":"key005","value":"overwritten"}"#));
+        assert!(text.contains(r#""key_base64":true"#));
+        assert!(text.contains(r#""value_base64":true"#));
+        assert!(!text.contains("key006"));
+
+        let imported = Db::open(copy).unwrap();
+        assert_eq!(imported.import_json(exported.as_slice()).unwrap(), 120);
+        let original: Vec<_> = db.iter().map(Result::unwrap).collect();
+        let copied: Vec<_> = imported.iter().map(Result::unwrap).collect();
+        assert_eq!(original, copied);
+
+        let err = imported.import_json("{\"key\": \"a\"}\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(copy).unwrap();
+    }
+
+    const QUOTED_CSV: &str = "key,value\n\
+        plain,simple\n\
+        \"with,comma\",\"a, b, c\"\n\
+        multiline,\"first line\nsecond line\"\n\
+        too,many,fields\n\
+        quotes,\"say \"\"hi\"\"\"\n";
+
+    #[test]
+    fn test_import_csv_quoting() {
+        let dir = "test_import_csv";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let options = CsvImportOptions {
+            has_headers: true,
+            on_malformed: OnMalformed::Skip,
+            ..CsvImportOptions::default()
+        };
+        let summary = db.import_csv(QUOTED_CSV.as_bytes(), &options).unwrap();
+        assert_eq!(summary.imported, 4);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.malformed, vec![(6, "expected 2 fields, found 3".to_string())]);
+
+        assert_eq!(db.get("plain"), Some("simple".to_string()));
+        assert_eq!(db.get("with,comma"), Some("a, b, c".to_string()));
+        assert_eq!(db.get("multiline"), Some("first line\nsecond line".to_string()));
+        assert_eq!(db.get("quotes"), Some("say \"hi\"".to_string()));
+        assert_eq!(db.get("too"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_csv_abort_and_bulk() {
+        let dir = "test_import_csv_bulk";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let bulk = CsvImportOptions {
+            bulk: true,
+            ..CsvImportOptions::default()
+        };
+        let err = db.import_csv("a,1\nb\nc,3\n".as_bytes(), &bulk).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert_eq!(db.get("a"), None);
+
+        let summary = db.import_csv("a,1\nb,2\nc,3\n".as_bytes(), &bulk).unwrap();
+        assert_eq!(summary.imported, 3);
+        assert_eq!(db.get("b"), Some("2".to_string()));
+        assert_eq!(db.size(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,723 @@
+//! Failpoints for crash and IO-error testing.
+//!
+//! [`FaultStorage`] wraps a [`MemStorage`] and can be armed to fail the nth write,
+//! sync, rename, removal, or directory sync, either with an error or with a simulated
+//! crash after which nothing more is persisted. It also counts reads, syncs, and open
+//! files and records the directory changes made, for tests of IO patterns, and can
+//! simulate a power loss that drops whatever was written but not synced.
+//! The tests below use it to run a flush, a WAL append, or a compaction once per
+//! failpoint and check what survives.
+
+use crate::storage::{MemStorage, Storage, StorageFile};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The kinds of operation a failpoint counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FaultOp {
+    /// A write to an open file, or truncating one
+    Write,
+    Sync,
+    Rename,
+    Remove,
+    SyncDir,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// The operation fails without effect; later operations succeed
+    Error,
+    /// The process dies: a write persists only its first half, and every later change
+    /// fails without effect
+    Crash,
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// The armed failpoint: which operation, how many more to let through, and what
+    /// then happens
+    armed: Option<(FaultOp, u64, Fault)>,
+    fired: bool,
+    crashed: bool,
+    /// Read calls made on files opened through the storage
+    reads: u64,
+    /// Files opened for reading and not yet closed, and the most there have been at once
+    open_files: u64,
+    peak_open_files: u64,
+    /// Renames (by destination), removals, and directory syncs, in order
+    changes: Vec<(FaultOp, PathBuf)>,
+    /// File syncs that succeeded
+    syncs: u64,
+    /// Length of each file as of its last sync, 0 for files created and not yet synced
+    synced: HashMap<PathBuf, u64>,
+}
+
+/// In-memory storage with a programmable failpoint
+#[derive(Clone, Default)]
+pub(crate) struct FaultStorage {
+    files: MemStorage,
+    state: Arc<Mutex<FaultState>>,
+}
+
+/// What to do with an operation that passed [`FaultStorage::check`]
+enum Outcome {
+    Proceed,
+    /// Persist half of the write, then report the crash
+    Tear,
+}
+
+impl FaultStorage {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply `fault` to the `nth` (counting from 1) operation of kind `op` from now on
+    pub(crate) fn arm(&self, op: FaultOp, nth: u64, fault: Fault) {
+        self.state().armed = Some((op, nth, fault));
+    }
+
+    /// Whether the armed failpoint has been reached
+    pub(crate) fn fired(&self) -> bool {
+        self.state().fired
+    }
+
+    pub(crate) fn reads(&self) -> u64 {
+        self.state().reads
+    }
+
+    /// The most files opened for reading that were open at the same time
+    pub(crate) fn peak_open_files(&self) -> u64 {
+        self.state().peak_open_files
+    }
+
+    pub(crate) fn changes(&self) -> Vec<(FaultOp, PathBuf)> {
+        self.state().changes.clone()
+    }
+
+    pub(crate) fn syncs(&self) -> u64 {
+        self.state().syncs
+    }
+
+    /// The machine loses power: every file is cut back to its length at its last sync,
+    /// and then the process is gone as after a crash. Files that existed before this
+    /// storage first saw them created are kept whole.
+    pub(crate) fn lose_power(&self) {
+        let mut state = self.state();
+        for (path, &len) in &state.synced {
+            if self.files.exists(path) {
+                self.files.set_len(path, len).unwrap();
+            }
+        }
+        state.crashed = true;
+    }
+
+    /// The files as they stand, without failpoints: after a crash, what a restarted
+    /// process would find
+    pub(crate) fn files(&self) -> MemStorage {
+        self.files.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count a change of kind `op`, failing it if the failpoint says so
+    fn check(&self, op: FaultOp) -> io::Result<Outcome> {
+        let mut state = self.state();
+        if state.crashed {
+            return Err(crashed());
+        }
+        let Some((armed, nth, fault)) = state.armed else {
+            return Ok(Outcome::Proceed);
+        };
+        if armed != op {
+            return Ok(Outcome::Proceed);
+        }
+        if nth > 1 {
+            state.armed = Some((armed, nth - 1, fault));
+            return Ok(Outcome::Proceed);
+        }
+
+        state.armed = None;
+        state.fired = true;
+        match fault {
+            Fault::Error => Err(io::Error::other(format!("injected {:?} failure", op))),
+            Fault::Crash => {
+                state.crashed = true;
+                match op {
+                    FaultOp::Write => Ok(Outcome::Tear),
+                    _ => Err(crashed()),
+                }
+            }
+        }
+    }
+
+    /// Fail once the process has crashed; for changes that are not counted
+    fn alive(&self) -> io::Result<()> {
+        if self.state().crashed {
+            return Err(crashed());
+        }
+        Ok(())
+    }
+
+    fn record(&self, op: FaultOp, path: &Path) {
+        self.state().changes.push((op, path.to_path_buf()));
+    }
+
+    fn wrap(&self, file: Box<dyn StorageFile>, path: &Path) -> Box<dyn StorageFile> {
+        let path = path.to_path_buf();
+        Box::new(FaultFile { file, storage: self.clone(), path, read_only: false })
+    }
+}
+
+impl Storage for FaultStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.alive()?;
+        if !self.files.exists(path) {
+            self.state().synced.insert(path.to_path_buf(), 0);
+        }
+        Ok(self.wrap(self.files.open_append(path)?, path))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.alive()?;
+        self.state().synced.insert(path.to_path_buf(), 0);
+        Ok(self.wrap(self.files.create(path)?, path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = self.files.open(path)?;
+        let mut state = self.state();
+        state.open_files += 1;
+        state.peak_open_files = state.peak_open_files.max(state.open_files);
+        let path = path.to_path_buf();
+        Ok(Box::new(FaultFile { file, storage: self.clone(), path, read_only: true }))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.alive()?;
+        Ok(self.wrap(self.files.open_write(path)?, path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(FaultOp::Rename)?;
+        self.files.rename(from, to)?;
+        let mut state = self.state();
+        match state.synced.remove(from) {
+            Some(len) => state.synced.insert(to.to_path_buf(), len),
+            None => state.synced.remove(to),
+        };
+        drop(state);
+        self.record(FaultOp::Rename, to);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.check(FaultOp::Remove)?;
+        self.files.remove(path)?;
+        self.state().synced.remove(path);
+        self.record(FaultOp::Remove, path);
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.check(FaultOp::SyncDir)?;
+        self.record(FaultOp::SyncDir, dir);
+        Ok(())
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.files.list_dir(dir)
+    }
+
+    fn list_subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.files.list_subdirs(dir)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        match self.check(FaultOp::Write)? {
+            Outcome::Proceed => self.files.set_len(path, len),
+            Outcome::Tear => Err(crashed()),
+        }
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.files.file_len(path)
+    }
+}
+
+/// A file opened through a [`FaultStorage`]
+struct FaultFile {
+    file: Box<dyn StorageFile>,
+    storage: FaultStorage,
+    path: PathBuf,
+    /// Opened by [`Storage::open`], so counted among the open files
+    read_only: bool,
+}
+
+impl Drop for FaultFile {
+    fn drop(&mut self) {
+        if self.read_only {
+            self.storage.state().open_files -= 1;
+        }
+    }
+}
+
+impl Read for FaultFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.storage.state().reads += 1;
+        self.file.read(buf)
+    }
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.storage.check(FaultOp::Write)? {
+            Outcome::Proceed => self.file.write(buf),
+            Outcome::Tear => {
+                self.file.write_all(&buf[..buf.len() / 2])?;
+                Err(crashed())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl StorageFile for FaultFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.storage.check(FaultOp::Sync)?;
+        self.file.sync()?;
+        self.synced()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.storage.check(FaultOp::Sync)?;
+        self.file.sync_data()?;
+        self.synced()
+    }
+}
+
+impl FaultFile {
+    /// Note a successful sync, which makes the file's current length durable
+    fn synced(&self) -> io::Result<()> {
+        let len = self.storage.files.file_len(&self.path)?;
+        let mut state = self.storage.state();
+        state.syncs += 1;
+        state.synced.insert(self.path.clone(), len);
+        Ok(())
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("simulated crash")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, FaultOp, FaultStorage};
+    use crate::batch::WriteBatch;
+    use crate::error::EngineError;
+    use crate::memtable::{MemTable, TABLE_LIST};
+    use crate::options::{Options, SyncMode, SyncPolicy, DEFAULT_MEMTABLE_ENTRIES};
+    use crate::storage::Storage;
+    use crate::wal::WAL_FILE;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    type Contents = Vec<(Vec<u8>, Vec<u8>)>;
+
+    const OPS: [FaultOp; 5] =
+        [FaultOp::Write, FaultOp::Sync, FaultOp::Rename, FaultOp::Remove, FaultOp::SyncDir];
+
+    fn open(storage: Arc<dyn Storage>) -> MemTable {
+        open_with(storage, &Options::default())
+    }
+
+    /// Open with `options`, plus the storage and a concatenating merge operator
+    fn open_with(storage: Arc<dyn Storage>, options: &Options) -> MemTable {
+        let options = Options {
+            storage: Some(storage),
+            merge_operator: Some(Arc::new(|_key, existing, operand| {
+                let mut value = existing.unwrap_or_default().to_vec();
+                value.extend_from_slice(operand);
+                value
+            })),
+            ..options.clone()
+        };
+        MemTable::with_options(&format!("db/{}", WAL_FILE), &options).unwrap()
+    }
+
+    fn contents(memtable: &MemTable) -> Contents {
+        memtable.iter_range(None, None, u64::MAX).collect::<io::Result<_>>().unwrap()
+    }
+
+    /// Run `scenario` once per failpoint of kind `op`, each time on a fresh memtable
+    /// prepared by `setup`, until it gets through without reaching one. `check` is
+    /// given the memtable the scenario ran on (failed or not) and the memtable reopened
+    /// from the files that survive. Returns the number of failpoints exercised.
+    fn each_failpoint<S, R, C>(op: FaultOp, fault: Fault, setup: S, scenario: R, check: C) -> u64
+    where
+        S: Fn(&mut MemTable),
+        R: Fn(&mut MemTable) -> io::Result<()>,
+        C: Fn(&MemTable, &MemTable),
+    {
+        each_failpoint_with(&Options::default(), op, fault, setup, scenario, check)
+    }
+
+    /// [`each_failpoint`] on memtables opened with `options`
+    fn each_failpoint_with<S, R, C>(
+        options: &Options,
+        op: FaultOp,
+        fault: Fault,
+        setup: S,
+        scenario: R,
+        check: C,
+    ) -> u64
+    where
+        S: Fn(&mut MemTable),
+        R: Fn(&mut MemTable) -> io::Result<()>,
+        C: Fn(&MemTable, &MemTable),
+    {
+        for nth in 1.. {
+            let storage = FaultStorage::new();
+            let mut memtable = open_with(Arc::new(storage.clone()), options);
+            setup(&mut memtable);
+            storage.arm(op, nth, fault);
+            let result = scenario(&mut memtable);
+            if !storage.fired() {
+                result.unwrap();
+                return nth - 1;
+            }
+
+            let mut reopened = open_with(Arc::new(storage.files()), options);
+            check(&memtable, &reopened);
+            drop(memtable);
+            // Whatever survived is still a working database
+            reopened.put("after", "crash").unwrap();
+            reopened.flush().unwrap();
+            assert_eq!(reopened.get("after"), Some(b"crash".to_vec()));
+        }
+        unreachable!()
+    }
+
+    /// Puts, a delete, and merges whose operands would be applied twice if a flushed
+    /// WAL were ever replayed on top of its table
+    fn write_rows(memtable: &mut MemTable) {
+        for i in 0..20 {
+            memtable.put(format!("key{:02}", i), format!("v{}", i)).unwrap();
+        }
+        memtable.delete("key03").unwrap();
+        memtable.merge("counter", "a").unwrap();
+        memtable.merge("counter", "b").unwrap();
+    }
+
+    fn expected_rows() -> Contents {
+        let storage = FaultStorage::new();
+        let mut memtable = open(Arc::new(storage));
+        write_rows(&mut memtable);
+        contents(&memtable)
+    }
+
+    #[test]
+    fn test_crash_during_flush_loses_nothing() {
+        let expected = expected_rows();
+        for op in OPS {
+            let points = each_failpoint(
+                op,
+                Fault::Crash,
+                write_rows,
+                MemTable::flush,
+                |_, reopened| {
+                    assert_eq!(contents(reopened), expected, "crash at {:?}", op);
+                    // Never a table and the WAL it came from, nor a half-written table
+                    assert!(reopened.sstable_paths().len() <= 1);
+                },
+            );
+            assert!(points > 0, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_failed_flush_sync_keeps_data_and_retries() {
+        let expected = expected_rows();
+        each_failpoint(
+            FaultOp::Sync,
+            Fault::Error,
+            write_rows,
+            |memtable| {
+                if memtable.flush().is_ok() {
+                    return Ok(());
+                }
+                // Nothing was lost in memory, and trying again works unless the WAL
+                // was already gone, in which case the memtable refuses writes
+                assert_eq!(contents(memtable), expected);
+                if memtable.flush().is_ok() {
+                    return memtable.put("retried", "yes");
+                }
+                assert!(memtable.put("retried", "yes").is_err());
+                Ok(())
+            },
+            |_, reopened| {
+                let mut found = contents(reopened);
+                found.retain(|(key, _)| key != b"retried");
+                assert_eq!(found, expected);
+            },
+        );
+    }
+
+    #[test]
+    fn test_failed_flush_after_put_keeps_the_write() {
+        // A memtable one write short of full, whose flush fails at the table's sync,
+        // the one after the write's own
+        let fill = || {
+            let storage = FaultStorage::new();
+            let mut memtable = open(Arc::new(storage.clone()));
+            for i in 1..DEFAULT_MEMTABLE_ENTRIES {
+                memtable.put(format!("key{:03}", i), "v").unwrap();
+            }
+            storage.arm(FaultOp::Sync, 2, Fault::Error);
+            memtable.put("last", "write").unwrap();
+            assert!(storage.fired());
+            (storage, memtable)
+        };
+
+        let (storage, mut memtable) = fill();
+        assert_eq!(memtable.get("last"), Some(b"write".to_vec()));
+        assert!(memtable.background_error().is_some());
+        let err = memtable.put("next", "write").unwrap_err();
+        let Some(EngineError::BackgroundError { .. }) = EngineError::from_io(&err) else {
+            panic!("{}", err)
+        };
+        assert_eq!(memtable.get("next"), None);
+        storage.lose_power();
+        let reopened = open(Arc::new(storage.files()));
+        assert_eq!(reopened.get("last"), Some(b"write".to_vec()));
+
+        // Retrying drains the memtable and lets writes through again
+        let (_storage, mut memtable) = fill();
+        memtable.try_flush().unwrap();
+        assert_eq!(memtable.size(), 0);
+        assert_eq!(memtable.background_error(), None);
+        assert_eq!(memtable.sstable_paths().len(), 1);
+        memtable.put("next", "write").unwrap();
+        assert_eq!(memtable.get("last"), Some(b"write".to_vec()));
+    }
+
+    #[test]
+    fn test_failed_rename_during_flush_is_recovered_on_open() {
+        let expected = expected_rows();
+        let points = each_failpoint(
+            FaultOp::Rename,
+            Fault::Error,
+            write_rows,
+            |memtable| {
+                if memtable.flush().is_ok() {
+                    return Ok(());
+                }
+                assert_eq!(contents(memtable), expected);
+                assert!(memtable.put("late", "write").is_err());
+                Ok(())
+            },
+            |_, reopened| {
+                assert_eq!(contents(reopened), expected);
+                assert_eq!(reopened.sstable_paths().len(), 1);
+            },
+        );
+        // The table, then the table list
+        assert_eq!(points, 2);
+    }
+
+    #[test]
+    fn test_flush_syncs_directory_around_each_change() {
+        let storage = FaultStorage::new();
+        let mut memtable = open(Arc::new(storage.clone()));
+        write_rows(&mut memtable);
+        let before = storage.changes().len();
+        memtable.flush().unwrap();
+
+        let dir = PathBuf::from("db");
+        let wal = dir.join(WAL_FILE);
+        let table = dir.join("sstable_000000.sst");
+        assert_eq!(
+            storage.changes()[before..],
+            [
+                (FaultOp::Remove, wal.clone()),
+                (FaultOp::SyncDir, dir.clone()),
+                (FaultOp::Rename, table),
+                (FaultOp::SyncDir, dir.clone()),
+                (FaultOp::Rename, dir.join(TABLE_LIST)),
+                (FaultOp::SyncDir, dir.clone()),
+                // The new WAL segment
+                (FaultOp::SyncDir, dir),
+            ]
+        );
+        assert!(storage.files().exists(&wal));
+    }
+
+    #[test]
+    fn test_crash_during_wal_rotation_and_append() {
+        // Rotation: the flush retires the WAL and the next writes go to a new one
+        let scenario = |memtable: &mut MemTable| {
+            memtable.flush()?;
+            for i in 0..5 {
+                memtable.put(format!("new{}", i), "x")?;
+            }
+            Ok(())
+        };
+        for fault in [Fault::Crash, Fault::Error] {
+            each_failpoint(FaultOp::Write, fault, write_rows, scenario, |memtable, reopened| {
+                // Exactly the acknowledged writes survive; a torn one is discarded
+                assert_eq!(contents(reopened), contents(memtable), "{:?}", fault);
+            });
+        }
+    }
+
+    #[test]
+    fn test_sync_makes_relaxed_writes_survive_power_loss() {
+        let storage = FaultStorage::new();
+        let options = Options { sync_policy: SyncPolicy::Never, ..Options::default() };
+        let mut memtable = open_with(Arc::new(storage.clone()), &options);
+        memtable.put("before", "sync").unwrap();
+        memtable.delete("gone").unwrap();
+        let syncs = storage.syncs();
+        memtable.sync().unwrap();
+        assert_eq!(storage.syncs(), syncs + 1);
+
+        // A clean log needs no sync
+        memtable.sync().unwrap();
+        assert_eq!(storage.syncs(), syncs + 1);
+
+        memtable.put("after", "sync").unwrap();
+        storage.lose_power();
+        drop(memtable);
+        let reopened = open_with(Arc::new(storage.files()), &options);
+        assert_eq!(contents(&reopened), [(b"before".to_vec(), b"sync".to_vec())]);
+    }
+
+    #[test]
+    fn test_crash_while_recycling_preallocated_wal() {
+        let options = Options {
+            wal_preallocate_bytes: Some(4096),
+            ..Options::default()
+        };
+        // Two flushes, so the second reuses the file the first retired
+        let scenario = |memtable: &mut MemTable| {
+            for round in 0..2 {
+                memtable.flush()?;
+                for i in 0..5 {
+                    memtable.merge(format!("new{}", i), round.to_string())?;
+                }
+            }
+            Ok(())
+        };
+        for op in [FaultOp::Write, FaultOp::Rename, FaultOp::Remove, FaultOp::SyncDir] {
+            let check = |memtable: &MemTable, reopened: &MemTable| {
+                // No record of a retired WAL is replayed again
+                assert_eq!(contents(reopened), contents(memtable), "{:?}", op);
+            };
+            each_failpoint_with(&options, op, Fault::Crash, write_rows, scenario, check);
+        }
+    }
+
+    #[test]
+    fn test_crash_during_batch_is_all_or_nothing() {
+        let scenario = |memtable: &mut MemTable| {
+            let mut batch = WriteBatch::new();
+            batch.put("batch_a", "1").delete("key00").merge("counter", "c").put("batch_b", "2");
+            memtable.write_batch(&batch)
+        };
+        each_failpoint(FaultOp::Write, Fault::Crash, write_rows, scenario, |_, reopened| {
+            let applied = [
+                reopened.get("batch_a").is_some(),
+                reopened.get("batch_b").is_some(),
+                reopened.get("key00").is_none(),
+                reopened.get("counter") == Some(b"abc".to_vec()),
+            ];
+            assert!(applied == [true; 4] || applied == [false; 4], "{:?}", applied);
+            if applied[0] {
+                return;
+            }
+            assert_eq!(contents(reopened), expected_rows());
+        });
+    }
+
+    #[test]
+    fn test_sync_data_mode_keeps_recovery_guarantees() {
+        // A crash at each sync: of WAL appends, of the flushed table, of the new WAL
+        let sync_data = Options {
+            wal_sync_mode: SyncMode::SyncData,
+            ..Options::default()
+        };
+        for nth in 1.. {
+            let storage = FaultStorage::new();
+            let mut memtable = open_with(Arc::new(storage.clone()), &sync_data);
+            storage.arm(FaultOp::Sync, nth, Fault::Crash);
+            let mut acknowledged = 0;
+            for i in 0..20 {
+                let flushed = if i == 10 { memtable.flush() } else { Ok(()) };
+                if flushed.and_then(|()| memtable.put(format!("key{:02}", i), "v")).is_err() {
+                    break;
+                }
+                acknowledged += 1;
+            }
+            if !storage.fired() {
+                assert!(nth > 20);
+                return;
+            }
+
+            // Every acknowledged write survives, and at most the one in flight besides
+            let reopened = open_with(Arc::new(storage.files()), &sync_data);
+            let found = contents(&reopened);
+            assert!(found.len() == acknowledged || found.len() == acknowledged + 1, "{}", nth);
+            for (i, (key, _)) in found.iter().take(acknowledged).enumerate() {
+                assert_eq!(key, format!("key{:02}", i).as_bytes());
+            }
+        }
+    }
+
+    /// Several tables with overlapping keys, deletes, and merge operands
+    fn write_tables(memtable: &mut MemTable) {
+        for round in 0..3 {
+            for i in 0..10 {
+                memtable.put(format!("key{:02}", i * (round + 1)), format!("r{}", round)).unwrap();
+            }
+            memtable.delete(format!("key{:02}", round)).unwrap();
+            memtable.merge("counter", round.to_string()).unwrap();
+            memtable.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_crash_or_error_during_compaction() {
+        let storage = FaultStorage::new();
+        let mut memtable = open(Arc::new(storage));
+        write_tables(&mut memtable);
+        let expected = contents(&memtable);
+        assert_eq!(memtable.get("counter"), Some(b"012".to_vec()));
+
+        for fault in [Fault::Crash, Fault::Error] {
+            for op in OPS {
+                let compact = |memtable: &mut MemTable| memtable.compact().map(drop);
+                let points = each_failpoint(op, fault, write_tables, compact, |failed, reopened| {
+                    assert_eq!(contents(reopened), expected, "{:?} at {:?}", fault, op);
+                    if fault == Fault::Error {
+                        assert_eq!(contents(failed), expected, "{:?}", op);
+                    }
+                });
+                assert!(points > 0, "{:?}", op);
+            }
+        }
+    }
+}
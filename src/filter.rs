@@ -0,0 +1,273 @@
+//! Bloom filters over the keys of an SSTable.
+//!
+//! A table's filter is split into partitions, each covering a run of consecutive keys,
+//! so a lookup loads only the partition its key falls in rather than the filter for
+//! the whole table. Each column family keeps the partitions it loads in a cache of
+//! [`Options::filter_cache_bytes`](crate::Options#structfield.filter_cache_bytes).
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Filter bits per key, for a false-positive rate of about 1%
+const BITS_PER_KEY: usize = 10;
+/// Bits set per key: `BITS_PER_KEY` × ln 2, rounded down
+const PROBES: u8 = 6;
+/// Distinct keys covered by each partition of a table's filter
+pub const PARTITION_KEYS: usize = 1024;
+/// Bytes of filter partitions kept in memory per column family when
+/// [`Options::filter_cache_bytes`](crate::Options#structfield.filter_cache_bytes) is unset
+pub const DEFAULT_FILTER_CACHE_BYTES: usize = 8 << 20;
+
+/// A Bloom filter: answers whether a key may have been added, with no false negatives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    probes: u8,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// A filter holding the keys whose [`key_hash`] values are `hashes`
+    pub fn build(hashes: &[u64]) -> Self {
+        let bytes = (hashes.len() * BITS_PER_KEY).div_ceil(8).max(8);
+        let mut filter = BloomFilter { probes: PROBES, bits: vec![0; bytes] };
+        for &hash in hashes {
+            for bit in filter.probe_bits(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Whether `key` may have been added; `false` means it definitely was not
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probe_bits(key_hash(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Size of the filter in bytes
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Bit positions for `hash`, by double hashing
+    fn probe_bits(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 8;
+        let delta = hash.rotate_left(32) | 1;
+        (0..u64::from(self.probes))
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % bits) as usize)
+    }
+
+    /// `[probes u8][bits]`
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.push(self.probes);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    /// `None` if `bytes` is not an encoded filter
+    pub(crate) fn decode(mut bytes: Vec<u8>) -> Option<Self> {
+        if bytes.len() < 2 || bytes[0] == 0 {
+            return None;
+        }
+        let probes = bytes.remove(0);
+        Some(BloomFilter { probes, bits: bytes })
+    }
+}
+
+/// Hash of `key` as stored in filters: FNV-1a, then mixed so every bit depends on
+/// every byte. Part of the table format, so it must never change.
+pub fn key_hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Where one partition of a table's filter is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterPartition {
+    /// Largest key the partition covers
+    pub last_key: Vec<u8>,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// The partitions of a table's filter, in key order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterIndex {
+    pub partitions: Vec<FilterPartition>,
+}
+
+impl FilterIndex {
+    /// The partition covering `key`, or `None` if it is past the table's last key
+    pub fn partition_for(&self, key: &[u8]) -> Option<usize> {
+        let index = self.partitions.partition_point(|p| p.last_key.as_slice() < key);
+        (index < self.partitions.len()).then_some(index)
+    }
+}
+
+/// Filter cache activity, as reported in [`crate::Stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct FilterCacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) bytes: u64,
+}
+
+/// The most recently used filter partitions of a column family's tables, up to a
+/// byte budget
+#[derive(Debug)]
+pub(crate) struct FilterCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Each partition by table path and partition number, with when it was last used
+    entries: HashMap<(String, usize), (Arc<BloomFilter>, u64)>,
+    /// Keys of `entries` by last use, oldest first
+    recency: BTreeMap<u64, (String, usize)>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl FilterCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        FilterCache {
+            capacity,
+            state: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Partition `partition` of the table at `path`, from the cache or else read by
+    /// `load` and cached, evicting the least recently used partitions to make room
+    pub(crate) fn get_or_load<F>(
+        &self,
+        path: &str,
+        partition: usize,
+        load: F,
+    ) -> io::Result<Arc<BloomFilter>>
+    where
+        F: FnOnce() -> io::Result<BloomFilter>,
+    {
+        let key = (path.to_string(), partition);
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let state = &mut *state;
+            state.clock += 1;
+            if let Some((filter, used)) = state.entries.get_mut(&key) {
+                state.recency.remove(used);
+                *used = state.clock;
+                state.recency.insert(state.clock, key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(filter.clone());
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let filter = Arc::new(load()?);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if filter.len() > self.capacity || state.entries.contains_key(&key) {
+            return Ok(filter);
+        }
+        while state.bytes + filter.len() > self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else { break };
+            if let Some((evicted, _)) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.len();
+            }
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.bytes += filter.len();
+        state.recency.insert(used, key.clone());
+        state.entries.insert(key, (filter.clone(), used));
+        Ok(filter)
+    }
+
+    /// Drop the partitions of the table at `path`, once it is no longer read
+    pub(crate) fn forget(&self, path: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        let mut freed = 0;
+        state.entries.retain(|(table, _), (filter, used)| {
+            let keep = table != path;
+            if !keep {
+                freed += filter.len();
+                state.recency.remove(used);
+            }
+            keep
+        });
+        state.bytes -= freed;
+    }
+
+    pub(crate) fn stats(&self) -> FilterCacheStats {
+        let bytes = self.state.lock().unwrap_or_else(PoisonError::into_inner).bytes;
+        FilterCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: bytes as u64,
+        }
+    }
+
+    /// Zero the hit and miss counts
+    pub(crate) fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter_has_no_false_negatives_and_few_false_positives() {
+        let keys: Vec<Vec<u8>> = (0..5000).map(|i| format!("key{:06}", i).into_bytes()).collect();
+        let hashes: Vec<u64> = keys.iter().map(|key| key_hash(key)).collect();
+        let filter = BloomFilter::decode(BloomFilter::build(&hashes).encode()).unwrap();
+
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(format!("absent{:06}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_filter_cache_evicts_least_recently_used() {
+        let filter = |n: u64| BloomFilter::build(&[n]);
+        let size = filter(0).len();
+        let cache = FilterCache::new(2 * size);
+
+        cache.get_or_load("a.sst", 0, || Ok(filter(0))).unwrap();
+        cache.get_or_load("a.sst", 1, || Ok(filter(1))).unwrap();
+        cache.get_or_load("a.sst", 0, || panic!("cached")).unwrap();
+        cache.get_or_load("b.sst", 0, || Ok(filter(2))).unwrap();
+        assert_eq!(cache.stats(), FilterCacheStats { hits: 1, misses: 3, bytes: 2 * size as u64 });
+
+        // Partition 1 was the least recently used, so it was the one evicted
+        cache.get_or_load("a.sst", 0, || panic!("cached")).unwrap();
+        cache.get_or_load("a.sst", 1, || Ok(filter(1))).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+
+        cache.forget("a.sst");
+        assert_eq!(cache.stats().bytes, 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! gRPC front end for service-to-service use, served by `storage-engine serve-grpc`.
+//!
+//! [`proto`] holds the messages, client, and server generated from
+//! `proto/storage.proto`. Failures are reported with the canonical status codes: a
+//! missing key is `NOT_FOUND`, a key or value the engine rejects `INVALID_ARGUMENT`,
+//! and a write past [`Options::max_disk_bytes`](crate::Options::max_disk_bytes)
+//! `RESOURCE_EXHAUSTED`. A read that needs an SSTable that has gone missing is
+//! `DATA_LOSS`.
+
+use crate::async_db::blocking;
+use crate::db::Db;
+use crate::error::EngineError;
+use proto::storage_server::{Storage, StorageServer};
+use proto::{
+    DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, PutRequest, PutResponse,
+    ScanRequest, StatsRequest, StatsResponse,
+};
+use std::io;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Code generated from `proto/storage.proto`
+pub mod proto {
+    tonic::include_proto!("storage_engine");
+}
+
+/// Pairs a scan reads ahead of a client that is slow to take them
+const SCAN_BUFFER: usize = 64;
+
+/// The `Storage` service over an open database
+#[derive(Clone)]
+pub struct GrpcService {
+    db: Db,
+}
+
+impl GrpcService {
+    pub fn new(db: Db) -> Self {
+        GrpcService { db }
+    }
+
+    /// The service, to add to a `tonic::transport::Server`
+    pub fn into_server(self) -> StorageServer<Self> {
+        StorageServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for GrpcService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let db = self.db.clone();
+        let key = request.into_inner().key;
+        match blocking(move || db.try_get_bytes(key)).await.map_err(status)? {
+            Some(value) => Ok(Response::new(GetResponse { value })),
+            None => Err(Status::not_found("key not found")),
+        }
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let db = self.db.clone();
+        let PutRequest { key, value, ttl_ms } = request.into_inner();
+        let put = move || match ttl_ms {
+            0 => db.put(key, value),
+            ttl_ms => db.put_with_ttl(key, value, Duration::from_millis(ttl_ms)),
+        };
+        blocking(put).await.map_err(status)?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let db = self.db.clone();
+        let key = request.into_inner().key;
+        match blocking(move || db.delete_bytes(key)).await.map_err(status)? {
+            Some(_) => Ok(Response::new(DeleteResponse {})),
+            None => Err(Status::not_found("key not found")),
+        }
+    }
+
+    type ScanStream = ReceiverStream<Result<KeyValue, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { start, end, limit } = request.into_inner();
+        if !end.is_empty() && start > end {
+            return Err(Status::invalid_argument("scan start is after its end"));
+        }
+        let limit = match limit {
+            0 => usize::MAX,
+            limit => usize::try_from(limit).unwrap_or(usize::MAX),
+        };
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        let db = self.db.clone();
+        // Pairs are read as the client takes them, at most SCAN_BUFFER ahead, and the read
+        // stops once the client goes away
+        task::spawn_blocking(move || {
+            for item in db.iter_from(&start).take(limit) {
+                let pair = match item {
+                    Ok((key, _)) if !end.is_empty() && key >= end => break,
+                    Ok((key, value)) => Ok(KeyValue { key, value }),
+                    Err(e) => Err(status(e)),
+                };
+                let failed = pair.is_err();
+                if sender.blocking_send(pair).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn stats(&self, _: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let db = self.db.clone();
+        let stats = blocking(move || db.stats()).await.map_err(status)?;
+        Ok(Response::new(StatsResponse {
+            puts: stats.puts,
+            deletes: stats.deletes,
+            memtable_hits: stats.memtable_hits,
+            sstable_hits: stats.sstable_hits,
+            misses: stats.misses,
+            flushes: stats.flushes,
+            compactions: stats.compactions,
+            memtable_entries: stats.memtable_entries,
+            sstable_count: stats.sstable_count,
+            sstable_bytes: stats.sstable_bytes,
+            wal_bytes: stats.wal_bytes,
+            disk_bytes: stats.disk_bytes,
+        }))
+    }
+}
+
+/// The status a failed engine call is reported with
+fn status(err: io::Error) -> Status {
+    let message = err.to_string();
+    // Of kind NotFound, like a missing key, but the data is lost rather than absent
+    if let Some(EngineError::MissingTables { .. }) = EngineError::from_io(&err) {
+        return Status::data_loss(message);
+    }
+    match err.kind() {
+        io::ErrorKind::InvalidInput => Status::invalid_argument(message),
+        io::ErrorKind::NotFound => Status::not_found(message),
+        io::ErrorKind::StorageFull => Status::resource_exhausted(message),
+        // Writes stopped until compaction catches up
+        io::ErrorKind::WouldBlock => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+    use proto::storage_client::StorageClient;
+    use std::fs;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tokio_stream::StreamExt;
+    use tonic::transport::{Channel, Server};
+    use tonic::Code;
+
+    /// A client of `db` served on an ephemeral port
+    async fn serve(db: Db) -> StorageClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::builder()
+            .add_service(GrpcService::new(db).into_server())
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+        StorageClient::connect(format!("http://{}", addr)).await.unwrap()
+    }
+
+    fn put(key: &str, value: &str, ttl_ms: u64) -> PutRequest {
+        PutRequest { key: key.into(), value: value.into(), ttl_ms }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpcs() {
+        let dir = "test_grpc";
+        let _ = fs::remove_dir_all(dir);
+        let db = Db::open(dir).unwrap();
+        let mut client = serve(db.clone()).await;
+
+        client.put(put("greeting", "hello", 0)).await.unwrap();
+        client.put(put("brief", "gone", 1)).await.unwrap();
+        let get = |key: &str| GetRequest { key: key.into() };
+        let value = client.get(get("greeting")).await.unwrap().into_inner().value;
+        assert_eq!(value, b"hello");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(client.get(get("brief")).await.unwrap_err().code(), Code::NotFound);
+
+        client.delete(DeleteRequest { key: "greeting".into() }).await.unwrap();
+        let err = client.delete(DeleteRequest { key: "greeting".into() }).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        assert_eq!(client.get(get("greeting")).await.unwrap_err().code(), Code::NotFound);
+        assert_eq!(client.put(put("", "x", 0)).await.unwrap_err().code(), Code::InvalidArgument);
+
+        let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+        assert_eq!((stats.puts, stats.deletes, stats.memtable_hits), (2, 2, 1));
+
+        // Streamed a few at a time as the client reads them
+        for i in 0..3000 {
+            db.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        let scan = |start: &str, end: &str, limit| ScanRequest {
+            start: start.into(),
+            end: end.into(),
+            limit,
+        };
+        let mut stream = client.scan(scan("key0100", "key2900", 0)).await.unwrap().into_inner();
+        let mut i = 100;
+        while let Some(pair) = stream.next().await {
+            let pair = pair.unwrap();
+            assert_eq!(pair.key, format!("key{:04}", i).into_bytes());
+            assert_eq!(pair.value, format!("value{}", i).into_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 2900);
+
+        let stream = client.scan(scan("", "", 10)).await.unwrap().into_inner();
+        let keys: Vec<Vec<u8>> = stream.map(|pair| pair.unwrap().key).collect().await;
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], b"key0000");
+        let err = client.scan(scan("b", "a", 0)).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quota_is_resource_exhausted() {
+        let dir = "test_grpc_quota";
+        let _ = fs::remove_dir_all(dir);
+        let options = Options { max_disk_bytes: Some(1024), ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        let mut client = serve(db).await;
+
+        let err = client.put(put("big", &"x".repeat(4096), 0)).await.unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted, "{}", err.message());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+use crate::entry::{now_millis, Entry, Op, RangeTombstone};
+use crate::options::MergeOperator;
+use crate::storage::Storage;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A sorted stream of versions: ascending key, newest version first within a key
+pub(crate) type Source = Box<dyn Iterator<Item = io::Result<(Vec<u8>, Entry)>> + Send>;
+
+/// Merging iterator over every live key-value pair in the database, in key order or,
+/// when built over descending sources, from the highest key down.
+///
+/// Holds one pending version per source in a heap (memtable first, then SSTables from
+/// newest to oldest) and resolves each key from all of its versions, so newer sources
+/// shadow older ones and deleted keys never appear. A failed SSTable read is yielded as
+/// an `Err` item, after which the iterator ends.
+///
+/// The view is fixed when the iterator is created: it copies the memtable's versions in
+/// range and pins the SSTables it reads, so later writes, flushes, and compactions
+/// neither show through nor delete a file from under it. Pinned tables are deleted once
+/// the iterator is dropped.
+pub struct DbIterator {
+    sources: Vec<Source>,
+    heap: BinaryHeap<HeapEntry>,
+    seq: u64,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    /// Whether the sources, and so the keys yielded, descend
+    reverse: bool,
+    range_tombstones: Vec<RangeTombstone>,
+    merge_operator: Option<MergeOperator>,
+    /// Storage and directory of the value log that separated values are read from
+    blobs: (Arc<dyn Storage>, PathBuf),
+    error: Option<io::Error>,
+    done: bool,
+}
+
+impl DbIterator {
+    /// Merge `sources` (newest first) as of sequence number `seq`, restricted to keys in
+    /// `[start, end)` where a missing bound is open. With `reverse` the sources descend
+    /// by key, each key's versions still newest first, and so do the keys yielded.
+    pub(crate) fn new(
+        sources: Vec<Source>,
+        seq: u64,
+        (start, end): (Option<&[u8]>, Option<&[u8]>),
+        reverse: bool,
+        range_tombstones: Vec<RangeTombstone>,
+        merge_operator: Option<MergeOperator>,
+        blobs: (Arc<dyn Storage>, PathBuf),
+    ) -> Self {
+        let mut iter = DbIterator {
+            sources,
+            heap: BinaryHeap::new(),
+            seq,
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+            reverse,
+            range_tombstones,
+            merge_operator,
+            blobs,
+            error: None,
+            done: false,
+        };
+        for source in 0..iter.sources.len() {
+            iter.advance(source);
+        }
+        iter
+    }
+
+    /// Push the next visible version from `source` onto the heap
+    fn advance(&mut self, source: usize) {
+        while let Some(item) = self.sources[source].next() {
+            match item {
+                Ok((key, entry)) => {
+                    if entry.seq > self.seq || self.before_range(&key) {
+                        continue;
+                    }
+                    let reverse = self.reverse;
+                    self.heap.push(HeapEntry { key, entry, source, reverse });
+                    return;
+                }
+                Err(err) => {
+                    self.error.get_or_insert(err);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether `key` comes before the range in the direction of iteration, so that
+    /// sources skip it
+    fn before_range(&self, key: &[u8]) -> bool {
+        match self.reverse {
+            false => self.start.as_deref().is_some_and(|start| key < start),
+            true => self.end.as_deref().is_some_and(|end| key >= end),
+        }
+    }
+
+    /// Whether `key` comes after the range in the direction of iteration, so that the
+    /// merge is over
+    fn past_range(&self, key: &[u8]) -> bool {
+        match self.reverse {
+            false => self.end.as_deref().is_some_and(|end| key >= end),
+            true => self.start.as_deref().is_some_and(|start| key < start),
+        }
+    }
+
+    /// Number of live keys left, resolved without reading separated values or running
+    /// the merge operator
+    pub(crate) fn count_live(mut self) -> io::Result<u64> {
+        let mut count = 0;
+        while let Some(item) = self.next_resolved(false) {
+            item?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Whether any key is left live, stopping at the first
+    pub(crate) fn has_live(mut self) -> io::Result<bool> {
+        Ok(self.next_resolved(false).transpose()?.is_some())
+    }
+
+    /// Next key whose versions resolve to a live value, with that value. Without
+    /// `values`, only liveness is determined and the value returned is meaningless.
+    fn next_resolved(&mut self, values: bool) -> Option<io::Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if let Some(err) = self.error.take() {
+                self.done = true;
+                return Some(Err(err));
+            }
+            if self.done {
+                return None;
+            }
+
+            let Some(first) = self.heap.pop() else {
+                self.done = true;
+                return None;
+            };
+            if self.past_range(&first.key) {
+                self.done = true;
+                return None;
+            }
+
+            // Gather every version of this key; the heap yields them newest first
+            self.advance(first.source);
+            let key = first.key;
+            let mut versions = vec![first.entry];
+            while self.heap.peek().is_some_and(|top| top.key == key) {
+                let Some(next) = self.heap.pop() else { break };
+                self.advance(next.source);
+                versions.push(next.entry);
+            }
+            if self.error.is_some() {
+                continue;
+            }
+
+            let covered_below = covered_below(&self.range_tombstones, &key);
+            let resolved = if values {
+                let merge_operator = self.merge_operator.as_ref();
+                let blobs = (&*self.blobs.0, self.blobs.1.as_path());
+                resolve(&key, versions, covered_below, merge_operator, Some(blobs))
+            } else {
+                resolve(&key, versions, covered_below, None, None)
+            };
+            match resolved {
+                Ok(Some((Some(value), _))) => return Some(Ok((key, value))),
+                Ok(_) => {}
+                Err(err) => self.error = Some(err),
+            }
+        }
+    }
+}
+
+impl Iterator for DbIterator {
+    type Item = io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_resolved(true)
+    }
+}
+
+/// Live keys in order, from [`Db::keys`](crate::Db::keys). Values are never read:
+/// SSTable values are seeked past and merge operands are not combined.
+pub struct KeyIterator {
+    inner: DbIterator,
+}
+
+impl KeyIterator {
+    /// Keys of `inner`, which must have been built over keys-only sources
+    pub(crate) fn new(inner: DbIterator) -> Self {
+        KeyIterator { inner }
+    }
+}
+
+impl Iterator for KeyIterator {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next_resolved(false).map(|item| item.map(|(key, _)| key))
+    }
+}
+
+/// A pending version in the merge heap. The greatest entry is the one to emit next:
+/// smallest key (largest when `reverse`), then highest sequence, then newest source.
+struct HeapEntry {
+    key: Vec<u8>,
+    entry: Entry,
+    source: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let keys = match self.reverse {
+            false => other.key.cmp(&self.key),
+            true => self.key.cmp(&other.key),
+        };
+        keys.then(self.entry.seq.cmp(&other.entry.seq))
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// Sequence of the newest range tombstone covering `key`, or 0 if none does
+pub(crate) fn covered_below(range_tombstones: &[RangeTombstone], key: &[u8]) -> u64 {
+    range_tombstones
+        .iter()
+        .filter(|t| t.covers(key))
+        .map(|t| t.seq)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Resolve `key` from its versions, newest first and all visible at the read sequence:
+/// the value (`None` if deleted) and the sequence of the newest version. Merge operands
+/// are collected until a put or delete serves as their base, or a version older than
+/// `covered_below` (the newest range tombstone covering the key) is reached.
+///
+/// Separated values are read from the value log in `blobs`; without one they
+/// resolve to an empty value, which is enough for callers that only need liveness.
+pub(crate) fn resolve<I>(
+    key: &[u8],
+    versions: I,
+    covered_below: u64,
+    merge_operator: Option<&MergeOperator>,
+    blobs: Option<(&dyn Storage, &Path)>,
+) -> io::Result<Option<(Option<Vec<u8>>, u64)>>
+where
+    I: IntoIterator<Item = Entry>,
+{
+    let now = now_millis();
+    let mut newest = None;
+    let mut operands = Vec::new();
+    let mut base = None;
+    for entry in versions {
+        if entry.seq < covered_below {
+            break;
+        }
+        newest.get_or_insert(entry.seq);
+        match entry.op {
+            // An expired put hides older versions just like a delete
+            Op::Put(_) | Op::Blob(_) if entry.is_expired(now) => break,
+            Op::Put(value) => {
+                base = Some(value);
+                break;
+            }
+            Op::Blob(pointer) => {
+                base = Some(match blobs {
+                    Some((storage, dir)) => pointer.read(storage, dir)?,
+                    None => Vec::new(),
+                });
+                break;
+            }
+            Op::Delete => break,
+            Op::Merge(operand) => operands.push(operand),
+        }
+    }
+
+    let newest = match newest {
+        Some(newest) => newest,
+        None if covered_below > 0 => return Ok(Some((None, covered_below))),
+        None => return Ok(None),
+    };
+    let value = operands.into_iter().rev().fold(base, |value, operand| {
+        Some(merge_value(merge_operator, key, value.as_deref(), &operand))
+    });
+    Ok(Some((value, newest)))
+}
+
+/// Combine `value` with one merge operand. Without a merge operator (a database that
+/// was written with one but reopened without), the operand simply replaces the value.
+fn merge_value(
+    merge_operator: Option<&MergeOperator>,
+    key: &[u8],
+    value: Option<&[u8]>,
+    operand: &[u8],
+) -> Vec<u8> {
+    match merge_operator {
+        Some(merge) => merge(key, value, operand),
+        None => operand.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use std::fs;
+
+    #[test]
+    fn test_newest_source_wins() {
+        let dir = "test_iterator_newest";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("a", "old").unwrap();
+        db.put("dup", "table1").unwrap();
+        db.flush().unwrap();
+        db.put("b", "middle").unwrap();
+        db.put("dup", "table2").unwrap();
+        db.flush().unwrap();
+        db.put("dup", "memtable").unwrap();
+        db.put("c", "new").unwrap();
+
+        let items: Vec<_> = db.iter().collect::<std::io::Result<_>>().unwrap();
+        assert_eq!(
+            items,
+            vec![
+                (b"a".to_vec(), b"old".to_vec()),
+                (b"b".to_vec(), b"middle".to_vec()),
+                (b"c".to_vec(), b"new".to_vec()),
+                (b"dup".to_vec(), b"memtable".to_vec()),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_deleted_keys_skipped() {
+        let dir = "test_iterator_deleted";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("gone", "value").unwrap();
+        db.put("kept", "value").unwrap();
+        db.put("ranged", "value").unwrap();
+        db.flush().unwrap();
+        db.delete("gone").unwrap();
+        db.delete_range("r", "s").unwrap();
+
+        let keys: Vec<_> = db.iter().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, vec![b"kept".to_vec()]);
+
+        db.flush().unwrap();
+        let keys: Vec<_> = db.iter().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, vec![b"kept".to_vec()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_iterator_keeps_its_view_across_flush_and_compaction() {
+        let dir = "test_iterator_pinned";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options {
+            value_log_threshold: Some(32),
+            compaction_threads: Some(2),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(dir, options).unwrap();
+        for round in 0..3 {
+            for i in 0..60 {
+                let value = format!("round {} {}", round, "long ".repeat(i % 10));
+                db.put(format!("key{:03}", i), value).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        for i in (0..60).step_by(7) {
+            db.delete(format!("key{:03}", i)).unwrap();
+        }
+        let expected = db.scan_bytes("a", "z").unwrap();
+        let tables = db.sstable_paths();
+
+        let mut iter = db.iter();
+        let mut rows: Vec<_> = iter.by_ref().take(10).map(Result::unwrap).collect();
+
+        // Overwrite and delete what is still ahead, flush by threshold and by hand, and
+        // compact away every table the iterator reads
+        for i in 0..150 {
+            db.put(format!("key{:03}", i), "after").unwrap();
+        }
+        db.delete_range("key030", "key040").unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert!(db.sstable_paths().iter().all(|path| !tables.contains(path)));
+
+        rows.extend(iter.map(Result::unwrap));
+        assert_eq!(rows, expected);
+        assert!(tables.iter().all(|path| !path.exists()));
+        assert_eq!(db.get("key001"), Some("after".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_database() {
+        let dir = "test_iterator_empty";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.iter().count(), 0);
+        assert!(db.scan_prefix("any").unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = "test_iterator_prefix";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        for key in ["user_1", "user_2", "userx", "video_1"] {
+            db.put(key, "v").unwrap();
+        }
+        db.flush().unwrap();
+        db.put("user_3", "v").unwrap();
+
+        let keys: Vec<_> = db.scan_prefix("user_").unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["user_1", "user_2", "user_3"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Write-optimized LSM-based key-value storage engine.
+
+#[cfg(feature = "tokio")]
+pub mod async_db;
+pub mod backup;
+pub mod batch;
+pub mod client;
+pub mod column_family;
+pub mod compaction;
+mod compression;
+mod config;
+pub mod db;
+mod doctor;
+#[cfg(feature = "encryption")]
+mod encryption;
+pub mod entry;
+pub mod error;
+pub mod event;
+mod export;
+#[cfg(test)]
+mod fault;
+pub mod filter;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod iterator;
+mod lock;
+pub mod memtable;
+mod migrate;
+pub mod options;
+mod quota;
+mod rate_limit;
+mod repair;
+mod replication;
+mod row_cache;
+mod sample;
+pub mod snapshot;
+pub mod sstable;
+pub mod stats;
+pub mod storage;
+mod table_cache;
+pub mod transaction;
+pub mod value_log;
+pub mod wal;
+mod verify;
+mod write_stall;
+
+#[cfg(feature = "tokio")]
+pub use async_db::AsyncDb;
+pub use backup::BackupInfo;
+pub use batch::WriteBatch;
+pub use client::{Client, ClientError, ClientOptions};
+pub use column_family::DEFAULT_CF;
+pub use compaction::{CompactionInfo, CompactionTotals};
+pub use config::Config;
+pub use db::{Db, Listing, Page};
+pub use doctor::{DoctorReport, Finding, Severity, Symptom};
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptionKey, KeyProvider};
+pub use entry::{ValueMeta, ValueSource};
+pub use error::EngineError;
+pub use event::{EventListener, FlushInfo, WalRotateInfo, WriteStallInfo};
+pub use export::{CsvImportOptions, CsvImportSummary, OnMalformed};
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcService;
+pub use iterator::{DbIterator, KeyIterator};
+pub use options::{
+    Compression, MergeOperator, Options, SyncMode, SyncPolicy, DEFAULT_MAX_KEY_SIZE,
+    DEFAULT_MAX_OPEN_FILES, DEFAULT_MAX_VALUE_SIZE, DEFAULT_MEMTABLE_ENTRIES,
+    DEFAULT_WAL_COMPRESSION_THRESHOLD,
+};
+pub use repair::{DamagedFile, RepairReport};
+pub use replication::{Change, Changes, WalRecords};
+pub use snapshot::Snapshot;
+pub use stats::{Latencies, Latency, Stats};
+pub use storage::{FileStorage, MemStorage, Storage};
+pub use transaction::Transaction;
+pub use verify::{Check, CheckResult, VerifyReport};
+pub use write_stall::{WriteStall, SLOWDOWN_DELAY};
@@ -0,0 +1,51 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// Name of the lock file in a database directory
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// Advisory lock on a database directory, held until dropped.
+///
+/// Uses the platform's file locking (`flock` on Unix, `LockFileEx` on Windows), so a
+/// lock left by a crashed process is released by the operating system.
+pub(crate) struct DirLock {
+    _file: Option<File>,
+}
+
+impl DirLock {
+    /// Lock `dir` exclusively, or shared with other shared holders when `shared` is set.
+    ///
+    /// A shared lock is for reading, so it creates nothing: it fails if `dir` is
+    /// missing, and takes no lock if the lock file is, as in a copied database.
+    pub(crate) fn acquire(dir: &Path, shared: bool) -> io::Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let opened = OpenOptions::new()
+            .create(!shared)
+            .write(true)
+            .truncate(false)
+            .open(&path);
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) if shared && e.kind() == io::ErrorKind::NotFound => {
+                if !dir.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("database {} does not exist", dir.display()),
+                    ));
+                }
+                return Ok(DirLock { _file: None });
+            }
+            Err(e) => return Err(e),
+        };
+        let locked = if shared { file.try_lock_shared() } else { file.try_lock() };
+        match locked {
+            Ok(()) => Ok(DirLock { _file: Some(file) }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("database {} is locked by another process", dir.display()),
+            )),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
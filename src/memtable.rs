@@ -1,218 +1,2950 @@
-use std::collections::{HashMap, BTreeMap};
-use crate::wal::WriteAheadLog;
-use crate::sstable::SSTable;
-use std::io;
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::batch::WriteBatch;
+use crate::compaction::{self, CompactionInfo, CompactionTotals, Retention, TableFile};
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptedStorage;
+use crate::entry::{now_millis, Entry, Op, RangeTombstone, ValueMeta, ValueSource};
+use crate::iterator::{covered_below, resolve, DbIterator, KeyIterator, Source};
+use crate::error::EngineError;
+use crate::event::{Event, EventListener, FlushInfo, WalRotateInfo, WriteStallInfo};
+use crate::filter::FilterCache;
+use crate::options::{Compression, MergeOperator, Options, SyncMode, SyncPolicy};
+use crate::quota::DiskUsage;
+use crate::rate_limit::{RateLimitedStorage, RateLimiter};
+use crate::replication::{self, ArchivedWal, WalRecords};
+use crate::row_cache::{AbsentKeys, RowCache};
+use crate::sample::{self, Rng, PICKS_PER_KEY};
+use crate::verify::{self, Check};
+use crate::snapshot::SnapshotList;
+use crate::stats::Counters;
+use crate::storage::Storage;
+use crate::table_cache::TableCache;
+use crate::wal::{self, WalKeys, WalRecord, WriteAheadLog, RECYCLE_SUFFIX, WAL_FILE};
+use crate::sstable::{SSTable, SSTableIterator, SSTableReader, SSTableRevIterator, SSTableWriter};
+use crate::value_log::{self, ValueLog, ValuePointer};
+use crate::write_stall::WriteController;
+use log::{debug, error, info, warn};
+use std::io::{self, Read};
+use std::mem;
+use std::ops::Bound;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size at which bulk ingest starts a new table
+const MAX_INGEST_TABLE_BYTES: u64 = 64 << 20;
+/// Suffix of a table written by bulk ingest that has not been registered yet
+const INGEST_SUFFIX: &str = ".ingest";
+/// Estimated bookkeeping cost of a key in the map beyond its bytes: the key and version
+/// vectors plus a share of the tree node
+const KEY_OVERHEAD: usize = 2 * mem::size_of::<Vec<u8>>() + 16;
+/// Present while ingested tables are being renamed into place
+const INGEST_MARKER: &str = "INGEST_COMMIT";
+/// Suffix of a flushed table until the WAL it replaces has been retired
+const FLUSH_SUFFIX: &str = ".flush";
+/// Separates the WAL's name from the number of the shard whose segment a file is
+const SHARD_SEGMENT: &str = ".shard";
+
+/// Names the live SSTables, one per line, so that opening can tell when one has gone
+/// missing, followed by the line holding the [`CompactionTotals`]. Rewritten whenever
+/// the tables change.
+pub(crate) const TABLE_LIST: &str = "TABLES";
+
+/// An SSTable holding a version, and the offset of the version's value length there
+type Located = (Arc<TableFile>, u64);
+
+/// Keys and common prefixes found by [`MemTable::list`]
+pub type KeyListing = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+/// The versions of the keys that hash to one part of the memtable
+#[derive(Default)]
+struct Shard {
+    /// Versions of each key, oldest first. Overwritten versions are kept (and flushed)
+    /// so reads at an older sequence number stay answerable.
+    data: BTreeMap<Vec<u8>, Vec<Entry>>,
+    /// Logs the writes made through this shard under a shared lock; unset with a
+    /// single shard, whose writes all go to the WAL
+    wal: Option<WriteAheadLog>,
+    /// Versions added so far, so that a read can tell whether a write raced it
+    writes: u64,
+}
 
 pub struct MemTable {
-    data: HashMap<String, String>,
+    /// The versions in memory, split by key hash into [`Options::memtable_shards`]
+    shards: Vec<Mutex<Shard>>,
+    range_tombstones: Vec<RangeTombstone>,
+    entries: AtomicUsize,
+    /// Estimated bytes held by the shards and `range_tombstones`, kept up to date on
+    /// writes
+    memory_usage: AtomicUsize,
     wal: WriteAheadLog,
     wal_path: String,
+    dir: PathBuf,
+    storage: Arc<dyn Storage>,
+    /// `storage` as flushes and compactions see it, paced by `rate_limiter`
+    background_storage: Arc<dyn Storage>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Buffer size for scans and compaction inputs
+    read_ahead: usize,
+    /// Threads each compaction merges on
+    compaction_threads: usize,
+    /// Threads a point lookup probes SSTables on; 1 probes them in turn
+    lookup_threads: usize,
+    /// Read on without a table that has gone missing rather than failing the read
+    allow_missing_tables: bool,
     max_size: usize,
-    sstable_counter: usize,
+    sync_policy: SyncPolicy,
+    sync_mode: SyncMode,
+    /// Size WALs are created at, reusing the file of the last one; unset for growing
+    /// WALs
+    wal_preallocate: Option<u64>,
+    /// How WAL values are compressed, and the shortest compressed
+    wal_compression: Option<(Compression, usize)>,
+    /// How long retired WALs are archived for shipping; unset deletes them
+    wal_retention: Option<Duration>,
+    /// WALs retired by flushes and kept for shipping, oldest first
+    archived_wals: Vec<ArchivedWal>,
+    /// What WAL records are encrypted with
+    wal_keys: WalKeys,
+    /// The live SSTables, oldest first. Compaction leaves gaps in their numbering.
+    tables: Vec<Arc<TableFile>>,
+    /// Number given to the next SSTable written
+    next_table: usize,
+    /// Taken by writes through the shards under a shared lock as well as by those under
+    /// the exclusive one
+    last_seq: AtomicU64,
+    /// The number every write through which has been applied, which reads and snapshots
+    /// are taken at. Behind `last_seq` only while writes through the shards are under
+    /// way, each raising it to its own number once the writes before it have.
+    visible_seq: AtomicU64,
+    snapshots: Arc<SnapshotList>,
+    merge_operator: Option<MergeOperator>,
+    /// Present when large values are separated from keys
+    value_log: Option<ValueLog>,
+    value_log_threshold: usize,
+    max_key_size: usize,
+    max_value_size: usize,
+    counters: Counters,
+    /// Cumulative figures kept in the [`TABLE_LIST`]
+    totals: CompactionTotals,
+    /// Key filter partitions of the SSTables, loaded as lookups need them
+    filters: FilterCache,
+    /// SSTables held open for point lookups
+    readers: TableCache,
+    /// Values recently read from the SSTables alone, dropped when their keys are written
+    rows: RowCache,
+    /// Keys gets recently found absent, dropped when they are written
+    absent: AbsentKeys,
+    listener: Option<Arc<dyn EventListener>>,
+    /// Events raised under the lock, delivered by the caller once it is released
+    events: Vec<Event>,
+    /// Why the last flush a write triggered failed, until a flush succeeds
+    background_error: Option<String>,
+    disk_usage: Arc<DiskUsage>,
+    /// Holds writes back while the SSTables outnumber the configured limits
+    write_controller: Arc<WriteController>,
 }
 
-impl MemTable {
-    pub fn new(wal_path: &str) -> io::Result<Self> {
-        let wal = WriteAheadLog::new(wal_path)?;
-        
-        let mut memtable = MemTable {
-            data: HashMap::new(),
-            wal,
-            wal_path: wal_path.to_string(),
-            max_size: 100, 
-            sstable_counter: 0,
-        };
-        
-        // Replay WAL to recover data
-        memtable.recover()?;
-        
-        Ok(memtable)
+impl MemTable {
+    /// Open a MemTable backed by the WAL at `wal_path`. SSTables live next to the WAL.
+    pub fn new(wal_path: &str) -> io::Result<Self> {
+        Self::with_options(wal_path, &Options::default())
+    }
+
+    pub fn with_options(wal_path: &str, options: &Options) -> io::Result<Self> {
+        let disk_usage = Arc::new(DiskUsage::new(options.max_disk_bytes));
+        let rate_limiter = Arc::new(RateLimiter::new(options.rate_limit_bytes_per_sec()));
+        Self::with_shared(wal_path, options, disk_usage, rate_limiter)
+    }
+
+    /// Open with the files counted in `disk_usage` and flushes and compactions paced by
+    /// `rate_limiter`, both of which may be shared with other column families
+    pub(crate) fn with_shared(
+        wal_path: &str,
+        options: &Options,
+        disk_usage: Arc<DiskUsage>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> io::Result<Self> {
+        let storage = options.storage();
+        #[cfg(feature = "encryption")]
+        let storage = EncryptedStorage::wrap(storage, options.encryption.clone());
+        let dir = Path::new(wal_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Self::check_tables(&*storage, &dir, options.allow_missing_tables)?;
+        Self::recover_unfinished(&*storage, &dir, Path::new(wal_path))?;
+        let wal_keys = WalKeys::from_options(options);
+        let wal_compression = options
+            .wal_compression
+            .map(|compression| (compression, options.wal_compression_threshold()));
+        let wal = Self::open_wal(
+            storage.clone(),
+            wal_path,
+            options.sync_policy,
+            options.wal_sync_mode,
+            options.wal_preallocate_bytes,
+            wal_compression,
+            wal_keys.clone(),
+        )?;
+        compaction::recover(&*storage, &dir)?;
+        compaction::sweep_obsolete(&*storage, &dir)?;
+        let numbers = Self::table_numbers(&*storage, &dir)?;
+        let next_table = numbers.last().map_or(0, |newest| newest + 1);
+        let value_log = match options.value_log_threshold {
+            Some(_) => Some(ValueLog::open(storage.clone(), &dir)?),
+            None => None,
+        };
+        let archived_wals = replication::archived_wals(&*storage, &dir, &wal_keys)?;
+        let totals = Self::read_totals(&*storage, &dir)?;
+        // Nothing is written read-only, so there are no segments to open
+        let shards = if options.read_only { 1 } else { options.memtable_shards() };
+
+        let mut memtable = MemTable {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            range_tombstones: Vec::new(),
+            entries: AtomicUsize::new(0),
+            memory_usage: AtomicUsize::new(0),
+            wal,
+            wal_path: wal_path.to_string(),
+            dir,
+            storage: storage.clone(),
+            background_storage: RateLimitedStorage::wrap(storage.clone(), rate_limiter.clone()),
+            rate_limiter,
+            read_ahead: options.read_ahead(),
+            compaction_threads: options.compaction_threads(),
+            lookup_threads: options.lookup_threads(),
+            allow_missing_tables: options.allow_missing_tables,
+            max_size: options.memtable_entries(),
+            sync_policy: options.sync_policy,
+            sync_mode: options.wal_sync_mode,
+            wal_preallocate: options.wal_preallocate_bytes,
+            wal_compression,
+            wal_retention: options.wal_retention,
+            archived_wals,
+            wal_keys,
+            tables: Vec::new(),
+            next_table,
+            last_seq: AtomicU64::new(0),
+            visible_seq: AtomicU64::new(0),
+            snapshots: Arc::new(SnapshotList::default()),
+            merge_operator: options.merge_operator.clone(),
+            value_log,
+            value_log_threshold: options.value_log_threshold.unwrap_or(usize::MAX),
+            max_key_size: options.max_key_size(),
+            max_value_size: options.max_value_size(),
+            counters: Counters {
+                timed: !options.disable_latency_histograms,
+                ..Counters::default()
+            },
+            totals,
+            filters: FilterCache::new(options.filter_cache_bytes()),
+            readers: TableCache::new(options.max_open_files(), storage),
+            rows: RowCache::new(options.row_cache_bytes.unwrap_or(0)),
+            absent: AbsentKeys::new(options.negative_cache_keys.unwrap_or(0)),
+            listener: options.event_listener.clone(),
+            events: Vec::new(),
+            background_error: None,
+            disk_usage,
+            write_controller: Arc::new(WriteController::new(
+                options.slowdown_writes_sstables,
+                options.stop_writes_sstables,
+            )),
+        };
+
+        memtable.replace_tables(numbers);
+        if !options.read_only {
+            memtable.write_table_list()?;
+        }
+        // Sequence numbers continue from the highest in any table; after a range
+        // compaction that need not be the newest table
+        for table in memtable.tables.clone() {
+            let max_seq = SSTable::max_sequence(&*memtable.storage, table.path())?;
+            memtable.raise_sequence(max_seq);
+        }
+        
+        // Replay WAL to recover data
+        memtable.recover()?;
+        memtable.wal.set_sequence(memtable.last_sequence() + 1);
+        if memtable.shards.len() > 1 {
+            memtable.open_segments()?;
+        }
+        memtable.counters.reset();
+        memtable.disk_usage.grow(memtable.disk_bytes()?);
+        memtable.wal.track_usage(memtable.disk_usage.clone());
+        memtable.wal.count_writes(memtable.counters.wal.clone());
+
+        // A replay that reached the threshold is flushed now rather than on the next
+        // write, so the WAL (and the next startup's replay) doesn't keep growing
+        let full = memtable.size() >= memtable.max_size;
+        let wanted = !options.read_only && (full || options.flush_on_recovery);
+        if wanted && memtable.disk_usage.reserve(memtable.wal_len()?).is_ok() {
+            memtable.flush()?;
+        }
+        memtable.write_controller.update(memtable.tables.len());
+
+        Ok(memtable)
+    }
+
+    /// Numbers of the SSTables in `dir`, in ascending order
+    fn table_numbers(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<usize>> {
+        let mut numbers = Vec::new();
+        for path in storage.list_dir(dir)? {
+            let Some(name) = path.file_name() else { continue };
+            if let Some(n) = table_number(&name.to_string_lossy()) {
+                numbers.push(n);
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    /// Fail with [`EngineError::MissingTables`] if a table the [`TABLE_LIST`] names is
+    /// gone, or with `allow_missing` only log it
+    fn check_tables(storage: &dyn Storage, dir: &Path, allow_missing: bool) -> io::Result<()> {
+        let missing: Vec<String> = Self::missing_tables(storage, dir)?
+            .iter()
+            .map(|name| dir.join(name).to_string_lossy().into_owned())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if !allow_missing {
+            return Err(EngineError::MissingTables { tables: missing }.into());
+        }
+        error!("opening without the missing SSTables {}: their data is lost", missing.join(", "));
+        Ok(())
+    }
+
+    /// Names of the tables in `dir` that the [`TABLE_LIST`] has as live but are not
+    /// there. The inputs of a compaction are left out, as they are deleted before the
+    /// list stops naming them.
+    pub(crate) fn missing_tables(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<String>> {
+        let listed = match storage.read(&dir.join(TABLE_LIST)) {
+            Ok(listed) => String::from_utf8_lossy(&listed).into_owned(),
+            // Never written yet, or removed by a repair
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut removed = compaction::read_obsolete(storage, dir)?;
+        removed.extend(compaction::interrupted_inputs(storage, dir)?);
+        Ok(listed
+            .lines()
+            .filter(|name| !name.starts_with(compaction::TOTALS_PREFIX))
+            .filter(|name| !removed.iter().any(|removed| removed == name))
+            .filter(|name| !storage.exists(&dir.join(name)))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// The [`CompactionTotals`] the [`TABLE_LIST`] in `dir` holds, all 0 without one
+    fn read_totals(storage: &dyn Storage, dir: &Path) -> io::Result<CompactionTotals> {
+        match storage.read(&dir.join(TABLE_LIST)) {
+            Ok(listed) => Ok(CompactionTotals::from_list(&String::from_utf8_lossy(&listed))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(CompactionTotals::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Finish an ingest or flush interrupted by a crash.
+    ///
+    /// With the ingest commit marker present every ingested table was complete, so the
+    /// rest are registered; otherwise they are discarded. A flushed table is installed
+    /// if the WAL it replaces was already retired, and discarded while the WAL remains.
+    fn recover_unfinished(storage: &dyn Storage, dir: &Path, wal_path: &Path) -> io::Result<()> {
+        let marker = dir.join(INGEST_MARKER);
+        let committed = storage.exists(&marker);
+        let wal_retired = !storage.exists(wal_path);
+        for path in storage.list_dir(dir)? {
+            let name = path.to_string_lossy();
+            let (table, install) = match name.strip_suffix(INGEST_SUFFIX) {
+                Some(table) => (table, committed),
+                None => match name.strip_suffix(FLUSH_SUFFIX) {
+                    Some(table) => (table, wal_retired),
+                    None => continue,
+                },
+            };
+            if install {
+                storage.rename(&path, Path::new(table))?;
+            } else {
+                storage.remove(&path)?;
+            }
+        }
+        if committed {
+            storage.remove(&marker)?;
+        }
+        Ok(())
+    }
+
+    fn open_wal(
+        storage: Arc<dyn Storage>,
+        wal_path: &str,
+        sync_policy: SyncPolicy,
+        sync_mode: SyncMode,
+        preallocate: Option<u64>,
+        compression: Option<(Compression, usize)>,
+        keys: WalKeys,
+    ) -> io::Result<WriteAheadLog> {
+        let mut wal = WriteAheadLog::open_log(storage, wal_path, sync_policy, preallocate, keys)?;
+        wal.set_sync_mode(sync_mode);
+        if let Some((compression, threshold)) = compression {
+            wal.set_compression(compression, threshold)?;
+        }
+        Ok(wal)
+    }
+
+    /// Start a WAL segment for each shard, appending to any it already has
+    fn open_segments(&mut self) -> io::Result<()> {
+        for (number, shard) in self.shards.iter_mut().enumerate() {
+            let mut wal = Self::open_wal(
+                self.storage.clone(),
+                &format!("{}{}{}", self.wal_path, SHARD_SEGMENT, number),
+                self.sync_policy,
+                self.sync_mode,
+                None,
+                self.wal_compression,
+                self.wal_keys.clone(),
+            )?;
+            wal.track_usage(self.disk_usage.clone());
+            wal.count_writes(self.counters.wal.clone());
+            shard_mut(shard).wal = Some(wal);
+        }
+        Ok(())
+    }
+
+    /// Paths of the shard segments next to the WAL at `wal_path`, including those of
+    /// shards a memtable opened with fewer shards no longer has
+    fn shard_segments(storage: &dyn Storage, wal_path: &str) -> io::Result<Vec<String>> {
+        let wal_path = Path::new(wal_path);
+        let (Some(dir), Some(wal_name)) = (wal_path.parent(), wal_path.file_name()) else {
+            return Ok(Vec::new());
+        };
+        let mut segments = Vec::new();
+        for path in storage.list_dir(dir)? {
+            let Some(name) = path.file_name() else { continue };
+            if shard_segment(&wal_name.to_string_lossy(), &name.to_string_lossy()).is_some() {
+                segments.push(path.to_string_lossy().into_owned());
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// The shard `key` hashes to
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, Shard> {
+        lock(&self.shards[self.shard_number(key)])
+    }
+
+    fn shard_number(&self, key: &[u8]) -> usize {
+        match self.shards.len() {
+            1 => 0,
+            shards => crc32fast::hash(key) as usize % shards,
+        }
+    }
+
+    /// Whether `key` has versions in memory
+    fn in_memtable(&self, key: &[u8]) -> bool {
+        self.shard(key).data.contains_key(key)
+    }
+
+    /// Keys with versions in memory, across the shards
+    fn memtable_keys(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).data.len()).sum()
+    }
+
+    /// The `n`th key in memory, counting through the shards in turn
+    fn nth_memtable_key(&self, mut n: usize) -> Option<Vec<u8>> {
+        for shard in &self.shards {
+            let shard = lock(shard);
+            match shard.data.keys().nth(n) {
+                Some(key) => return Some(key.clone()),
+                None => n -= shard.data.len(),
+            }
+        }
+        None
+    }
+
+    /// Whether `name` is one of the files a memtable keeps in its directory
+    pub(crate) fn owns_file(name: &str) -> bool {
+        let table = name.strip_suffix(INGEST_SUFFIX).unwrap_or(name);
+        let table = table.strip_suffix(FLUSH_SUFFIX).unwrap_or(table);
+        let table = table.strip_suffix(compaction::COMPACT_SUFFIX).unwrap_or(table);
+        table_number(table).is_some()
+            || name == WAL_FILE
+            || name.strip_suffix(RECYCLE_SUFFIX) == Some(WAL_FILE)
+            || shard_segment(WAL_FILE, name).is_some()
+            || name == INGEST_MARKER
+            || name == compaction::COMPACT_MARKER
+            || name == compaction::OBSOLETE_LIST
+            || name == TABLE_LIST
+            || value_log::is_blob_file(name)
+            || replication::is_archived_wal(name)
+    }
+
+    /// Whether `name` is a file of a flush, ingest, or compaction interrupted by a
+    /// crash, which opening finishes or discards
+    pub(crate) fn is_unfinished_file(name: &str) -> bool {
+        [INGEST_SUFFIX, FLUSH_SUFFIX, compaction::COMPACT_SUFFIX]
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+            || name == INGEST_MARKER
+            || name == compaction::COMPACT_MARKER
+    }
+
+    fn sstable_path(&self, number: usize) -> String {
+        self.dir
+            .join(format!("sstable_{:06}.sst", number))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn table_file(&self, number: usize) -> Arc<TableFile> {
+        TableFile::new(self.sstable_path(number), self.storage.clone())
+    }
+
+    /// Record the live tables in the [`TABLE_LIST`]
+    fn write_table_list(&self) -> io::Result<()> {
+        let mut names = String::new();
+        for table in &self.tables {
+            if let Some(name) = Path::new(table.path()).file_name() {
+                names.push_str(&name.to_string_lossy());
+                names.push('\n');
+            }
+        }
+        names.push_str(&self.totals.to_line());
+        names.push('\n');
+        let pending = self.dir.join(format!("{}.tmp", TABLE_LIST));
+        self.storage.write(&pending, names.as_bytes())?;
+        self.storage.rename(&pending, &self.dir.join(TABLE_LIST))?;
+        self.storage.sync_dir(&self.dir)
+    }
+
+    /// Bring the [`TABLE_LIST`] up to date once the live tables have changed, returning
+    /// whether it is. A list left behind only lacks new tables, which opening accepts.
+    fn update_table_list(&self) -> bool {
+        match self.write_table_list() {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("could not update the table list in {}: {}", self.dir.display(), err);
+                false
+            }
+        }
+    }
+
+    /// Make the tables numbered `numbers` the live set. Tables that drop out are
+    /// retired: their files go once no iterator is reading them.
+    fn replace_tables(&mut self, numbers: Vec<usize>) {
+        let mut previous: BTreeMap<String, Arc<TableFile>> =
+            self.tables.drain(..).map(|table| (table.path().to_string(), table)).collect();
+        self.tables = numbers
+            .into_iter()
+            .map(|number| {
+                let path = self.sstable_path(number);
+                previous.remove(&path).unwrap_or_else(|| self.table_file(number))
+            })
+            .collect();
+        for table in previous.values() {
+            table.retire();
+            self.filters.forget(table.path());
+            self.readers.forget(table.path());
+        }
+    }
+
+    // Replay gives each record the sequence number its log stamped it with, or numbers
+    // it on in log order from the highest one in the tables. Records numbered at or
+    // below that are already in a table, e.g. after a crash that left the table and its
+    // WAL both in place, and are skipped rather than applied twice. The shards'
+    // segments are merged in by number, as their writes took numbers in between.
+    fn recover(&mut self) -> io::Result<()> {
+        let covered = self.last_sequence();
+        let mut records = Vec::new();
+        let mut collect = |seq, record, written_at| records.push((seq, record, written_at));
+        self.wal.replay_numbered(covered, &mut collect)?;
+        for segment in Self::shard_segments(&*self.storage, &self.wal_path)? {
+            let wal = Self::open_wal(
+                self.storage.clone(),
+                &segment,
+                self.sync_policy,
+                self.sync_mode,
+                None,
+                self.wal_compression,
+                self.wal_keys.clone(),
+            )?;
+            wal.replay_numbered(covered, &mut collect)?;
+        }
+        records.sort_by_key(|(seq, _, _)| seq.unwrap_or(0));
+
+        // Expired puts are replayed too; reads treat them as absent
+        for (seq, record, written_at) in records {
+            if record.key().is_some_and(<[u8]>::is_empty) {
+                warn!("skipping a write to the empty key in {}", self.wal_path);
+                continue;
+            }
+            if let Some(seq) = seq {
+                self.raise_sequence(seq - 1);
+            }
+            match record {
+                WalRecord::Put { key, value, expires_at } => {
+                    self.apply_entry(key, Op::Put(value), expires_at, written_at)
+                }
+                WalRecord::PutBlob { key, pointer, expires_at } => {
+                    self.apply_entry(key, Op::Blob(pointer), expires_at, written_at)
+                }
+                WalRecord::Delete { key } => self.apply_entry(key, Op::Delete, None, written_at),
+                WalRecord::Merge { key, operand } => {
+                    self.apply_entry(key, Op::Merge(operand), None, written_at)
+                }
+                WalRecord::DeleteRange { start, end } => self.apply_range_delete(start, end),
+            }
+        }
+        Ok(())
+    }
+
+    /// Assign the next sequence number and record a new version of `key`, stamped with
+    /// the time of the WAL record just written
+    fn apply(&mut self, key: Vec<u8>, op: Op) {
+        self.apply_entry(key, op, None, Some(self.wal.last_write_time()));
+    }
+
+    fn apply_entry(
+        &mut self,
+        key: Vec<u8>,
+        op: Op,
+        expires_at: Option<u64>,
+        written_at: Option<u64>,
+    ) {
+        let seq = self.next_sequence();
+        let mut shard = self.shard(&key);
+        self.add_version(&mut shard, key, Entry { seq, op, expires_at, written_at });
+    }
+
+    /// Add `entry` to the versions of `key` in `shard`, the one the key hashes to
+    fn add_version(&self, shard: &mut Shard, key: Vec<u8>, entry: Entry) {
+        match entry.op {
+            Op::Put(_) | Op::Blob(_) => Counters::add(&self.counters.puts, 1),
+            Op::Delete => Counters::add(&self.counters.deletes, 1),
+            Op::Merge(_) => {}
+        }
+        let mut bytes = mem::size_of::<Entry>() + match &entry.op {
+            Op::Put(value) | Op::Merge(value) => value.len(),
+            Op::Delete | Op::Blob(_) => 0,
+        };
+        if !shard.data.contains_key(&key) {
+            bytes += key.len() + KEY_OVERHEAD;
+        }
+        self.memory_usage.fetch_add(bytes, Ordering::Relaxed);
+        self.rows.invalidate(&key);
+        self.absent.invalidate(&key);
+        shard.data.entry(key).or_default().push(entry);
+        shard.writes += 1;
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn apply_range_delete(&mut self, start: Vec<u8>, end: Vec<u8>) {
+        Counters::add(&self.counters.deletes, 1);
+        let seq = self.next_sequence();
+        let bytes = mem::size_of::<RangeTombstone>() + start.len() + end.len();
+        self.memory_usage.fetch_add(bytes, Ordering::Relaxed);
+        self.rows.invalidate_range(&start, &end);
+        self.range_tombstones.push(RangeTombstone { start, end, seq });
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the next sequence number for a write under the exclusive lock. No write
+    /// through the shards can be under way, so it is visible at once.
+    fn next_sequence(&mut self) -> u64 {
+        let seq = *self.last_seq.get_mut() + 1;
+        self.raise_sequence(seq);
+        seq
+    }
+
+    /// Raise the last sequence number to `seq`, if it is lower, under the exclusive lock
+    fn raise_sequence(&mut self, seq: u64) {
+        let last = self.last_seq.get_mut();
+        *last = (*last).max(seq);
+        *self.visible_seq.get_mut() = *last;
+    }
+
+    /// Make `seq`, taken by a write through a shard, visible once every write numbered
+    /// before it is, so that no read or snapshot sees a write without those before it
+    fn publish(&self, seq: u64) {
+        while self
+            .visible_seq
+            .compare_exchange_weak(seq - 1, seq, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+    }
+
+    /// The WAL, set to stamp its next record with its sequence number when the
+    /// memtable is sharded, as writes through the shards take numbers in between
+    fn main_wal(&mut self) -> &mut WriteAheadLog {
+        if self.shards.len() > 1 {
+            self.wal.set_sequence(self.last_sequence() + 1);
+        }
+        &mut self.wal
+    }
+
+    /// Keys must be non-empty and within [`Options::max_key_size`]
+    fn check_key(&self, key: &[u8]) -> io::Result<()> {
+        if key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "keys must not be empty"));
+        }
+        check_size("key", key.len(), self.max_key_size, "max_key_size")
+    }
+
+    /// Values (and merge operands) may be empty but not over [`Options::max_value_size`]
+    fn check_value(&self, value: &[u8]) -> io::Result<()> {
+        check_size("value", value.len(), self.max_value_size, "max_value_size")
+    }
+
+    /// The version written for a put of `value`: the value itself, or a pointer to it
+    /// once it is over the threshold and has been appended to the value log
+    fn put_op(&mut self, value: Vec<u8>) -> io::Result<Op> {
+        match &mut self.value_log {
+            Some(log) if value.len() > self.value_log_threshold => {
+                Ok(Op::Blob(log.append(&value)?))
+            }
+            _ => Ok(Op::Put(value)),
+        }
+    }
+
+    fn log_put_op(&mut self, key: &[u8], op: &Op, expires_at: Option<u64>) -> io::Result<()> {
+        let wal = self.main_wal();
+        match (op, expires_at) {
+            (Op::Blob(pointer), _) => wal.log_blob(key, pointer, expires_at),
+            (Op::Put(value), Some(expires_at)) => wal.log_put_with_expiry(key, value, expires_at),
+            (Op::Put(value), None) => wal.log_put(key, value),
+            _ => unreachable!("not a put"),
+        }
+    }
+
+    pub fn put<K, V>(&mut self, key: K, value: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let started = self.counters.start();
+        self.check_background_error()?;
+        let (key, value) = (key.into(), value.into());
+        self.check_key(&key)?;
+        self.check_value(&value)?;
+        self.reserve(key.len() + value.len(), 1)?;
+        let op = self.put_op(value)?;
+
+        // Log FIRST (durability)
+        self.log_put_op(&key, &op, None)?;
+        
+        // Then update memory
+        self.apply(key, op);
+        
+        // Check if we need to flush
+        self.flush_if_full();
+        
+        Counters::finish(&self.counters.put_latency, started);
+        Ok(())
+    }
+
+    /// Delete every key in `[start, end)` with a single WAL record and range tombstone.
+    /// An empty range is a no-op.
+    pub fn delete_range<K: AsRef<[u8]>>(&mut self, start: K, end: K) -> io::Result<()> {
+        let (start, end) = (start.as_ref(), end.as_ref());
+        if start >= end {
+            return Ok(());
+        }
+        self.check_background_error()?;
+
+        self.main_wal().log_delete_range(start, end)?;
+        self.apply_range_delete(start.to_vec(), end.to_vec());
+        self.flush_when_full()
+    }
+
+    /// Write `value` so that it stops being visible once `ttl` has elapsed
+    pub fn put_with_ttl<K, V>(&mut self, key: K, value: V, ttl: Duration) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.put_expiring(key.into(), value.into(), Some(expires_at))
+    }
+
+    /// Write `value`, to stop being visible at Unix time `expires_at` in milliseconds
+    /// if that is set
+    fn put_expiring(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> io::Result<()> {
+        let started = self.counters.start();
+        self.check_background_error()?;
+        self.check_key(&key)?;
+        self.check_value(&value)?;
+        self.reserve(key.len() + value.len(), 1)?;
+        let op = self.put_op(value)?;
+        self.log_put_op(&key, &op, expires_at)?;
+        let written_at = Some(self.wal.last_write_time());
+        self.apply_entry(key, op, expires_at, written_at);
+        self.flush_if_full();
+
+        Counters::finish(&self.counters.put_latency, started);
+        Ok(())
+    }
+
+    /// Apply every operation in `batch` atomically: one WAL write, all-or-nothing on replay
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.check_background_error()?;
+        for (key, op) in batch.ops() {
+            self.check_key(key)?;
+            if let Op::Put(value) | Op::Merge(value) = op {
+                self.check_value(value)?;
+            }
+        }
+
+        let has_merge = batch.ops().iter().any(|(_, op)| matches!(op, Op::Merge(_)));
+        if has_merge && self.merge_operator.is_none() {
+            return Err(no_merge_operator());
+        }
+        if batch.ops().iter().any(|(_, op)| !matches!(op, Op::Delete)) {
+            let bytes = batch.ops().iter().map(|(key, op)| match op {
+                Op::Put(value) | Op::Merge(value) => key.len() + value.len(),
+                _ => key.len(),
+            });
+            self.reserve(bytes.sum(), batch.len())?;
+        }
+
+        let separated;
+        let batch = if self.value_log.is_some() {
+            let mut copy = WriteBatch::new();
+            for (key, op) in batch.ops() {
+                let op = match op {
+                    Op::Put(value) => self.put_op(value.clone())?,
+                    op => op.clone(),
+                };
+                copy.push(key.clone(), op);
+            }
+            separated = copy;
+            &separated
+        } else {
+            batch
+        };
+
+        self.main_wal().log_batch(batch)?;
+        for (key, op) in batch.ops() {
+            self.apply(key.clone(), op.clone());
+        }
+        self.flush_when_full()
+    }
+
+    /// Log and apply a write a primary gave sequence number `seq`, which must be the
+    /// next number here, so the write keeps its number. Values are expected inline, as
+    /// [`WalRecords`] yields them; merges need a merge operator here too.
+    pub fn apply_replicated(&mut self, seq: u64, record: WalRecord) -> io::Result<()> {
+        let expected = self.last_sequence() + 1;
+        if seq != expected {
+            return Err(EngineError::ReplicationOutOfOrder { expected, received: seq }.into());
+        }
+        match record {
+            WalRecord::Put { key, value, expires_at } => self.put_expiring(key, value, expires_at),
+            WalRecord::Delete { key } => self.delete(key).map(drop),
+            WalRecord::Merge { key, operand } => self.merge(key, operand),
+            WalRecord::DeleteRange { start, end } => self.delete_range(start, end),
+            WalRecord::PutBlob { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a replicated put must carry its value, not a value log pointer",
+            )),
+        }
+    }
+
+    /// Record a merge operand for `key`, resolved against the older value on read
+    pub fn merge<K, V>(&mut self, key: K, operand: V) -> io::Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        if self.merge_operator.is_none() {
+            return Err(no_merge_operator());
+        }
+
+        self.check_background_error()?;
+        let (key, operand) = (key.into(), operand.into());
+        self.check_key(&key)?;
+        self.check_value(&operand)?;
+        self.reserve(key.len() + operand.len(), 1)?;
+        self.main_wal().log_merge(&key, &operand)?;
+        self.apply(key, Op::Merge(operand));
+        self.flush_if_full();
+
+        Ok(())
+    }
+
+    /// Whether `op`, a put or merge, can be written through its key's shard under a
+    /// shared lock, alongside other such writes. Values the value log would take are
+    /// written under the exclusive lock.
+    pub(crate) fn writes_shared(&self, op: &Op) -> bool {
+        self.shards.len() > 1
+            && match op {
+                Op::Put(value) => value.len() <= self.value_log_threshold,
+                Op::Merge(_) => true,
+                Op::Delete | Op::Blob(_) => false,
+            }
+    }
+
+    /// Log and apply a put or merge of `key` through its shard, under a shared lock.
+    /// The shard is held while the write takes its sequence number, is logged to the
+    /// shard's segment, and is added to its map, so writes to other shards go on at the
+    /// same time. The write becomes visible once those numbered before it have, see
+    /// [`MemTable::last_sequence`]. Returns whether the memtable is full, for the caller
+    /// to flush it under the exclusive lock.
+    pub(crate) fn write_shared(
+        &self,
+        key: Vec<u8>,
+        op: Op,
+        expires_at: Option<u64>,
+    ) -> io::Result<bool> {
+        let started = self.counters.start();
+        self.check_background_error()?;
+        self.wal.ensure_open()?;
+        let value = match &op {
+            Op::Put(value) => value,
+            Op::Merge(operand) if self.merge_operator.is_some() => operand,
+            Op::Merge(_) => return Err(no_merge_operator()),
+            Op::Delete | Op::Blob(_) => unreachable!("only puts and merges are written shared"),
+        };
+        self.check_key(&key)?;
+        self.check_value(value)?;
+        self.reserve(key.len() + value.len(), 1)?;
+
+        let mut shard = self.shard(&key);
+        let Some(wal) = shard.wal.as_mut() else {
+            unreachable!("a memtable with several shards opens their segments");
+        };
+        // Taken under the shard, so that its segment logs its writes in order
+        let seq = self.last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        wal.set_sequence(seq);
+        let logged = match (&op, expires_at) {
+            (Op::Put(value), Some(expires_at)) => wal.log_put_with_expiry(&key, value, expires_at),
+            (Op::Put(value), None) => wal.log_put(&key, value),
+            _ => wal.log_merge(&key, value),
+        };
+        let written_at = Some(wal.last_write_time());
+        let is_put = matches!(op, Op::Put(_));
+        if logged.is_ok() {
+            self.add_version(&mut shard, key, Entry { seq, op, expires_at, written_at });
+        }
+        drop(shard);
+        // A failed write still gives up its number, or the writes after it would wait
+        self.publish(seq);
+        logged?;
+
+        if is_put {
+            Counters::finish(&self.counters.put_latency, started);
+        }
+        Ok(self.size() >= self.max_size)
+    }
+
+    /// Write `op`, a put or merge, to `key` under the exclusive lock
+    pub(crate) fn write_key(
+        &mut self,
+        key: Vec<u8>,
+        op: Op,
+        expires_at: Option<u64>,
+    ) -> io::Result<()> {
+        match op {
+            Op::Put(value) => self.put_expiring(key, value, expires_at),
+            Op::Merge(operand) => self.merge(key, operand),
+            Op::Delete | Op::Blob(_) => unreachable!("not a put or merge"),
+        }
+    }
+
+    /// Apply `new` (a put, or a delete for `None`) only if the current value equals
+    /// `expected` (`None` meaning absent). Returns whether the swap happened.
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> io::Result<bool> {
+        let key = key.as_ref();
+        if self.try_get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => self.put(key, value)?,
+            None => {
+                self.delete(key)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Write `value` only if `key` has no live value; a deleted key counts as absent.
+    /// Returns whether the value was written.
+    pub fn put_if_absent<K, V>(&mut self, key: K, value: V) -> io::Result<bool>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let key = key.into();
+        if self.try_get(&key)?.is_some() {
+            return Ok(false);
+        }
+        self.put(key, value)?;
+        Ok(true)
+    }
+
+    /// The live value of `key`, or else the value made by `default`, which is written
+    /// and returned. `default` is only called when the key is absent or deleted.
+    pub fn get_or_insert_with<K, F>(&mut self, key: K, default: F) -> io::Result<Vec<u8>>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce() -> Vec<u8>,
+    {
+        let key = key.into();
+        if let Some(value) = self.try_get(&key)? {
+            return Ok(value);
+        }
+        let value = default();
+        self.put(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Replace the live value of `key` with what `f` makes of it, `None` standing for an
+    /// absent key on the way in and for a delete on the way out. The result is written
+    /// even when it equals the current value. Returns the new value.
+    pub fn update<K, F>(&mut self, key: K, f: F) -> io::Result<Option<Vec<u8>>>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = key.into();
+        self.check_key(&key)?;
+        let current = self.try_get(&key)?;
+        match f(current.as_deref()) {
+            Some(value) => {
+                self.put(key, value.clone())?;
+                Ok(Some(value))
+            }
+            None => {
+                self.delete(key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Add `delta` to the decimal integer stored at `key` (missing counts as 0) and
+    /// return the new value
+    pub fn increment<K: AsRef<[u8]>>(&mut self, key: K, delta: i64) -> io::Result<i64> {
+        let key = key.as_ref();
+        let invalid = |reason: String| EngineError::InvalidValue {
+            key: String::from_utf8_lossy(key).into_owned(),
+            reason,
+        };
+
+        let current = match self.try_get(key)? {
+            Some(value) => std::str::from_utf8(&value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or_else(|| {
+                    let value = String::from_utf8_lossy(&value);
+                    invalid(format!("{:?} is not an integer", value))
+                })?,
+            None => 0,
+        };
+        let updated = current
+            .checked_add(delta)
+            .ok_or_else(|| invalid(format!("{} + {} overflows", current, delta)))?;
+
+        self.put(key, updated.to_string())?;
+        Ok(updated)
+    }
+
+    /// The live value of `key`. A read that fails, as when an SSTable in use has gone
+    /// missing, is logged and answers `None`; see [`MemTable::try_get`].
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
+        let key = key.as_ref();
+        self.try_get(key).unwrap_or_else(|err| {
+            error!("could not read key {:?}: {}", String::from_utf8_lossy(key), err);
+            None
+        })
+    }
+
+    /// The live value of `key`, failing with [`EngineError::MissingTables`] rather than
+    /// answering `None` when a table that may hold it has gone missing
+    pub fn try_get<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<Vec<u8>>> {
+        let started = self.counters.start();
+        let key = key.as_ref();
+        if self.absent.contains(key) {
+            Counters::add(&self.counters.misses, 1);
+            Counters::finish(&self.counters.get_latency, started);
+            return Ok(None);
+        }
+        let writes = self.shard(key).writes;
+        let value = self.try_lookup(key, u64::MAX)?.and_then(|(value, _)| value);
+        if value.is_none() {
+            // Checked under the shard, so a write that raced the lookup isn't hidden
+            let shard = self.shard(key);
+            if shard.writes == writes {
+                self.absent.insert(key);
+            }
+        }
+        let counter = match value {
+            None => &self.counters.misses,
+            Some(_) if self.in_memtable(key) => &self.counters.memtable_hits,
+            Some(_) => &self.counters.sstable_hits,
+        };
+        Counters::add(counter, 1);
+        Counters::finish(&self.counters.get_latency, started);
+        Ok(value)
+    }
+
+    /// Value of `key` as of sequence number `seq`, with the sequence it was written at
+    pub fn get_at<K: AsRef<[u8]>>(&self, key: K, seq: u64) -> Option<(Vec<u8>, u64)> {
+        self.lookup(key.as_ref(), seq)
+            .and_then(|(value, seq)| value.map(|value| (value, seq)))
+    }
+
+    /// Sequence number of the newest write (put or delete) to `key`
+    pub fn latest_sequence<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.lookup(key.as_ref(), u64::MAX).map(|(_, seq)| seq)
+    }
+
+    /// The live value of `key` with the sequence number, write time, and location of
+    /// its newest version
+    pub fn get_with_metadata<K: AsRef<[u8]>>(&self, key: K) -> Option<ValueMeta> {
+        let key = key.as_ref();
+        let mut newest = None;
+        let versions = self.versions(key, u64::MAX).map_while(|version| match version {
+            Ok((source, entry)) => {
+                newest.get_or_insert((source, entry.written_at));
+                Some(entry)
+            }
+            Err(err) => {
+                error!("could not read key {:?}: {}", String::from_utf8_lossy(key), err);
+                None
+            }
+        });
+        let (value, seq) = self.resolve(key, u64::MAX, versions)?;
+        let (source, written_at) = newest?;
+        Some(ValueMeta { value: value?, seq, written_at, source })
+    }
+
+    /// The live value of `key` as a reader yielding it a chunk at a time, with its
+    /// length, or `None` if the key has none. A value in an SSTable or the value log is
+    /// read from its file, and its table kept from being deleted until the reader is
+    /// dropped; one in the memtable, or merged from operands, is copied out whole.
+    pub fn value_reader<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> io::Result<Option<(Box<dyn Read + Send>, u64)>> {
+        let key = key.as_ref();
+        let in_memtable = self.shard(key).data.get(key).and_then(|v| v.last().cloned());
+        let newest = match in_memtable {
+            Some(entry) => Some((entry, None)),
+            None => self.locate_newest(key)?,
+        };
+        let Some((entry, location)) = newest else { return Ok(None) };
+        let covered_below = covered_below(&self.visible_range_tombstones(u64::MAX), key);
+        if entry.seq < covered_below || entry.is_expired(now_millis()) {
+            return Ok(None);
+        }
+
+        let in_table = |table: &Arc<TableFile>, offset| {
+            SSTable::open_value(&*self.storage, table.path(), offset)
+        };
+        let from_blob = |pointer: ValuePointer| -> io::Result<(Box<dyn Read + Send>, u64)> {
+            let len = u64::from(pointer.len);
+            Ok((Box::new(pointer.open(&*self.storage, &self.dir)?.take(len)), len))
+        };
+        let reader: (Box<dyn Read + Send>, u64) = match (entry.op, location) {
+            (Op::Delete, _) => return Ok(None),
+            (Op::Put(value), None) => {
+                let len = value.len() as u64;
+                (Box::new(io::Cursor::new(value)), len)
+            }
+            (Op::Put(_), Some((table, offset))) => {
+                let (file, len) = in_table(&table, offset)?;
+                (Box::new(Pinned { inner: file.take(len), _pin: table }), len)
+            }
+            (Op::Blob(pointer), None) => from_blob(pointer)?,
+            (Op::Blob(_), Some((table, offset))) => {
+                let (mut file, len) = in_table(&table, offset)?;
+                let mut encoded = vec![0u8; len as usize];
+                file.read_exact(&mut encoded)?;
+                let pointer = ValuePointer::decode(&encoded).ok_or_else(|| {
+                    let message = format!("malformed value pointer in {}", table.path());
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                })?;
+                from_blob(pointer)?
+            }
+            // Operands are only combined in memory
+            (Op::Merge(_), _) => match self.lookup(key, u64::MAX) {
+                Some((Some(value), _)) => {
+                    let len = value.len() as u64;
+                    (Box::new(io::Cursor::new(value)), len)
+                }
+                _ => return Ok(None),
+            },
+        };
+        Ok(Some(reader))
+    }
+
+    /// The newest version of `key` in the SSTables, with its value left out, and the
+    /// table holding it and the offset of its value there
+    fn locate_newest(&self, key: &[u8]) -> io::Result<Option<(Entry, Option<Located>)>> {
+        for table in self.tables.iter().rev() {
+            let reader = self.readers.get(table.path())?;
+            if !self.may_contain(table.path(), &reader, key) {
+                continue;
+            }
+            if let Some((entry, offset)) = reader.locate_versions(key)?.pop() {
+                return Ok(Some((entry, Some((table.clone(), offset)))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolve `key` as of `seq`: the value (`None` if deleted) and the sequence of the
+    /// newest version. SSTables are only read until the key's versions resolve, and not
+    /// at all when the row cache holds the key.
+    fn lookup(&self, key: &[u8], seq: u64) -> Option<(Option<Vec<u8>>, u64)> {
+        self.try_lookup(key, seq).unwrap_or_else(|err| {
+            error!("could not read key {:?}: {}", String::from_utf8_lossy(key), err);
+            None
+        })
+    }
+
+    /// [`MemTable::lookup`], failing if a table it has to read has gone missing
+    fn try_lookup(&self, key: &[u8], seq: u64) -> io::Result<Option<(Option<Vec<u8>>, u64)>> {
+        if let Some((value, written)) = self.rows.get(key, seq) {
+            return Ok(Some((Some(value), written)));
+        }
+        let mut expiring = false;
+        let mut failed = None;
+        let versions = self.versions(key, seq).map_while(|version| match version {
+            Ok((_, entry)) => {
+                expiring |= entry.expires_at.is_some();
+                Some(entry)
+            }
+            Err(err) => {
+                failed = Some(err);
+                None
+            }
+        });
+        let resolved = self.resolve(key, seq, versions);
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        // Only the newest value of a key that lives in SSTables alone is cached, and
+        // never one that could expire while cached. The memtable is checked under the
+        // key's shard, so a write that raced the lookup can't be shadowed by the value.
+        if let Some((Some(value), written)) = &resolved {
+            if seq >= self.last_sequence() && !expiring && self.rows.is_enabled() {
+                let shard = self.shard(key);
+                if !shard.data.contains_key(key) {
+                    self.rows.insert(key, value, *written);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Versions of `key` at or below `seq`, newest first, each with where it is stored,
+    /// and an error for a table that could not be probed. SSTables are read lazily,
+    /// unless lookups probe them on several threads.
+    fn versions<'a>(
+        &'a self,
+        key: &'a [u8],
+        seq: u64,
+    ) -> impl Iterator<Item = io::Result<(ValueSource, Entry)>> + 'a {
+        let memtable_versions = self.shard(key).data.get(key).cloned().unwrap_or_default();
+        let sstable_versions: Box<dyn Iterator<Item = _>> =
+            if self.lookup_threads > 1 && self.tables.len() > 1 {
+                Box::new(self.probe_tables(key).into_iter())
+            } else {
+                Box::new(self.tables.iter().rev().map(move |table| self.probe(table, key)))
+            };
+        std::iter::once(Ok((ValueSource::MemTable, memtable_versions)))
+            .chain(sstable_versions)
+            .flat_map(|probed| {
+                let (found, failed) = match probed {
+                    Ok((source, versions)) => (Some((source, versions)), None),
+                    Err(err) => (None, Some(Err(err))),
+                };
+                let found = found.into_iter().flat_map(|(source, versions)| {
+                    versions.into_iter().rev().map(move |entry| Ok((source.clone(), entry)))
+                });
+                found.chain(failed)
+            })
+            .filter(move |version| !matches!(version, Ok((_, entry)) if entry.seq > seq))
+    }
+
+    /// The versions of `key` in `table`, oldest first. A table that has gone missing
+    /// fails with [`EngineError::MissingTables`], unless the memtable was opened to go
+    /// on without such tables; any other unreadable table is logged and holds none.
+    fn probe(&self, table: &TableFile, key: &[u8]) -> io::Result<(ValueSource, Vec<Entry>)> {
+        let path = table.path();
+        let versions = self.readers.get(path).and_then(|reader| {
+            if !self.may_contain(path, &reader, key) {
+                return Ok(Vec::new());
+            }
+            reader.get_versions(key)
+        });
+        let versions = match versions {
+            Ok(versions) => versions,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !self.allow_missing_tables => {
+                return Err(EngineError::MissingTables { tables: vec![path.to_string()] }.into());
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                error!("SSTable {} is missing; reads go on without it", path);
+                Vec::new()
+            }
+            Err(err) => {
+                warn!("skipping unreadable SSTable {}: {}", path, err);
+                Vec::new()
+            }
+        };
+        Ok((ValueSource::SSTable(PathBuf::from(path)), versions))
+    }
+
+    /// [`MemTable::probe`] every table for `key`, sharing them between
+    /// `lookup_threads` threads. Each thread takes a run of tables and the runs are
+    /// joined in order, so the results are newest first whichever thread finishes first.
+    fn probe_tables(&self, key: &[u8]) -> Vec<io::Result<(ValueSource, Vec<Entry>)>> {
+        let tables: Vec<_> = self.tables.iter().rev().collect();
+        let run = tables.len().div_ceil(self.lookup_threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = tables
+                .chunks(run)
+                .map(|run| {
+                    scope.spawn(move || {
+                        run.iter().map(|table| self.probe(table, key)).collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+
+    /// Whether `table` may hold `key`, by the partition of its key filter covering the
+    /// key. Tables without a usable filter may hold anything.
+    fn may_contain(&self, path: &str, table: &SSTableReader, key: &[u8]) -> bool {
+        let Some(index) = table.filter_index() else { return true };
+        let Some(partition) = index.partition_for(key) else { return false };
+        let filter = self
+            .filters
+            .get_or_load(path, partition, || table.read_filter(&index.partitions[partition]));
+        match filter {
+            Ok(filter) => filter.may_contain(key),
+            Err(err) => {
+                warn!("reading {} without its key filter: {}", path, err);
+                true
+            }
+        }
+    }
+
+    fn resolve<I>(&self, key: &[u8], seq: u64, versions: I) -> Option<(Option<Vec<u8>>, u64)>
+    where
+        I: IntoIterator<Item = Entry>,
+    {
+        let covered_below = covered_below(&self.visible_range_tombstones(seq), key);
+        let merge_operator = self.merge_operator.as_ref();
+        let blobs = Some((&*self.storage, self.dir.as_path()));
+        resolve(key, versions, covered_below, merge_operator, blobs)
+            .unwrap_or_else(|err| {
+                warn!("could not resolve key {:?}: {}", String::from_utf8_lossy(key), err);
+                None
+            })
+    }
+
+    /// Merging iterator over live keys in `[start, end)` as of `seq`; a missing bound
+    /// is open
+    pub fn iter_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        seq: u64,
+    ) -> DbIterator {
+        self.merge_range(start, end, seq, false)
+    }
+
+    /// Number of live keys in `[start, end)` as of `seq`. Values are skipped on disk
+    /// and merge operands are not combined, so this is cheaper than a scan.
+    pub fn count_range(&self, start: &[u8], end: &[u8], seq: u64) -> io::Result<u64> {
+        self.merge_range(Some(start), Some(end), seq, true).count_live()
+    }
+
+    /// Iterator over every key for counting and listing keys; values are skipped on
+    /// disk and left out of memtable versions
+    pub(crate) fn live_keys(&self, seq: u64) -> DbIterator {
+        self.merge_range(None, None, seq, true)
+    }
+
+    /// Like [`MemTable::live_keys`], from the first key at or after `start`
+    pub(crate) fn live_keys_from(&self, start: &[u8], seq: u64) -> DbIterator {
+        self.merge_range(Some(start), None, seq, true)
+    }
+
+    fn merge_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        seq: u64,
+        keys_only: bool,
+    ) -> DbIterator {
+        let empty = matches!((start, end), (Some(start), Some(end)) if start >= end);
+        let mut sources: Vec<Source> = Vec::new();
+        if !empty {
+            let lower = start.map_or(Bound::Unbounded, Bound::Included);
+            let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+            let mut memtable = Vec::new();
+            for shard in &self.shards {
+                for (key, versions) in lock(shard).data.range::<[u8], _>((lower, upper)) {
+                    memtable.extend(versions.iter().rev().map(|entry| {
+                        let entry = if keys_only { entry.without_value() } else { entry.clone() };
+                        (key.clone(), entry)
+                    }));
+                }
+            }
+            // Each shard's keys come in order; the sort being stable keeps each key's
+            // versions newest first
+            if self.shards.len() > 1 {
+                memtable.sort_by(|(a, _), (b, _)| a.cmp(b));
+            }
+            sources.push(Box::new(memtable.into_iter().map(Ok)));
+
+            for file in self.tables.iter().rev() {
+                let table =
+                    SSTableIterator::open_sequential(&*self.storage, file.path(), self.read_ahead)
+                        .and_then(|mut table| {
+                            if let Some(start) = start {
+                                table.skip_to(start)?;
+                            }
+                            Ok(table)
+                        });
+                let source: Source = match table {
+                    Ok(table) => {
+                        let inner: Source =
+                            if keys_only { Box::new(table.keys_only()) } else { Box::new(table) };
+                        Box::new(Pinned { inner, _pin: file.clone() })
+                    }
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                };
+                sources.push(source);
+            }
+        }
+
+        DbIterator::new(
+            sources,
+            seq,
+            (start, end),
+            false,
+            self.visible_range_tombstones(seq),
+            self.merge_operator.clone(),
+            (self.storage.clone(), self.dir.clone()),
+        )
+    }
+
+    /// Merging iterator over live keys in `[start, end)` as of `seq`, from the highest
+    /// key down. The memtable's versions in range are copied as for a forward merge;
+    /// the SSTables are read a block at a time down from `end`, so a merge that is
+    /// stopped early reads only the blocks it reached.
+    pub fn iter_range_rev(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        seq: u64,
+    ) -> DbIterator {
+        let empty = matches!((start, end), (Some(start), Some(end)) if start >= end);
+        let mut sources: Vec<Source> = Vec::new();
+        if !empty {
+            let lower = start.map_or(Bound::Unbounded, Bound::Included);
+            let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+            let mut memtable = Vec::new();
+            for shard in &self.shards {
+                for (key, versions) in lock(shard).data.range::<[u8], _>((lower, upper)).rev() {
+                    let newest_first = versions.iter().rev();
+                    memtable.extend(newest_first.map(|entry| (key.clone(), entry.clone())));
+                }
+            }
+            // Stable, like the forward merge's sort, so each key's versions stay newest first
+            if self.shards.len() > 1 {
+                memtable.sort_by(|(a, _), (b, _)| b.cmp(a));
+            }
+            sources.push(Box::new(memtable.into_iter().map(Ok)));
+
+            for file in self.tables.iter().rev() {
+                let source: Source =
+                    match SSTableRevIterator::open(&*self.storage, file.path(), end) {
+                        Ok(table) => Box::new(Pinned { inner: table, _pin: file.clone() }),
+                        Err(err) => Box::new(std::iter::once(Err(err))),
+                    };
+                sources.push(source);
+            }
+        }
+
+        DbIterator::new(
+            sources,
+            seq,
+            (start, end),
+            true,
+            self.visible_range_tombstones(seq),
+            self.merge_operator.clone(),
+            (self.storage.clone(), self.dir.clone()),
+        )
+    }
+
+    /// Smallest live key as of `seq`, found by merging only until the first live key
+    pub fn first_key(&self, seq: u64) -> io::Result<Option<Vec<u8>>> {
+        KeyIterator::new(self.live_keys(seq)).next().transpose()
+    }
+
+    /// Live keys under `prefix` as of `seq` with no `delimiter` after the prefix, and the
+    /// distinct common prefixes, each ending in `delimiter`, that the other keys roll up
+    /// into. The merge restarts past each common prefix found, so the keys under it are
+    /// never visited. An empty delimiter rolls nothing up.
+    pub fn list(&self, prefix: &[u8], delimiter: &[u8], seq: u64) -> io::Result<KeyListing> {
+        let end = prefix_end(prefix);
+        let (mut keys, mut common_prefixes) = (Vec::new(), Vec::new());
+        let mut start = prefix.to_vec();
+        'merge: loop {
+            let merged = self.merge_range(Some(&start), end.as_deref(), seq, true);
+            for key in KeyIterator::new(merged) {
+                let key = key?;
+                let rest = &key[prefix.len()..];
+                let found = match delimiter.len() {
+                    0 => None,
+                    len => rest.windows(len).position(|window| window == delimiter),
+                };
+                let Some(at) = found else {
+                    keys.push(key);
+                    continue;
+                };
+                let common = key[..prefix.len() + at + delimiter.len()].to_vec();
+                let next = prefix_end(&common);
+                common_prefixes.push(common);
+                match next {
+                    Some(next) => {
+                        start = next;
+                        continue 'merge;
+                    }
+                    None => break 'merge,
+                }
+            }
+            break;
+        }
+        Ok((keys, common_prefixes))
+    }
+
+    /// Largest live key as of `seq`. The key space is searched downwards in windows
+    /// between the SSTables' block index boundaries, so each window reads about a block
+    /// of every table, and only windows holding nothing live lead to the next one down.
+    pub fn last_key(&self, seq: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut boundaries = Vec::new();
+        for table in &self.tables {
+            boundaries.extend(self.readers.get(table.path())?.index_boundaries());
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut end: Option<Vec<u8>> = None;
+        loop {
+            let start = match &end {
+                Some(end) => boundaries.iter().rev().find(|key| *key < end),
+                None => boundaries.last(),
+            };
+            let window = self.merge_range(start.map(Vec::as_slice), end.as_deref(), seq, true);
+            let mut last = None;
+            for key in KeyIterator::new(window) {
+                last = Some(key?);
+            }
+            if last.is_some() || start.is_none() {
+                return Ok(last);
+            }
+            end = start.cloned();
+        }
+    }
+
+    /// Up to `n` live keys as of `seq`, picked at random and returned in key order.
+    /// See [`Db::sample_keys`](crate::Db::sample_keys) for how far from uniform that is.
+    pub fn sample_keys(&self, n: usize, seq: u64) -> io::Result<Vec<Vec<u8>>> {
+        let mut rng = Rng::new();
+        let mut weights = vec![self.memtable_keys() as u64];
+        let mut readers = Vec::with_capacity(self.tables.len());
+        for table in &self.tables {
+            let reader = self.readers.get(table.path())?;
+            weights.push(reader.header().entries);
+            readers.push(reader);
+        }
+        let total: u64 = weights.iter().sum();
+        if n == 0 || total == 0 {
+            return Ok(Vec::new());
+        }
+        // Few enough versions to list every live key and sample exactly
+        if total <= (n * PICKS_PER_KEY) as u64 {
+            let mut keys = KeyIterator::new(self.live_keys(seq)).collect::<io::Result<Vec<_>>>()?;
+            while keys.len() > n {
+                keys.swap_remove(rng.index(keys.len()));
+            }
+            keys.sort_unstable();
+            return Ok(keys);
+        }
+
+        let mut sample = BTreeSet::new();
+        for _ in 0..n * PICKS_PER_KEY {
+            if sample.len() == n {
+                break;
+            }
+            let mut pick = rng.below(total);
+            let mut source = 0;
+            while pick >= weights[source] {
+                pick -= weights[source];
+                source += 1;
+            }
+            let key = if source == 0 {
+                self.nth_memtable_key(pick as usize)
+            } else {
+                let (reader, path) = (&readers[source - 1], self.tables[source - 1].path());
+                let boundaries = reader.index_boundaries();
+                let span = rng.index(boundaries.len().max(1));
+                let after = span.checked_sub(1).map(|i| boundaries[i].as_slice());
+                let through = boundaries.get(span).map(Vec::as_slice);
+                let keys = sample::span_keys(&*self.storage, path, after, through)?;
+                (!keys.is_empty()).then(|| keys[rng.index(keys.len())].clone())
+            };
+            let Some(key) = key else { continue };
+            if !sample.contains(&key) && matches!(self.lookup(&key, seq), Some((Some(_), _))) {
+                sample.insert(key);
+            }
+        }
+        Ok(sample.into_iter().collect())
+    }
+
+    /// Live key-value pairs in `[start, end)`, in key order
+    pub fn scan<K: AsRef<[u8]>>(&self, start: K, end: K) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_at(start.as_ref(), end.as_ref(), u64::MAX)
+    }
+
+    /// Live key-value pairs in `[start, end)`, from the highest key down
+    pub fn scan_rev<K: AsRef<[u8]>>(
+        &self,
+        start: K,
+        end: K,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_rev_at(start.as_ref(), end.as_ref(), u64::MAX)
+    }
+
+    /// Live key-value pairs in `[start, end)` as of `seq`, from the highest key down,
+    /// merged in that order. Version resolution is identical to the forward scan.
+    pub fn scan_rev_at(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        seq: u64,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.iter_range_rev(Some(start), Some(end), seq).collect()
+    }
+
+    /// Live key-value pairs in `[start, end)` as of sequence number `seq`
+    pub fn scan_at(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        seq: u64,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.iter_range(Some(start), Some(end), seq).collect()
+    }
+
+    /// Write a tombstone for `key`, returning the live value it hides, wherever that is
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> io::Result<Option<Vec<u8>>> {
+        let started = self.counters.start();
+        self.check_background_error()?;
+        let key = key.as_ref();
+        self.check_key(key)?;
+        // The live value wherever it is, so a flushed key reports what it held too
+        let result = self.try_lookup(key, u64::MAX)?.and_then(|(value, _)| value);
+        self.main_wal().log_delete(key)?;
+        self.apply(key.to_vec(), Op::Delete);
+        
+        Counters::finish(&self.counters.delete_latency, started);
+        Ok(result)
+    }
+
+    /// Range tombstones from the memtable and every SSTable with a sequence at or
+    /// below `seq`
+    fn visible_range_tombstones(&self, seq: u64) -> Vec<RangeTombstone> {
+        let flushed = self.tables.iter().flat_map(|table| {
+            let path = table.path();
+            match self.readers.get(path) {
+                Ok(reader) => reader.range_tombstones().to_vec(),
+                Err(err) => {
+                    warn!("skipping range tombstones of unreadable SSTable {}: {}", path, err);
+                    Vec::new()
+                }
+            }
+        });
+        self.range_tombstones
+            .iter()
+            .cloned()
+            .chain(flushed)
+            .filter(|t| t.seq <= seq)
+            .collect()
+    }
+
+    /// Make every write logged so far durable, whatever the WAL
+    /// [`SyncPolicy`] left unsynced. Costs nothing when there is no such write.
+    pub fn sync(&mut self) -> io::Result<()> {
+        for shard in &mut self.shards {
+            if let Some(wal) = &mut shard_mut(shard).wal {
+                wal.sync()?;
+            }
+        }
+        self.wal.sync()
+    }
+
+    /// Write the current contents to a new SSTable and truncate the WAL.
+    ///
+    /// The table is written under a temporary name, the WAL is removed, and then the
+    /// table is renamed into place, so a crash at any point leaves exactly one of the
+    /// two to recover from. A failure after the WAL is gone closes the memtable; its
+    /// contents stay readable and reopening installs the table.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.wal.ensure_open()?;
+        if self.memtable_keys() == 0 && self.range_tombstones.is_empty() {
+            return Ok(());
+        }
+
+        // The table written holds about as much as the WAL it replaces
+        self.disk_usage.reserve(self.wal_len()?)?;
+        let timer = self.counters.start();
+        let number = self.next_table;
+        let sstable_path = self.sstable_path(number);
+        self.next_table += 1;
+        let started = Instant::now();
+        self.raise(Event::FlushBegin {
+            path: PathBuf::from(&sstable_path),
+            entries: self.size(),
+        });
+
+        let pending = format!("{}{}", sstable_path, FLUSH_SUFFIX);
+        let written = {
+            let shards: Vec<_> = self.shards.iter().map(lock).collect();
+            let mut data: Vec<_> = shards.iter().flat_map(|shard| &shard.data).collect();
+            // Each shard's keys come in order, and no key is in two
+            if shards.len() > 1 {
+                data.sort_unstable_by_key(|(key, _)| *key);
+            }
+            SSTable::write_versions(
+                &*self.background_storage,
+                &pending,
+                data,
+                &self.range_tombstones,
+                self.last_sequence(),
+                self.sync_mode,
+            )
+        };
+        let retired = written.and_then(|()| self.wal_len()).and_then(|retired_bytes| {
+            self.retire_wal()?;
+            // The WAL must be gone for good before the table can appear
+            self.storage.sync_dir(&self.dir)?;
+            Ok(retired_bytes)
+        });
+        let retired_bytes = match retired {
+            Ok(retired_bytes) => retired_bytes,
+            Err(err) => {
+                if let Err(err) = self.storage.remove(Path::new(&pending)) {
+                    warn!("could not remove unfinished flush output {}: {}", pending, err);
+                }
+                return Err(err);
+            }
+        };
+        self.disk_usage.shrink(retired_bytes);
+
+        if let Err(err) = self.install_flush(&pending, number, retired_bytes, started) {
+            self.close("a flush failed after retiring the WAL; reopen to recover");
+            return Err(err);
+        }
+        self.background_error = None;
+        Counters::finish(&self.counters.flush_latency, timer);
+        Ok(())
+    }
+
+    /// Archive the WAL, and the shards' segments with it, if WALs are retained for
+    /// shipping, or else remove it, keeping its file to be reused for the next one if
+    /// WALs are preallocated
+    fn retire_wal(&mut self) -> io::Result<()> {
+        let path = Path::new(&self.wal_path);
+        if self.wal_retention.is_some() {
+            let archived = replication::archive_path(&self.dir, self.last_sequence());
+            let mut segments = Vec::new();
+            for segment in Self::shard_segments(&*self.storage, &self.wal_path)? {
+                let mut segment_archive = archived.clone().into_os_string();
+                segment_archive.push(&segment[self.wal_path.len()..]);
+                self.storage.rename(Path::new(&segment), Path::new(&segment_archive))?;
+                segments.push(PathBuf::from(segment_archive));
+            }
+            self.storage.rename(path, &archived)?;
+            let shard_writes = self.shards.iter_mut().filter_map(|shard| {
+                shard_mut(shard).wal.as_ref().map(WriteAheadLog::last_write_time)
+            });
+            // A WAL only replayed since opening has no write time of its own yet
+            let written_at = match shard_writes.fold(self.wal.last_write_time(), u64::max) {
+                0 => now_millis(),
+                written_at => written_at,
+            };
+            self.archived_wals.push(ArchivedWal {
+                path: archived,
+                segments,
+                last_seq: self.last_sequence(),
+                written_at,
+            });
+            return Ok(());
+        }
+        match self.wal_preallocate {
+            Some(_) => {
+                let recycle = wal::recycle_path(&self.wal_path);
+                self.storage.rename(path, Path::new(&recycle))
+            }
+            None => self.storage.remove(path),
+        }
+    }
+
+    /// Second half of a flush, once the WAL has been retired: put the table written to
+    /// `pending` in place and start an empty WAL
+    fn install_flush(
+        &mut self,
+        pending: &str,
+        number: usize,
+        retired_bytes: u64,
+        started: Instant,
+    ) -> io::Result<()> {
+        let sstable_path = self.sstable_path(number);
+        self.storage.rename(Path::new(pending), Path::new(&sstable_path))?;
+        self.storage.sync_dir(&self.dir)?;
+        self.tables.push(self.table_file(number));
+        let bytes = self.storage.file_len(Path::new(&sstable_path))?;
+        self.totals.bytes_flushed += bytes;
+        self.update_table_list();
+        self.disk_usage.grow(bytes);
+        Counters::add(&self.counters.flushes, 1);
+        Counters::add(&self.counters.bytes_flushed, bytes);
+        self.raise(Event::FlushComplete(FlushInfo {
+            path: PathBuf::from(&sstable_path),
+            entries: self.size(),
+            bytes,
+            duration: started.elapsed(),
+        }));
+
+        debug!("flushed {} entries to {}", self.size(), sstable_path);
+
+        for shard in &mut self.shards {
+            shard_mut(shard).data.clear();
+        }
+        self.range_tombstones.clear();
+        *self.entries.get_mut() = 0;
+        *self.memory_usage.get_mut() = 0;
+
+        self.wal = Self::open_wal(
+            self.storage.clone(),
+            &self.wal_path,
+            self.sync_policy,
+            self.sync_mode,
+            self.wal_preallocate,
+            self.wal_compression,
+            self.wal_keys.clone(),
+        )?;
+        self.wal.set_sequence(self.last_sequence() + 1);
+        // The table holds what the shards' segments logged too
+        for shard in &mut self.shards {
+            shard_mut(shard).wal = None;
+        }
+        for segment in Self::shard_segments(&*self.storage, &self.wal_path)? {
+            self.storage.remove(Path::new(&segment))?;
+        }
+        if self.shards.len() > 1 {
+            self.open_segments()?;
+        }
+        self.disk_usage.grow(self.wal_len()?);
+        self.wal.track_usage(self.disk_usage.clone());
+        self.wal.count_writes(self.counters.wal.clone());
+        self.raise(Event::WalRotate(WalRotateInfo {
+            path: PathBuf::from(&self.wal_path),
+            retired_bytes,
+        }));
+        self.prune_archived_wals();
+        Ok(())
+    }
+
+    /// Remove the archived WALs older than the retention, or every one of them once
+    /// WALs are no longer retained
+    fn prune_archived_wals(&mut self) {
+        let cutoff = self
+            .wal_retention
+            .map(|retention| now_millis().saturating_sub(retention.as_millis() as u64));
+        self.archived_wals.retain(|wal| {
+            if cutoff.is_some_and(|cutoff| wal.written_at >= cutoff) {
+                return true;
+            }
+            // Segments first, so that a WAL still listed keeps every file not removed
+            let removed = wal.files().iter().rev().try_for_each(|path| {
+                match self.storage.remove(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    removed => removed,
+                }
+            });
+            match removed {
+                Ok(()) => false,
+                Err(err) => {
+                    warn!("could not remove archived WAL {}: {}", wal.path.display(), err);
+                    true
+                }
+            }
+        });
+    }
+
+    /// Every record logged after sequence number `seq`, from the archived WALs and the
+    /// live one, with the shards' segments merged in; see [`WalRecords`]. The live WAL
+    /// is read now, so flushes while the records are consumed cannot cause any to be
+    /// missed.
+    pub fn wal_records_since(&self, seq: u64) -> io::Result<WalRecords> {
+        // Writes through the shards still under way are left for the next call
+        let last_seq = self.last_sequence();
+        let segments = Self::shard_segments(&*self.storage, &self.wal_path)?;
+        let mut live = vec![PathBuf::from(&self.wal_path)];
+        live.extend(segments.into_iter().map(PathBuf::from));
+        let mut live = replication::read_logs(&*self.storage, &self.dir, &live, &self.wal_keys)?;
+        live.retain(|(record_seq, _)| *record_seq <= last_seq);
+        let archived = self
+            .archived_wals
+            .iter()
+            .filter(|wal| wal.last_seq > seq)
+            .map(ArchivedWal::files)
+            .collect();
+        Ok(WalRecords::new(
+            self.storage.clone(),
+            self.dir.clone(),
+            self.wal_keys.clone(),
+            archived,
+            live,
+            seq,
+            last_seq,
+        ))
+    }
+
+    /// Merge every SSTable into as few tables as possible. Overwritten versions that no
+    /// snapshot reads are dropped, and so are tombstones once nothing older is left
+    /// beneath them; range tombstones are kept. With fewer than two tables there is
+    /// nothing to do.
+    pub fn compact(&mut self) -> io::Result<CompactionInfo> {
+        self.compact_newest(self.tables.len())
+    }
+
+    /// Merge the `count` newest SSTables like [`MemTable::compact`], leaving older tables
+    /// as they are. Tombstones are carried forward unless every table is merged, since
+    /// an older table may still hold what they delete.
+    pub fn compact_newest(&mut self, count: usize) -> io::Result<CompactionInfo> {
+        self.wal.ensure_open()?;
+        let first_input = self.tables.len().saturating_sub(count);
+        if self.tables.len() - first_input < 2 {
+            let inputs: Vec<String> =
+                self.tables[first_input..].iter().map(|t| t.path().to_string()).collect();
+            let input_bytes = total_size(&*self.storage, &inputs)?;
+            return Ok(CompactionInfo {
+                input_tables: inputs.len(),
+                input_bytes,
+                output_tables: inputs.len(),
+                output_bytes: input_bytes,
+                ..CompactionInfo::default()
+            });
+        }
+        let selected = (0..self.tables.len()).map(|i| i >= first_input).collect();
+        self.compact_selected(selected, first_input == 0)
+    }
+
+    /// Merge only the SSTables holding keys in `[start, end)`, a missing bound being
+    /// open, so a region full of deletes can be reclaimed without rewriting the rest.
+    ///
+    /// The merged tables take the place of the newest, so a newer table sharing keys
+    /// with a chosen one is merged too, keeping the newest version of each key in the
+    /// newest table. Tombstones are dropped when no table left out could hold what
+    /// they delete.
+    pub fn compact_range(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> io::Result<CompactionInfo> {
+        self.wal.ensure_open()?;
+        let mut spans = Vec::with_capacity(self.tables.len());
+        for table in &self.tables {
+            spans.push(self.readers.get(table.path())?.key_span()?);
+        }
+        let overlaps = |(first, last): &(Vec<u8>, Vec<u8>), (lo, hi): &(Vec<u8>, Vec<u8>)| {
+            first <= hi && lo <= last
+        };
+        let in_range = |(first, last): &(Vec<u8>, Vec<u8>)| {
+            start.is_none_or(|start| last.as_slice() >= start)
+                && end.is_none_or(|end| first.as_slice() < end)
+        };
+        let hull = |covered: Option<(Vec<u8>, Vec<u8>)>, (first, last): &(Vec<u8>, Vec<u8>)| {
+            Some(match covered {
+                Some((lo, hi)) => (lo.min(first.clone()), hi.max(last.clone())),
+                None => (first.clone(), last.clone()),
+            })
+        };
+
+        // Oldest first, so every table a selected one pulls in is checked after it
+        let mut selected = vec![false; spans.len()];
+        let mut covered = None;
+        for (i, span) in spans.iter().enumerate() {
+            let Some(span) = span else { continue };
+            if in_range(span) || covered.as_ref().is_some_and(|c| overlaps(span, c)) {
+                selected[i] = true;
+                covered = hull(covered, span);
+            }
+        }
+        let Some(covered) = covered else {
+            return Ok(CompactionInfo::default());
+        };
+        let bottommost = spans
+            .iter()
+            .zip(&selected)
+            .all(|(span, &chosen)| chosen || span.as_ref().is_none_or(|s| !overlaps(s, &covered)));
+        self.compact_selected(selected, bottommost)
+    }
+
+    /// Merge the SSTables flagged in `selected` into new tables placed after all the
+    /// others, which must not change what any read finds. `bottommost` tells whether
+    /// no table left out can hold what the tombstones delete.
+    fn compact_selected(
+        &mut self,
+        selected: Vec<bool>,
+        bottommost: bool,
+    ) -> io::Result<CompactionInfo> {
+        let mut chosen = Vec::new();
+        let mut kept = Vec::new();
+        for (table, selected) in self.tables.iter().zip(selected) {
+            if selected { &mut chosen } else { &mut kept }.push(table.clone());
+        }
+        let inputs: Vec<String> = chosen.iter().map(|t| t.path().to_string()).collect();
+        let input_bytes = total_size(&*self.storage, &inputs)?;
+
+        let timer = self.counters.start();
+        let started = Instant::now();
+        let first = self.next_table;
+        let retention = Retention { snapshots: self.snapshots.pinned(), bottommost };
+        let merged = compaction::merge_tables(
+            &*self.background_storage,
+            &inputs,
+            self.read_ahead,
+            self.sync_mode,
+            &retention,
+            self.compaction_threads,
+            |i| self.sstable_path(first + i),
+        )?;
+        let outputs = merged.outputs;
+        self.next_table = first + outputs.len();
+        if let Err(err) = compaction::install(&*self.storage, &self.dir, &inputs, &outputs) {
+            // Part of the compaction may be on disk already: settle it the way opening
+            // would and carry on with whichever tables are left
+            let recovered = compaction::recover(&*self.storage, &self.dir)
+                .and_then(|()| Self::table_numbers(&*self.storage, &self.dir));
+            match recovered {
+                Ok(tables) => {
+                    self.replace_tables(tables);
+                    self.update_table_list();
+                }
+                Err(_) => self.close("a compaction failed part-way; reopen to recover"),
+            }
+            return Err(err);
+        }
+        // Inputs no iterator is reading are deleted here, the rest when it is dropped
+        for input in chosen {
+            input.retire();
+            self.filters.forget(input.path());
+            self.readers.forget(input.path());
+        }
+        self.tables = kept;
+        for number in first..first + outputs.len() {
+            let table = self.table_file(number);
+            self.tables.push(table);
+        }
+        let info = CompactionInfo {
+            input_tables: inputs.len(),
+            input_bytes,
+            output_tables: outputs.len(),
+            output_bytes: total_size(&*self.storage, &outputs)?,
+            keys: merged.keys,
+            versions_dropped: merged.versions_dropped,
+            tombstones_dropped: merged.tombstones_dropped,
+            duration: started.elapsed(),
+        };
+        self.totals.add(&info);
+        // The obsolete list keeps naming the deleted inputs until the table list no
+        // longer does
+        if self.update_table_list() {
+            if let Err(err) = compaction::prune_obsolete(&*self.storage, &self.dir) {
+                let dir = self.dir.display();
+                warn!("could not update the obsolete table list in {}: {}", dir, err);
+            }
+        }
+
+        self.counters.add_compaction(&info);
+        self.disk_usage.grow(info.output_bytes);
+        self.disk_usage.shrink(info.input_bytes);
+        info!(
+            "compacted {} tables ({} bytes) into {} ({} bytes) in {}: {} keys, {} versions and \
+             {} tombstones dropped",
+            info.input_tables,
+            info.input_bytes,
+            info.output_tables,
+            info.output_bytes,
+            self.dir.display(),
+            info.keys,
+            info.versions_dropped,
+            info.tombstones_dropped
+        );
+        self.raise(Event::CompactionComplete(info.clone()));
+        Counters::finish(&self.counters.compaction_latency, timer);
+        Ok(info)
+    }
+
+    /// Stop accepting writes: every later write or flush fails with `reason`
+    pub(crate) fn close(&mut self, reason: &'static str) {
+        self.wal.close(reason);
+    }
+
+    /// Write pre-sorted key-value pairs straight into new SSTables, bypassing the WAL,
+    /// and return how many were ingested.
+    ///
+    /// Keys must be strictly increasing; otherwise the ingest fails with `InvalidInput`
+    /// and nothing is added. The memtable is flushed first because the ingested rows
+    /// are newer than anything already written: they share one sequence number, so
+    /// later writes shadow them as usual. Tables are written under temporary names and
+    /// renamed into place only once all of them are complete.
+    pub fn ingest<I, K, V>(&mut self, rows: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.try_ingest(rows.into_iter().map(Ok))
+    }
+
+    /// Like [`MemTable::ingest`], for input that can fail part-way: the first error
+    /// aborts the ingest and nothing is added
+    pub(crate) fn try_ingest<I, K, V>(&mut self, rows: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = io::Result<(K, V)>>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.flush()?;
+        // The ingested rows shadow whatever values were cached for their keys
+        self.rows.clear();
+        self.absent.clear();
+        let seq = self.last_sequence() + 1;
+
+        let mut tables = Vec::new();
+        let count = match self.write_ingest_tables(rows, seq, &mut tables) {
+            Ok(count) => count,
+            Err(err) => {
+                for table in &tables {
+                    let path = format!("{}{}", table, INGEST_SUFFIX);
+                    if let Err(err) = self.storage.remove(Path::new(&path)) {
+                        warn!("could not remove unfinished ingest table {}: {}", path, err);
+                    }
+                }
+                return Err(err);
+            }
+        };
+        if tables.is_empty() {
+            return Ok(0);
+        }
+
+        let marker = self.dir.join(INGEST_MARKER);
+        self.storage.write(&marker, &[])?;
+        self.storage.sync_dir(&self.dir)?;
+        for table in &tables {
+            let pending = format!("{}{}", table, INGEST_SUFFIX);
+            self.storage.rename(Path::new(&pending), Path::new(table))?;
+        }
+        self.storage.sync_dir(&self.dir)?;
+        self.storage.remove(&marker)?;
+        self.disk_usage.grow(total_size(&*self.storage, &tables)?);
+
+        for number in self.next_table..self.next_table + tables.len() {
+            let table = self.table_file(number);
+            self.tables.push(table);
+        }
+        self.update_table_list();
+        self.next_table += tables.len();
+        self.raise_sequence(seq);
+        self.wal.set_sequence(seq + 1);
+        Ok(count)
+    }
+
+    fn write_ingest_tables<I, K, V>(
+        &mut self,
+        rows: I,
+        seq: u64,
+        tables: &mut Vec<String>,
+    ) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = io::Result<(K, V)>>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let mut writer: Option<SSTableWriter> = None;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut count = 0;
+        let written_at = Some(now_millis());
+        for row in rows {
+            let (key, value) = row?;
+            let (key, value) = (key.into(), value.into());
+            self.check_key(&key)?;
+            self.check_value(&value)?;
+            if previous.as_ref().is_some_and(|previous| &key <= previous) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "bulk ingest keys must be strictly increasing",
+                ));
+            }
+
+            if writer.as_ref().is_some_and(|w| w.len() >= MAX_INGEST_TABLE_BYTES) {
+                if let Some(full) = writer.take() {
+                    full.finish(&[], seq)?;
+                }
+            }
+            let writer = match &mut writer {
+                Some(writer) => writer,
+                None => {
+                    let table = self.sstable_path(self.next_table + tables.len());
+                    let path = format!("{}{}", table, INGEST_SUFFIX);
+                    tables.push(table);
+                    let writer = writer.insert(SSTableWriter::create(&*self.storage, &path)?);
+                    writer.set_sync_mode(self.sync_mode);
+                    writer
+                }
+            };
+
+            let op = self.put_op(value)?;
+            writer.add(&key, &Entry { written_at, ..Entry::new(seq, op) })?;
+            previous = Some(key);
+            count += 1;
+        }
+
+        if let Some(writer) = writer {
+            writer.finish(&[], seq)?;
+        }
+        Ok(count)
+    }
+
+    /// Every file backing this memtable, oldest first, each with whether it is sealed.
+    /// Sealed files (SSTables and full value logs) never change again; the WAL and the
+    /// active value log are only appended to.
+    pub(crate) fn files(&self) -> io::Result<Vec<(PathBuf, bool)>> {
+        let active_blob = self.value_log.as_ref().map(ValueLog::path);
+        let mut files: Vec<_> = self
+            .sstable_paths()
+            .into_iter()
+            .filter(|path| self.storage.exists(path))
+            .map(|path| (path, true))
+            .collect();
+        for path in value_log::blob_files(&*self.storage, &self.dir)? {
+            let sealed = active_blob.as_ref() != Some(&path);
+            files.push((path, sealed));
+        }
+        let list = self.dir.join(TABLE_LIST);
+        if self.storage.exists(&list) {
+            files.push((list, false));
+        }
+        files.push((PathBuf::from(&self.wal_path), false));
+        for segment in Self::shard_segments(&*self.storage, &self.wal_path)? {
+            files.push((PathBuf::from(segment), false));
+        }
+        Ok(files)
+    }
+
+    /// Seal the active value log so that everything written so far is in sealed files
+    pub(crate) fn seal_value_log(&mut self) -> io::Result<()> {
+        match &mut self.value_log {
+            Some(log) => log.seal(),
+            None => Ok(()),
+        }
+    }
+
+    /// Paths of the live SSTables, oldest first
+    pub fn sstable_paths(&self) -> Vec<PathBuf> {
+        self.tables.iter().map(|table| PathBuf::from(table.path())).collect()
+    }
+
+    /// Check this family's files against what it has in use; see
+    /// [`Db::verify`](crate::Db::verify)
+    pub(crate) fn verify(&self) -> Vec<(Check, Vec<String>)> {
+        let in_use: Vec<String> =
+            self.tables.iter().map(|table| table.path().to_string()).collect();
+        verify::check_family(
+            &*self.storage,
+            &self.dir,
+            &in_use,
+            self.next_table,
+            self.last_sequence(),
+            &self.wal_path,
+            &self.wal_keys,
+        )
+    }
+
+    /// Number the next SSTable written will get
+    pub fn next_sstable_number(&self) -> usize {
+        self.next_table
+    }
+
+    pub fn compaction_totals(&self) -> &CompactionTotals {
+        &self.totals
     }
 
-    fn recover(&mut self) -> io::Result<()> {
-        self.wal.replay(|key, value| {
-            match value {
-                Some(v) => {
-                    self.data.insert(key.to_string(), v.to_string());
-                }
-                None => {
-                    self.data.remove(key);
-                }
-            }
-        })
+    pub fn wal_path(&self) -> &Path {
+        Path::new(&self.wal_path)
     }
 
-    pub fn put(&mut self, key: String, value: String) -> io::Result<()> {
-        // Log FIRST (durability)
-        self.wal.log_put(&key, &value)?;
-        
-        // Then update memory
-        self.data.insert(key, value);
-        
-        // Check if we need to flush
-        if self.data.len() >= self.max_size {
-            self.flush()?;
+    /// Number of versions (writes and deletions) held in memory
+    pub fn size(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Keys in memory plus the versions counted in each SSTable header, without
+    /// reading any entries. See [`crate::Db::estimated_key_count`].
+    pub fn estimated_key_count(&self) -> io::Result<u64> {
+        let mut count = self.memtable_keys() as u64;
+        for table in &self.tables {
+            count += SSTable::header(&*self.storage, table.path())?.entries;
         }
-        
-        Ok(())
+        Ok(count)
+    }
+
+    /// Estimated heap bytes held by the versions in memory: keys, values, and per-entry
+    /// bookkeeping. A delete adds a tombstone rather than freeing the versions it
+    /// shadows, which stay readable at older sequence numbers until the next flush. WAL
+    /// appends are written straight through, so the log holds no buffer to count.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// Sequence number of the most recent write. While writes through the shards are
+    /// under way it is that of the last one every write before which is applied too,
+    /// so reads and snapshots taken at it are a point in time.
+    pub fn last_sequence(&self) -> u64 {
+        self.visible_seq.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn snapshots(&self) -> &Arc<SnapshotList> {
+        &self.snapshots
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub(crate) fn filters(&self) -> &FilterCache {
+        &self.filters
+    }
+
+    pub(crate) fn row_cache(&self) -> &RowCache {
+        &self.rows
+    }
+
+    pub(crate) fn absent_keys(&self) -> &AbsentKeys {
+        &self.absent
+    }
+
+    pub(crate) fn disk_usage(&self) -> &Arc<DiskUsage> {
+        &self.disk_usage
+    }
+
+    pub(crate) fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-    if let Some(value) = self.data.get(key) {
-        return Some(value.clone());
+    pub(crate) fn write_controller(&self) -> &Arc<WriteController> {
+        &self.write_controller
     }
 
-    for i in (0..self.sstable_counter).rev() {
-        let sstable_path = format!("sstable_{:06}.sst", i);
-        if let Ok(Some(value)) = SSTable::get(&sstable_path, key) {
-            return Some(value);
+    /// Bring the write stall condition up to date with the SSTable count, raising an
+    /// event if it changed. Called after each locked operation, since flushes and
+    /// compactions are what move the count.
+    pub(crate) fn update_write_stall(&mut self) {
+        let sstables = self.tables.len();
+        if let Some((previous, current)) = self.write_controller.update(sstables) {
+            self.raise(Event::WriteStallChange(WriteStallInfo { previous, current, sstables }));
         }
     }
-    
-    None
-}
 
-    pub fn delete(&mut self, key: &str) -> io::Result<Option<String>> {
-        self.wal.log_delete(key)?;
+    /// Where this memtable's files are kept
+    pub(crate) fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
 
-        let result = self.data.remove(key);
-        
-        Ok(result)
+    /// Bytes of the WAL and the shards' segments
+    pub(crate) fn wal_len(&self) -> io::Result<u64> {
+        let mut len = self.storage.file_len(Path::new(&self.wal_path))?;
+        for segment in Self::shard_segments(&*self.storage, &self.wal_path)? {
+            len += self.storage.file_len(Path::new(&segment))?;
+        }
+        Ok(len)
+    }
+
+    /// Bytes of this column family's live SSTables and WAL, as found on disk
+    pub(crate) fn disk_bytes(&self) -> io::Result<u64> {
+        let tables: Vec<String> = self.tables.iter().map(|t| t.path().to_string()).collect();
+        Ok(total_size(&*self.storage, &tables)? + self.wal_len()?)
+    }
+
+    /// Check that a write of about `bytes` fits under the disk quota, together with the
+    /// flush it triggers if its `entries` fill the memtable
+    fn reserve(&self, bytes: usize, entries: usize) -> io::Result<()> {
+        let mut needed = bytes as u64;
+        if self.size() + entries >= self.max_size {
+            needed += self.wal_len()? + bytes as u64;
+        }
+        self.disk_usage.reserve(needed)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        if self.data.is_empty() {
+    /// Flush a full memtable after a write that may only have deleted. A flush the disk
+    /// quota can't take is put off rather than failing the write, so deletes keep
+    /// working when the database is over quota.
+    pub(crate) fn flush_when_full(&mut self) -> io::Result<()> {
+        if self.size() < self.max_size {
+            return Ok(());
+        }
+        if self.disk_usage.reserve(self.wal_len()?).is_err() {
             return Ok(());
         }
+        self.flush_if_full();
+        Ok(())
+    }
 
-        // Convert HashMap to sorted BTreeMap
-        let sorted_data: BTreeMap<String, String> = 
-            self.data.iter()
-                .map(|(k, v)| (k.clone(), v.clone()))
-                .collect();
+    /// Flush once a write has filled the memtable. The write is already in the WAL, so
+    /// a failed flush doesn't fail it: the failure becomes the background error, and
+    /// later writes are refused until a flush succeeds.
+    fn flush_if_full(&mut self) {
+        if self.size() < self.max_size {
+            return;
+        }
+        if let Err(err) = self.flush() {
+            warn!("flush failed; writes are refused until one succeeds: {}", err);
+            self.background_error = Some(err.to_string());
+            self.raise(Event::BackgroundError(err));
+        }
+    }
+
+    /// Refuse a write with [`EngineError::BackgroundError`] while a flush is pending
+    /// after failing
+    fn check_background_error(&self) -> io::Result<()> {
+        match &self.background_error {
+            Some(error) => Err(EngineError::BackgroundError { error: error.clone() }.into()),
+            None => Ok(()),
+        }
+    }
 
-        let sstable_path = format!("sstable_{:06}.sst", self.sstable_counter);
-        self.sstable_counter += 1;
+    /// Why the last flush a write triggered failed, while no flush has succeeded since
+    pub fn background_error(&self) -> Option<EngineError> {
+        let error = self.background_error.clone()?;
+        Some(EngineError::BackgroundError { error })
+    }
 
-        SSTable::write(&sstable_path, &sorted_data)?;
+    /// Retry a flush that failed after a write, so that writes resume if it succeeds.
+    /// Does nothing without a [`MemTable::background_error`].
+    pub fn try_flush(&mut self) -> io::Result<()> {
+        if self.background_error.is_none() {
+            return Ok(());
+        }
+        self.flush()
+    }
 
-        println!("Flushed {} entries to {}", sorted_data.len(), sstable_path);
+    /// Queue `event` if a listener wants it
+    fn raise(&mut self, event: Event) {
+        if self.listener.is_some() {
+            self.events.push(event);
+        }
+    }
 
+    /// The listener with the events raised since the last call, if there are any
+    pub(crate) fn take_events(&mut self) -> Option<(Arc<dyn EventListener>, Vec<Event>)> {
+        if self.events.is_empty() {
+            return None;
+        }
+        let listener = self.listener.clone()?;
+        Some((listener, std::mem::take(&mut self.events)))
+    }
+}
 
-        self.data.clear();
+/// An SSTable source that keeps its table's file from being deleted while it is read
+struct Pinned<I> {
+    inner: I,
+    _pin: Arc<TableFile>,
+}
 
-        // Truncate WAL (data is now in SSTable)
-        fs::remove_file(&self.wal_path)?;
-        self.wal = WriteAheadLog::new(&self.wal_path)?;
+impl<I: Iterator> Iterator for Pinned<I> {
+    type Item = I::Item;
 
-        Ok(())
+    fn next(&mut self) -> Option<I::Item> {
+        self.inner.next()
     }
+}
 
-    pub fn size(&self) -> usize {
-        self.data.len()
+impl<R: Read> Read for Pinned<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// The number of the SSTable named `name`, or `None` if it names something else
+pub(crate) fn table_number(name: &str) -> Option<usize> {
+    name.strip_prefix("sstable_")?.strip_suffix(".sst")?.parse().ok()
+}
+
+/// The number of the shard whose segment of the WAL named `wal_name` is named `name`,
+/// or `None` if it names something else
+fn shard_segment(wal_name: &str, name: &str) -> Option<usize> {
+    split_segment(name).filter(|(wal, _)| *wal == wal_name).map(|(_, shard)| shard)
+}
+
+/// The name of the WAL the shard segment named `name` belongs to, and its shard number
+pub(crate) fn split_segment(name: &str) -> Option<(&str, usize)> {
+    let (wal_name, shard) = name.rsplit_once(SHARD_SEGMENT)?;
+    Some((wal_name, shard.parse().ok()?))
+}
+
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn shard_mut(shard: &mut Mutex<Shard>) -> &mut Shard {
+    shard.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The smallest key greater than every key starting with `prefix`, or `None` when there
+/// is none (the prefix is empty or all `0xff`)
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
     }
+    None
+}
+
+fn total_size(storage: &dyn Storage, paths: &[String]) -> io::Result<u64> {
+    paths.iter().map(|path| storage.file_len(Path::new(path))).sum()
+}
+
+fn check_size(what: &str, len: usize, limit: usize, option: &str) -> io::Result<()> {
+    if len > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} of {} bytes exceeds Options::{} ({} bytes)", what, len, option, limit),
+        ));
+    }
+    Ok(())
+}
+
+fn no_merge_operator() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "merge requires Options::merge_operator")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::fault::FaultStorage;
+    use crate::sstable::TableHeader;
+    use crate::storage::MemStorage;
+
+    /// Open a memtable whose files live in `storage`
+    fn open(storage: &MemStorage, wal_path: &str) -> MemTable {
+        let options = Options { storage: Some(Arc::new(storage.clone())), ..Options::default() };
+        MemTable::with_options(wal_path, &options).unwrap()
+    }
 
     #[test]
     fn test_put_and_get() {
         let wal_path = "test_memtable_put_get.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let mut memtable = MemTable::new(wal_path).unwrap();
-        memtable.put("key1".to_string(), "value1".to_string()).unwrap();
+        let mut memtable = open(&storage, wal_path);
+        memtable.put("key1", "value1").unwrap();
         
-        assert_eq!(memtable.get("key1"), Some("value1".to_string()));
-        
-        fs::remove_file(wal_path).unwrap();
+        assert_eq!(memtable.get("key1"), Some(b"value1".to_vec()));
     }
 
     #[test]
     fn test_get_nonexistent_key() {
         let wal_path = "test_memtable_nonexistent.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let memtable = MemTable::new(wal_path).unwrap();
+        let memtable = open(&storage, wal_path);
         assert_eq!(memtable.get("nonexistent"), None);
-        
-        fs::remove_file(wal_path).unwrap();
     }
 
     #[test]
     fn test_update_existing_key() {
         let wal_path = "test_memtable_update.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let mut memtable = MemTable::new(wal_path).unwrap();
-        memtable.put("key1".to_string(), "value1".to_string()).unwrap();
-        memtable.put("key1".to_string(), "value2".to_string()).unwrap();
+        let mut memtable = open(&storage, wal_path);
+        memtable.put("key1", "value1").unwrap();
+        memtable.put("key1", "value2").unwrap();
         
-        assert_eq!(memtable.get("key1"), Some("value2".to_string()));
-        
-        fs::remove_file(wal_path).unwrap();
+        assert_eq!(memtable.get("key1"), Some(b"value2".to_vec()));
     }
 
     #[test]
     fn test_delete() {
         let wal_path = "test_memtable_delete.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let mut memtable = MemTable::new(wal_path).unwrap();
-        memtable.put("key1".to_string(), "value1".to_string()).unwrap();
+        let mut memtable = open(&storage, wal_path);
+        memtable.put("key1", "value1").unwrap();
         
         let deleted_value = memtable.delete("key1").unwrap();
-        assert_eq!(deleted_value, Some("value1".to_string()));
+        assert_eq!(deleted_value, Some(b"value1".to_vec()));
         assert_eq!(memtable.get("key1"), None);
-        
-        fs::remove_file(wal_path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_returns_flushed_value() {
+        let wal_path = "test_memtable_delete_flushed.log";
+        let storage = MemStorage::new();
+
+        let mut memtable = open(&storage, wal_path);
+        memtable.put("key1", "value1").unwrap();
+        memtable.put("key2", "value2").unwrap();
+        memtable.flush().unwrap();
+
+        assert_eq!(memtable.delete("key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(memtable.get("key1"), None);
+        assert_eq!(memtable.delete("key1").unwrap(), None);
+
+        // Range tombstones hide flushed values from deletes as they do from gets
+        memtable.delete_range("key2", "key3").unwrap();
+        assert_eq!(memtable.delete("key2").unwrap(), None);
+
+        // The tombstone is written either way
+        drop(memtable);
+        let memtable = open(&storage, wal_path);
+        assert_eq!(memtable.get("key1"), None);
+        assert_eq!(memtable.size(), 4);
     }
 
     #[test]
     fn test_delete_nonexistent_key() {
         let wal_path = "test_memtable_delete_nonexistent.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let mut memtable = MemTable::new(wal_path).unwrap();
+        let mut memtable = open(&storage, wal_path);
         let result = memtable.delete("nonexistent").unwrap();
         assert_eq!(result, None);
-        
-        fs::remove_file(wal_path).unwrap();
     }
 
     #[test]
     fn test_crash_recovery() {
         let wal_path = "test_memtable_recovery.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
         // Simulate: write data and "crash"
         {
-            let mut memtable = MemTable::new(wal_path).unwrap();
-            memtable.put("key1".to_string(), "value1".to_string()).unwrap();
-            memtable.put("key2".to_string(), "value2".to_string()).unwrap();
+            let mut memtable = open(&storage, wal_path);
+            memtable.put("key1", "value1").unwrap();
+            memtable.put("key2", "value2").unwrap();
             memtable.delete("key1").unwrap();
         }
         
         // Simulate: restart and recover
         {
-            let memtable = MemTable::new(wal_path).unwrap();
+            let memtable = open(&storage, wal_path);
             assert_eq!(memtable.get("key1"), None);
-            assert_eq!(memtable.get("key2"), Some("value2".to_string()));
+            assert_eq!(memtable.get("key2"), Some(b"value2".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_flush_records_sequence_range() {
+        let wal_path = "test_memtable_sequence_range.log";
+        let storage = MemStorage::new();
+
+        let mut memtable = open(&storage, wal_path);
+        let before = now_millis();
+        for key in ["a", "b", "c"] {
+            memtable.put(key, "value").unwrap();
+        }
+        memtable.flush().unwrap();
+        memtable.delete_range("a", "b").unwrap();
+        memtable.put("d", "value").unwrap();
+        memtable.flush().unwrap();
+
+        let paths = memtable.sstable_paths();
+        let headers: Vec<TableHeader> = paths
+            .iter()
+            .map(|path| SSTable::header(&storage, &path.to_string_lossy()).unwrap())
+            .collect();
+        assert_eq!((headers[0].min_seq, headers[0].max_seq), (1, 3));
+        assert_eq!((headers[1].min_seq, headers[1].max_seq), (4, 5));
+        let created_at = headers[0].created_at.unwrap();
+        assert!(created_at >= before && created_at <= now_millis());
+    }
+
+    #[test]
+    fn test_flushes_at_configured_entries() {
+        let storage = MemStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            memtable_entries: Some(3),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_memtable_entries.log", &options).unwrap();
+        for key in ["a", "b"] {
+            memtable.put(key, "value").unwrap();
+        }
+        assert!(memtable.sstable_paths().is_empty());
+        memtable.put("c", "value").unwrap();
+        assert_eq!((memtable.sstable_paths().len(), memtable.size()), (1, 0));
+    }
+
+    #[test]
+    fn test_shared_write_waits_for_those_numbered_before_it() {
+        let storage = MemStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            memtable_shards: Some(4),
+            ..Options::default()
+        };
+        let memtable = MemTable::with_options("test_memtable_visible.log", &options).unwrap();
+
+        // A write that has taken number 1 and not been applied yet
+        memtable.last_seq.fetch_add(1, Ordering::SeqCst);
+        thread::scope(|scope| {
+            let write = || memtable.write_shared(b"b".to_vec(), Op::Put(b"2".to_vec()), None);
+            let later = scope.spawn(write);
+            thread::sleep(Duration::from_millis(20));
+            assert!(!later.is_finished());
+            assert_eq!(memtable.last_sequence(), 0);
+            assert_eq!(memtable.get_at(b"b", memtable.last_sequence()), None);
+
+            memtable.publish(1);
+            later.join().unwrap().unwrap();
+        });
+        assert_eq!(memtable.last_sequence(), 2);
+        assert_eq!(memtable.get("b"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_recovery_skips_records_already_flushed() {
+        let wal_path = "test_memtable_flushed_wal.log";
+        let storage = MemStorage::new();
+
+        // A crash that leaves the flushed table and the WAL it came from both in place
+        {
+            let mut memtable = open(&storage, wal_path);
+            memtable.put("key1", "value1").unwrap();
+            memtable.put("key2", "value2").unwrap();
+            let wal = storage.read(Path::new(wal_path)).unwrap();
+            memtable.flush().unwrap();
+            drop(memtable);
+            storage.write(Path::new(wal_path), &wal).unwrap();
+        }
+
+        let mut memtable = open(&storage, wal_path);
+        assert_eq!(memtable.size(), 0);
+        assert_eq!(memtable.last_sequence(), 2);
+        assert_eq!(memtable.get("key1"), Some(b"value1".to_vec()));
+        memtable.put("key3", "value3").unwrap();
+        drop(memtable);
+
+        // Only the record logged after the flush is replayed
+        let memtable = open(&storage, wal_path);
+        assert_eq!(memtable.size(), 1);
+        assert_eq!(memtable.last_sequence(), 3);
+        assert_eq!(memtable.get("key3"), Some(b"value3".to_vec()));
+    }
+
+    #[test]
+    fn test_open_fails_on_missing_table() {
+        let wal_path = "test_memtable_missing.log";
+        let storage = MemStorage::new();
+        {
+            let mut memtable = open(&storage, wal_path);
+            for key in ["key0", "key1", "key2"] {
+                memtable.put(key, "value").unwrap();
+                memtable.flush().unwrap();
+            }
+        }
+        storage.remove(Path::new("sstable_000001.sst")).unwrap();
+
+        let options = Options { storage: Some(Arc::new(storage.clone())), ..Options::default() };
+        let err = MemTable::with_options(wal_path, &options).err().unwrap();
+        let Some(EngineError::MissingTables { tables }) = EngineError::from_io(&err) else {
+            panic!("{}", err)
+        };
+        assert_eq!(tables, &["sstable_000001.sst"]);
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Allowed, the loss is accepted and the next open no longer minds
+        let options = Options { allow_missing_tables: true, ..options };
+        let memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!(memtable.get("key0"), Some(b"value".to_vec()));
+        assert_eq!(memtable.get("key1"), None);
+        drop(memtable);
+        let mut memtable = open(&storage, wal_path);
+
+        // Compaction inputs are not missing once gone
+        memtable.compact().unwrap();
+        drop(memtable);
+        let memtable = open(&storage, wal_path);
+        assert_eq!(memtable.get("key2"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_get_reports_table_gone_since_open() {
+        let wal_path = "test_memtable_gone.log";
+        let storage = MemStorage::new();
+        let mut memtable = open(&storage, wal_path);
+        for key in ["key0", "key1", "key2"] {
+            memtable.put(key, "value").unwrap();
+            memtable.flush().unwrap();
+        }
+        drop(memtable);
+        let mut memtable = open(&storage, wal_path);
+        storage.remove(Path::new("sstable_000001.sst")).unwrap();
+
+        // Lost data is an error rather than a missing key, for reads and for the writes
+        // that read the old value
+        let err = memtable.try_get("key1").unwrap_err();
+        let missing = EngineError::MissingTables { tables: vec!["sstable_000001.sst".into()] };
+        assert_eq!(EngineError::from_io(&err), Some(&missing));
+        assert!(memtable.try_get("key0").is_err());
+        assert!(memtable.delete("key1").is_err());
+        assert_eq!(memtable.get("key1"), None);
+        // A key the newer tables resolve never needs the missing one
+        assert_eq!(memtable.try_get("key2").unwrap(), Some(b"value".to_vec()));
+        drop(memtable);
+
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            allow_missing_tables: true,
+            ..Options::default()
+        };
+        let memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!(memtable.try_get("key1").unwrap(), None);
+        assert_eq!(memtable.try_get("key0").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_lookups_load_one_filter_partition() {
+        let wal_path = "test_memtable_filters.log";
+        let storage = MemStorage::new();
+        let key = |i: usize| format!("key{:05}", i);
+        open(&storage, wal_path).ingest((0..5000).map(|i| (key(i), "value"))).unwrap();
+
+        let memtable = open(&storage, wal_path);
+        assert_eq!(memtable.get(key(2500)), Some(b"value".to_vec()));
+        let loaded = memtable.filters().stats();
+        assert_eq!((loaded.hits, loaded.misses), (0, 1));
+        let index = SSTable::filter_index(&storage, "sstable_000000.sst").unwrap().unwrap();
+        let total: u64 = index.partitions.iter().map(|p| u64::from(p.len)).sum();
+        assert_eq!(index.partitions.len(), 5);
+        assert!(loaded.bytes > 0 && loaded.bytes * 4 < total);
+
+        // Absent keys are answered from the same partition, or without one past the end
+        assert_eq!(memtable.get("key02500x"), None);
+        assert_eq!(memtable.get("zzz"), None);
+        let stats = memtable.filters().stats();
+        assert_eq!((stats.hits, stats.misses, stats.bytes), (1, 1, loaded.bytes));
+    }
+
+    #[test]
+    fn test_lookups_keep_within_max_open_files() {
+        let storage = FaultStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            max_open_files: Some(2),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_max_open_files.log", &options).unwrap();
+        for table in 0..9 {
+            memtable.put(format!("key{}", table), format!("value{}", table)).unwrap();
+            memtable.flush().unwrap();
+        }
+        memtable.delete_range("key3", "key4").unwrap();
+        memtable.flush().unwrap();
+        assert_eq!(memtable.sstable_paths().len(), 10);
+
+        let expected = |table: usize| (table != 3).then(|| format!("value{}", table).into_bytes());
+        for _ in 0..2 {
+            for table in 0..9 {
+                assert_eq!(memtable.get(format!("key{}", table)), expected(table));
+            }
+        }
+        assert_eq!(storage.peak_open_files(), 2);
+
+        // A scan holds its own handles, so evictions under it leave it reading on
+        let mut scan = memtable.iter_range(None, None, u64::MAX);
+        assert_eq!(scan.next().unwrap().unwrap().0, b"key0");
+        for table in (0..9).rev() {
+            assert_eq!(memtable.get(format!("key{}", table)), expected(table));
+        }
+        let rest: Vec<Vec<u8>> = scan.map(|item| item.unwrap().0).collect();
+        assert_eq!(rest.len(), 7);
+    }
+
+    #[test]
+    fn test_parallel_lookups_return_the_newest_version() {
+        let storage = MemStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage)),
+            lookup_threads: Some(3),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_lookup_threads.log", &options).unwrap();
+        for table in 0..7 {
+            match table {
+                0 => memtable.put("shared", "old").unwrap(),
+                1 => memtable.put("gone", "value").unwrap(),
+                4 => memtable.delete("gone").map(drop).unwrap(),
+                5 => memtable.put("shared", "new").unwrap(),
+                _ => {}
+            }
+            memtable.put(format!("key{}", table), "value").unwrap();
+            memtable.flush().unwrap();
+        }
+        assert_eq!(memtable.sstable_paths().len(), 7);
+
+        for _ in 0..20 {
+            assert_eq!(memtable.get("shared"), Some(b"new".to_vec()));
+            assert_eq!(memtable.get("gone"), None);
+            assert_eq!(memtable.get("missing"), None);
+            assert_eq!(memtable.get("key2"), Some(b"value".to_vec()));
         }
-        
-        fs::remove_file(wal_path).unwrap();
     }
 
     #[test]
     fn test_flush_to_sstable() {
         let wal_path = "test_memtable_flush.log";
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
         
-        let mut memtable = MemTable::new(wal_path).unwrap();
+        let mut memtable = open(&storage, wal_path);
         
         for i in 0..105 {
             memtable.put(format!("key_{}", i), format!("value_{}", i)).unwrap();
@@ -220,9 +2952,317 @@ mod tests {
 
         assert!(memtable.size() < 100);
 
-        assert!(std::path::Path::new("sstable_000000.sst").exists());
-        
-        fs::remove_file(wal_path).unwrap();
-        fs::remove_file("sstable_000000.sst").unwrap();
+        assert!(storage.exists(Path::new("sstable_000000.sst")));
+    }
+
+    #[test]
+    fn test_scans_read_ahead() {
+        let scan_reads = |read_ahead_bytes| {
+            let storage = FaultStorage::new();
+            let options = Options {
+                storage: Some(Arc::new(storage.clone())),
+                read_ahead_bytes,
+                ..Options::default()
+            };
+            let mut memtable = MemTable::with_options("test_read_ahead.log", &options).unwrap();
+            let rows = (0..5000).map(|i| (format!("key{:05}", i), format!("value{}", i)));
+            memtable.ingest(rows).unwrap();
+
+            let before = storage.reads();
+            let rows = memtable.scan("a", "z").unwrap();
+            let scan = storage.reads() - before;
+            // A point lookup reads the table with a small buffer either way
+            let before = storage.reads();
+            assert_eq!(memtable.get("key02500"), Some(b"value2500".to_vec()));
+            (rows, scan, storage.reads() - before)
+        };
+
+        let (rows, buffered, lookup) = scan_reads(None);
+        let (unbuffered_rows, unbuffered, unbuffered_lookup) = scan_reads(Some(0));
+        assert_eq!(rows.len(), 5000);
+        assert_eq!(rows, unbuffered_rows);
+        assert!(buffered * 100 < unbuffered, "{} vs {}", buffered, unbuffered);
+        assert_eq!(lookup, unbuffered_lookup);
+    }
+
+    #[test]
+    fn test_list_skips_past_common_prefixes() {
+        let storage = FaultStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            read_ahead_bytes: Some(1024),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_memtable_list.log", &options).unwrap();
+        let rows = (0..5000).map(|i| (format!("big/{:05}", i), "value"));
+        memtable.ingest(rows.chain([("top".to_string(), "value")])).unwrap();
+
+        let before = storage.reads();
+        assert_eq!(memtable.count_range(b"big/", b"big0", u64::MAX).unwrap(), 5000);
+        let scanned = storage.reads() - before;
+
+        let before = storage.reads();
+        let (keys, common_prefixes) = memtable.list(b"", b"/", u64::MAX).unwrap();
+        assert_eq!((keys, common_prefixes), (vec![b"top".to_vec()], vec![b"big/".to_vec()]));
+        let listed = storage.reads() - before;
+        assert!(listed * 4 < scanned, "listing read {} times, scanning {}", listed, scanned);
+    }
+
+    #[test]
+    fn test_empty_value_survives_flush_and_recovery() {
+        let storage = MemStorage::new();
+        let wal_path = "test_memtable_empty_value.log";
+        {
+            let mut memtable = open(&storage, wal_path);
+            memtable.put("flushed", "").unwrap();
+            memtable.flush().unwrap();
+            memtable.put("logged", "").unwrap();
+            assert_eq!(memtable.get("flushed"), Some(Vec::new()));
+        }
+
+        // Empty is a value, not a deletion
+        let mut memtable = open(&storage, wal_path);
+        assert_eq!(memtable.get("flushed"), Some(Vec::new()));
+        assert_eq!(memtable.get("logged"), Some(Vec::new()));
+        assert_eq!(memtable.scan("a", "z").unwrap().len(), 2);
+        assert_eq!(memtable.delete("logged").unwrap(), Some(Vec::new()));
+        assert_eq!(memtable.get("logged"), None);
+    }
+
+    #[test]
+    fn test_recovery_flushes_full_wal() {
+        let storage = MemStorage::new();
+        let wal_path = "db/test_memtable_recovery_flush.log";
+        let log = |count: usize| {
+            let shared = Arc::new(storage.clone());
+            let mut wal = WriteAheadLog::with_storage(shared, wal_path, SyncPolicy::Never).unwrap();
+            for i in 0..count {
+                wal.log_put(format!("key{:04}", i).as_bytes(), b"value").unwrap();
+            }
+        };
+        let wal_len = || storage.file_len(Path::new(wal_path)).unwrap();
+
+        // A crash left far more than the threshold unflushed
+        log(250);
+        let memtable = open(&storage, wal_path);
+        assert_eq!(memtable.sstable_paths().len(), 1);
+        assert_eq!(memtable.size(), 0);
+        assert_eq!(wal_len(), 4);
+        assert_eq!(memtable.scan("", "z").unwrap().len(), 250);
+        drop(memtable);
+
+        // Under the threshold the replayed entries stay in memory unless asked otherwise
+        log(10);
+        let memtable = open(&storage, wal_path);
+        assert_eq!((memtable.sstable_paths().len(), memtable.size()), (1, 10));
+        drop(memtable);
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            flush_on_recovery: true,
+            ..Options::default()
+        };
+        let memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!((memtable.sstable_paths().len(), memtable.size()), (2, 0));
+        assert_eq!(wal_len(), 4);
+        assert_eq!(memtable.scan("", "z").unwrap().len(), 250);
+    }
+
+    #[test]
+    fn test_preallocated_wal_is_recycled() {
+        let storage = MemStorage::new();
+        let wal_path = "db/data.log";
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            wal_preallocate_bytes: Some(1 << 16),
+            merge_operator: Some(Arc::new(|_key, existing, operand| {
+                let mut value = existing.unwrap_or_default().to_vec();
+                value.extend_from_slice(operand);
+                value
+            })),
+            ..Options::default()
+        };
+        let wal_len = || storage.file_len(Path::new(wal_path)).unwrap();
+
+        let mut memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!(wal_len(), 1 << 16);
+        memtable.put("a", "1").unwrap();
+        memtable.merge("counter", "x").unwrap();
+        memtable.flush().unwrap();
+        // The flushed WAL's file became the new one, with none of its records
+        assert_eq!(wal_len(), 1 << 16);
+        assert!(!storage.exists(Path::new(&wal::recycle_path(wal_path))));
+        memtable.merge("counter", "y").unwrap();
+        memtable.put("b", "2").unwrap();
+        drop(memtable);
+
+        let memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!(memtable.get("counter"), Some(b"xy".to_vec()));
+        assert_eq!(memtable.scan("", "z").unwrap().len(), 3);
+        assert!(MemTable::owns_file("data.log.recycle"));
+    }
+
+    #[test]
+    fn test_empty_key_rejected() {
+        let storage = MemStorage::new();
+        let wal_path = "test_memtable_empty_key.log";
+        let mut memtable = open(&storage, wal_path);
+        let wal_len = || storage.file_len(Path::new(wal_path)).unwrap();
+        let before = wal_len();
+
+        let mut batch = WriteBatch::new();
+        batch.put("fine", "1").put("", "2");
+        let errors = [
+            memtable.put("", "value").unwrap_err(),
+            memtable.put_with_ttl("", "value", Duration::from_secs(60)).unwrap_err(),
+            memtable.delete("").unwrap_err(),
+            memtable.write_batch(&batch).unwrap_err(),
+            memtable.ingest([("", "value")]).unwrap_err(),
+        ];
+        assert!(errors.iter().all(|err| err.kind() == io::ErrorKind::InvalidInput));
+        assert_eq!(wal_len(), before);
+        assert_eq!(memtable.get("fine"), None);
+        assert_eq!(memtable.size(), 0);
+
+        // A log written before keys were checked replays without the empty key
+        let legacy = "test_memtable_empty_key_legacy.log";
+        {
+            let shared = Arc::new(storage.clone());
+            let mut wal = WriteAheadLog::with_storage(shared, legacy, SyncPolicy::Always).unwrap();
+            wal.log_put(b"", b"dropped").unwrap();
+            wal.log_put(b"kept", b"1").unwrap();
+        }
+        let memtable = open(&storage, legacy);
+        assert_eq!(memtable.scan("", "z").unwrap(), [(b"kept".to_vec(), b"1".to_vec())]);
+    }
+
+    #[test]
+    fn test_approximate_memory_usage() {
+        let storage = MemStorage::new();
+        let mut memtable = open(&storage, "test_memtable_memory.log");
+        assert_eq!(memtable.approximate_memory_usage(), 0);
+
+        memtable.put("key1", "v".repeat(1000)).unwrap();
+        let one = memtable.approximate_memory_usage();
+        assert!(one > 1000 + 4, "{}", one);
+        memtable.put("key2", "v".repeat(1000)).unwrap();
+        let two = memtable.approximate_memory_usage();
+        assert_eq!(two, 2 * one);
+
+        // A delete only adds its tombstone; the shadowed value stays until the flush
+        memtable.delete("key1").unwrap();
+        let deleted = memtable.approximate_memory_usage();
+        assert!(deleted > two && deleted - two < 100, "{} {}", two, deleted);
+        memtable.delete_range("a", "z").unwrap();
+        assert!(memtable.approximate_memory_usage() > deleted);
+
+        memtable.flush().unwrap();
+        assert_eq!(memtable.approximate_memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_key_and_value_size_limits() {
+        let storage = MemStorage::new();
+        let wal_path = "test_memtable_size_limits.log";
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            max_key_size: Some(8),
+            max_value_size: Some(16),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options(wal_path, &options).unwrap();
+        let before = storage.file_len(Path::new(wal_path)).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("fine", "1").put("big", "v".repeat(17));
+        let errors = [
+            memtable.put("k".repeat(9), "value").unwrap_err(),
+            memtable.put("key", "v".repeat(17)).unwrap_err(),
+            memtable.put_with_ttl("key", "v".repeat(17), Duration::from_secs(60)).unwrap_err(),
+            memtable.delete("k".repeat(9)).unwrap_err(),
+            memtable.write_batch(&batch).unwrap_err(),
+            memtable.ingest([("key", "v".repeat(17))]).unwrap_err(),
+        ];
+        assert!(errors.iter().all(|err| err.kind() == io::ErrorKind::InvalidInput));
+        assert!(errors[1].to_string().contains("max_value_size"), "{}", errors[1]);
+        assert_eq!(storage.file_len(Path::new(wal_path)).unwrap(), before);
+        assert_eq!(memtable.size(), 0);
+
+        // Exactly at the limits is fine, and survives a flush and a reopen
+        let (key, value) = ("k".repeat(8), "v".repeat(16));
+        memtable.put(key.as_str(), value.as_str()).unwrap();
+        memtable.flush().unwrap();
+        drop(memtable);
+        let memtable = MemTable::with_options(wal_path, &options).unwrap();
+        assert_eq!(memtable.get(key.as_str()), Some(value.into_bytes()));
+    }
+
+    /// Records every log message; installed once for the whole test binary
+    struct CaptureLogger;
+
+    static CAPTURED: std::sync::Mutex<Vec<(log::Level, String)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = record.args().to_string();
+            CAPTURED.lock().unwrap().push((record.level(), message));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_flush_logs_instead_of_printing() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        let storage = MemStorage::new();
+        let mut memtable = open(&storage, &format!("test_memtable_log/{}", WAL_FILE));
+        memtable.put("a", "1").unwrap();
+        memtable.put("b", "2").unwrap();
+        memtable.flush().unwrap();
+
+        // Other tests log concurrently, so only look at this directory's records
+        let sstable = memtable.sstable_path(0);
+        let records: Vec<_> = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, message)| message.contains(&sstable))
+            .cloned()
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (log::Level::Debug, format!("writing 2 versions to {}.flush", sstable)),
+                (log::Level::Debug, format!("flushed 2 entries to {}", sstable)),
+            ]
+        );
+
+        // Nor does anything else in the library print: stdout belongs to the CLI in
+        // src/cli. The macro names are split so this check does not find itself.
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for file in std::fs::read_dir(src).unwrap() {
+            let path = file.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            for (number, line) in source.lines().enumerate() {
+                let prints = line.match_indices("print").any(|(at, _)| {
+                    let macro_call = ["!(", "ln!("].iter().any(|rest| {
+                        line[at + "print".len()..].starts_with(rest)
+                    });
+                    macro_call && !line[..at].ends_with('e')
+                });
+                assert!(!prints, "{}:{} prints to stdout", path.display(), number + 1);
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+//! Conversion of a WAL in the original text format to the binary format.
+//!
+//! The text log held one `OP,field,...` line per record: `PUT,key,value`,
+//! `PUTEX,key,value,expires_at`, `DELETE,key`, `MERGE,key,operand`, and
+//! `DELRANGE,start,end`, with batches between `BEGIN` and `COMMIT` lines. It is read
+//! the way it always was: lines with the wrong number of fields or an unknown
+//! operation are skipped, and a batch whose `COMMIT` never made it to disk is dropped.
+//! The binary log's magic header is what tells later opens which format they have.
+
+use crate::lock::DirLock;
+use crate::memtable::table_number;
+use crate::sstable::SSTable;
+use crate::storage::{FileStorage, Storage};
+use crate::options::SyncPolicy;
+use crate::wal::{is_text_log, WalRecord, WriteAheadLog, WAL_FILE};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Suffix the original text log is kept under once converted
+pub(crate) const TEXT_LOG_SUFFIX: &str = ".v0";
+/// Suffix of the binary log while it is being written
+pub(crate) const MIGRATING_SUFFIX: &str = ".migrating";
+
+/// Convert the WAL of the database in `dir` from the text format, returning the number
+/// of records written, or `None` if it is not a text log.
+///
+/// The records are numbered on from the highest sequence number in any SSTable, as they
+/// were all written after those tables. The original is copied to `data.log.v0` and
+/// the binary log synced before it replaces the original.
+pub(crate) fn migrate_wal(dir: &Path) -> io::Result<Option<u64>> {
+    let _lock = DirLock::acquire(dir, false)?;
+    let path = dir.join(WAL_FILE);
+    let text = fs::read(&path)?;
+    if !is_text_log(&text) {
+        return Ok(None);
+    }
+    let backup = dir.join(format!("{}{}", WAL_FILE, TEXT_LOG_SUFFIX));
+    if backup.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists; move it away to migrate again", backup.display()),
+        ));
+    }
+    let text = String::from_utf8(text)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "text WAL is not UTF-8"))?;
+    let records = parse_text_log(&text);
+
+    let mut last_seq = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if table_number(&entry.file_name().to_string_lossy()).is_some() {
+            let table = entry.path();
+            last_seq = last_seq.max(SSTable::max_sequence(&FileStorage, &table.to_string_lossy())?);
+        }
+    }
+
+    let migrating = dir.join(format!("{}{}", WAL_FILE, MIGRATING_SUFFIX));
+    let _ = fs::remove_file(&migrating);
+    {
+        let storage = Arc::new(FileStorage);
+        let mut wal =
+            WriteAheadLog::with_storage(storage, &migrating.to_string_lossy(), SyncPolicy::Never)?;
+        wal.set_sequence(last_seq + 1);
+        for record in &records {
+            match record {
+                WalRecord::Put { key, value, expires_at: None } => wal.log_put(key, value)?,
+                WalRecord::Put { key, value, expires_at: Some(expires_at) } => {
+                    wal.log_put_with_expiry(key, value, *expires_at)?
+                }
+                WalRecord::Delete { key } => wal.log_delete(key)?,
+                WalRecord::Merge { key, operand } => wal.log_merge(key, operand)?,
+                WalRecord::DeleteRange { start, end } => wal.log_delete_range(start, end)?,
+                WalRecord::PutBlob { .. } => unreachable!("text logs hold no blob pointers"),
+            }
+        }
+        wal.sync()?;
+    }
+
+    fs::copy(&path, &backup)?;
+    File::open(&backup)?.sync_all()?;
+    FileStorage.rename(&migrating, &path)?;
+    FileStorage.sync_dir(dir)?;
+    Ok(Some(records.len() as u64))
+}
+
+/// The records of a text log, in order, as replay used to read them
+fn parse_text_log(text: &str) -> Vec<WalRecord> {
+    let mut records = Vec::new();
+    // Records of a batch whose COMMIT hasn't been seen yet
+    let mut pending: Option<Vec<WalRecord>> = None;
+    for line in text.lines() {
+        let parts: Vec<&str> = line.split(',').collect();
+        let bytes = |i: usize| parts[i].as_bytes().to_vec();
+        let record = match parts[0] {
+            "PUT" if parts.len() == 3 => {
+                WalRecord::Put { key: bytes(1), value: bytes(2), expires_at: None }
+            }
+            "PUTEX" if parts.len() == 4 => match parts[3].parse() {
+                Ok(expires_at) => {
+                    WalRecord::Put { key: bytes(1), value: bytes(2), expires_at: Some(expires_at) }
+                }
+                Err(_) => continue,
+            },
+            "DELETE" if parts.len() == 2 => WalRecord::Delete { key: bytes(1) },
+            "MERGE" if parts.len() == 3 => WalRecord::Merge { key: bytes(1), operand: bytes(2) },
+            "DELRANGE" if parts.len() == 3 => {
+                WalRecord::DeleteRange { start: bytes(1), end: bytes(2) }
+            }
+            "BEGIN" => {
+                pending = Some(Vec::new());
+                continue;
+            }
+            "COMMIT" => {
+                records.extend(pending.take().unwrap_or_default());
+                continue;
+            }
+            _ => continue,
+        };
+        match pending.as_mut() {
+            Some(batch) => batch.push(record),
+            None => records.push(record),
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Db, Options};
+
+    #[test]
+    fn test_parse_text_log() {
+        let text = "PUT,a,1\nPUTEX,b,2,1234\nDELETE,a\nBAD,x\nPUT,too,many,fields\n\
+                    BEGIN\nMERGE,c,+1\nDELRANGE,m,n\nCOMMIT\nBEGIN\nPUT,torn,1\n";
+        let put = |key: &[u8], value: &[u8], expires_at| WalRecord::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at,
+        };
+        assert_eq!(
+            parse_text_log(text),
+            [
+                put(b"a", b"1", None),
+                put(b"b", b"2", Some(1234)),
+                WalRecord::Delete { key: b"a".to_vec() },
+                WalRecord::Merge { key: b"c".to_vec(), operand: b"+1".to_vec() },
+                WalRecord::DeleteRange { start: b"m".to_vec(), end: b"n".to_vec() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_wal() {
+        let dir = Path::new("test_migrate_wal");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let db = Db::open(dir).unwrap();
+            db.put("flushed", "old").unwrap();
+            db.put("kept", "1").unwrap();
+            db.flush().unwrap();
+        }
+        let text = "PUT,flushed,new\nDELETE,kept\nPUT,added,2\nBEGIN\nPUT,batched,3\nCOMMIT\n\
+                    BEGIN\nPUT,torn,4\n";
+        let wal = dir.join(WAL_FILE);
+        fs::write(&wal, text).unwrap();
+
+        let err = Db::open(dir).err().unwrap();
+        assert!(err.to_string().contains("migrate-wal"), "{}", err);
+        assert_eq!(Db::migrate_wal(dir).unwrap(), Some(4));
+        assert_eq!(fs::read_to_string(dir.join("data.log.v0")).unwrap(), text);
+        assert_eq!(Db::migrate_wal(dir).unwrap(), None);
+
+        // Read-only, so the replayed records stay in the WAL rather than being flushed
+        let options = Options { read_only: true, ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.get("flushed"), Some("new".to_string()));
+        assert_eq!(db.get("kept"), None);
+        assert_eq!(db.get("added"), Some("2".to_string()));
+        assert_eq!(db.get("batched"), Some("3".to_string()));
+        assert_eq!(db.get("torn"), None);
+        assert_eq!(db.last_sequence(), 6);
+        drop(db);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
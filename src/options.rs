@@ -0,0 +1,309 @@
+use crate::event::EventListener;
+use crate::filter::DEFAULT_FILTER_CACHE_BYTES;
+use crate::sstable::DEFAULT_READ_AHEAD;
+use crate::storage::{FileStorage, Storage, StorageFile};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest key accepted when [`Options::max_key_size`](Options#structfield.max_key_size)
+/// is unset
+pub const DEFAULT_MAX_KEY_SIZE: usize = 4 << 10;
+
+/// Longest value accepted when
+/// [`Options::max_value_size`](Options#structfield.max_value_size) is unset
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 << 20;
+
+/// Smallest value compressed when
+/// [`Options::wal_compression_threshold`](Options#structfield.wal_compression_threshold)
+/// is unset
+pub const DEFAULT_WAL_COMPRESSION_THRESHOLD: usize = 512;
+
+/// Entries a memtable holds before it is flushed when
+/// [`Options::memtable_entries`](Options#structfield.memtable_entries) is unset
+pub const DEFAULT_MEMTABLE_ENTRIES: usize = 100;
+
+/// SSTables each column family keeps open for point lookups when
+/// [`Options::max_open_files`](Options#structfield.max_open_files) is unset
+pub const DEFAULT_MAX_OPEN_FILES: usize = 1000;
+
+/// Combines an existing value (`None` if the key is absent or deleted) with one merge
+/// operand for `key`, producing the new value.
+pub type MergeOperator = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// When WAL appends are synced to disk.
+///
+/// Every append reaches the operating system before the write returns, so a process
+/// crash loses nothing under any policy; the relaxed policies only risk the most recent
+/// writes on power loss or an OS crash, in exchange for far fewer fsyncs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write
+    #[default]
+    Always,
+    /// Sync after every `n` writes
+    EveryN(u32),
+    /// Sync on the first write once this long has passed since the last sync
+    Interval(Duration),
+    /// Leave syncing to the operating system
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// `always`, `never`, `every:<writes>`, or `interval:<milliseconds>`
+    fn from_str(text: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("expected always, never, every:<writes>, or interval:<ms>, not {:?}", text)
+        };
+        match text.split_once(':') {
+            None if text == "always" => Ok(SyncPolicy::Always),
+            None if text == "never" => Ok(SyncPolicy::Never),
+            Some(("every", n)) => n.parse().map(SyncPolicy::EveryN).map_err(|_| invalid()),
+            Some(("interval", ms)) => ms
+                .parse()
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How WAL appends and finished SSTables are made durable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Sync contents and all metadata (`fsync`)
+    #[default]
+    SyncAll,
+    /// Sync contents and only the metadata needed to read them back, such as the file
+    /// size but not the modification time (`fdatasync`). Recovery sees the same data
+    /// as with [`SyncMode::SyncAll`]; appends are cheaper on filesystems like ext4.
+    SyncData,
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    /// `all` or `data`
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text {
+            "all" => Ok(SyncMode::SyncAll),
+            "data" => Ok(SyncMode::SyncData),
+            _ => Err(format!("expected all or data, not {:?}", text)),
+        }
+    }
+}
+
+/// Algorithm values are compressed with, each available with the cargo feature of the
+/// same name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, the `lz4` feature (on by default)
+    Lz4,
+    /// Snappy, the `snappy` feature
+    Snappy,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    /// `lz4` or `snappy`
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text {
+            "lz4" => Ok(Compression::Lz4),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(format!("expected lz4 or snappy, not {:?}", text)),
+        }
+    }
+}
+
+impl SyncMode {
+    pub(crate) fn sync(self, file: &mut dyn StorageFile) -> io::Result<()> {
+        match self {
+            SyncMode::SyncAll => file.sync(),
+            SyncMode::SyncData => file.sync_data(),
+        }
+    }
+}
+
+/// Engine configuration passed to [`crate::Db::open_with_options`]
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Used to resolve [`crate::Db::merge`] operands. Merges are rejected when unset.
+    pub merge_operator: Option<MergeOperator>,
+    /// Values longer than this many bytes are written to a separate value log and only
+    /// a pointer to them goes through the WAL and SSTables. Unset keeps every value inline.
+    pub value_log_threshold: Option<usize>,
+    /// Open without write access: every write fails, and other read-only opens of the
+    /// same directory may coexist while a writable open is refused
+    pub read_only: bool,
+    /// Open as a follower of another database: reads are served, but the only writes
+    /// accepted are those shipped from the primary through
+    /// [`crate::Db::apply_replicated`], until [`crate::Db::promote`] is called. Not
+    /// remembered across opens.
+    pub follower: bool,
+    /// How often the WAL is synced. Flushed SSTables are always synced.
+    pub sync_policy: SyncPolicy,
+    /// Which kind of sync the WAL and newly written SSTables get
+    pub wal_sync_mode: SyncMode,
+    /// Create each WAL at this many bytes and reuse the file of a flushed WAL for the
+    /// next one, so appends overwrite allocated space rather than growing the file.
+    /// Unset creates a new, growing file for every WAL.
+    pub wal_preallocate_bytes: Option<u64>,
+    /// Compress the values of puts and merges in WAL records with this algorithm once
+    /// they are at least
+    /// [`Options::wal_compression_threshold`](Options#structfield.wal_compression_threshold)
+    /// bytes long, keeping a value as it is when compression does not shrink it. Opening
+    /// fails if the algorithm's feature is not built in. Unset writes every value as it
+    /// is; compressed records are read back either way.
+    pub wal_compression: Option<Compression>,
+    /// Shortest value compressed under
+    /// [`Options::wal_compression`](Options#structfield.wal_compression); unset means
+    /// [`DEFAULT_WAL_COMPRESSION_THRESHOLD`]
+    pub wal_compression_threshold: Option<usize>,
+    /// Keep each WAL a flush retires, as `wal_<sequence>.log` next to the live one, for
+    /// this long after its last record was written, so
+    /// [`crate::Db::wal_records_since`] can ship its records to a replica. Archived WALs
+    /// are pruned on flush and not counted against
+    /// [`Options::max_disk_bytes`]; preallocated WALs are not recycled while this is
+    /// set. Unset deletes every WAL once it is flushed.
+    pub wal_retention: Option<Duration>,
+    /// Told about flushes, compactions, and WAL rotations in every column family
+    pub event_listener: Option<Arc<dyn EventListener>>,
+    /// Skip timing operations for [`crate::Stats::latency`], saving two clock reads
+    /// per operation
+    pub disable_latency_histograms: bool,
+    /// Refuse puts, merges, and flushes with [`crate::EngineError::DiskQuotaExceeded`]
+    /// once SSTables and WALs would take up more than this many bytes. Deletes and
+    /// compactions are always allowed, so space can be reclaimed. Unset means no limit.
+    pub max_disk_bytes: Option<u64>,
+    /// Where WALs, SSTables, and value logs are kept; unset means the local filesystem.
+    /// The database directory, its lock, and backups always use the local filesystem.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Bytes read at a time when scans, compaction, and exports walk an SSTable from
+    /// front to back; unset means 256 KiB and 0 turns read-ahead off. Point lookups
+    /// always read in small chunks.
+    pub read_ahead_bytes: Option<usize>,
+    /// Bytes of SSTable key filter partitions each column family keeps in memory, the
+    /// least recently used going first; unset means 8 MiB
+    pub filter_cache_bytes: Option<usize>,
+    /// Bytes of recently read values each column family keeps in memory, so repeated
+    /// gets of a key that lives in SSTables skip them. Writes drop the values of the
+    /// keys they touch. Unset or 0 means no row cache.
+    pub row_cache_bytes: Option<usize>,
+    /// Keys each column family remembers its gets found missing or deleted, so
+    /// probing them again skips the SSTables until they are written. Unset or 0 means
+    /// absent keys are looked up every time.
+    pub negative_cache_keys: Option<usize>,
+    /// SSTables each column family keeps open for point lookups, with their indexes
+    /// and range tombstones; past it the least recently used is closed and reopened
+    /// when next read. Unset means [`DEFAULT_MAX_OPEN_FILES`]. Scans open the tables
+    /// they read separately.
+    pub max_open_files: Option<usize>,
+    /// Threads a point lookup probes SSTables on, each taking a share of the tables;
+    /// the value still comes from the newest table holding the key. Every table is
+    /// probed, so this pays off when lookups often miss tables the key filters can't
+    /// rule out. Unset means 1: tables are probed in turn, newest first, only until the
+    /// key resolves.
+    pub lookup_threads: Option<usize>,
+    /// Shards the memtable is split into by key hash, each with its own map and WAL
+    /// segment, so that puts and merges to keys in different shards are written at the
+    /// same time rather than one after another. Unset means 1, every write taking the
+    /// write lock. Such a write becomes visible to reads and snapshots only once every
+    /// write numbered before it has, so a snapshot stays a point in time.
+    pub memtable_shards: Option<usize>,
+    /// Entries (writes, not keys) the memtable holds before it is flushed to an
+    /// SSTable; unset means [`DEFAULT_MEMTABLE_ENTRIES`]
+    pub memtable_entries: Option<usize>,
+    /// Threads a compaction merges on, each taking its own range of keys and writing
+    /// its own tables; unset means 1, merging everything on the calling thread
+    pub compaction_threads: Option<usize>,
+    /// Bytes per second that flushes and compactions together may read and write,
+    /// shared by every column family; they sleep as needed to stay under it. Unset or
+    /// 0 means no limit.
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// SSTables in a column family at which each write to it is delayed by
+    /// [`SLOWDOWN_DELAY`](crate::SLOWDOWN_DELAY), giving compaction time to catch up.
+    /// Unset means writes are never slowed.
+    pub slowdown_writes_sstables: Option<usize>,
+    /// SSTables in a column family at which writes to it wait until a compaction
+    /// brings the count back under the limit; [`crate::Db::try_put`] fails with
+    /// [`crate::EngineError::WriteStopped`] instead. Compactions must then run on
+    /// another thread than the one writing. Unset means writes are never stopped.
+    pub stop_writes_sstables: Option<usize>,
+    /// Flush whatever WAL replay recovers when opening, leaving an empty WAL. Without
+    /// it, only a replay that reaches the flush threshold is flushed on open. Ignored
+    /// for read-only opens.
+    pub flush_on_recovery: bool,
+    /// Run [`crate::Db::verify`] when opening and fail with
+    /// [`crate::EngineError::VerificationFailed`] if it finds anything wrong. Every
+    /// SSTable is read in full, so opening takes as long as a full scan.
+    pub verify_on_open: bool,
+    /// Open even if SSTables the database was using are missing, logging them as an
+    /// error, rather than failing with [`crate::EngineError::MissingTables`]. Reads then
+    /// miss whatever those tables held; for salvaging what is left.
+    pub allow_missing_tables: bool,
+    /// Keys SSTables and WAL records are encrypted with when written and decrypted with
+    /// when read. Tables and records written without a key stay readable; value logs
+    /// and other files are not encrypted.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Arc<dyn crate::KeyProvider>>,
+    /// Longest key accepted by writes, in bytes; unset means [`DEFAULT_MAX_KEY_SIZE`]
+    pub max_key_size: Option<usize>,
+    /// Longest value or merge operand accepted by writes, in bytes; unset means
+    /// [`DEFAULT_MAX_VALUE_SIZE`]
+    pub max_value_size: Option<usize>,
+}
+
+impl Options {
+    /// The configured storage, or the local filesystem
+    pub(crate) fn storage(&self) -> Arc<dyn Storage> {
+        self.storage.clone().unwrap_or_else(|| Arc::new(FileStorage))
+    }
+
+    pub(crate) fn read_ahead(&self) -> usize {
+        self.read_ahead_bytes.unwrap_or(DEFAULT_READ_AHEAD)
+    }
+
+    pub(crate) fn filter_cache_bytes(&self) -> usize {
+        self.filter_cache_bytes.unwrap_or(DEFAULT_FILTER_CACHE_BYTES)
+    }
+
+    pub(crate) fn max_open_files(&self) -> usize {
+        self.max_open_files.unwrap_or(DEFAULT_MAX_OPEN_FILES)
+    }
+
+    pub(crate) fn lookup_threads(&self) -> usize {
+        self.lookup_threads.unwrap_or(1)
+    }
+
+    pub(crate) fn memtable_shards(&self) -> usize {
+        self.memtable_shards.unwrap_or(1).max(1)
+    }
+
+    pub(crate) fn memtable_entries(&self) -> usize {
+        self.memtable_entries.unwrap_or(DEFAULT_MEMTABLE_ENTRIES).max(1)
+    }
+
+    pub(crate) fn compaction_threads(&self) -> usize {
+        self.compaction_threads.unwrap_or(1)
+    }
+
+    pub(crate) fn rate_limit_bytes_per_sec(&self) -> u64 {
+        self.rate_limit_bytes_per_sec.unwrap_or(0)
+    }
+
+    pub(crate) fn max_key_size(&self) -> usize {
+        self.max_key_size.unwrap_or(DEFAULT_MAX_KEY_SIZE)
+    }
+
+    pub(crate) fn max_value_size(&self) -> usize {
+        self.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE)
+    }
+
+    pub(crate) fn wal_compression_threshold(&self) -> usize {
+        self.wal_compression_threshold.unwrap_or(DEFAULT_WAL_COMPRESSION_THRESHOLD)
+    }
+}
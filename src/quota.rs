@@ -0,0 +1,126 @@
+//! Disk usage accounting for [`Options::max_disk_bytes`](crate::Options::max_disk_bytes)
+
+use crate::error::EngineError;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes of live SSTables and WALs, shared by every column family of a database and
+/// kept up to date as files are written, replaced, and removed
+#[derive(Debug, Default)]
+pub(crate) struct DiskUsage {
+    bytes: AtomicU64,
+    limit: Option<u64>,
+}
+
+impl DiskUsage {
+    pub(crate) fn new(limit: Option<u64>) -> Self {
+        DiskUsage { bytes: AtomicU64::new(0), limit }
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn grow(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn shrink(&self, bytes: u64) {
+        let _ = self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            Some(current.saturating_sub(bytes))
+        });
+    }
+
+    /// Fail with [`EngineError::DiskQuotaExceeded`] unless `needed` more bytes fit under
+    /// the limit
+    pub(crate) fn reserve(&self, needed: u64) -> io::Result<()> {
+        let usage = self.bytes();
+        match self.limit {
+            Some(limit) if usage.saturating_add(needed) > limit => {
+                Err(EngineError::DiskQuotaExceeded { limit, usage, needed }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Db, EngineError, Options};
+    use std::fs;
+    use std::io;
+
+    fn is_quota_error(err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::StorageFull
+            && matches!(
+                EngineError::from_io(err),
+                Some(EngineError::DiskQuotaExceeded { limit: 4096, .. })
+            )
+    }
+
+    #[test]
+    fn test_writes_stop_at_quota_and_resume_after_compaction() {
+        let dir = "test_quota";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { max_disk_bytes: Some(4096), ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        let value = "v".repeat(100);
+        // Flushing every put leaves many small tables for compaction to merge
+        let mut written = 0;
+        let err = loop {
+            let key = format!("key{:03}", written);
+            match db.put(key, value.as_str()).and_then(|()| db.flush()) {
+                Ok(()) => written += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(is_quota_error(&err), "{}", err);
+        assert!(written > 10, "{}", written);
+        let stats = db.stats().unwrap();
+        assert!(stats.disk_bytes <= 4096, "{:?}", stats);
+        assert_eq!(stats.disk_bytes, stats.sstable_bytes + stats.wal_bytes);
+        assert!(is_quota_error(&db.put("more", value.repeat(2)).unwrap_err()));
+
+        // Deletes and compaction still work, and the merged table is smaller
+        db.delete("key000").unwrap();
+        db.delete_range("key001", "key003").unwrap();
+        db.compact().unwrap();
+        let compacted = db.stats().unwrap();
+        assert!(compacted.disk_bytes < stats.disk_bytes, "{:?}", compacted);
+        assert_eq!(compacted.disk_bytes, compacted.sstable_bytes + compacted.wal_bytes);
+
+        db.put("more", value.as_str()).unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.get("more"), Some(value));
+        assert_eq!(db.get("key000"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_quota_spans_column_families() {
+        let dir = "test_quota_families";
+        let _ = fs::remove_dir_all(dir);
+
+        let options = Options { max_disk_bytes: Some(4096), ..Options::default() };
+        let db = Db::open_with_options(dir, options.clone()).unwrap();
+        let scratch = db.create_cf("scratch").unwrap();
+        let err = (0..)
+            .find_map(|i| scratch.put(format!("key{}", i), "x".repeat(100)).err())
+            .unwrap();
+        assert!(is_quota_error(&err), "{}", err);
+        assert!(is_quota_error(&db.put("a", "x".repeat(100)).unwrap_err()));
+
+        // Dropping the family gives its space back, and reopening counts from disk
+        db.drop_cf("scratch").unwrap();
+        db.put("a", "1").unwrap();
+        let usage = db.stats().unwrap().disk_bytes;
+        drop((db, scratch));
+        let db = Db::open_with_options(dir, options).unwrap();
+        assert_eq!(db.stats().unwrap().disk_bytes, usage);
+        assert_eq!(db.get("a"), Some("1".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
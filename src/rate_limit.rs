@@ -0,0 +1,251 @@
+//! Pacing of flush and compaction IO under
+//! [`Options::rate_limit_bytes_per_sec`](crate::Options#structfield.rate_limit_bytes_per_sec).
+//!
+//! Flushes and compactions read and write through a [`RateLimitedStorage`], which asks
+//! the database's one [`RateLimiter`] for every chunk before passing it on, so
+//! background work cannot take the whole disk from foreground reads.
+
+use crate::storage::{Storage, StorageFile};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at a fixed number of bytes per second, shared by every
+/// column family of a database
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// 0 for no limit
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+    bytes: AtomicU64,
+    waited_micros: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may pass without waiting; negative once requests run ahead of the
+    /// rate, and then the next request waits for the debt too
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Throttling so far, as reported in [`crate::Stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ThrottleStats {
+    pub(crate) bytes: u64,
+    pub(crate) waited_micros: u64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket { tokens: 0.0, refilled: Instant::now() }),
+            bytes: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.bytes_per_sec > 0
+    }
+
+    /// Take `bytes` from the bucket, sleeping until the rate allows them. At most a
+    /// tenth of a second's worth of unused tokens is saved up.
+    pub(crate) fn request(&self, bytes: usize) {
+        if !self.is_enabled() || bytes == 0 {
+            return;
+        }
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate / 10.0) - bytes as f64;
+            bucket.refilled = now;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+            self.waited_micros.fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            waited_micros: self.waited_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.bytes.store(0, Ordering::Relaxed);
+        self.waited_micros.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Storage whose file reads and writes wait on a [`RateLimiter`]; everything else goes
+/// straight through
+pub(crate) struct RateLimitedStorage {
+    storage: Arc<dyn Storage>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedStorage {
+    /// `storage` paced by `limiter`, or `storage` itself when the limiter is off
+    pub(crate) fn wrap(storage: Arc<dyn Storage>, limiter: Arc<RateLimiter>) -> Arc<dyn Storage> {
+        if !limiter.is_enabled() {
+            return storage;
+        }
+        Arc::new(RateLimitedStorage { storage, limiter })
+    }
+
+    fn limit(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+        Box::new(RateLimitedFile { file, limiter: self.limiter.clone() })
+    }
+}
+
+impl Storage for RateLimitedStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.limit(self.storage.open_append(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.limit(self.storage.create(path)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.limit(self.storage.open(path)?))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.limit(self.storage.open_write(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.storage.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.storage.remove(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage.list_dir(dir)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.storage.set_len(path, len)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.storage.file_len(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.storage.sync_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.storage.create_dir_all(dir)
+    }
+
+    fn list_subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage.list_subdirs(dir)
+    }
+
+    fn rename_dir(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.storage.rename_dir(from, to)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.storage.remove_dir_all(dir)
+    }
+
+    fn lock_dir(&self, dir: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        self.storage.lock_dir(dir, shared)
+    }
+}
+
+struct RateLimitedFile {
+    file: Box<dyn StorageFile>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Read for RateLimitedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.limiter.request(read);
+        Ok(read)
+    }
+}
+
+impl Write for RateLimitedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.limiter.request(buf.len());
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for RateLimitedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl StorageFile for RateLimitedFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThrottleStats;
+    use crate::memtable::MemTable;
+    use crate::options::Options;
+    use crate::storage::MemStorage;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// How long a flush of 90 values of 512 bytes takes, and the throttling it saw
+    fn timed_flush(rate_limit_bytes_per_sec: Option<u64>) -> (Duration, ThrottleStats) {
+        let options = Options {
+            storage: Some(Arc::new(MemStorage::new())),
+            rate_limit_bytes_per_sec,
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_rate_limit.log", &options).unwrap();
+        for i in 0..90 {
+            memtable.put(format!("key{:02}", i), [b'x'; 512]).unwrap();
+        }
+        let started = Instant::now();
+        memtable.flush().unwrap();
+        (started.elapsed(), memtable.rate_limiter().stats())
+    }
+
+    #[test]
+    fn test_rate_limit_slows_flushes() {
+        // About 47 KiB at 64 KiB/s takes around 0.7 seconds
+        let (limited, throttle) = timed_flush(Some(64 << 10));
+        assert!(limited >= Duration::from_millis(400), "{:?}", limited);
+        assert!(throttle.bytes >= 90 * 512);
+        assert!(throttle.waited_micros >= 400_000);
+
+        let (unlimited, throttle) = timed_flush(None);
+        assert!(unlimited < Duration::from_millis(250), "{:?}", unlimited);
+        assert_eq!(throttle, ThrottleStats::default());
+    }
+}
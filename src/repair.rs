@@ -0,0 +1,266 @@
+//! Rebuild a database from whatever of its files can still be read, after a crash or a
+//! partial restore has damaged some of them.
+//!
+//! Every SSTable is read back in full. A damaged one has its readable prefix, the
+//! entries before the first one that fails to parse or is out of order, written to a
+//! new table that then takes its place; one with nothing readable is set aside. A WAL
+//! with a damaged tail keeps the records before the damage, which the reopen replays.
+//! Nothing is deleted: every original that is replaced, cut short, or set aside is
+//! moved or copied into the `lost/` subdirectory first, and only once its replacement
+//! has been synced.
+
+use crate::column_family;
+use crate::db::Db;
+use crate::lock::DirLock;
+use crate::memtable::{table_number, TABLE_LIST};
+use crate::sstable::{SSTableIterator, SSTableWriter, DEFAULT_READ_AHEAD};
+use crate::storage::{FileStorage, Storage};
+use crate::verify::{check_table, is_damage, TableCheck};
+use crate::wal::{is_torn, WalIterator, WAL_FILE};
+use log::warn;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Subdirectory damaged originals are kept in
+pub(crate) const LOST_DIR: &str = "lost";
+/// Suffix of a salvaged table until it replaces the original
+pub(crate) const SALVAGE_SUFFIX: &str = ".salvage";
+
+/// What [`Db::repair`] found and did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Files that read back whole and were left alone, relative to the database
+    /// directory
+    pub kept: Vec<PathBuf>,
+    /// Damaged files whose readable part was kept
+    pub salvaged: Vec<DamagedFile>,
+    /// Damaged files with nothing readable, moved to `lost/`
+    pub abandoned: Vec<DamagedFile>,
+    /// Highest sequence number of any column family once reopened
+    pub last_sequence: u64,
+}
+
+/// A file [`Db::repair`] found damaged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedFile {
+    /// Relative to the database directory, as is its original's copy under `lost/`
+    pub path: PathBuf,
+    /// Entries (for an SSTable) or records (for a WAL) kept
+    pub kept: u64,
+    /// What could not be read
+    pub error: String,
+}
+
+pub(crate) fn repair(dir: &Path) -> io::Result<RepairReport> {
+    if !dir.join(WAL_FILE).is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not contain a database", dir.display()),
+        ));
+    }
+    let lock = DirLock::acquire(dir, false)?;
+    let mut report = RepairReport::default();
+    for (_, family) in column_family::family_dirs(dir)? {
+        repair_family(dir, &family, &mut report)?;
+    }
+    drop(lock);
+
+    // Sequence numbers and table numbers are rebuilt from the files on open, which also
+    // replays the WAL up to its damage and cuts the rest off
+    let db = Db::open(dir)?;
+    for name in db.column_families() {
+        if let Some(family) = db.cf(&name) {
+            report.last_sequence = report.last_sequence.max(family.last_sequence());
+        }
+    }
+    Ok(report)
+}
+
+/// Check the tables and WAL of the column family in `family`, relative to `dir`
+fn repair_family(dir: &Path, family: &Path, report: &mut RepairReport) -> io::Result<()> {
+    let mut tables = Vec::new();
+    for entry in fs::read_dir(dir.join(family))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if table_number(&name).is_some() {
+            tables.push(family.join(name));
+        }
+    }
+    tables.sort();
+    // Tables set aside here would be missing by the list; the reopen writes a new one
+    match fs::remove_file(dir.join(family).join(TABLE_LIST)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    for table in tables {
+        let mut check = check_table(&FileStorage, &dir.join(&table).to_string_lossy())?;
+        match check.damage.take() {
+            None => report.kept.push(table),
+            Some(damage) if check.entries == 0 && check.range_tombstones.is_empty() => {
+                lose(dir, &table, false)?;
+                report.abandoned.push(damaged(table, 0, damage));
+            }
+            Some(damage) => {
+                salvage_table(dir, &table, &check)?;
+                report.salvaged.push(damaged(table, check.entries, damage));
+            }
+        }
+    }
+
+    let wal = family.join(WAL_FILE);
+    if !dir.join(&wal).is_file() {
+        return Ok(());
+    }
+    let mut records = 0;
+    let mut frames = match WalIterator::open(&dir.join(&wal).to_string_lossy()) {
+        Ok(frames) => frames,
+        Err(err) if is_damage(&err) => {
+            // Unreadable from the start; the reopen starts a new one
+            lose(dir, &wal, false)?;
+            report.abandoned.push(damaged(wal, 0, err));
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    for frame in frames.by_ref() {
+        match frame {
+            Ok(frame) => records += frame.records.len() as u64,
+            Err(err) if is_torn(&err) => {
+                lose(dir, &wal, true)?;
+                report.salvaged.push(damaged(wal, records, err));
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    report.kept.push(wal);
+    Ok(())
+}
+
+/// Replace the table at `table` with the readable part `check` found, once that is
+/// synced, moving the original to `lost/`
+fn salvage_table(dir: &Path, table: &Path, check: &TableCheck) -> io::Result<()> {
+    let path = dir.join(table);
+    let name = path.to_string_lossy();
+    let salvage = format!("{}{}", name, SALVAGE_SUFFIX);
+    let mut writer = SSTableWriter::create(&FileStorage, &salvage)?;
+    let entries = SSTableIterator::open_salvage(&FileStorage, &name, DEFAULT_READ_AHEAD)?;
+    for item in entries.take(check.entries as usize) {
+        let (key, entry) = item?;
+        writer.add(&key, &entry)?;
+    }
+    let max_seq = check.header.map_or(0, |header| header.max_seq);
+    writer.finish(&check.range_tombstones, max_seq)?;
+
+    lose(dir, table, false)?;
+    FileStorage.rename(Path::new(&salvage), &path)?;
+    FileStorage.sync_dir(path.parent().unwrap_or(dir))
+}
+
+/// Move the file at `file` into `lost/` under the same relative path, or with `copy`
+/// put a synced copy there and leave it in place. A name already taken there gets a
+/// numbered suffix.
+fn lose(dir: &Path, file: &Path, copy: bool) -> io::Result<()> {
+    let source = dir.join(file);
+    let mut dest = dir.join(LOST_DIR).join(file);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut n = 1;
+    while dest.exists() {
+        dest = dir.join(LOST_DIR).join(format!("{}.{}", file.display(), n));
+        n += 1;
+    }
+    if copy {
+        fs::copy(&source, &dest)?;
+        File::open(&dest)?.sync_all()?;
+    } else {
+        fs::rename(&source, &dest)?;
+    }
+    let action = if copy { "copied" } else { "moved" };
+    warn!("repair {} {} to {}", action, source.display(), dest.display());
+    for parent in [dest.parent(), source.parent()].into_iter().flatten() {
+        FileStorage.sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+fn damaged(path: PathBuf, kept: u64, error: io::Error) -> DamagedFile {
+    DamagedFile { path, kept, error: error.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    fn cut(path: &Path, bytes: u64) -> u64 {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - bytes).unwrap();
+        len
+    }
+
+    #[test]
+    fn test_repair_salvages_damaged_table_and_wal() {
+        let dir = Path::new("test_repair");
+        let _ = fs::remove_dir_all(dir);
+        let key = |i: usize| format!("key{:03}", i);
+        {
+            let db = Db::open(dir).unwrap();
+            for i in 0..300 {
+                db.put(key(i), format!("value{}", i)).unwrap();
+            }
+            for i in 0..10 {
+                db.put(format!("wal{}", i), "1").unwrap();
+            }
+        }
+        let mut tables: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| PathBuf::from(entry.unwrap().file_name()))
+            .filter(|name| name.extension() == Some("sst".as_ref()))
+            .collect();
+        tables.sort();
+        assert_eq!(tables.len(), 3);
+
+        // The middle table loses its index and last entries, the WAL its last record
+        let table_len = cut(&dir.join(&tables[1]), 300);
+        let wal_len = cut(&dir.join(WAL_FILE), 3);
+
+        let report = Db::repair(dir).unwrap();
+        assert_eq!(report.kept, [tables[0].clone(), tables[2].clone()]);
+        assert!(report.abandoned.is_empty());
+        let [table, wal] = &report.salvaged[..] else { panic!("{:?}", report.salvaged) };
+        assert_eq!(table.path, tables[1]);
+        assert!(table.kept > 50 && table.kept < 100, "{} entries kept", table.kept);
+        assert_eq!((&wal.path, wal.kept), (&PathBuf::from(WAL_FILE), 9));
+        assert_eq!(report.last_sequence, 309);
+
+        // The damaged originals are set aside as they were
+        let lost = dir.join(LOST_DIR);
+        assert_eq!(fs::metadata(lost.join(&tables[1])).unwrap().len(), table_len - 300);
+        assert_eq!(fs::metadata(lost.join(WAL_FILE)).unwrap().len(), wal_len - 3);
+
+        let db = Db::open(dir).unwrap();
+        let salvaged = 100 + table.kept as usize;
+        for i in (0..salvaged).chain(200..300) {
+            assert_eq!(db.get(key(i)), Some(format!("value{}", i)), "{}", key(i));
+        }
+        for i in salvaged..200 {
+            assert_eq!(db.get(key(i)), None);
+        }
+        assert_eq!(db.get("wal8"), Some("1".to_string()));
+        assert_eq!(db.get("wal9"), None);
+        db.put("after", "repair").unwrap();
+        drop(db);
+
+        // A repaired database needs no further repair
+        let report = Db::repair(dir).unwrap();
+        assert_eq!(report.kept.len(), 4);
+        assert!(report.salvaged.is_empty() && report.abandoned.is_empty());
+        assert_eq!(Db::open(dir).unwrap().get("after"), Some("repair".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
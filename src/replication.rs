@@ -0,0 +1,552 @@
+//! Shipping the WAL to a replica.
+//!
+//! With [`Options::wal_retention`](crate::Options#structfield.wal_retention) set, a flush
+//! archives the WAL it retires as `wal_<last sequence number>.log` rather than deleting
+//! it, and later flushes remove archived WALs once they are older than the retention.
+//! A sharded memtable's segments are archived with it, as `wal_<...>.log.shard<n>`.
+//! [`WalRecords`] reads the archived WALs and then the live one, so a replica that has
+//! applied everything up to some sequence number can fetch and apply the rest.
+//! [`Changes`] presents the same records key by key, for change data capture.
+
+use crate::error::EngineError;
+use crate::memtable::{self, MemTable};
+use crate::storage::Storage;
+use crate::wal::{self, WalIterator, WalKeys, WalRecord};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+const ARCHIVE_PREFIX: &str = "wal_";
+const ARCHIVE_SUFFIX: &str = ".log";
+
+/// A WAL retired by a flush and kept for shipping
+#[derive(Debug, Clone)]
+pub(crate) struct ArchivedWal {
+    pub(crate) path: PathBuf,
+    /// The shards' segments of a sharded memtable's WAL, archived alongside it
+    pub(crate) segments: Vec<PathBuf>,
+    /// Sequence number of its last record
+    pub(crate) last_seq: u64,
+    /// Unix time in milliseconds its last record was written
+    pub(crate) written_at: u64,
+}
+
+/// Where the WAL whose last record is numbered `last_seq` is archived in `dir`
+pub(crate) fn archive_path(dir: &Path, last_seq: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", ARCHIVE_PREFIX, last_seq, ARCHIVE_SUFFIX))
+}
+
+fn archived_sequence(name: &str) -> Option<u64> {
+    name.strip_prefix(ARCHIVE_PREFIX)?.strip_suffix(ARCHIVE_SUFFIX)?.parse().ok()
+}
+
+impl ArchivedWal {
+    /// Its own file and its segments'
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.path.clone()];
+        files.extend(self.segments.iter().cloned());
+        files
+    }
+}
+
+/// Whether `name` is an archived WAL or one of its shard segments
+pub(crate) fn is_archived_wal(name: &str) -> bool {
+    let wal_name = memtable::split_segment(name).map_or(name, |(wal_name, _)| wal_name);
+    archived_sequence(wal_name).is_some()
+}
+
+/// The archived WALs in `dir`, oldest first
+pub(crate) fn archived_wals(
+    storage: &dyn Storage,
+    dir: &Path,
+    keys: &WalKeys,
+) -> io::Result<Vec<ArchivedWal>> {
+    let files = storage.list_dir(dir)?;
+    let file_name = |path: &Path| path.file_name().map(|name| name.to_string_lossy().into_owned());
+    let mut archived = Vec::new();
+    for path in &files {
+        let Some(name) = file_name(path) else { continue };
+        let Some(last_seq) = archived_sequence(&name) else { continue };
+        let segments: Vec<PathBuf> = files
+            .iter()
+            .filter(|segment| {
+                let segment = file_name(segment).unwrap_or_default();
+                memtable::split_segment(&segment).is_some_and(|(wal_name, _)| wal_name == name)
+            })
+            .cloned()
+            .collect();
+        let mut written_at = 0;
+        for log in std::iter::once(path).chain(&segments) {
+            written_at = written_at.max(last_write_time(storage, log, keys)?);
+        }
+        archived.push(ArchivedWal { path: path.clone(), segments, last_seq, written_at });
+    }
+    archived.sort_by_key(|wal| wal.last_seq);
+    Ok(archived)
+}
+
+/// Unix time in milliseconds the last readable record of the log at `path` was written
+fn last_write_time(storage: &dyn Storage, path: &Path, keys: &WalKeys) -> io::Result<u64> {
+    Ok(WalIterator::with_keys(storage, &path.to_string_lossy(), keys.clone())?
+        .map_while(Result::ok)
+        .filter_map(|frame| frame.written_at)
+        .last()
+        .unwrap_or(0))
+}
+
+/// The numbered records of the log at `path`, up to the first torn frame, with values
+/// kept in the value log in `dir` read back in. Records logged before any sequence
+/// number was set cannot be placed and are left out.
+fn read_log(
+    storage: &dyn Storage,
+    dir: &Path,
+    path: &Path,
+    keys: &WalKeys,
+) -> io::Result<VecDeque<(u64, WalRecord)>> {
+    let mut records = VecDeque::new();
+    let mut next_seq = None;
+    for frame in WalIterator::with_keys(storage, &path.to_string_lossy(), keys.clone())? {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) if wal::is_torn(&e) => break,
+            Err(e) => return Err(e),
+        };
+        next_seq = frame.sequence.or(next_seq);
+        for record in frame.records {
+            let Some(seq) = next_seq else { continue };
+            next_seq = Some(seq + 1);
+            let record = match record {
+                WalRecord::PutBlob { key, pointer, expires_at } => {
+                    WalRecord::Put { key, value: pointer.read(storage, dir)?, expires_at }
+                }
+                record => record,
+            };
+            records.push_back((seq, record));
+        }
+    }
+    Ok(records)
+}
+
+/// The numbered records of the logs at `paths`, a WAL and its shards' segments, merged
+/// in sequence order like [`read_log`] reads one
+pub(crate) fn read_logs(
+    storage: &dyn Storage,
+    dir: &Path,
+    paths: &[PathBuf],
+    keys: &WalKeys,
+) -> io::Result<VecDeque<(u64, WalRecord)>> {
+    let mut records = Vec::new();
+    for path in paths {
+        records.extend(read_log(storage, dir, path, keys)?);
+    }
+    records.sort_by_key(|(seq, _)| *seq);
+    Ok(records.into())
+}
+
+/// Every write after a sequence number, as returned by
+/// [`Db::wal_records_since`](crate::Db::wal_records_since).
+///
+/// Yields each record with its sequence number, in order, reading the archived WALs one
+/// at a time and then the live WAL as it was when the iterator was created. Values kept
+/// in the value log come back as plain puts. If a record after the starting point is no
+/// longer kept, because its WAL was pruned or it was never logged (as with ingested
+/// tables), the iterator yields [`EngineError::WalGap`] and stops: the replica has to be
+/// rebuilt from a backup.
+pub struct WalRecords {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    keys: WalKeys,
+    /// Files of the archived WALs not read yet, oldest first
+    archived: VecDeque<Vec<PathBuf>>,
+    /// The live WAL's records, taken when the iterator was created
+    live: Option<VecDeque<(u64, WalRecord)>>,
+    /// Records of the WAL being read
+    pending: VecDeque<(u64, WalRecord)>,
+    /// Sequence number of the next record to yield
+    next: u64,
+    /// Highest sequence number assigned when the iterator was created
+    last_seq: u64,
+    done: bool,
+}
+
+impl WalRecords {
+    pub(crate) fn new(
+        storage: Arc<dyn Storage>,
+        dir: PathBuf,
+        keys: WalKeys,
+        archived: Vec<Vec<PathBuf>>,
+        live: VecDeque<(u64, WalRecord)>,
+        after: u64,
+        last_seq: u64,
+    ) -> Self {
+        WalRecords {
+            storage,
+            dir,
+            keys,
+            archived: archived.into(),
+            live: Some(live),
+            pending: VecDeque::new(),
+            next: after + 1,
+            last_seq,
+            done: false,
+        }
+    }
+
+    fn gap(&mut self) -> io::Error {
+        self.done = true;
+        EngineError::WalGap { sequence: self.next }.into()
+    }
+}
+
+impl Iterator for WalRecords {
+    type Item = io::Result<(u64, WalRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((seq, record)) = self.pending.pop_front() {
+                if seq < self.next {
+                    continue;
+                }
+                if seq > self.next {
+                    return Some(Err(self.gap()));
+                }
+                self.next += 1;
+                return Some(Ok((seq, record)));
+            }
+
+            if let Some(paths) = self.archived.pop_front() {
+                match read_logs(&*self.storage, &self.dir, &paths, &self.keys) {
+                    Ok(records) => self.pending = records,
+                    // Pruned by a flush since the iterator was created
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Some(Err(self.gap())),
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            } else if let Some(live) = self.live.take() {
+                self.pending = live;
+            } else if self.next <= self.last_seq {
+                return Some(Err(self.gap()));
+            } else {
+                self.done = true;
+            }
+        }
+        None
+    }
+}
+
+/// A committed write, as yielded by [`Db::changes_since`](crate::Db::changes_since)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub sequence: u64,
+    pub key: Vec<u8>,
+    /// The key's value after the write, `None` once it is deleted
+    pub value: Option<Vec<u8>>,
+    /// Set for a range deletion, which deletes every key from `key` up to this one,
+    /// exclusive
+    pub range_end: Option<Vec<u8>>,
+}
+
+/// Every committed write after a sequence number, as returned by
+/// [`Db::changes_since`](crate::Db::changes_since).
+///
+/// Reads the same WALs as [`WalRecords`] and ends the same way when records are no
+/// longer kept. Merges come back with the value they resolve to, read from the column
+/// family when the change is reached; if compaction has since folded that version
+/// into a newer one, the change fails with `NotFound`.
+pub struct Changes {
+    records: WalRecords,
+    memtable: Arc<RwLock<MemTable>>,
+}
+
+impl Changes {
+    pub(crate) fn new(records: WalRecords, memtable: Arc<RwLock<MemTable>>) -> Self {
+        Changes { records, memtable }
+    }
+
+    fn change(&self, sequence: u64, record: WalRecord) -> io::Result<Change> {
+        let (key, value, range_end) = match record {
+            WalRecord::Put { key, value, .. } => (key, Some(value), None),
+            WalRecord::Delete { key } => (key, None, None),
+            WalRecord::DeleteRange { start, end } => (start, None, Some(end)),
+            WalRecord::Merge { key, .. } => {
+                let memtable = self.memtable.read().unwrap_or_else(PoisonError::into_inner);
+                let value = match memtable.get_at(&key, sequence) {
+                    Some((value, seq)) if seq == sequence => value,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "the value merged into {:?} at sequence {} is no longer kept",
+                                String::from_utf8_lossy(&key),
+                                sequence
+                            ),
+                        ))
+                    }
+                };
+                (key, Some(value), None)
+            }
+            WalRecord::PutBlob { .. } => unreachable!("shipped records carry their values"),
+        };
+        Ok(Change { sequence, key, value, range_end })
+    }
+}
+
+impl Iterator for Changes {
+    type Item = io::Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.records.next()?.and_then(|(sequence, record)| self.change(sequence, record)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Change;
+    use crate::storage::Storage;
+    use crate::wal::WalRecord;
+    use crate::{Db, EngineError, MemStorage, Options, WriteBatch};
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn open(dir: &str, wal_retention: Option<Duration>) -> Db {
+        open_with(dir, Options { wal_retention, ..Options::default() })
+    }
+
+    fn open_with(dir: &str, options: Options) -> Db {
+        let options = Options {
+            storage: Some(Arc::new(MemStorage::new())),
+            value_log_threshold: Some(32),
+            ..options
+        };
+        Db::open_with_options(dir, options).unwrap()
+    }
+
+    /// Apply everything `primary` logged after `since` to `replica`, returning the
+    /// sequence number applied through
+    fn ship(primary: &Db, replica: &Db, since: u64) -> io::Result<u64> {
+        let mut applied = since;
+        for record in primary.wal_records_since(since)? {
+            let (seq, record) = record?;
+            match record {
+                WalRecord::Put { key, value, .. } => replica.put(key, value)?,
+                WalRecord::Delete { key } => drop(replica.delete_bytes(key)?),
+                WalRecord::Merge { key, operand } => replica.merge(key, operand)?,
+                WalRecord::DeleteRange { start, end } => replica.delete_range(start, end)?,
+                WalRecord::PutBlob { .. } => unreachable!("values are read back in"),
+            }
+            applied = seq;
+        }
+        Ok(applied)
+    }
+
+    fn contents(db: &Db) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.scan_bytes(&b"\x00"[..], &b"\xff"[..]).unwrap()
+    }
+
+    #[test]
+    fn test_replica_catches_up_across_wal_rotations() {
+        let primary = open("test_ship_primary", Some(Duration::from_secs(3600)));
+        let replica = open("test_ship_replica", None);
+
+        for i in 0..40 {
+            primary.put(format!("key{:03}", i), format!("value{}", i)).unwrap();
+        }
+        let mut applied = ship(&primary, &replica, 0).unwrap();
+        assert_eq!(applied, 40);
+
+        // 100 writes rotate the WAL on their own; the flush rotates it again
+        for i in 40..150 {
+            primary.put(format!("key{:03}", i), format!("value{}", i)).unwrap();
+        }
+        primary.put("big", vec![b'x'; 100]).unwrap();
+        primary.delete("key007").unwrap();
+        primary.delete_range("key100", "key120").unwrap();
+        primary.flush().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("key200", "batched");
+        batch.delete("key008");
+        primary.write(&batch).unwrap();
+        assert!(primary.sstable_paths().len() >= 2);
+
+        applied = ship(&primary, &replica, applied).unwrap();
+        assert_eq!(applied, primary.last_sequence());
+        assert_eq!(contents(&replica), contents(&primary));
+        assert_eq!(ship(&primary, &replica, applied).unwrap(), applied);
+
+        // Ingested rows never go through the WAL, so they cannot be shipped
+        primary.bulk_ingest([("key300", "ingested")]).unwrap();
+        primary.put("key301", "after").unwrap();
+        let err = ship(&primary, &replica, applied).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let gap = EngineError::WalGap { sequence: applied + 1 };
+        assert_eq!(EngineError::from_io(&err), Some(&gap));
+    }
+
+    #[test]
+    fn test_sharded_primary_ships_every_segment() {
+        let dir = "test_ship_sharded";
+        let storage = Arc::new(MemStorage::new());
+        let options = || Options {
+            storage: Some(storage.clone()),
+            memtable_shards: Some(4),
+            wal_retention: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let primary = Db::open_with_options(dir, options()).unwrap();
+        let replica = open("test_ship_sharded_replica", None);
+
+        // Puts go to the shards' segments, the delete to the WAL
+        primary.put("a", "1").unwrap();
+        primary.put("b", "2").unwrap();
+        primary.delete("a").unwrap();
+        let records: Vec<_> = primary.wal_records_since(0).unwrap().map(Result::unwrap).collect();
+        let sequences: Vec<u64> = records.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(sequences, [1, 2, 3]);
+        let changes: Vec<Change> = primary.changes_since(0).unwrap().map(Result::unwrap).collect();
+        assert_eq!(changes.len(), 3);
+        assert_eq!((changes[2].key.as_slice(), changes[2].value.as_ref()), (&b"a"[..], None));
+
+        // Segments are archived with the WAL a flush retires, and found again on reopening
+        for i in 0..250 {
+            primary.put(format!("key{:03}", i), format!("value{}", i)).unwrap();
+        }
+        primary.delete("key010").unwrap();
+        drop(primary);
+        let primary = Db::open_with_options(dir, options()).unwrap();
+        assert!(primary.sstable_paths().len() >= 2);
+        let files = storage.list_dir(Path::new(dir)).unwrap();
+        let names = files.iter().map(|path| path.file_name().unwrap().to_string_lossy());
+        let archived = |name: &str| name.starts_with("wal_") && name.contains(".shard");
+        assert!(names.filter(|name| archived(name)).count() > 0);
+        assert_eq!(ship(&primary, &replica, 0).unwrap(), primary.last_sequence());
+        assert_eq!(contents(&replica), contents(&primary));
+    }
+
+    #[test]
+    fn test_flushed_records_are_a_gap_without_retention() {
+        let dir = "test_ship_no_retention";
+        let db = open(dir, None);
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+        db.put("b", "2").unwrap();
+
+        let mut records = db.wal_records_since(0).unwrap();
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(EngineError::from_io(&err), Some(&EngineError::WalGap { sequence: 1 }));
+        assert!(records.next().is_none());
+        let shipped: Vec<_> = db.wal_records_since(1).unwrap().map(Result::unwrap).collect();
+        assert_eq!(shipped.len(), 1);
+        assert_eq!(shipped[0].0, 2);
+    }
+
+    #[test]
+    fn test_follower_applies_shipped_records_until_promoted() {
+        let primary = open("test_follower_primary", Some(Duration::from_secs(3600)));
+        let follower_options = Options { follower: true, ..Options::default() };
+        let follower = open_with("test_follower_replica", follower_options);
+
+        for i in 0..120 {
+            primary.put(format!("key{:03}", i), format!("value{}", i)).unwrap();
+        }
+        primary.delete("key005").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("key005", "restored");
+        batch.delete("key006");
+        batch.put("large", vec![b'v'; 64]);
+        primary.write(&batch).unwrap();
+        primary.flush().unwrap();
+        primary.delete_range("key100", "key110").unwrap();
+
+        for record in primary.wal_records_since(follower.last_sequence()).unwrap() {
+            let (seq, record) = record.unwrap();
+            follower.apply_replicated(seq, record).unwrap();
+        }
+        assert_eq!(follower.last_sequence(), primary.last_sequence());
+        assert_eq!(contents(&follower), contents(&primary));
+        assert_eq!(follower.get("key005"), Some("restored".to_string()));
+
+        // Direct writes are refused, and so is a replicated write that skips one
+        let err = follower.put("direct", "1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(follower.cf("default").unwrap().delete("key001").is_err());
+        let next = follower.last_sequence() + 1;
+        let put = WalRecord::Put { key: b"late".to_vec(), value: b"1".to_vec(), expires_at: None };
+        let err = follower.apply_replicated(next + 1, put.clone()).unwrap_err();
+        let skipped = EngineError::ReplicationOutOfOrder { expected: next, received: next + 1 };
+        assert_eq!(EngineError::from_io(&err), Some(&skipped));
+        assert_eq!(follower.get("late"), None);
+
+        follower.promote();
+        assert!(!follower.is_follower());
+        follower.put("direct", "1").unwrap();
+        let err = follower.apply_replicated(next + 1, put).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_change_consumer_resumes_without_gaps_or_duplicates() {
+        let dir = "test_changes_since";
+        let options = Options {
+            storage: Some(Arc::new(MemStorage::new())),
+            wal_retention: Some(Duration::from_secs(3600)),
+            merge_operator: Some(Arc::new(|_key, existing, operand| {
+                let mut value = existing.unwrap_or_default().to_vec();
+                value.extend_from_slice(operand);
+                value
+            })),
+            ..Options::default()
+        };
+
+        // The consumer checkpoints the sequence number of each change it processes
+        let mut seen = Vec::new();
+        let mut checkpoint = 0;
+        let mut consume = |db: &Db| {
+            for change in db.changes_since(checkpoint).unwrap() {
+                let change = change.unwrap();
+                checkpoint = change.sequence;
+                seen.push(change);
+            }
+        };
+
+        let db = Db::open_with_options(dir, options.clone()).unwrap();
+        for i in 0..60 {
+            db.put(format!("key{:02}", i), "v1").unwrap();
+        }
+        consume(&db);
+        db.flush().unwrap();
+        db.delete("key01").unwrap();
+        db.merge("log", "a").unwrap();
+        db.merge("log", "b").unwrap();
+        db.delete_range("key10", "key20").unwrap();
+        for i in 0..120 {
+            db.put(format!("key{:02}", i % 60), "v2").unwrap();
+        }
+        consume(&db);
+        db.put("before-restart", "1").unwrap();
+        drop(db);
+
+        let db = Db::open_with_options(dir, options).unwrap();
+        db.put("after-restart", "1").unwrap();
+        consume(&db);
+        consume(&db);
+
+        let sequences: Vec<u64> = seen.iter().map(|change| change.sequence).collect();
+        assert_eq!(sequences, (1..=db.last_sequence()).collect::<Vec<_>>());
+        let change = |sequence: u64, key: &str, value: Option<&str>| Change {
+            sequence,
+            key: key.into(),
+            value: value.map(Into::into),
+            range_end: None,
+        };
+        assert_eq!(seen[61], change(62, "log", Some("a")));
+        assert_eq!(seen[62], change(63, "log", Some("ab")));
+        assert_eq!(seen[63].range_end.as_deref(), Some(&b"key20"[..]));
+        assert_eq!(seen[60], change(61, "key01", None));
+        assert_eq!(seen.last(), Some(&change(186, "after-restart", Some("1"))));
+    }
+}
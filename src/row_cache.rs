@@ -0,0 +1,365 @@
+//! Recently read values of keys that live in SSTables.
+//!
+//! With [`Options::row_cache_bytes`](crate::Options#structfield.row_cache_bytes) set,
+//! each column family keeps the values its gets resolved from SSTables alone, so the
+//! next get of a hot key skips the tables. Every write drops the entries of the keys it
+//! touches, and a cached value is only used for reads at or above the sequence number
+//! it was written at, so neither newer writes nor older snapshots see it wrongly.
+//!
+//! With [`Options::negative_cache_keys`](crate::Options#structfield.negative_cache_keys)
+//! set, each column family also remembers keys its gets found missing or deleted, so
+//! probing them again reads no SSTables until they are written.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// Estimated bookkeeping cost of a cached row beyond its key and value: the map and
+/// recency entries
+const ROW_OVERHEAD: usize = 64;
+
+/// Row cache activity, as reported in [`crate::Stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RowCacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) bytes: u64,
+}
+
+/// The most recently read values of a column family, by key, up to a byte budget
+#[derive(Debug)]
+pub(crate) struct RowCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Each key's value with the sequence it was written at and when it was last used
+    rows: BTreeMap<Vec<u8>, Row>,
+    /// Keys of `rows` by last use, oldest first
+    recency: BTreeMap<u64, Vec<u8>>,
+    bytes: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Row {
+    seq: u64,
+    value: Vec<u8>,
+    used: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(row) = self.rows.remove(key) {
+            self.recency.remove(&row.used);
+            self.bytes -= row_size(key, &row.value);
+        }
+    }
+}
+
+fn row_size(key: &[u8], value: &[u8]) -> usize {
+    key.len() + value.len() + ROW_OVERHEAD
+}
+
+impl RowCache {
+    /// A cache of up to `capacity` bytes; 0 caches nothing
+    pub(crate) fn new(capacity: usize) -> Self {
+        RowCache {
+            capacity,
+            state: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The cached value of `key` with its sequence number, if it is visible at `seq`
+    pub(crate) fn get(&self, key: &[u8], seq: u64) -> Option<(Vec<u8>, u64)> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.clock += 1;
+        match state.rows.get_mut(key) {
+            Some(row) if row.seq <= seq => {
+                state.recency.remove(&row.used);
+                row.used = state.clock;
+                state.recency.insert(state.clock, key.to_vec());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((row.value.clone(), row.seq))
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache `value` as the newest version of `key`, written at `seq`, evicting the
+    /// least recently used rows to make room
+    pub(crate) fn insert(&self, key: &[u8], value: &[u8], seq: u64) {
+        let size = row_size(key, value);
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.remove(key);
+        while state.bytes + size > self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else { break };
+            if let Some(evicted) = state.rows.remove(&oldest) {
+                state.bytes -= row_size(&oldest, &evicted.value);
+            }
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.bytes += size;
+        state.recency.insert(used, key.to_vec());
+        state.rows.insert(key.to_vec(), Row { seq, value: value.to_vec(), used });
+    }
+
+    /// Drop the row of `key`, which has just been written
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        if self.is_enabled() {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+        }
+    }
+
+    /// Drop the rows of the keys in `[start, end)`
+    pub(crate) fn invalidate_range(&self, start: &[u8], end: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let keys: Vec<Vec<u8>> = state
+            .rows
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+
+    /// Drop every row
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.rows.clear();
+        state.recency.clear();
+        state.bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> RowCacheStats {
+        let bytes = self.state.lock().unwrap_or_else(PoisonError::into_inner).bytes;
+        RowCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: bytes as u64,
+        }
+    }
+
+    /// Zero the hit and miss counts
+    pub(crate) fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Keys a column family's gets recently found absent, up to a count
+#[derive(Debug)]
+pub(crate) struct AbsentKeys {
+    capacity: usize,
+    state: Mutex<AbsentState>,
+    hits: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct AbsentState {
+    /// Each key with when it was last found absent or asked about
+    keys: HashMap<Vec<u8>, u64>,
+    /// Keys of `keys` by last use, oldest first
+    recency: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl AbsentKeys {
+    /// A cache of up to `capacity` keys; 0 caches nothing
+    pub(crate) fn new(capacity: usize) -> Self {
+        AbsentKeys { capacity, state: Mutex::default(), hits: AtomicU64::new(0) }
+    }
+
+    /// Whether `key` was found absent and has not been written since
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.clock += 1;
+        let Some(used) = state.keys.get_mut(key) else { return false };
+        state.recency.remove(used);
+        *used = state.clock;
+        state.recency.insert(state.clock, key.to_vec());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Remember that `key` is absent, forgetting the least recently used key if full
+    pub(crate) fn insert(&self, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.clock += 1;
+        if let Some(used) = state.keys.insert(key.to_vec(), state.clock) {
+            state.recency.remove(&used);
+        } else if state.keys.len() > self.capacity {
+            if let Some((_, oldest)) = state.recency.pop_first() {
+                state.keys.remove(&oldest);
+            }
+        }
+        state.recency.insert(state.clock, key.to_vec());
+    }
+
+    /// Forget `key`, which has just been written
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(used) = state.keys.remove(key) {
+            state.recency.remove(&used);
+        }
+    }
+
+    /// Forget every key
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.keys.clear();
+        state.recency.clear();
+    }
+
+    /// Gets answered without a lookup
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::FaultStorage;
+    use crate::memtable::MemTable;
+    use crate::{Db, MemStorage, Options};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_row_cache_evicts_least_recently_used() {
+        let cache = RowCache::new(2 * row_size(b"a", b"1"));
+        cache.insert(b"a", b"1", 1);
+        cache.insert(b"b", b"2", 2);
+        assert_eq!(cache.get(b"a", u64::MAX), Some((b"1".to_vec(), 1)));
+        cache.insert(b"c", b"3", 3);
+
+        // "b" was the least recently used, so it was the one evicted
+        assert_eq!(cache.get(b"b", u64::MAX), None);
+        assert_eq!(cache.get(b"c", 2), None, "written after the read");
+        cache.invalidate_range(b"a", b"c");
+        assert_eq!(cache.get(b"a", u64::MAX), None);
+        assert_eq!(cache.get(b"c", 3), Some((b"3".to_vec(), 3)));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 3));
+        assert_eq!(stats.bytes, row_size(b"c", b"3") as u64);
+    }
+
+    #[test]
+    fn test_gets_hit_the_row_cache_until_the_key_is_written() {
+        let dir = "test_row_cache";
+
+        let options = Options {
+            storage: Some(Arc::new(MemStorage::new())),
+            row_cache_bytes: Some(1 << 20),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(dir, options).unwrap();
+        db.put("hot", "1").unwrap();
+        db.put("cold", "1").unwrap();
+        db.flush().unwrap();
+
+        assert_eq!(db.get("hot"), Some("1".to_string()));
+        assert_eq!(db.get("hot"), Some("1".to_string()));
+        let stats = db.stats().unwrap();
+        assert_eq!((stats.row_cache_hits, stats.row_cache_misses), (1, 1));
+        assert_eq!(stats.sstable_hits, 2);
+
+        db.put("hot", "2").unwrap();
+        assert_eq!(db.get("hot"), Some("2".to_string()));
+        db.flush().unwrap();
+        assert_eq!(db.get("hot"), Some("2".to_string()));
+        assert_eq!(db.get("hot"), Some("2".to_string()));
+
+        // The delete reads the value it hides through the cache
+        db.get("cold");
+        assert_eq!(db.delete("cold").unwrap(), Some("1".to_string()));
+        assert_eq!(db.get("cold"), None);
+        db.get("hot");
+        db.delete_range("a", "z").unwrap();
+        assert_eq!(db.get("hot"), None);
+        assert_eq!(db.stats().unwrap().row_cache_hits, 4);
+    }
+
+    #[test]
+    fn test_repeated_misses_read_no_sstables() {
+        let storage = FaultStorage::new();
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            negative_cache_keys: Some(16),
+            ..Options::default()
+        };
+        let mut memtable = MemTable::with_options("test_negative_cache.log", &options).unwrap();
+        memtable.put("gone", "1").unwrap();
+        memtable.flush().unwrap();
+        memtable.delete("gone").unwrap();
+        memtable.flush().unwrap();
+
+        // The key filters cannot rule out a deleted key, so only the cache saves reads
+        assert_eq!(memtable.get("gone"), None);
+        let before = storage.reads();
+        assert_eq!(memtable.get("gone"), None);
+        assert_eq!(storage.reads(), before);
+        assert_eq!(memtable.absent_keys().hits(), 1);
+
+        memtable.put("gone", "2").unwrap();
+        assert_eq!(memtable.get("gone"), Some(b"2".to_vec()));
+        memtable.flush().unwrap();
+        assert_eq!(memtable.get("gone"), Some(b"2".to_vec()));
+        assert_eq!(memtable.absent_keys().hits(), 1);
+    }
+
+    #[test]
+    fn test_absent_keys_forget_least_recently_used() {
+        let absent = AbsentKeys::new(2);
+        absent.insert(b"a");
+        absent.insert(b"b");
+        assert!(absent.contains(b"a"));
+        absent.insert(b"c");
+        assert!(!absent.contains(b"b"));
+        absent.invalidate(b"a");
+        assert!(!absent.contains(b"a"));
+        assert!(absent.contains(b"c"));
+        assert_eq!(absent.hits(), 2);
+    }
+}
@@ -0,0 +1,77 @@
+//! Random live keys for [`Db::sample_keys`](crate::Db::sample_keys), without a full scan.
+//!
+//! Each pick chooses the memtable or one SSTable in proportion to the versions it holds,
+//! then a key inside it: any key of the memtable, or in a table one of the spans its
+//! top-level block index divides it into, and a key among the first [`SPAN_KEYS`] of
+//! that span. Picks that turn out deleted, expired, or already taken are discarded.
+//!
+//! The picks are not exactly uniform. Keys with several versions, or copies in several
+//! tables, are picked more often, as are keys in spans holding fewer keys and, in spans
+//! holding more than [`SPAN_KEYS`], the keys at their start. A database with few enough
+//! versions is instead listed in full and sampled exactly.
+
+use crate::sstable::SSTableIterator;
+use crate::storage::Storage;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+
+/// Keys read from the start of a span to pick one from
+pub(crate) const SPAN_KEYS: usize = 64;
+/// Picks made per key asked for, before giving up on finding more live keys
+pub(crate) const PICKS_PER_KEY: usize = 4;
+
+/// xorshift64*, seeded differently on every call
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Rng(seed | 1)
+    }
+
+    /// A number in `[0, n)`; `n` must not be 0
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+
+    pub(crate) fn index(&mut self, len: usize) -> usize {
+        self.below(len as u64) as usize
+    }
+}
+
+/// The distinct keys of the table at `path` after `after` and up to `through`, missing
+/// bounds being open, at most [`SPAN_KEYS`] of them
+pub(crate) fn span_keys(
+    storage: &dyn Storage,
+    path: &str,
+    after: Option<&[u8]>,
+    through: Option<&[u8]>,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut table = SSTableIterator::open(storage, path)?;
+    if let Some(after) = after {
+        table.skip_to(after)?;
+    }
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for item in table.keys_only() {
+        let (key, _) = item?;
+        if after.is_some_and(|after| key.as_slice() <= after) {
+            continue;
+        }
+        if through.is_some_and(|through| key.as_slice() > through) {
+            break;
+        }
+        if keys.last() != Some(&key) {
+            if keys.len() == SPAN_KEYS {
+                break;
+            }
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
@@ -0,0 +1,152 @@
+use crate::db::Db;
+use crate::iterator::DbIterator;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Mutex, PoisonError};
+
+/// Registry of the sequence numbers pinned by live snapshots.
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    pinned: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub(crate) fn acquire(&self, seq: u64) {
+        *self.lock().entry(seq).or_insert(0) += 1;
+    }
+
+    pub(crate) fn release(&self, seq: u64) {
+        let mut pinned = self.lock();
+        if let Some(count) = pinned.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(&seq);
+            }
+        }
+    }
+
+    /// Every pinned sequence number, lowest first
+    pub(crate) fn pinned(&self) -> Vec<u64> {
+        self.lock().keys().copied().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, usize>> {
+        self.pinned.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A consistent, read-only view of the database as of the moment it was taken.
+///
+/// Reads through a snapshot ignore every write with a higher sequence number. Overwritten
+/// versions are kept in the memtable, in flushed SSTables, and through compaction, so a
+/// snapshot stays valid for as long as it is held.
+/// Dropping it releases the pin.
+pub struct Snapshot {
+    db: Db,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(db: Db, seq: u64) -> Self {
+        Snapshot { db, seq }
+    }
+
+    /// The sequence number this snapshot is pinned to
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<String> {
+        self.db.get_at(key, self.seq).map(|(value, _)| value)
+    }
+
+    /// Iterate over every live key-value pair as of this snapshot, in key order
+    pub fn iter(&self) -> DbIterator {
+        self.db.iter_at_sequence(self.seq)
+    }
+
+    /// Live key-value pairs in `[start, end)` as of this snapshot
+    pub fn scan<K: AsRef<[u8]>>(&self, start: K, end: K) -> io::Result<Vec<(String, String)>> {
+        self.db.scan_at_sequence(start.as_ref(), end.as_ref(), self.seq)
+    }
+
+    /// Live key-value pairs in `[start, end)` as of this snapshot, highest key first
+    pub fn scan_rev<K: AsRef<[u8]>>(
+        &self,
+        start: K,
+        end: K,
+    ) -> io::Result<Vec<(String, String)>> {
+        self.db.scan_rev_at_sequence(start.as_ref(), end.as_ref(), self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.db.release_snapshot(self.seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_snapshot_ignores_later_writes() {
+        let dir = "test_snapshot_later_writes";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("key1", "old1").unwrap();
+        db.put("key2", "old2").unwrap();
+
+        let snapshot = db.snapshot();
+        db.put("key1", "new1").unwrap();
+        db.delete("key2").unwrap();
+        db.put("key3", "new3").unwrap();
+
+        assert_eq!(snapshot.get("key1"), Some("old1".to_string()));
+        assert_eq!(snapshot.get("key2"), Some("old2".to_string()));
+        assert_eq!(snapshot.get("key3"), None);
+        assert_eq!(
+            snapshot.scan("a", "z").unwrap(),
+            vec![
+                ("key1".to_string(), "old1".to_string()),
+                ("key2".to_string(), "old2".to_string()),
+            ]
+        );
+
+        assert_eq!(db.get("key1"), Some("new1".to_string()));
+        assert_eq!(db.get("key2"), None);
+        assert_eq!(
+            db.scan("a", "z").unwrap(),
+            vec![
+                ("key1".to_string(), "new1".to_string()),
+                ("key3".to_string(), "new3".to_string()),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_survives_flush() {
+        let dir = "test_snapshot_flush";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("key1", "old1").unwrap();
+        db.flush().unwrap();
+
+        let snapshot = db.snapshot();
+        db.put("key1", "new1").unwrap();
+        db.delete("key1").unwrap();
+        db.put("key1", "newest1").unwrap();
+        db.flush().unwrap();
+
+        assert_eq!(snapshot.get("key1"), Some("old1".to_string()));
+        assert_eq!(db.get("key1"), Some("newest1".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
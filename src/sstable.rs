@@ -1,129 +1,1829 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use crate::entry::{now_millis, Entry, Op, RangeTombstone};
+use crate::filter::{self, BloomFilter, FilterIndex, FilterPartition};
+use crate::options::SyncMode;
+use crate::storage::{Storage, StorageFile};
+use crate::value_log::ValuePointer;
+use log::{debug, warn};
+
+/// Marks a versioned table. Legacy tables start directly with the entry count.
+const MAGIC: [u8; 4] = *b"SST8";
+/// Tables written before the entry count moved from the header to the footer and grew
+/// to 64 bits
+const MAGIC_V7: [u8; 4] = *b"SST7";
+/// Tables written before they carried a block index
+const MAGIC_V6: [u8; 4] = *b"SST6";
+/// Tables written before they carried a key filter
+const MAGIC_V5: [u8; 4] = *b"SST5";
+/// Tables written before the header recorded the creation time and lowest sequence
+const MAGIC_V4: [u8; 4] = *b"SST4";
+/// Tables written before versions carried their write time
+const MAGIC_V3: [u8; 4] = *b"SST3";
+/// Versioned tables written before range tombstones existed
+const MAGIC_V2: [u8; 4] = *b"SST2";
+/// Starts a table written encrypted, which only a build with the `encryption` feature
+/// can read
+pub(crate) const ENCRYPTED_MAGIC: [u8; 4] = *b"SSTX";
+/// `[magic][count u32, 0 from version 8][max_seq u64][min_seq u64][created_at u64]`
+const HEADER_LEN: u64 = 32;
+/// `[count u64][filter_index_offset u64][block_index_offset u64][block_index_len u32]
+/// [levels u32]`
+const FOOTER_LEN: u64 = 32;
+/// The footer of version 7, without the count
+const FOOTER_LEN_V7: u64 = 24;
+/// Smallest encoded entry: a versioned entry with an empty key and value
+const MIN_ENTRY_LEN: u64 = 25;
+/// Size at which the writer starts a new data block, the run of entries one block
+/// index entry points to
+const BLOCK_BYTES: u64 = 4 << 10;
+/// Most entries in one block index block. A table with more data blocks than this
+/// gets a second, top-level index over its index blocks.
+const INDEX_BLOCK_ENTRIES: usize = 128;
+/// Read size for sequential scans unless [`crate::Options::read_ahead_bytes`] says
+/// otherwise
+pub const DEFAULT_READ_AHEAD: usize = 256 << 10;
+
+const KIND_PUT: u8 = 0;
+const KIND_DELETE: u8 = 1;
+const KIND_MERGE: u8 = 2;
+/// A put followed by its expiry time (u64 Unix milliseconds)
+const KIND_PUT_EXPIRING: u8 = 3;
+/// A put whose value is an encoded value log pointer
+const KIND_BLOB: u8 = 4;
+/// A blob put followed by its expiry time
+const KIND_BLOB_EXPIRING: u8 = 5;
+
+/// Every version of every key, oldest first per key
+type VersionMap = BTreeMap<Vec<u8>, Vec<Entry>>;
+
+type Reader = BufReader<Box<dyn StorageFile>>;
 
 pub struct SSTable;
 
 impl SSTable {
     /// Write a sorted key-value map to an SSTable file
-    pub fn write(path: &str, data: &BTreeMap<String, String>) -> io::Result<()> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
+    pub fn write(
+        storage: &dyn Storage,
+        path: &str,
+        data: &BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> io::Result<()> {
+        let versions: VersionMap = data
+            .iter()
+            .map(|(k, v)| (k.clone(), vec![Entry::put(0, v.clone())]))
+            .collect();
+        Self::write_versions(storage, path, &versions, &[], 0, SyncMode::default())
+    }
+
+    /// Write every version of every key plus any range tombstones. Versions are ordered
+    /// oldest to newest, and `max_seq` is recorded in the header so the engine can
+    /// resume numbering. The finished file is synced with `sync_mode`.
+    ///
+    /// Layout: `[magic][0 u32][max_seq][min_seq][created_at]` followed by
+    /// `[key_len][key][seq][written_at][kind][value_len][value]` per version, newest
+    /// version first, with 0 for an unknown write time.
+    /// Values held in the value log are stored as their pointer. Expiring puts append
+    /// `[expires_at]`. The entries are followed by
+    /// `[range_count]` and `[start_len][start][end_len][end][seq]` per range tombstone,
+    /// then the key filter: its partitions, `[partition_count]` and
+    /// `[last_key_len][last_key][offset u64][len u32]` per partition. Next comes the
+    /// block index, `[count]` and `[last_key_len][last_key][offset u64][len u32]
+    /// [first_entry u64]` per data block of about 4 KiB; a table with many blocks
+    /// stores this in several index blocks followed by a top-level index of them in
+    /// the same format. The footer holds the number of entries, written once they are
+    /// all added, and locates both indexes.
+    pub fn write_versions<'a>(
+        storage: &dyn Storage,
+        path: &str,
+        data: impl IntoIterator<Item = (&'a Vec<u8>, &'a Vec<Entry>)>,
+        range_tombstones: &[RangeTombstone],
+        max_seq: u64,
+        sync_mode: SyncMode,
+    ) -> io::Result<()> {
+        let mut writer = SSTableWriter::create(storage, path)?;
+        writer.set_sync_mode(sync_mode);
+        for (key, versions) in data {
+            for entry in versions.iter().rev() {
+                writer.add(key, entry)?;
+            }
+        }
+        debug!("writing {} versions to {}", writer.count, path);
+        writer.finish(range_tombstones, max_seq)
+    }
+
+    /// Read the latest value of every key whose newest version is an unexpired put
+    pub fn read(storage: &dyn Storage, path: &str) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let versions = Self::read_versions(storage, path)?;
+        Ok(versions
+            .into_iter()
+            .filter_map(|(key, mut versions)| {
+                let value = versions.pop()?.value()?.to_vec();
+                Some((key, value))
+            })
+            .collect())
+    }
+
+    /// Read every version of every key, ordered oldest to newest per key
+    pub fn read_versions(storage: &dyn Storage, path: &str) -> io::Result<VersionMap> {
+        Ok(Self::read_table(storage, path)?.0)
+    }
+
+    /// Read the range tombstones stored in the table without loading its entries
+    pub fn read_range_tombstones(
+        storage: &dyn Storage,
+        path: &str,
+    ) -> io::Result<Vec<RangeTombstone>> {
+        if !storage.exists(Path::new(path)) {
+            return Ok(Vec::new());
+        }
+        SSTableIterator::open(storage, path)?.into_range_tombstones()
+    }
+
+    fn read_table(
+        storage: &dyn Storage,
+        path: &str,
+    ) -> io::Result<(VersionMap, Vec<RangeTombstone>)> {
+        if !storage.exists(Path::new(path)) {
+            return Ok((BTreeMap::new(), Vec::new()));
+        }
+
+        let mut iter = SSTableIterator::open(storage, path)?;
+        let mut data = VersionMap::new();
+        for item in iter.by_ref() {
+            let (key, entry) = item?;
+            data.entry(key).or_default().push(entry);
+        }
+
+        // Versions are stored newest first
+        for versions in data.values_mut() {
+            versions.reverse();
+        }
+
+        Ok((data, iter.into_range_tombstones()?))
+    }
+
+    /// Summarize the table at `path`, reading keys but skipping values
+    pub fn stats(storage: &dyn Storage, path: &str) -> io::Result<TableStats> {
+        let bytes = storage.file_len(Path::new(path))?;
+        let mut keys = SSTableIterator::open(storage, path)?.keys_only();
+        let header = keys.header();
+        let mut first_key = None;
+        let mut last_key = None;
+        for item in keys.by_ref() {
+            let (key, _) = item?;
+            if first_key.is_none() {
+                first_key = Some(key.clone());
+            }
+            last_key = Some(key);
+        }
 
-        let num_entries = data.len() as u32;
-        file.write_all(&num_entries.to_le_bytes())?;
+        Ok(TableStats {
+            bytes,
+            version: header.version,
+            entries: header.entries,
+            max_seq: header.max_seq,
+            min_seq: header.min_seq,
+            created_at: header.created_at,
+            first_key,
+            last_key,
+            range_tombstones: keys.into_range_tombstones()?.len(),
+        })
+    }
 
-        for (key, value) in data.iter() {
-            let key_bytes = key.as_bytes();
-            file.write_all(&(key_bytes.len() as u32).to_le_bytes())?;
-            file.write_all(key_bytes)?;
+    /// Read only the table's header
+    pub fn header(storage: &dyn Storage, path: &str) -> io::Result<TableHeader> {
+        Ok(SSTableIterator::open_sequential(storage, path, HEADER_LEN as usize)?.header())
+    }
 
-            let value_bytes = value.as_bytes();
-            file.write_all(&(value_bytes.len() as u32).to_le_bytes())?;
-            file.write_all(value_bytes)?;
+    /// Highest sequence number recorded in the table header (0 for legacy tables)
+    pub fn max_sequence(storage: &dyn Storage, path: &str) -> io::Result<u64> {
+        let mut file = storage.open(Path::new(path))?;
+        let mut magic = [0u8; 4];
+        let versioned = [MAGIC, MAGIC_V7, MAGIC_V6, MAGIC_V5, MAGIC_V4, MAGIC_V3, MAGIC_V2];
+        match file.read_exact(&mut magic) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            result => result?,
+        }
+        if magic == ENCRYPTED_MAGIC {
+            return Err(encrypted_table());
         }
+        if !versioned.contains(&magic) {
+            return Ok(0);
+        }
+        read_u32(&mut file)?;
+        read_u64(&mut file)
+    }
 
-        file.sync_all()?;
+    /// Get a value by key from an SSTable file
+    pub fn get(storage: &dyn Storage, path: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let entry = Self::get_at(storage, path, key, u64::MAX)?;
+        Ok(entry.and_then(|entry| entry.value().map(<[u8]>::to_vec)))
+    }
+
+    /// Newest version of `key` with a sequence number at or below `seq`, tombstones included
+    pub fn get_at(
+        storage: &dyn Storage,
+        path: &str,
+        key: &[u8],
+        seq: u64,
+    ) -> io::Result<Option<Entry>> {
+        Ok(Self::get_versions(storage, path, key)?
+            .into_iter()
+            .rev()
+            .find(|e| e.seq <= seq))
+    }
+
+    /// Where the partitions of the table's key filter are stored, read from the end of
+    /// the file. `None` for tables written before filters existed.
+    pub fn filter_index(storage: &dyn Storage, path: &str) -> io::Result<Option<FilterIndex>> {
+        let mut table = SSTableIterator::open(storage, path)?;
+        let Some(footer) = table.footer()? else { return Ok(None) };
+        table.read_filter_index(footer.filter_index, path).map(Some)
+    }
+
+    /// Last keys of the blocks the top level of the block index points to, in order: a
+    /// sample of the table's keys spaced by data size. Empty before version 7.
+    pub fn index_keys(storage: &dyn Storage, path: &str) -> io::Result<Vec<Vec<u8>>> {
+        let mut table = SSTableIterator::open(storage, path)?;
+        let Some(Footer { block_index: Some((offset, len, _)), .. }) = table.footer()? else {
+            return Ok(Vec::new());
+        };
+        Ok(table.read_index(offset, len)?.into_iter().map(|block| block.last_key).collect())
+    }
+
+    /// Load one partition of the table's key filter
+    pub fn read_filter(
+        storage: &dyn Storage,
+        path: &str,
+        partition: &FilterPartition,
+    ) -> io::Result<BloomFilter> {
+        let mut file = storage.open(Path::new(path))?;
+        read_filter(&mut file, path, partition)
+    }
+
+    /// Every stored version of `key`, oldest first. The block index leads straight to
+    /// the data block holding the key; tables written before it are read from the start.
+    pub fn get_versions(storage: &dyn Storage, path: &str, key: &[u8]) -> io::Result<Vec<Entry>> {
+        if !storage.exists(Path::new(path)) {
+            return Ok(Vec::new());
+        }
+        let mut table = SSTableIterator::open(storage, path)?;
+        if !table.seek_block(key, false)? {
+            return Ok(Vec::new());
+        }
+        table.versions_of(key)
+    }
+
+    /// Open the table at `path` at the value whose length field is at `offset`, as
+    /// [`SSTableReader::locate_versions`] gives it, returning the file positioned at
+    /// the value and the value's length
+    pub fn open_value(
+        storage: &dyn Storage,
+        path: &str,
+        offset: u64,
+    ) -> io::Result<(Box<dyn StorageFile>, u64)> {
+        let mut file = storage.open(Path::new(path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let len = read_u32(&mut file)?;
+        Ok((file, u64::from(len)))
+    }
+}
+
+/// Writes an SSTable one entry at a time, so tables larger than memory can be built.
+/// The entry count goes in the footer and the sequence range is patched into the header
+/// by [`SSTableWriter::finish`].
+pub struct SSTableWriter {
+    file: BufWriter<Box<dyn StorageFile>>,
+    count: u64,
+    bytes: u64,
+    /// Lowest sequence number added so far
+    min_seq: Option<u64>,
+    /// Last key added
+    last_key: Option<Vec<u8>>,
+    /// Hashes of the keys of the filter partition being built
+    filter_keys: Vec<u64>,
+    /// Each finished partition's last key and encoded filter
+    partitions: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Offset and entry number of the start of the data block being written
+    block_start: (u64, u64),
+    /// The finished data blocks
+    blocks: Vec<BlockHandle>,
+    sync_mode: SyncMode,
+}
+
+impl SSTableWriter {
+    pub fn create(storage: &dyn Storage, path: &str) -> io::Result<Self> {
+        let mut file = BufWriter::new(storage.create(Path::new(path))?);
+        file.write_all(&MAGIC)?;
+        file.write_all(&[0; HEADER_LEN as usize - MAGIC.len()])?;
+
+        Ok(SSTableWriter {
+            file,
+            count: 0,
+            min_seq: None,
+            last_key: None,
+            filter_keys: Vec::new(),
+            partitions: Vec::new(),
+            block_start: (HEADER_LEN, 0),
+            blocks: Vec::new(),
+            sync_mode: SyncMode::default(),
+            bytes: HEADER_LEN,
+        })
+    }
+
+    /// Append one version. Keys must be added in ascending order and the versions of a
+    /// key newest first.
+    pub fn add(&mut self, key: &[u8], entry: &Entry) -> io::Result<()> {
+        let expiring = entry.expires_at.is_some();
+        let encoded;
+        let (kind, value): (u8, &[u8]) = match &entry.op {
+            Op::Put(value) if expiring => (KIND_PUT_EXPIRING, value),
+            Op::Put(value) => (KIND_PUT, value),
+            Op::Delete => (KIND_DELETE, &[]),
+            Op::Merge(operand) => (KIND_MERGE, operand),
+            Op::Blob(pointer) => {
+                encoded = pointer.encode();
+                let kind = if expiring { KIND_BLOB_EXPIRING } else { KIND_BLOB };
+                (kind, &encoded)
+            }
+        };
+
+        let mut buf = Vec::with_capacity(key.len() + value.len() + 33);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.written_at.unwrap_or(0).to_le_bytes());
+        buf.push(kind);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+        if let Some(expires_at) = entry.expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+
+        if self.bytes - self.block_start.0 >= BLOCK_BYTES {
+            self.finish_block();
+        }
+        self.file.write_all(&buf)?;
+        self.count += 1;
+        self.min_seq = Some(self.min_seq.map_or(entry.seq, |min| min.min(entry.seq)));
+        if self.last_key.as_deref() != Some(key) {
+            if self.filter_keys.len() == filter::PARTITION_KEYS {
+                self.finish_partition();
+            }
+            self.filter_keys.push(filter::key_hash(key));
+            self.last_key = Some(key.to_vec());
+        }
+        self.bytes += buf.len() as u64;
         Ok(())
     }
 
-    pub fn read(path: &str) -> io::Result<BTreeMap<String, String>> {
-        if !Path::new(path).exists() {
-            return Ok(BTreeMap::new());
+    /// Close the data block of the entries added since the last one
+    fn finish_block(&mut self) {
+        let (offset, first_entry) = self.block_start;
+        if let (Some(last_key), true) = (&self.last_key, self.count > first_entry) {
+            self.blocks.push(BlockHandle {
+                last_key: last_key.clone(),
+                offset,
+                len: (self.bytes - offset) as u32,
+                first_entry,
+            });
         }
+        self.block_start = (self.bytes, self.count);
+    }
 
-        let mut file = File::open(path)?;
-        let mut data = BTreeMap::new();
+    /// Close the filter partition of the keys added since the last one
+    fn finish_partition(&mut self) {
+        if let Some(last_key) = &self.last_key {
+            let filter = BloomFilter::build(&self.filter_keys);
+            self.partitions.push((last_key.clone(), filter.encode()));
+        }
+        self.filter_keys.clear();
+    }
 
-        let mut num_entries_bytes = [0u8; 4];
-        file.read_exact(&mut num_entries_bytes)?;
-        let num_entries = u32::from_le_bytes(num_entries_bytes);
+    /// Sync the finished table with `sync_mode` rather than a full sync
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    /// Bytes written so far
+    pub fn len(&self) -> u64 {
+        self.bytes
+    }
 
-        for _ in 0..num_entries {
-            let mut key_len_bytes = [0u8; 4];
-            file.read_exact(&mut key_len_bytes)?;
-            let key_len = u32::from_le_bytes(key_len_bytes) as usize;
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
 
-            let mut key_bytes = vec![0u8; key_len];
-            file.read_exact(&mut key_bytes)?;
-            let key = String::from_utf8(key_bytes)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    /// Write the range tombstones and key filter, fill in the header, and sync the file.
+    /// The header records the lowest sequence number among the entries and range
+    /// tombstones, and the current time as the table's creation time.
+    pub fn finish(mut self, range_tombstones: &[RangeTombstone], max_seq: u64) -> io::Result<()> {
+        self.finish_block();
+        let range_seqs = range_tombstones.iter().map(|tombstone| tombstone.seq);
+        let min_seq = range_seqs.chain(self.min_seq).min().unwrap_or(0);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(range_tombstones.len() as u32).to_le_bytes());
+        for tombstone in range_tombstones {
+            for bound in [&tombstone.start, &tombstone.end] {
+                buf.extend_from_slice(&(bound.len() as u32).to_le_bytes());
+                buf.extend_from_slice(bound);
+            }
+            buf.extend_from_slice(&tombstone.seq.to_le_bytes());
+        }
 
-            let mut value_len_bytes = [0u8; 4];
-            file.read_exact(&mut value_len_bytes)?;
-            let value_len = u32::from_le_bytes(value_len_bytes) as usize;
+        if !self.filter_keys.is_empty() {
+            self.finish_partition();
+        }
+        let mut index = Vec::new();
+        index.extend_from_slice(&(self.partitions.len() as u32).to_le_bytes());
+        for (last_key, filter) in &self.partitions {
+            push_bytes(&mut index, last_key);
+            index.extend_from_slice(&(self.bytes + buf.len() as u64).to_le_bytes());
+            index.extend_from_slice(&(filter.len() as u32).to_le_bytes());
+            buf.extend_from_slice(filter);
+        }
+        let filter_index_offset = self.bytes + buf.len() as u64;
+        buf.extend_from_slice(&index);
 
-            let mut value_bytes = vec![0u8; value_len];
-            file.read_exact(&mut value_bytes)?;
-            let value = String::from_utf8(value_bytes)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let (block_index, levels) = if self.blocks.len() <= INDEX_BLOCK_ENTRIES {
+            (encode_index(&self.blocks), 1u32)
+        } else {
+            let mut top = Vec::new();
+            for chunk in self.blocks.chunks(INDEX_BLOCK_ENTRIES) {
+                let block = encode_index(chunk);
+                top.push(BlockHandle {
+                    last_key: chunk[chunk.len() - 1].last_key.clone(),
+                    offset: self.bytes + buf.len() as u64,
+                    len: block.len() as u32,
+                    first_entry: chunk[0].first_entry,
+                });
+                buf.extend_from_slice(&block);
+            }
+            (encode_index(&top), 2)
+        };
+        let block_index_offset = self.bytes + buf.len() as u64;
+        buf.extend_from_slice(&block_index);
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(&filter_index_offset.to_le_bytes());
+        buf.extend_from_slice(&block_index_offset.to_le_bytes());
+        buf.extend_from_slice(&(block_index.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&levels.to_le_bytes());
+        self.file.write_all(&buf)?;
 
-            data.insert(key, value);
+        let mut file = self.file.into_inner().map_err(|err| err.into_error())?;
+        // Past the count slot of older versions, left at 0
+        file.seek(SeekFrom::Start(MAGIC.len() as u64 + 4))?;
+        file.write_all(&max_seq.to_le_bytes())?;
+        file.write_all(&min_seq.to_le_bytes())?;
+        file.write_all(&now_millis().to_le_bytes())?;
+        self.sync_mode.sync(&mut *file)
+    }
+}
+
+/// Metadata stored at the start of a table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableHeader {
+    /// 8 for current tables, 7 for tables with a 32-bit entry count in the header, 6 for
+    /// tables without a block index either, 5 for tables without a key filter either,
+    /// 4 for tables without a creation time or lowest sequence either, 3 for tables
+    /// without write times either, 2 for tables without range tombstones either, 1 for
+    /// legacy unversioned tables
+    pub version: u8,
+    pub entries: u64,
+    /// Highest sequence number written to the table, 0 for legacy tables
+    pub max_seq: u64,
+    /// Lowest sequence number of an entry or range tombstone, 0 for an empty table or
+    /// one written before version 5
+    pub min_seq: u64,
+    /// Unix time in milliseconds the table was written, `None` before version 5
+    pub created_at: Option<u64>,
+}
+
+/// Summary of one table, from [`SSTable::stats`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableStats {
+    /// Size of the file
+    pub bytes: u64,
+    pub version: u8,
+    /// Number of versions stored, counting each version of a key
+    pub entries: u64,
+    pub max_seq: u64,
+    pub min_seq: u64,
+    pub created_at: Option<u64>,
+    /// Smallest and largest keys, `None` for a table without entries
+    pub first_key: Option<Vec<u8>>,
+    pub last_key: Option<Vec<u8>>,
+    pub range_tombstones: usize,
+}
+
+/// Streams the versions stored in an SSTable in file order: ascending key, newest
+/// version first within a key. Only one entry is held in memory at a time.
+pub struct SSTableIterator {
+    reader: Reader,
+    /// For locating read errors
+    path: String,
+    /// Size of the file, which no length field can exceed
+    file_len: u64,
+    header: TableHeader,
+    /// Offset of the first entry
+    entries_start: u64,
+    remaining: u64,
+    /// Number of the next entry within the table
+    next_entry: u64,
+    /// Offset of the next field to read, while reading entries and range tombstones
+    position: u64,
+    versioned: bool,
+    has_ranges: bool,
+    has_write_times: bool,
+    skip_values: bool,
+    /// Offset of the value length of the entry read last
+    value_offset: u64,
+}
+
+impl SSTableIterator {
+    /// Open for a short read such as a point lookup, buffering in small chunks
+    pub fn open(storage: &dyn Storage, path: &str) -> io::Result<Self> {
+        let file_len = storage.file_len(Path::new(path))?;
+        Self::from_reader(BufReader::new(storage.open(Path::new(path))?), path, file_len, false)
+    }
+
+    /// Open for reading the whole table front to back, in chunks of `read_ahead` bytes.
+    /// With 0 every field is read from the file on its own.
+    pub fn open_sequential(
+        storage: &dyn Storage,
+        path: &str,
+        read_ahead: usize,
+    ) -> io::Result<Self> {
+        let file_len = storage.file_len(Path::new(path))?;
+        let file = storage.open(Path::new(path))?;
+        Self::from_reader(BufReader::with_capacity(read_ahead, file), path, file_len, false)
+    }
+
+    /// Open a table whose footer may be lost, to salvage its entries: a table that
+    /// keeps its entry count in the footer is read until an entry fails to parse, and
+    /// its header reports 0 entries. Not for tables known to be whole, as the entries
+    /// may run on into whatever follows them.
+    pub fn open_salvage(
+        storage: &dyn Storage,
+        path: &str,
+        read_ahead: usize,
+    ) -> io::Result<Self> {
+        let file_len = storage.file_len(Path::new(path))?;
+        let file = storage.open(Path::new(path))?;
+        Self::from_reader(BufReader::with_capacity(read_ahead, file), path, file_len, true)
+    }
+
+    /// Read the header, and the entry count from the footer unless `salvage` says to
+    /// read entries without one
+    fn from_reader(
+        mut reader: Reader,
+        path: &str,
+        file_len: u64,
+        salvage: bool,
+    ) -> io::Result<Self> {
+        let truncated = || {
+            let message = format!("{}: file truncated in the header, at {} bytes", path, file_len);
+            io::Error::new(io::ErrorKind::UnexpectedEof, message)
+        };
+        if file_len < 4 {
+            return Err(truncated());
         }
+        let header = read_u32(&mut reader)?.to_le_bytes();
+        if header == ENCRYPTED_MAGIC {
+            return Err(encrypted_table());
+        }
+        let has_properties = [MAGIC, MAGIC_V7, MAGIC_V6, MAGIC_V5].contains(&header);
+        let has_write_times = has_properties || header == MAGIC_V4;
+        let has_ranges = has_write_times || header == MAGIC_V3;
+        let versioned = has_ranges || header == MAGIC_V2;
+        let header_len = match (has_properties, versioned) {
+            (true, _) => HEADER_LEN,
+            (false, true) => 16,
+            (false, false) => 4,
+        };
+        if file_len < header_len {
+            return Err(truncated());
+        }
+        let count = if versioned {
+            read_u32(&mut reader)?
+        } else {
+            u32::from_le_bytes(header)
+        };
+        let max_seq = if versioned { read_u64(&mut reader)? } else { 0 };
+        let (min_seq, created_at) = if has_properties {
+            (read_u64(&mut reader)?, Some(read_u64(&mut reader)?))
+        } else {
+            (0, None)
+        };
+        let version = match (has_write_times, has_ranges, versioned) {
+            (true, _, _) if header == MAGIC => 8,
+            (true, _, _) if header == MAGIC_V7 => 7,
+            (true, _, _) if header == MAGIC_V6 => 6,
+            (true, _, _) if has_properties => 5,
+            (true, _, _) => 4,
+            (false, true, _) => 3,
+            (false, false, true) => 2,
+            (false, false, false) => 1,
+        };
+        let entries_start = reader.stream_position()?;
+        let (entries, remaining) = match version {
+            8 if salvage => (0, u64::MAX),
+            8 => {
+                let entries = read_count(&mut reader, path, file_len, entries_start)?;
+                (entries, entries)
+            }
+            _ => (u64::from(count), u64::from(count)),
+        };
 
-        Ok(data)
+        Ok(SSTableIterator {
+            reader,
+            path: path.to_string(),
+            file_len,
+            header: TableHeader {
+                version,
+                entries,
+                max_seq,
+                min_seq,
+                created_at,
+            },
+            entries_start,
+            remaining,
+            next_entry: 0,
+            position: entries_start,
+            versioned,
+            has_ranges,
+            has_write_times,
+            skip_values: false,
+            value_offset: 0,
+        })
     }
 
-    /// Get a value by key from an SSTable file
-    pub fn get(path: &str, key: &str) -> io::Result<Option<String>> {
-        let data = Self::read(path)?;
-        Ok(data.get(key).cloned())
+    pub fn header(&self) -> TableHeader {
+        self.header
+    }
+
+    /// Read the footer of a version 6 or later table, leaving the reader anywhere
+    fn footer(&mut self) -> io::Result<Option<Footer>> {
+        let footer_len = match self.header.version {
+            8 => FOOTER_LEN,
+            7 => FOOTER_LEN_V7,
+            6 => 8,
+            _ => return Ok(None),
+        };
+        let start = self.file_len.checked_sub(footer_len);
+        let corrupt = || corrupt_footer(&self.path, start.unwrap_or(0));
+        let start = start.ok_or_else(corrupt)?;
+        self.reader.seek(SeekFrom::Start(start))?;
+        if self.header.version >= 8 {
+            // The count, already read on opening
+            read_u64(&mut self.reader)?;
+        }
+        let filter_index = read_u64(&mut self.reader)?;
+        let block_index = if self.header.version >= 7 {
+            let offset = read_u64(&mut self.reader)?;
+            let len = read_u32(&mut self.reader)?;
+            let levels = read_u32(&mut self.reader)?;
+            let end = offset.checked_add(u64::from(len));
+            if end.is_none_or(|end| end > start) || !(1..=2).contains(&levels) {
+                return Err(corrupt());
+            }
+            Some((offset, len, levels))
+        } else {
+            None
+        };
+        if !(HEADER_LEN..=start).contains(&filter_index) {
+            return Err(corrupt());
+        }
+        Ok(Some(Footer { filter_index, block_index }))
+    }
+
+    /// Position a fresh iterator at the data block where `key` would be, by way of the
+    /// block index: footer, top-level index if there is one, index block. Without a
+    /// block index the iterator stays at the first entry. `false` if the key is past
+    /// the last one in the table, unless `clamp` sends such keys to the last block.
+    fn seek_block(&mut self, key: &[u8], clamp: bool) -> io::Result<bool> {
+        if self.header.version < 7 {
+            return Ok(true);
+        }
+        let Some(Footer { block_index: Some((offset, len, levels)), .. }) = self.footer()? else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing block index"));
+        };
+        let top = self.read_index(offset, len)?;
+        self.seek_indexed(&top, levels, key, clamp)
+    }
+
+    /// Position at the data block where `key` would be, given the top level of a block
+    /// index with `levels` levels, like [`SSTableIterator::seek_block`]
+    fn seek_indexed(
+        &mut self,
+        top: &[BlockHandle],
+        levels: u32,
+        key: &[u8],
+        clamp: bool,
+    ) -> io::Result<bool> {
+        let find = |handles: &[BlockHandle]| {
+            let found = find_block(handles, key).cloned();
+            if clamp { found.or_else(|| handles.last().cloned()) } else { found }
+        };
+        let lower;
+        let mut handles = top;
+        if levels == 2 {
+            let Some(block) = find(handles) else { return Ok(false) };
+            lower = self.read_index(block.offset, block.len)?;
+            handles = &lower;
+        }
+        let Some(block) = find(handles) else { return Ok(false) };
+        self.remaining = self.header.entries.checked_sub(block.first_entry).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "block index entry past the last entry")
+        })?;
+        self.seek_entry(block.offset, block.first_entry)?;
+        Ok(true)
+    }
+
+    /// Skip ahead, by the block index, to the data block where `key` would be; the
+    /// entries read next may still include some before `key`. Only for a fresh iterator,
+    /// and it stays put on tables without a block index.
+    pub fn skip_to(&mut self, key: &[u8]) -> io::Result<()> {
+        if !self.seek_block(key, true)? {
+            // Only an empty table has no block to go to
+            self.rewind()?;
+        }
+        Ok(())
+    }
+
+    /// Go back to the first entry
+    fn rewind(&mut self) -> io::Result<()> {
+        self.seek_entry(self.entries_start, 0)?;
+        self.remaining = self.header.entries;
+        Ok(())
+    }
+
+    /// Continue reading at `offset`, where entry `next_entry` starts
+    fn seek_entry(&mut self, offset: u64, next_entry: u64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        (self.position, self.next_entry) = (offset, next_entry);
+        Ok(())
+    }
+
+    /// Every version of `key` from here to the first larger key, oldest first
+    fn versions_of(&mut self, key: &[u8]) -> io::Result<Vec<Entry>> {
+        let versions = self.located_versions_of(key)?;
+        Ok(versions.into_iter().map(|(entry, _)| entry).collect())
+    }
+
+    /// [`SSTableIterator::versions_of`], each with the offset of its value length
+    fn located_versions_of(&mut self, key: &[u8]) -> io::Result<Vec<(Entry, u64)>> {
+        let mut versions = Vec::new();
+        while let Some(item) = self.next() {
+            let (found, entry) = item?;
+            match found.as_slice().cmp(key) {
+                Ordering::Less => {}
+                Ordering::Equal => versions.push((entry, self.value_offset)),
+                Ordering::Greater => break,
+            }
+        }
+        versions.reverse();
+        Ok(versions)
+    }
+
+    /// Read the filter index at `offset` of the table at `path`
+    fn read_filter_index(&mut self, offset: u64, path: &str) -> io::Result<FilterIndex> {
+        let (reader, file_len) = (&mut self.reader, self.file_len);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut partitions = Vec::new();
+        for _ in 0..read_u32(reader)? {
+            let partition = FilterPartition {
+                last_key: read_bytes(reader, file_len)?,
+                offset: read_u64(reader)?,
+                len: read_u32(reader)?,
+            };
+            if partition.offset + u64::from(partition.len) > offset {
+                let message = format!("a partition of the filter index in {} is corrupt", path);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            partitions.push(partition);
+        }
+        Ok(FilterIndex { partitions })
+    }
+
+    /// Read one block of a block index
+    fn read_index(&mut self, offset: u64, len: u32) -> io::Result<Vec<BlockHandle>> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0u8; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        let mut reader = bytes.as_slice();
+        let wide_entries = self.header.version >= 8;
+        let mut handles = Vec::new();
+        for _ in 0..read_u32(&mut reader)? {
+            handles.push(BlockHandle {
+                last_key: read_bytes(&mut reader, u64::from(len))?,
+                offset: read_u64(&mut reader)?,
+                len: read_u32(&mut reader)?,
+                first_entry: match wide_entries {
+                    true => read_u64(&mut reader)?,
+                    false => u64::from(read_u32(&mut reader)?),
+                },
+            });
+        }
+        Ok(handles)
+    }
+
+    /// Byte offset in the file of the next entry (or of the range tombstones once every
+    /// entry has been read)
+    pub fn offset(&mut self) -> io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    /// Seek past values instead of reading them; entries carry an empty value
+    pub fn keys_only(mut self) -> Self {
+        self.skip_values = true;
+        self
+    }
+
+    fn read_value(&mut self, record: Record) -> io::Result<Vec<u8>> {
+        if self.skip_values {
+            self.skip_field(record, "value")?;
+            return Ok(Vec::new());
+        }
+        self.read_field(record, "value")
+    }
+
+    fn read_entry(&mut self) -> io::Result<(Vec<u8>, Entry)> {
+        let record = Record::Entry(self.next_entry);
+        let key = self.read_field(record, "key")?;
+        if !self.versioned {
+            self.value_offset = self.position;
+            return Ok((key, Entry::put(0, self.read_value(record)?)));
+        }
+
+        let seq = self.read_u64(record, "sequence number")?;
+        let written_at = if self.has_write_times {
+            Some(self.read_u64(record, "write time")?).filter(|&time| time != 0)
+        } else {
+            None
+        };
+        let kind_offset = self.position;
+        let [kind] = self.read_fixed(record, &"kind")?;
+        let value_offset = self.position;
+        self.value_offset = value_offset;
+        let value = self.read_value(record)?;
+        let entry = match kind {
+            KIND_PUT => Entry::put(seq, value),
+            KIND_DELETE => Entry::tombstone(seq),
+            KIND_MERGE => Entry::merge(seq, value),
+            KIND_PUT_EXPIRING => Entry {
+                expires_at: Some(self.read_u64(record, "expiry time")?),
+                ..Entry::put(seq, value)
+            },
+            KIND_BLOB | KIND_BLOB_EXPIRING => {
+                // Keys-only reads skip the pointer; a null one still marks a live put
+                let pointer = if self.skip_values {
+                    ValuePointer { file: 0, offset: 0, len: 0 }
+                } else {
+                    ValuePointer::decode(&value).ok_or_else(|| {
+                        self.malformed(record, value_offset, "malformed value pointer")
+                    })?
+                };
+                let expires_at = if kind == KIND_BLOB_EXPIRING {
+                    Some(self.read_u64(record, "expiry time")?)
+                } else {
+                    None
+                };
+                Entry { expires_at, ..Entry::new(seq, Op::Blob(pointer)) }
+            }
+            other => {
+                let problem = format!("unknown entry kind {}", other);
+                return Err(self.malformed(record, kind_offset, &problem));
+            }
+        };
+        Ok((key, Entry { written_at, ..entry }))
+    }
+
+    /// Step over an entry by its length fields without reading the key or value
+    fn skip_entry(&mut self) -> io::Result<()> {
+        let record = Record::Entry(self.next_entry);
+        self.skip_field(record, "key")?;
+        if !self.versioned {
+            return self.skip_field(record, "value");
+        }
+
+        self.read_u64(record, "sequence number")?;
+        if self.has_write_times {
+            self.read_u64(record, "write time")?;
+        }
+        let [kind] = self.read_fixed(record, &"kind")?;
+        self.skip_field(record, "value")?;
+        if kind == KIND_PUT_EXPIRING || kind == KIND_BLOB_EXPIRING {
+            self.read_u64(record, "expiry time")?;
+        }
+        Ok(())
+    }
+
+    /// Read the `N` bytes of `field` of `record`
+    fn read_fixed<const N: usize>(
+        &mut self,
+        record: Record,
+        field: &dyn fmt::Display,
+    ) -> io::Result<[u8; N]> {
+        let mut bytes = [0u8; N];
+        if let Err(err) = self.reader.read_exact(&mut bytes) {
+            return Err(self.truncated(err, record, field, self.position));
+        }
+        self.position += N as u64;
+        Ok(bytes)
+    }
+
+    fn read_u64(&mut self, record: Record, field: &str) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_fixed(record, &field)?))
+    }
+
+    /// Read the length-prefixed `field` of `record`
+    fn read_field(&mut self, record: Record, field: &str) -> io::Result<Vec<u8>> {
+        let len = self.read_length(record, field)?;
+        let mut bytes = vec![0u8; len as usize];
+        if let Err(err) = self.reader.read_exact(&mut bytes) {
+            return Err(self.truncated(err, record, &field, self.position));
+        }
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Step over the length-prefixed `field` of `record`
+    fn skip_field(&mut self, record: Record, field: &str) -> io::Result<()> {
+        let len = self.read_length(record, field)?;
+        if self.position + len > self.file_len {
+            let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+            return Err(self.truncated(eof, record, &field, self.position));
+        }
+        self.reader.seek_relative(len as i64)?;
+        self.position += len;
+        Ok(())
+    }
+
+    /// Read the length of `field` of `record`, rejected when it is longer than the file
+    /// so that a corrupt length fails the read instead of allocating a huge buffer
+    fn read_length(&mut self, record: Record, field: &str) -> io::Result<u64> {
+        let offset = self.position;
+        let len = u32::from_le_bytes(self.read_fixed(record, &format_args!("{} length", field))?);
+        if u64::from(len) > self.file_len {
+            let message = format!(
+                "{}: the {} length {} of {} at offset {} is inconsistent with the file size \
+                 of {} bytes",
+                self.path, field, len, record, offset, self.file_len
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(u64::from(len))
+    }
+
+    /// Locate `err` from reading `field` of `record` at `offset`, if it is the file
+    /// running out; other failures to read are passed on as they are
+    fn truncated(
+        &self,
+        err: io::Error,
+        record: Record,
+        field: &dyn fmt::Display,
+        offset: u64,
+    ) -> io::Error {
+        if err.kind() != io::ErrorKind::UnexpectedEof {
+            return err;
+        }
+        let message = format!(
+            "{}: file truncated: the {} of {} at offset {} runs past the end of the file at \
+             {} bytes",
+            self.path, field, record, offset, self.file_len
+        );
+        io::Error::new(io::ErrorKind::UnexpectedEof, message)
+    }
+
+    /// An error for `record`, whose content at `offset` has `problem`
+    fn malformed(&self, record: Record, offset: u64, problem: &str) -> io::Error {
+        let message = format!("{}: {} in {} at offset {}", self.path, problem, record, offset);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    /// Skip any unread entries and return the table's range tombstones
+    pub fn into_range_tombstones(mut self) -> io::Result<Vec<RangeTombstone>> {
+        self.range_tombstones()
+    }
+
+    fn range_tombstones(&mut self) -> io::Result<Vec<RangeTombstone>> {
+        while self.remaining > 0 {
+            self.skip_entry()?;
+            self.remaining -= 1;
+            self.next_entry += 1;
+        }
+
+        let mut range_tombstones = Vec::new();
+        if self.has_ranges {
+            let count = self.read_fixed(Record::RangeTombstones, &"count")?;
+            for i in 0..u32::from_le_bytes(count) {
+                let record = Record::RangeTombstone(u64::from(i));
+                range_tombstones.push(RangeTombstone {
+                    start: self.read_field(record, "start")?,
+                    end: self.read_field(record, "end")?,
+                    seq: self.read_u64(record, "sequence number")?,
+                });
+            }
+        }
+        Ok(range_tombstones)
+    }
+}
+
+impl Iterator for SSTableIterator {
+    type Item = io::Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let entry = self.read_entry();
+        if entry.is_err() {
+            // The position is unknown after a failed read
+            self.remaining = 0;
+        }
+        self.next_entry += 1;
+        Some(entry)
+    }
+}
+
+/// Streams the versions stored in an SSTable from the last key down, the versions of
+/// each key still newest first. Data blocks are read one at a time from the end by way
+/// of the block index, so only the blocks reached are read; a table without a block
+/// index (before version 7) is read whole on the first call.
+pub struct SSTableRevIterator {
+    table: SSTableIterator,
+    /// Index blocks of a two-level block index not yet descended into, in file order
+    top: Vec<BlockHandle>,
+    /// Data blocks not yet read, in file order
+    blocks: Vec<BlockHandle>,
+    /// Keys at or past this are not needed, nor the blocks after the one they start in
+    end: Option<Vec<u8>>,
+    unindexed: bool,
+    /// Versions read and not yet yielded, in the reverse of the order they are yielded
+    ready: Vec<(Vec<u8>, Entry)>,
+    /// Versions of the lowest key read so far, when they go on in the block before
+    carry: Vec<(Vec<u8>, Entry)>,
+}
+
+impl SSTableRevIterator {
+    /// Open for reading down from `end`, exclusive, or from the last key without one.
+    /// Only the footer and the top level of the block index are read here.
+    pub fn open(storage: &dyn Storage, path: &str, end: Option<&[u8]>) -> io::Result<Self> {
+        let mut table = SSTableIterator::open(storage, path)?;
+        let (mut top, mut blocks, mut unindexed) = (Vec::new(), Vec::new(), false);
+        match table.footer()?.and_then(|footer| footer.block_index) {
+            Some((offset, len, levels)) => {
+                let mut handles = table.read_index(offset, len)?;
+                truncate_after(&mut handles, end);
+                match levels {
+                    2 => top = handles,
+                    _ => blocks = handles,
+                }
+            }
+            None => {
+                table.rewind()?;
+                unindexed = true;
+            }
+        }
+        Ok(SSTableRevIterator {
+            table,
+            top,
+            blocks,
+            end: end.map(<[u8]>::to_vec),
+            unindexed,
+            ready: Vec::new(),
+            carry: Vec::new(),
+        })
+    }
+
+    /// Read the block before those read so far into `ready`, `false` once there is none
+    fn read_previous(&mut self) -> io::Result<bool> {
+        if self.blocks.is_empty() {
+            if let Some(index) = self.top.pop() {
+                self.blocks = self.table.read_index(index.offset, index.len)?;
+                truncate_after(&mut self.blocks, self.end.as_deref());
+            }
+        }
+        let mut entries = if self.unindexed {
+            self.unindexed = false;
+            self.table.by_ref().collect::<io::Result<Vec<_>>>()?
+        } else if let Some(block) = self.blocks.pop() {
+            self.read_block(&block)?
+        } else if !self.carry.is_empty() {
+            Vec::new()
+        } else {
+            return Ok(false);
+        };
+        entries.append(&mut self.carry);
+
+        // The lowest key's versions go on in the block before if that block ends with it
+        let before = self.blocks.last().or(self.top.last()).map(|block| &block.last_key);
+        if let Some(before) = before {
+            let continued = entries.partition_point(|(key, _)| key == before);
+            self.carry = entries.drain(..continued).collect();
+        }
+        // Reverse each key's versions in place, so popping yields the keys highest first
+        // and the versions of each newest first
+        let mut start = 0;
+        while start < entries.len() {
+            let key = &entries[start].0;
+            let len = entries[start..].partition_point(|(other, _)| other == key);
+            entries[start..start + len].reverse();
+            start += len;
+        }
+        self.ready = entries;
+        Ok(true)
+    }
+
+    /// Every version in `block`, in file order
+    fn read_block(&mut self, block: &BlockHandle) -> io::Result<Vec<(Vec<u8>, Entry)>> {
+        let table = &mut self.table;
+        table.remaining = table.header.entries.checked_sub(block.first_entry).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "block index entry past the last entry")
+        })?;
+        table.seek_entry(block.offset, block.first_entry)?;
+        let end = block.offset + u64::from(block.len);
+        let mut entries = Vec::new();
+        while table.position < end {
+            match table.next() {
+                Some(item) => entries.push(item?),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Iterator for SSTableRevIterator {
+    type Item = io::Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.ready.pop() {
+                return Some(Ok(item));
+            }
+            match self.read_previous() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => {
+                    // Nothing more is read after a failure
+                    (self.top, self.blocks, self.carry) = (Vec::new(), Vec::new(), Vec::new());
+                    self.unindexed = false;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Drop the blocks after the first one that ends at or past `end`, which hold only
+/// keys past it
+fn truncate_after(handles: &mut Vec<BlockHandle>, end: Option<&[u8]>) {
+    if let Some(end) = end {
+        let keep = handles.partition_point(|handle| handle.last_key.as_slice() < end) + 1;
+        handles.truncate(keep);
     }
 }
 
+/// An SSTable held open for point lookups. The top level of its block index, its filter
+/// index, and its range tombstones are read once, when it is opened; lookups through it
+/// take turns on the one file handle.
+pub struct SSTableReader {
+    path: String,
+    table: Mutex<SSTableIterator>,
+    /// Top level of the block index and its number of levels, from version 7
+    block_index: Option<(Vec<BlockHandle>, u32)>,
+    filter_index: Option<FilterIndex>,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl SSTableReader {
+    pub fn open(storage: &dyn Storage, path: &str) -> io::Result<Self> {
+        let mut table = SSTableIterator::open(storage, path)?;
+        let footer = table.footer()?;
+        let block_index = match footer.as_ref().and_then(|footer| footer.block_index) {
+            Some((offset, len, levels)) => Some((table.read_index(offset, len)?, levels)),
+            None => None,
+        };
+        let filter_index = match footer {
+            Some(footer) => match table.read_filter_index(footer.filter_index, path) {
+                Ok(index) => Some(index),
+                Err(err) => {
+                    warn!("reading {} without its key filter: {}", path, err);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // The range tombstones follow the last data block
+        match &block_index {
+            Some((top, levels)) => {
+                let mut last = top.last().cloned();
+                if let (Some(block), 2) = (&last, levels) {
+                    last = table.read_index(block.offset, block.len)?.pop();
+                }
+                let end = last.map_or(table.entries_start, |block| {
+                    block.offset + u64::from(block.len)
+                });
+                table.seek_entry(end, table.header.entries)?;
+                table.remaining = 0;
+            }
+            None => table.rewind()?,
+        }
+        let range_tombstones = table.range_tombstones()?;
+
+        Ok(SSTableReader {
+            path: path.to_string(),
+            table: Mutex::new(table),
+            block_index,
+            filter_index,
+            range_tombstones,
+        })
+    }
+
+    pub fn header(&self) -> TableHeader {
+        self.table().header
+    }
+
+    /// Where the partitions of the key filter are, `None` if the table has no filter or
+    /// it could not be read
+    pub fn filter_index(&self) -> Option<&FilterIndex> {
+        self.filter_index.as_ref()
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Last keys of the spans the top level of the block index divides the table into,
+    /// in order; empty for a table without a block index
+    pub fn index_boundaries(&self) -> Vec<Vec<u8>> {
+        let Some((top, _)) = &self.block_index else { return Vec::new() };
+        top.iter().map(|block| block.last_key.clone()).collect()
+    }
+
+    /// Load one partition of the key filter
+    pub fn read_filter(&self, partition: &FilterPartition) -> io::Result<BloomFilter> {
+        read_filter(&mut self.table().reader, &self.path, partition)
+    }
+
+    /// Smallest and largest keys the table can affect, its range tombstones included
+    /// (with their exclusive ends taken as keys), or `None` if it holds nothing
+    pub fn key_span(&self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut span: Option<(Vec<u8>, Vec<u8>)> = None;
+        let mut widen = |key: &[u8]| match &mut span {
+            Some((first, last)) => {
+                if key < first.as_slice() {
+                    *first = key.to_vec();
+                }
+                if key > last.as_slice() {
+                    *last = key.to_vec();
+                }
+            }
+            None => span = Some((key.to_vec(), key.to_vec())),
+        };
+        {
+            let mut table = self.table();
+            table.rewind()?;
+            if let Some(item) = table.next() {
+                widen(&item?.0);
+            }
+            match &self.block_index {
+                // The last key of the last block is the table's last key
+                Some((top, _)) => {
+                    if let Some(block) = top.last() {
+                        widen(&block.last_key);
+                    }
+                }
+                None => {
+                    for item in table.by_ref() {
+                        widen(&item?.0);
+                    }
+                }
+            }
+        }
+        for tombstone in &self.range_tombstones {
+            widen(&tombstone.start);
+            widen(&tombstone.end);
+        }
+        Ok(span)
+    }
+
+    /// Every stored version of `key`, oldest first, like [`SSTable::get_versions`]
+    pub fn get_versions(&self, key: &[u8]) -> io::Result<Vec<Entry>> {
+        let mut table = self.table();
+        match &self.block_index {
+            Some((top, levels)) => {
+                if !table.seek_indexed(top, *levels, key, false)? {
+                    return Ok(Vec::new());
+                }
+            }
+            None => table.rewind()?,
+        }
+        table.versions_of(key)
+    }
+
+    /// Every stored version of `key`, oldest first, with its value left out and instead
+    /// the offset of the value's length field, for [`SSTable::open_value`]
+    pub fn locate_versions(&self, key: &[u8]) -> io::Result<Vec<(Entry, u64)>> {
+        let mut table = self.table();
+        match &self.block_index {
+            Some((top, levels)) => {
+                if !table.seek_indexed(top, *levels, key, false)? {
+                    return Ok(Vec::new());
+                }
+            }
+            None => table.rewind()?,
+        }
+        table.skip_values = true;
+        let versions = table.located_versions_of(key);
+        table.skip_values = false;
+        versions
+    }
+
+    fn table(&self) -> std::sync::MutexGuard<'_, SSTableIterator> {
+        self.table.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Read the filter partition at `partition` of the table at `path` from `file`
+fn read_filter<R: Read + Seek>(
+    file: &mut R,
+    path: &str,
+    partition: &FilterPartition,
+) -> io::Result<BloomFilter> {
+    file.seek(SeekFrom::Start(partition.offset))?;
+    let mut bytes = vec![0u8; partition.len as usize];
+    file.read_exact(&mut bytes)?;
+    BloomFilter::decode(bytes).ok_or_else(|| {
+        let offset = partition.offset;
+        let message = format!("corrupt filter partition at offset {} of {}", offset, path);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// A field length, rejected when it is longer than the file it was read from so that a
+/// corrupt length fails the read instead of allocating a huge buffer
+fn read_len<R: Read>(reader: &mut R, limit: u64) -> io::Result<usize> {
+    let len = read_u32(reader)?;
+    if u64::from(len) > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("field length {} exceeds the table size of {} bytes", len, limit),
+        ));
+    }
+    Ok(len as usize)
+}
+
+fn encrypted_table() -> io::Error {
+    let message = "table is encrypted; build with the `encryption` feature to read it";
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The entry count in the footer of the version 8 table at `path` whose entries start
+/// at `entries_start`, leaving `reader` there. A table cut short fails here, as what is
+/// left at its end does not locate a block index ending where the footer starts.
+fn read_count(
+    reader: &mut Reader,
+    path: &str,
+    file_len: u64,
+    entries_start: u64,
+) -> io::Result<u64> {
+    let start = file_len.checked_sub(FOOTER_LEN).filter(|&start| start >= entries_start);
+    let corrupt = || corrupt_footer(path, start.unwrap_or(0));
+    let start = start.ok_or_else(corrupt)?;
+    reader.seek(SeekFrom::Start(start))?;
+    let count = read_u64(reader)?;
+    let _filter_index = read_u64(reader)?;
+    let block_index_end = read_u64(reader)?.checked_add(u64::from(read_u32(reader)?));
+    if block_index_end != Some(start) || count > (start - entries_start) / MIN_ENTRY_LEN {
+        return Err(corrupt());
+    }
+    reader.seek(SeekFrom::Start(entries_start))?;
+    Ok(count)
+}
+
+/// The footer expected at `offset` of the table at `path` is not one
+fn corrupt_footer(path: &str, offset: u64) -> io::Error {
+    let message = format!(
+        "{}: no valid footer at offset {}; the file is truncated or its footer corrupt",
+        path, offset
+    );
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An entry or range tombstone of a table by number, for locating read errors
+#[derive(Clone, Copy)]
+enum Record {
+    Entry(u64),
+    RangeTombstones,
+    RangeTombstone(u64),
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::Entry(i) => write!(f, "entry {}", i),
+            Record::RangeTombstones => write!(f, "the range tombstones"),
+            Record::RangeTombstone(i) => write!(f, "range tombstone {}", i),
+        }
+    }
+}
+
+/// Where the indexes at the end of a table are
+struct Footer {
+    filter_index: u64,
+    /// Offset, length, and number of levels of the block index, which tables only have
+    /// from version 7
+    block_index: Option<(u64, u32, u32)>,
+}
+
+/// A block of entries, or of block index entries, and the last key in it
+#[derive(Clone, Debug)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    /// Number of the block's first entry within the table
+    first_entry: u64,
+}
+
+fn encode_index(handles: &[BlockHandle]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(handles.len() as u32).to_le_bytes());
+    for handle in handles {
+        push_bytes(&mut buf, &handle.last_key);
+        buf.extend_from_slice(&handle.offset.to_le_bytes());
+        buf.extend_from_slice(&handle.len.to_le_bytes());
+        buf.extend_from_slice(&handle.first_entry.to_le_bytes());
+    }
+    buf
+}
+
+/// The first block whose last key is at or past `key`
+fn find_block<'a>(handles: &'a [BlockHandle], key: &[u8]) -> Option<&'a BlockHandle> {
+    handles.get(handles.partition_point(|handle| handle.last_key.as_slice() < key))
+}
+
+/// Append `bytes` behind its length
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes<R: Read>(reader: &mut R, limit: u64) -> io::Result<Vec<u8>> {
+    let len = read_len(reader, limit)?;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::fault::FaultStorage;
+    use crate::storage::MemStorage;
 
     #[test]
     fn test_write_and_read_sstable() {
         let path = "test_sstable.sst";
-        let _ = fs::remove_file(path);
+        let storage = MemStorage::new();
 
         let mut data = BTreeMap::new();
-        data.insert("key1".to_string(), "value1".to_string());
-        data.insert("key2".to_string(), "value2".to_string());
-        data.insert("key3".to_string(), "value3".to_string());
+        data.insert(b"key1".to_vec(), b"value1".to_vec());
+        data.insert(b"key2".to_vec(), b"value2".to_vec());
+        data.insert(b"key3".to_vec(), b"value3".to_vec());
 
-        SSTable::write(path, &data).unwrap();
+        SSTable::write(&storage, path, &data).unwrap();
 
         // Read it back
-        let read_data = SSTable::read(path).unwrap();
+        let read_data = SSTable::read(&storage, path).unwrap();
 
         assert_eq!(read_data.len(), 3);
-        assert_eq!(read_data.get("key1"), Some(&"value1".to_string()));
-        assert_eq!(read_data.get("key2"), Some(&"value2".to_string()));
-        assert_eq!(read_data.get("key3"), Some(&"value3".to_string()));
-
-        fs::remove_file(path).unwrap();
+        assert_eq!(read_data.get(&b"key1"[..]), Some(&b"value1".to_vec()));
+        assert_eq!(read_data.get(&b"key2"[..]), Some(&b"value2".to_vec()));
+        assert_eq!(read_data.get(&b"key3"[..]), Some(&b"value3".to_vec()));
     }
 
     #[test]
     fn test_get_from_sstable() {
         let path = "test_sstable_get.sst";
-        let _ = fs::remove_file(path);
+        let storage = MemStorage::new();
+
+        let mut data = BTreeMap::new();
+        data.insert(b"user_1".to_vec(), b"Alice".to_vec());
+        data.insert(b"user_2".to_vec(), b"Bob".to_vec());
+
+        SSTable::write(&storage, path, &data).unwrap();
+
+        assert_eq!(SSTable::get(&storage, path, b"user_1").unwrap(), Some(b"Alice".to_vec()));
+        assert_eq!(SSTable::get(&storage, path, b"user_2").unwrap(), Some(b"Bob".to_vec()));
+        assert_eq!(SSTable::get(&storage, path, b"nonexistent").unwrap(), None);
+    }
+
+    #[test]
+    fn test_versions_and_tombstones_round_trip() {
+        let path = "test_sstable_versions.sst";
+        let storage = MemStorage::new();
+
+        let mut data = BTreeMap::new();
+        data.insert(
+            b"key1".to_vec(),
+            vec![Entry::put(1, b"old".to_vec()), Entry::put(3, b"new".to_vec())],
+        );
+        data.insert(
+            b"key2".to_vec(),
+            vec![Entry::put(2, b"value2".to_vec()), Entry::tombstone(4)],
+        );
+        data.insert(b"key3".to_vec(), vec![Entry::merge(5, b"+1".to_vec())]);
+
+        let ranges = vec![RangeTombstone {
+            start: b"a".to_vec(),
+            end: b"b".to_vec(),
+            seq: 6,
+        }];
+        SSTable::write_versions(&storage, path, &data, &ranges, 6, SyncMode::SyncData).unwrap();
+
+        assert_eq!(SSTable::read_versions(&storage, path).unwrap(), data);
+        assert_eq!(SSTable::read_range_tombstones(&storage, path).unwrap(), ranges);
+        assert_eq!(SSTable::max_sequence(&storage, path).unwrap(), 6);
+        let header = SSTable::header(&storage, path).unwrap();
+        assert_eq!((header.version, header.min_seq, header.max_seq), (8, 1, 6));
+        assert!(header.created_at.is_some_and(|created_at| created_at > 0));
+        assert_eq!(
+            SSTable::get_at(&storage, path, b"key1", 2).unwrap(),
+            Some(Entry::put(1, b"old".to_vec()))
+        );
+        assert_eq!(SSTable::get_at(&storage, path, b"key2", 4).unwrap(), Some(Entry::tombstone(4)));
+        assert_eq!(SSTable::get(&storage, path, b"key2").unwrap(), None);
+        assert_eq!(SSTable::read(&storage, path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_filter_partitions_cover_their_keys() {
+        let path = "test_sstable_filters.sst";
+        let storage = MemStorage::new();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+
+        let mut writer = SSTableWriter::create(&storage, path).unwrap();
+        for i in 0..3000 {
+            // Versions of one key count once towards a partition
+            writer.add(&key(i), &Entry::put(2, b"new".to_vec())).unwrap();
+            writer.add(&key(i), &Entry::put(1, b"old".to_vec())).unwrap();
+        }
+        writer.finish(&[], 2).unwrap();
+
+        let index = SSTable::filter_index(&storage, path).unwrap().unwrap();
+        assert_eq!(index.partitions.len(), 3);
+        assert_eq!(index.partitions[0].last_key, key(filter::PARTITION_KEYS - 1));
+        let filters: Vec<BloomFilter> = index
+            .partitions
+            .iter()
+            .map(|partition| SSTable::read_filter(&storage, path, partition).unwrap())
+            .collect();
+        for i in 0..3000 {
+            let partition = index.partition_for(&key(i)).unwrap();
+            assert_eq!(partition, i / filter::PARTITION_KEYS);
+            assert!(filters[partition].may_contain(&key(i)));
+        }
+        assert_eq!(index.partition_for(b"key99999"), None);
+        assert_eq!(SSTable::get(&storage, path, &key(1500)).unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_block_index_keeps_reads_per_get_constant() {
+        let storage = FaultStorage::new();
+        let key = |i: usize| format!("key{:06}", i).into_bytes();
+        let value = vec![b'v'; 32];
+        for (path, keys, levels) in [("small.sst", 50, 1), ("large.sst", 40_000, 2)] {
+            let mut writer = SSTableWriter::create(&storage, path).unwrap();
+            for i in 0..keys {
+                writer.add(&key(i), &Entry::put(i as u64 + 1, value.clone())).unwrap();
+            }
+            writer.finish(&[], keys as u64).unwrap();
+            let footer = SSTableIterator::open(&storage, path).unwrap().footer().unwrap();
+            assert_eq!(footer.unwrap().block_index.unwrap().2, levels);
+
+            // Header, footer, at most two index blocks, and the data block
+            for i in (0..keys).step_by(keys / 10).chain([keys - 1]) {
+                let before = storage.reads();
+                assert_eq!(SSTable::get(&storage, path, &key(i)).unwrap(), Some(value.clone()));
+                let reads = storage.reads() - before;
+                assert!(reads <= 6, "{} reads for key {} of {}", reads, i, path);
+            }
+            assert_eq!(SSTable::get(&storage, path, b"key000000x").unwrap(), None);
+            assert_eq!(SSTable::get(&storage, path, b"zzz").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_entry_count_is_written_last() {
+        let storage = MemStorage::new();
+        let path = "test_sstable_count.sst";
+        let key = |i: u64| format!("key{:05}", i).into_bytes();
+        let mut writer = SSTableWriter::create(&storage, path).unwrap();
+        for i in 0..2_000 {
+            writer.add(&key(i), &Entry::put(i + 1, b"value".to_vec())).unwrap();
+        }
+        writer.finish(&[], 2_000).unwrap();
+
+        // The header's old count slot stays 0; the count is in the footer
+        let bytes = storage.read(Path::new(path)).unwrap();
+        assert_eq!(bytes[..8], *b"SST8\0\0\0\0");
+        let footer = &bytes[bytes.len() - FOOTER_LEN as usize..];
+        assert_eq!(footer[..8], 2_000u64.to_le_bytes());
+
+        let stats = SSTable::stats(&storage, path).unwrap();
+        assert_eq!((stats.version, stats.entries), (8, 2_000u64));
+        let value = Some(Entry::put(1_501, b"value".to_vec()));
+        assert_eq!(SSTable::get_at(&storage, path, &key(1_500), u64::MAX).unwrap(), value);
+        let mut from = SSTableIterator::open(&storage, path).unwrap();
+        from.skip_to(&key(1_990)).unwrap();
+        let rest: Vec<_> = from.map(|item| item.unwrap().0).collect();
+        assert!(rest.len() < 100 && rest.contains(&key(1_990)), "{} entries", rest.len());
+        assert_eq!(rest.last(), Some(&key(1_999)));
+    }
+
+    #[test]
+    fn test_reverse_iterator_reads_blocks_from_the_end() {
+        let storage = FaultStorage::new();
+        let path = "test_sstable_rev.sst";
+        let key = |i: u64| format!("key{:06}", i).into_bytes();
+        let mut writer = SSTableWriter::create(&storage, path).unwrap();
+        for i in 0..40_000 {
+            // One key's versions run across several blocks
+            let versions = if i == 20_000 { 300 } else { 1 };
+            for seq in (1..=versions).rev() {
+                writer.add(&key(i), &Entry::put(seq, vec![b'v'; 32])).unwrap();
+            }
+        }
+        writer.finish(&[], 300).unwrap();
+        let footer = SSTableIterator::open(&storage, path).unwrap().footer().unwrap();
+        assert_eq!(footer.unwrap().block_index.unwrap().2, 2);
+
+        // Keys descend and each key's versions stay newest first
+        let forward = SSTableIterator::open(&storage, path).unwrap();
+        let forward: Vec<_> = forward.map(Result::unwrap).collect();
+        let expected = forward.chunk_by(|(a, _), (b, _)| a == b).rev().flatten().cloned();
+        let reverse = SSTableRevIterator::open(&storage, path, None).unwrap();
+        assert!(reverse.map(Result::unwrap).eq(expected));
+
+        // Starting below a key reads from the block it is in
+        let before = storage.reads();
+        let end = key(30_000);
+        let reverse = SSTableRevIterator::open(&storage, path, Some(&end)).unwrap();
+        let keys = reverse.map(|item| item.unwrap().0);
+        let below: Vec<_> = keys.filter(|k| *k < end).take(10).collect();
+        assert_eq!(below, (29_990..30_000).rev().map(key).collect::<Vec<_>>());
+        assert!(storage.reads() - before <= 8, "{} reads", storage.reads() - before);
+    }
+
+    #[test]
+    fn test_read_version_7_sstable() {
+        let storage = MemStorage::new();
+        let path = "test_sstable_v7.sst";
+        let mut writer = SSTableWriter::create(&storage, path).unwrap();
+        writer.add(b"key1", &Entry::put(1, b"value1".to_vec())).unwrap();
+        writer.finish(&[], 1).unwrap();
+
+        // Version 7 has the count in the header, a 32-bit first entry per block, and a
+        // footer without the count; this one has a single block
+        let bytes = storage.read(Path::new(path)).unwrap();
+        let footer_start = bytes.len() - FOOTER_LEN as usize;
+        let index_start = footer_start - (4 + 8 + 8 + 4 + 8);
+        let mut old = bytes[..index_start].to_vec();
+        old[..8].copy_from_slice(b"SST7\x01\0\0\0");
+        old.extend_from_slice(&bytes[index_start..footer_start - 4]);
+        old.extend_from_slice(&bytes[footer_start + 8..footer_start + 24]);
+        old.extend_from_slice(&28u32.to_le_bytes());
+        old.extend_from_slice(&1u32.to_le_bytes());
+        storage.write(Path::new(path), &old).unwrap();
+
+        let header = SSTable::header(&storage, path).unwrap();
+        assert_eq!((header.version, header.entries, header.max_seq), (7, 1, 1));
+        assert_eq!(SSTable::get(&storage, path, b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(SSTable::get(&storage, path, b"key2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_legacy_sstable() {
+        let storage = MemStorage::new();
+        let path = "test_sstable_legacy.sst";
+
+        // [count][key_len][key][value_len][value]
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(b"key1");
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend_from_slice(b"value1");
+        storage.write(Path::new(path), &bytes).unwrap();
+
+        assert_eq!(SSTable::get(&storage, path, b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(SSTable::max_sequence(&storage, path).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_corrupt_length_is_rejected() {
+        let path = "test_sstable_corrupt_len.sst";
+        let storage = MemStorage::new();
 
         let mut data = BTreeMap::new();
-        data.insert("user_1".to_string(), "Alice".to_string());
-        data.insert("user_2".to_string(), "Bob".to_string());
+        data.insert(b"key".to_vec(), b"value".to_vec());
+        SSTable::write(&storage, path, &data).unwrap();
+
+        // The first entry's key length follows the 32-byte header
+        let mut bytes = storage.read(Path::new(path)).unwrap();
+        bytes[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        storage.write(Path::new(path), &bytes).unwrap();
+
+        let err = SSTable::read(&storage, path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = SSTable::get(&storage, path, b"key").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 
-        SSTable::write(path, &data).unwrap();
+    #[test]
+    fn test_read_errors_locate_the_damage() {
+        let path = "test_sstable_damage.sst";
+        let storage = MemStorage::new();
+        let mut data = BTreeMap::new();
+        data.insert(b"key1".to_vec(), b"value1".to_vec());
+        data.insert(b"key2".to_vec(), b"v".to_vec());
+        SSTable::write(&storage, path, &data).unwrap();
+        let bytes = storage.read(Path::new(path)).unwrap();
+
+        // Entry 0 is the 35 bytes from 32: key length, key, sequence number, write
+        // time, kind, value length, value; entry 1 follows at 67
+        let cut = |len: usize| storage.write(Path::new(path), &bytes[..len]).unwrap();
+        for (len, keys_only, field, entry, offset) in [
+            (38, false, "key", 0, 36),
+            (60, false, "value length", 0, 57),
+            (63, true, "value", 0, 61),
+            (80, false, "sequence number", 1, 75),
+        ] {
+            cut(len);
+            let table = SSTableIterator::open_salvage(&storage, path, 64).unwrap();
+            let table = if keys_only { table.keys_only() } else { table };
+            let err = table.filter_map(Result::err).next().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            let message = format!(
+                "{}: file truncated: the {} of entry {} at offset {} runs past the end of \
+                 the file at {} bytes",
+                path, field, entry, offset, len
+            );
+            assert_eq!(err.to_string(), message);
+        }
 
-        assert_eq!(SSTable::get(path, "user_1").unwrap(), Some("Alice".to_string()));
-        assert_eq!(SSTable::get(path, "user_2").unwrap(), Some("Bob".to_string()));
-        assert_eq!(SSTable::get(path, "nonexistent").unwrap(), None);
+        // Without its footer, a table only opens for salvage
+        cut(80);
+        let err = SSTable::read(&storage, path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(&format!("{}: no valid footer at offset 48", path)));
+        cut(20);
+        let err = SSTable::read(&storage, path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let message = format!("{}: file truncated in the header, at 20 bytes", path);
+        assert_eq!(err.to_string(), message);
 
-        fs::remove_file(path).unwrap();
+        // A length longer than the whole file is corrupt rather than cut short
+        let mut corrupt = bytes.clone();
+        corrupt[92..96].copy_from_slice(&1_000u32.to_le_bytes());
+        storage.write(Path::new(path), &corrupt).unwrap();
+        let err = SSTable::read(&storage, path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message = format!(
+            "{}: the value length 1000 of entry 1 at offset 92 is inconsistent with the file \
+             size of {} bytes",
+            path,
+            bytes.len()
+        );
+        assert_eq!(err.to_string(), message);
     }
 
     #[test]
     fn test_read_nonexistent_sstable() {
-        let result = SSTable::read("nonexistent.sst").unwrap();
+        let result = SSTable::read(&MemStorage::new(), "nonexistent.sst").unwrap();
         assert_eq!(result.len(), 0);
     }
 }
\ No newline at end of file
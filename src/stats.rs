@@ -0,0 +1,804 @@
+//! Counters the engine keeps about its own work
+
+use crate::compaction::CompactionInfo;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Engine statistics, as returned by [`Db::stats`](crate::Db::stats).
+///
+/// Counters cover every column family and accumulate from when the database was opened
+/// or [`Db::reset_stats`](crate::Db::reset_stats) was last called; WAL replay at open is
+/// not counted. The table and file counts describe the database as it is now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Gets answered with a version held in a memtable
+    pub memtable_hits: u64,
+    /// Gets answered from SSTables alone
+    pub sstable_hits: u64,
+    /// Gets of keys that are missing, deleted, or expired
+    pub misses: u64,
+    /// Values written, singly or in batches
+    pub puts: u64,
+    /// Deletions, a range deletion counting once
+    pub deletes: u64,
+    pub flushes: u64,
+    /// Size of the SSTables written by flushes
+    pub bytes_flushed: u64,
+    pub compactions: u64,
+    /// Size of the tables compactions merged
+    pub compaction_bytes_read: u64,
+    /// Size of the tables compactions wrote
+    pub compaction_bytes_written: u64,
+    /// Distinct keys compactions read, counted once per compaction
+    pub compaction_keys: u64,
+    /// Versions of keys compactions dropped, overwritten or deleted by newer ones
+    pub compaction_versions_dropped: u64,
+    /// Point tombstones compactions dropped
+    pub compaction_tombstones_dropped: u64,
+    /// Time spent compacting, in microseconds
+    pub compaction_micros: u64,
+    pub sstable_count: u64,
+    pub sstable_bytes: u64,
+    pub wal_bytes: u64,
+    /// Bytes appended to WALs, compressed values counted as stored
+    pub wal_bytes_written: u64,
+    /// Bytes [`Options::wal_compression`](crate::Options#structfield.wal_compression)
+    /// kept out of the WALs
+    pub wal_bytes_saved: u64,
+    /// Versions held in memtables, waiting to be flushed
+    pub memtable_entries: u64,
+    /// SSTables, value logs, and WALs in use
+    pub file_count: u64,
+    /// Size of the SSTables and WALs, as counted against
+    /// [`Options::max_disk_bytes`](crate::Options::max_disk_bytes)
+    pub disk_bytes: u64,
+    /// SSTable key filter partitions found in memory when a lookup needed them
+    pub filter_cache_hits: u64,
+    /// Key filter partitions read from disk
+    pub filter_cache_misses: u64,
+    /// Size of the key filter partitions held in memory
+    pub filter_cache_bytes: u64,
+    /// Gets answered by the row cache of
+    /// [`Options::row_cache_bytes`](crate::Options#structfield.row_cache_bytes)
+    pub row_cache_hits: u64,
+    /// Gets the row cache could not answer, while it is enabled
+    pub row_cache_misses: u64,
+    /// Size of the values held in row caches
+    pub row_cache_bytes: u64,
+    /// Misses answered by the absent keys of
+    /// [`Options::negative_cache_keys`](crate::Options#structfield.negative_cache_keys)
+    /// without a lookup
+    pub negative_cache_hits: u64,
+    /// Bytes flushes and compactions read and wrote under
+    /// [`Options::rate_limit_bytes_per_sec`](crate::Options#structfield.rate_limit_bytes_per_sec)
+    pub throttled_bytes: u64,
+    /// Time flushes and compactions spent waiting for the rate limit, in microseconds
+    pub throttle_wait_micros: u64,
+    /// Writes delayed because a column family reached
+    /// [`Options::slowdown_writes_sstables`](crate::Options#structfield.slowdown_writes_sstables)
+    pub writes_slowed: u64,
+    /// Writes that found a column family at
+    /// [`Options::stop_writes_sstables`](crate::Options#structfield.stop_writes_sstables),
+    /// whether they waited or failed
+    pub writes_stopped: u64,
+    /// Time writes spent slowed or stopped, in microseconds
+    pub write_stall_micros: u64,
+    /// Column families whose writes are slowed now
+    pub slowed_column_families: u64,
+    /// Column families whose writes are stopped now
+    pub stopped_column_families: u64,
+    /// Latency distributions, all zero when
+    /// [`Options::disable_latency_histograms`](crate::Options::disable_latency_histograms)
+    /// is set
+    pub latency: Latencies,
+}
+
+/// Latency distributions of the timed operations. Only operations that succeed are
+/// timed. A put that fills the memtable includes the flush it triggers, which is also
+/// timed on its own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Latencies {
+    /// Single puts, with or without a TTL
+    pub put: Latency,
+    pub get: Latency,
+    /// Single-key deletes
+    pub delete: Latency,
+    /// Flushes that wrote an SSTable
+    pub flush: Latency,
+    /// Compactions that merged tables
+    pub compaction: Latency,
+}
+
+/// Summary of one operation's latency histogram. Percentiles are the upper bound of
+/// the bucket they fall in (buckets are at most 25% wide) capped at `max`, so they
+/// never decrease from p50 to `max`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    /// Every get, hit or miss
+    pub fn gets(&self) -> u64 {
+        self.memtable_hits + self.sstable_hits + self.misses
+    }
+
+    /// Bytes flushes and compactions wrote to SSTables per byte flushed, or 0 before
+    /// anything was flushed. WALs are not counted.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_flushed == 0 {
+            return 0.0;
+        }
+        (self.bytes_flushed + self.compaction_bytes_written) as f64 / self.bytes_flushed as f64
+    }
+
+    /// Render as Prometheus text exposition format (version 0.0.4)
+    pub fn to_prometheus(&self) -> String {
+        let plain = |value| vec![("", value)];
+        let families = [
+            (
+                "gets_total",
+                "counter",
+                "Gets, by where the answer came from.",
+                vec![
+                    (r#"{result="memtable_hit"}"#, self.memtable_hits),
+                    (r#"{result="sstable_hit"}"#, self.sstable_hits),
+                    (r#"{result="miss"}"#, self.misses),
+                ],
+            ),
+            ("puts_total", "counter", "Values written.", plain(self.puts)),
+            ("deletes_total", "counter", "Deletions, ranges included.", plain(self.deletes)),
+            ("flushes_total", "counter", "Memtable flushes.", plain(self.flushes)),
+            ("flushed_bytes_total", "counter", "Bytes flushed.", plain(self.bytes_flushed)),
+            ("compactions_total", "counter", "Compactions run.", plain(self.compactions)),
+            (
+                "compaction_read_bytes_total",
+                "counter",
+                "Size of the tables compactions merged.",
+                plain(self.compaction_bytes_read),
+            ),
+            (
+                "compaction_written_bytes_total",
+                "counter",
+                "Size of the tables compactions wrote.",
+                plain(self.compaction_bytes_written),
+            ),
+            (
+                "compaction_keys_total",
+                "counter",
+                "Distinct keys compactions read.",
+                plain(self.compaction_keys),
+            ),
+            (
+                "compaction_dropped_versions_total",
+                "counter",
+                "Overwritten or deleted versions compactions dropped.",
+                plain(self.compaction_versions_dropped),
+            ),
+            (
+                "compaction_dropped_tombstones_total",
+                "counter",
+                "Point tombstones compactions dropped.",
+                plain(self.compaction_tombstones_dropped),
+            ),
+            (
+                "compaction_microseconds_total",
+                "counter",
+                "Time spent compacting.",
+                plain(self.compaction_micros),
+            ),
+            ("sstables", "gauge", "Live SSTables.", plain(self.sstable_count)),
+            ("sstable_bytes", "gauge", "Size of the live SSTables.", plain(self.sstable_bytes)),
+            ("wal_bytes", "gauge", "Size of the write-ahead logs.", plain(self.wal_bytes)),
+            (
+                "wal_written_bytes_total",
+                "counter",
+                "Bytes appended to the write-ahead logs.",
+                plain(self.wal_bytes_written),
+            ),
+            (
+                "wal_compression_saved_bytes_total",
+                "counter",
+                "Bytes compression kept out of the write-ahead logs.",
+                plain(self.wal_bytes_saved),
+            ),
+            ("memtable_entries", "gauge", "Versions in memtables.", plain(self.memtable_entries)),
+            ("files", "gauge", "Files in use by the engine.", plain(self.file_count)),
+            ("disk_bytes", "gauge", "Size of the SSTables and WALs.", plain(self.disk_bytes)),
+            (
+                "filter_cache_lookups_total",
+                "counter",
+                "Key filter partitions needed, by whether they were in memory.",
+                vec![
+                    (r#"{result="hit"}"#, self.filter_cache_hits),
+                    (r#"{result="miss"}"#, self.filter_cache_misses),
+                ],
+            ),
+            (
+                "filter_cache_bytes",
+                "gauge",
+                "Size of the key filter partitions in memory.",
+                plain(self.filter_cache_bytes),
+            ),
+            (
+                "row_cache_lookups_total",
+                "counter",
+                "Gets, by whether the row cache answered them.",
+                vec![
+                    (r#"{result="hit"}"#, self.row_cache_hits),
+                    (r#"{result="miss"}"#, self.row_cache_misses),
+                ],
+            ),
+            (
+                "row_cache_bytes",
+                "gauge",
+                "Size of the values in row caches.",
+                plain(self.row_cache_bytes),
+            ),
+            (
+                "negative_cache_hits_total",
+                "counter",
+                "Gets of keys remembered as absent, answered without a lookup.",
+                plain(self.negative_cache_hits),
+            ),
+            (
+                "throttled_bytes_total",
+                "counter",
+                "Bytes flushes and compactions read and wrote under the rate limit.",
+                plain(self.throttled_bytes),
+            ),
+            (
+                "throttle_wait_microseconds_total",
+                "counter",
+                "Time flushes and compactions waited for the rate limit.",
+                plain(self.throttle_wait_micros),
+            ),
+            (
+                "write_stalls_total",
+                "counter",
+                "Writes held back for compaction, by whether they were slowed or stopped.",
+                vec![
+                    (r#"{condition="slowed"}"#, self.writes_slowed),
+                    (r#"{condition="stopped"}"#, self.writes_stopped),
+                ],
+            ),
+            (
+                "write_stall_wait_microseconds_total",
+                "counter",
+                "Time writes were held back for compaction.",
+                plain(self.write_stall_micros),
+            ),
+            (
+                "stalled_column_families",
+                "gauge",
+                "Column families holding writes back, by condition.",
+                vec![
+                    (r#"{condition="slowed"}"#, self.slowed_column_families),
+                    (r#"{condition="stopped"}"#, self.stopped_column_families),
+                ],
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, samples) in families {
+            out.push_str(&format!("# HELP storage_engine_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE storage_engine_{} {}\n", name, kind));
+            for (labels, value) in samples {
+                out.push_str(&format!("storage_engine_{}{} {}\n", name, labels, value));
+            }
+        }
+        out
+    }
+}
+
+/// Buckets per power of two in a [`Histogram`]
+const SUB_BUCKETS: u64 = 4;
+/// Enough buckets for any `u64` nanosecond count
+const BUCKETS: usize = 64 * SUB_BUCKETS as usize;
+
+/// Bucket holding `nanos`: exact below [`SUB_BUCKETS`], then [`SUB_BUCKETS`] equal
+/// buckets per power of two
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+    let exponent = 63 - nanos.leading_zeros() as u64;
+    let sub_bucket = (nanos >> (exponent - 2)) & (SUB_BUCKETS - 1);
+    ((exponent - 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// Largest value [`bucket_index`] maps to `index`
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let exponent = index / SUB_BUCKETS + 1;
+    let lower = (SUB_BUCKETS + index % SUB_BUCKETS) << (exponent - 2);
+    lower + ((1 << (exponent - 2)) - 1)
+}
+
+/// Latency histogram of one operation, in log-linear nanosecond buckets
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in self.buckets.iter().chain([&self.max]) {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Histograms of several column families added together
+#[derive(Debug, Clone)]
+struct HistogramTotals {
+    buckets: Vec<u64>,
+    max: u64,
+}
+
+impl Default for HistogramTotals {
+    fn default() -> Self {
+        HistogramTotals { buckets: vec![0; BUCKETS], max: 0 }
+    }
+}
+
+impl HistogramTotals {
+    fn add(&mut self, histogram: &Histogram) {
+        for (total, bucket) in self.buckets.iter_mut().zip(histogram.buckets.iter()) {
+            *total += bucket.load(Ordering::Relaxed);
+        }
+        self.max = self.max.max(histogram.max.load(Ordering::Relaxed));
+    }
+
+    fn summarize(&self) -> Latency {
+        let count = self.buckets.iter().sum();
+        let percentile = |fraction: f64| {
+            let rank = ((count as f64 * fraction).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (index, bucket) in self.buckets.iter().enumerate() {
+                seen += bucket;
+                if seen >= rank {
+                    return Duration::from_nanos(bucket_upper_bound(index).min(self.max));
+                }
+            }
+            Duration::from_nanos(self.max)
+        };
+        if count == 0 {
+            return Latency::default();
+        }
+        Latency {
+            count,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: Duration::from_nanos(self.max),
+        }
+    }
+}
+
+/// Latency histograms of every column family, added up for [`Stats::latency`]
+#[derive(Debug, Default)]
+pub(crate) struct LatencyTotals {
+    put: HistogramTotals,
+    get: HistogramTotals,
+    delete: HistogramTotals,
+    flush: HistogramTotals,
+    compaction: HistogramTotals,
+}
+
+impl LatencyTotals {
+    pub(crate) fn summarize(&self) -> Latencies {
+        Latencies {
+            put: self.put.summarize(),
+            get: self.get.summarize(),
+            delete: self.delete.summarize(),
+            flush: self.flush.summarize(),
+            compaction: self.compaction.summarize(),
+        }
+    }
+}
+
+/// What a column family's WALs have written, counted by each WAL as it appends
+#[derive(Debug, Default)]
+pub(crate) struct WalCounters {
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) bytes_saved: AtomicU64,
+}
+
+/// The live counters of one column family. Updates are relaxed atomic adds, cheap
+/// enough to leave on and possible under a shared lock.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) memtable_hits: AtomicU64,
+    pub(crate) sstable_hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) puts: AtomicU64,
+    pub(crate) deletes: AtomicU64,
+    pub(crate) flushes: AtomicU64,
+    pub(crate) bytes_flushed: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) compaction_bytes_read: AtomicU64,
+    pub(crate) compaction_bytes_written: AtomicU64,
+    pub(crate) compaction_keys: AtomicU64,
+    pub(crate) compaction_versions_dropped: AtomicU64,
+    pub(crate) compaction_tombstones_dropped: AtomicU64,
+    pub(crate) compaction_micros: AtomicU64,
+    /// Shared with each WAL in turn
+    pub(crate) wal: Arc<WalCounters>,
+    /// Whether operations are timed at all
+    pub(crate) timed: bool,
+    pub(crate) put_latency: Histogram,
+    pub(crate) get_latency: Histogram,
+    pub(crate) delete_latency: Histogram,
+    pub(crate) flush_latency: Histogram,
+    pub(crate) compaction_latency: Histogram,
+}
+
+impl Counters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a compaction that merged tables
+    pub(crate) fn add_compaction(&self, info: &CompactionInfo) {
+        Self::add(&self.compactions, 1);
+        Self::add(&self.compaction_bytes_read, info.input_bytes);
+        Self::add(&self.compaction_bytes_written, info.output_bytes);
+        Self::add(&self.compaction_keys, info.keys);
+        Self::add(&self.compaction_versions_dropped, info.versions_dropped);
+        Self::add(&self.compaction_tombstones_dropped, info.tombstones_dropped);
+        Self::add(&self.compaction_micros, info.duration.as_micros() as u64);
+    }
+
+    /// Start timing an operation, unless timing is off
+    pub(crate) fn start(&self) -> Option<Instant> {
+        self.timed.then(Instant::now)
+    }
+
+    /// Record the latency of an operation begun with [`Counters::start`]
+    pub(crate) fn finish(histogram: &Histogram, started: Option<Instant>) {
+        if let Some(started) = started {
+            histogram.record(started.elapsed());
+        }
+    }
+
+    /// Add the current counts to `stats` and the histograms to `latencies`
+    pub(crate) fn add_to(&self, stats: &mut Stats, latencies: &mut LatencyTotals) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        stats.memtable_hits += load(&self.memtable_hits);
+        stats.sstable_hits += load(&self.sstable_hits);
+        stats.misses += load(&self.misses);
+        stats.puts += load(&self.puts);
+        stats.deletes += load(&self.deletes);
+        stats.flushes += load(&self.flushes);
+        stats.bytes_flushed += load(&self.bytes_flushed);
+        stats.compactions += load(&self.compactions);
+        stats.compaction_bytes_read += load(&self.compaction_bytes_read);
+        stats.compaction_bytes_written += load(&self.compaction_bytes_written);
+        stats.compaction_keys += load(&self.compaction_keys);
+        stats.compaction_versions_dropped += load(&self.compaction_versions_dropped);
+        stats.compaction_tombstones_dropped += load(&self.compaction_tombstones_dropped);
+        stats.compaction_micros += load(&self.compaction_micros);
+        stats.wal_bytes_written += load(&self.wal.bytes_written);
+        stats.wal_bytes_saved += load(&self.wal.bytes_saved);
+        latencies.put.add(&self.put_latency);
+        latencies.get.add(&self.get_latency);
+        latencies.delete.add(&self.delete_latency);
+        latencies.flush.add(&self.flush_latency);
+        latencies.compaction.add(&self.compaction_latency);
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.memtable_hits,
+            &self.sstable_hits,
+            &self.misses,
+            &self.puts,
+            &self.deletes,
+            &self.flushes,
+            &self.bytes_flushed,
+            &self.compactions,
+            &self.compaction_bytes_read,
+            &self.compaction_bytes_written,
+            &self.compaction_keys,
+            &self.compaction_versions_dropped,
+            &self.compaction_tombstones_dropped,
+            &self.compaction_micros,
+            &self.wal.bytes_written,
+            &self.wal.bytes_saved,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for histogram in [
+            &self.put_latency,
+            &self.get_latency,
+            &self.delete_latency,
+            &self.flush_latency,
+            &self.compaction_latency,
+        ] {
+            histogram.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_index, bucket_upper_bound, Histogram, HistogramTotals, Latency, Stats};
+    use crate::{Db, Options, WriteBatch};
+    use std::fs;
+    use std::time::Duration;
+
+    fn sstable_bytes(db: &Db) -> u64 {
+        db.sstable_paths().iter().map(|path| fs::metadata(path).unwrap().len()).sum()
+    }
+
+    /// Stats without the timings, which vary from run to run
+    fn untimed_stats(db: &Db) -> Stats {
+        Stats { compaction_micros: 0, latency: Default::default(), ..db.stats().unwrap() }
+    }
+
+    #[test]
+    fn test_counters_follow_operations() {
+        let dir = "test_stats_counters";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.put("b", "2").unwrap();
+        db.put("c", "3").unwrap();
+        db.get("a");
+        db.get("missing");
+        db.flush().unwrap();
+        let first_flush = sstable_bytes(&db);
+        db.get("b");
+        db.put("a", "4").unwrap();
+        db.get("a");
+        db.delete("c").unwrap();
+        db.get("c");
+        db.delete_range("x", "y").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("d", "5").delete("e");
+        db.write(&batch).unwrap();
+        let events = db.create_cf("events").unwrap();
+        events.put("e1", "x").unwrap();
+        db.flush().unwrap();
+        let bytes_flushed = sstable_bytes(&db);
+        assert!(bytes_flushed > first_flush);
+        let compaction_bytes_read = sstable_bytes(&db);
+        db.compact().unwrap();
+        let wal_bytes = [db.wal_path(), events.wal_path()]
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        // Every record of the live WALs and of the one the flushes retired
+        let wal_bytes_written = db.stats().unwrap().wal_bytes_written;
+        assert!(wal_bytes_written > wal_bytes, "{} of {}", wal_bytes_written, wal_bytes);
+
+        let expected = Stats {
+            memtable_hits: 2,
+            sstable_hits: 1,
+            misses: 2,
+            puts: 6,
+            deletes: 3,
+            flushes: 2,
+            bytes_flushed,
+            compactions: 1,
+            compaction_bytes_read,
+            compaction_bytes_written: sstable_bytes(&db),
+            compaction_keys: 5,
+            // The first value of "a" and the value of "c" under its delete
+            compaction_versions_dropped: 2,
+            // The deletes of "c" and "e", with nothing left beneath them
+            compaction_tombstones_dropped: 2,
+            compaction_micros: 0,
+            sstable_count: 1,
+            sstable_bytes: sstable_bytes(&db),
+            wal_bytes,
+            wal_bytes_written,
+            wal_bytes_saved: 0,
+            memtable_entries: 1,
+            // The compacted table plus a WAL and table list for each family
+            file_count: 5,
+            disk_bytes: sstable_bytes(&db) + wal_bytes,
+            // The get of "b" and the delete of "c" reached a table, sharing one filter
+            // partition; compaction dropped it
+            filter_cache_hits: 1,
+            filter_cache_misses: 1,
+            filter_cache_bytes: 0,
+            row_cache_hits: 0,
+            row_cache_misses: 0,
+            row_cache_bytes: 0,
+            negative_cache_hits: 0,
+            throttled_bytes: 0,
+            throttle_wait_micros: 0,
+            writes_slowed: 0,
+            writes_stopped: 0,
+            write_stall_micros: 0,
+            slowed_column_families: 0,
+            stopped_column_families: 0,
+            latency: Default::default(),
+        };
+        assert_eq!(untimed_stats(&db), expected);
+        assert_eq!(db.stats().unwrap().gets(), 5);
+
+        db.reset_stats();
+        let gauges_only = Stats {
+            sstable_count: expected.sstable_count,
+            sstable_bytes: expected.sstable_bytes,
+            wal_bytes,
+            memtable_entries: expected.memtable_entries,
+            file_count: expected.file_count,
+            disk_bytes: expected.disk_bytes,
+            ..Stats::default()
+        };
+        assert_eq!(db.stats().unwrap(), gauges_only);
+        assert_eq!(db.stats().unwrap().latency.put.count, 0);
+
+        // Writes replayed from the WAL at open are not counted again
+        drop((db, events));
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.stats().unwrap().puts, 0);
+        assert_eq!(db.get("e1"), None);
+        assert_eq!(db.cf("events").unwrap().get("e1"), Some("x".to_string()));
+        assert_eq!((db.stats().unwrap().misses, db.stats().unwrap().memtable_hits), (1, 1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let dir = "test_stats_prometheus";
+        let _ = fs::remove_dir_all(dir);
+
+        // Parse into (name with labels, value), checking each family is declared first
+        let parse = |text: &str| {
+            let mut declared = Vec::new();
+            let mut samples = Vec::new();
+            for line in text.lines() {
+                if let Some(rest) = line.strip_prefix("# TYPE ") {
+                    let (name, kind) = rest.split_once(' ').unwrap();
+                    assert!(["counter", "gauge"].contains(&kind), "{}", line);
+                    assert_eq!(kind == "counter", name.ends_with("_total"), "{}", line);
+                    declared.push(name.to_string());
+                } else if !line.starts_with("# HELP ") {
+                    let (series, value) = line.rsplit_once(' ').unwrap();
+                    let name = series.split('{').next().unwrap();
+                    assert_eq!(declared.last().map(String::as_str), Some(name), "{}", line);
+                    samples.push((series.to_string(), value.parse::<u64>().unwrap()));
+                }
+            }
+            samples
+        };
+        let value = |samples: &[(String, u64)], series: &str| {
+            samples.iter().find(|(name, _)| name == series).map(|(_, value)| *value)
+        };
+
+        let db = Db::open(dir).unwrap();
+        let before = parse(&db.metrics_text().unwrap());
+        assert_eq!(before.len(), 36);
+        assert!(before.iter().all(|(name, _)| name.starts_with("storage_engine_")));
+
+        db.put("a", "1").unwrap();
+        db.get("a");
+        db.get("b");
+        let after = parse(&db.metrics_text().unwrap());
+        assert_eq!(value(&after, "storage_engine_puts_total"), Some(1));
+        assert_eq!(value(&after, r#"storage_engine_gets_total{result="memtable_hit"}"#), Some(1));
+        assert_eq!(value(&after, r#"storage_engine_gets_total{result="miss"}"#), Some(1));
+        assert_eq!(value(&after, "storage_engine_memtable_entries"), Some(1));
+        let wal_growth = value(&after, "storage_engine_wal_bytes").unwrap()
+            - value(&before, "storage_engine_wal_bytes").unwrap();
+        assert!(wal_growth > 0);
+        assert_eq!(value(&after, "storage_engine_wal_written_bytes_total"), Some(wal_growth));
+
+        db.flush().unwrap();
+        let flushed = parse(&db.metrics_text().unwrap());
+        assert_eq!(value(&flushed, "storage_engine_flushes_total"), Some(1));
+        assert_eq!(value(&flushed, "storage_engine_sstables"), Some(1));
+        assert_eq!(value(&flushed, "storage_engine_memtable_entries"), Some(0));
+        assert_eq!(
+            value(&flushed, "storage_engine_sstable_bytes"),
+            value(&flushed, "storage_engine_flushed_bytes_total")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        for nanos in (0..10_000).chain([u64::MAX / 3, u64::MAX]) {
+            let index = bucket_index(nanos);
+            assert!(nanos <= bucket_upper_bound(index), "{}", nanos);
+            assert!(index == 0 || nanos > bucket_upper_bound(index - 1), "{}", nanos);
+        }
+        // Buckets are at most a quarter of their lower bound wide
+        let (lower, upper) = (bucket_upper_bound(99) + 1, bucket_upper_bound(100));
+        assert!(upper - lower < lower / 4, "{}..={}", lower, upper);
+
+        let histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let mut totals = HistogramTotals::default();
+        totals.add(&histogram);
+        let latency = totals.summarize();
+        assert_eq!(latency.count, 1000);
+        assert_eq!(latency.max, Duration::from_micros(1000));
+        for (percentile, expected) in [(latency.p50, 500), (latency.p90, 900), (latency.p99, 990)] {
+            let expected = Duration::from_micros(expected);
+            assert!(percentile >= expected && percentile <= expected * 5 / 4, "{:?}", latency);
+        }
+        assert_eq!(HistogramTotals::default().summarize(), Latency::default());
+    }
+
+    #[test]
+    fn test_latency_histograms_follow_operations() {
+        let dir = "test_stats_latency";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        // The memtable flushes every 100 entries, inside the put that fills it
+        for i in 0..250 {
+            db.put(format!("key{}", i), "value").unwrap();
+        }
+        for i in 0..40 {
+            db.get(format!("key{}", i * 7));
+        }
+        for i in 0..30 {
+            db.delete(format!("key{}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+
+        let stats = db.stats().unwrap();
+        let latency = &stats.latency;
+        assert_eq!((latency.put.count, stats.puts), (250, 250));
+        assert_eq!((latency.get.count, stats.gets()), (40, 40));
+        assert_eq!((latency.delete.count, stats.deletes), (30, 30));
+        assert_eq!((latency.flush.count, stats.flushes), (3, 3));
+        assert_eq!((latency.compaction.count, stats.compactions), (1, 1));
+        for summary in [latency.put, latency.get, latency.delete, latency.flush] {
+            assert!(summary.p50 <= summary.p90, "{:?}", summary);
+            assert!(summary.p90 <= summary.p99, "{:?}", summary);
+            assert!(summary.p99 <= summary.max, "{:?}", summary);
+            assert!(summary.max > Duration::ZERO, "{:?}", summary);
+        }
+        // Two of the three flushes ran inside puts, so the slowest put took at least as
+        // long as the median flush (which is reported up to a bucket width high)
+        assert!(latency.put.max >= latency.flush.p50 * 4 / 5, "{:?}", latency);
+        drop(db);
+
+        let options = Options { disable_latency_histograms: true, ..Options::default() };
+        let db = Db::open_with_options(dir, options).unwrap();
+        db.put("a", "1").unwrap();
+        db.get("a");
+        let stats = db.stats().unwrap();
+        assert_eq!((stats.puts, stats.latency), (1, Default::default()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+//! File access for the WAL, SSTables, value logs, and memtable bookkeeping, behind a
+//! trait so that a column family can live somewhere other than the local filesystem.
+//!
+//! [`FileStorage`] is the default; [`MemStorage`] keeps everything in memory, which
+//! makes tests independent of the working directory and of each other.
+
+use crate::lock::DirLock;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// An open file
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    /// Make everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Make everything written so far durable, skipping metadata that is not needed to
+    /// read it back. The default is a full [`sync`](StorageFile::sync).
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+/// The file operations the engine needs. Paths are used as given; directories are
+/// created by the caller, with [`Storage::create_dir_all`].
+pub trait Storage: Send + Sync {
+    /// Open `path` for appending, creating it if it is missing
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create `path` for writing, truncating any existing file
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open an existing file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open an existing file for writing from the start, keeping its contents
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Move a file, replacing any file already at `to`
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Paths of the files in `dir`, each joined onto `dir`. A missing directory is empty.
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Make the files created, renamed, or removed in `dir` so far durable. The default
+    /// does nothing, which suits storage without directory entries to lose.
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Create `dir` and any parents it lacks. The default does nothing, which suits
+    /// storage where a directory is only a prefix of the paths in it.
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Paths of the directories in `dir`, each joined onto `dir`. A missing directory
+    /// has none, as does any directory by default.
+    fn list_subdirs(&self, _dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Move `from` and everything in it to `to`. The default moves one file at a time.
+    fn rename_dir(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.create_dir_all(to)?;
+        for subdir in self.list_subdirs(from)? {
+            if let Some(name) = subdir.file_name() {
+                self.rename_dir(&subdir, &to.join(name))?;
+            }
+        }
+        for path in self.list_dir(from)? {
+            if let Some(name) = path.file_name() {
+                self.rename(&path, &to.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `dir` and everything in it. The default removes one file at a time.
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        for subdir in self.list_subdirs(dir)? {
+            self.remove_dir_all(&subdir)?;
+        }
+        for path in self.list_dir(dir)? {
+            self.remove(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Lock `dir` against other opens until the returned guard is dropped: exclusively,
+    /// or alongside other shared holders when `shared` is set. The default takes no
+    /// lock, which suits storage no other process can reach.
+    fn lock_dir(&self, _dir: &Path, _shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        Ok(Box::new(()))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.file_len(path).is_ok()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Replace the contents of `path` with `data` and sync it
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.create(path)?;
+        file.write_all(data)?;
+        file.sync()
+    }
+}
+
+/// The local filesystem, through `std::fs`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileStorage;
+
+impl StorageFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+impl Storage for FileStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(OpenOptions::new().write(true).open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let read_from = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let entries = match fs::read_dir(read_from) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(dir.join(entry.file_name()));
+            }
+        }
+        Ok(paths)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.set_len(len)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn list_subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let read_from = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let entries = match fs::read_dir(read_from) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                paths.push(dir.join(entry.file_name()));
+            }
+        }
+        Ok(paths)
+    }
+
+    fn rename_dir(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    /// Takes the platform's advisory lock on the directory's `LOCK` file, which other
+    /// processes see as well
+    fn lock_dir(&self, dir: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        Ok(Box::new(DirLock::acquire(dir, shared)?))
+    }
+
+    /// Fsyncs the directory itself. Windows cannot open a directory for this, so there
+    /// it does nothing and relies on NTFS journaling its metadata.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        if cfg!(unix) {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+type Contents = Arc<Mutex<Vec<u8>>>;
+
+/// Files held in memory, lost when the last handle to the storage is dropped. Clones
+/// share the same files, so a database can be closed and reopened on them.
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    files: Arc<Mutex<BTreeMap<PathBuf, Contents>>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn files(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, Contents>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn contents(&self, path: &Path) -> io::Result<Contents> {
+        self.files().get(&normalize(path)).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn handle(&self, contents: Contents, append: bool) -> Box<dyn StorageFile> {
+        Box::new(MemFile { contents, position: 0, append })
+    }
+}
+
+impl Storage for MemStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let contents = self.files().entry(normalize(path)).or_default().clone();
+        Ok(self.handle(contents, true))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let contents = Contents::default();
+        self.files().insert(normalize(path), contents.clone());
+        Ok(self.handle(contents, false))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.handle(self.contents(path)?, false))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files();
+        let contents = files.remove(&normalize(from)).ok_or_else(|| not_found(from))?;
+        files.insert(normalize(to), contents);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.files().remove(&normalize(path)).map(drop).ok_or_else(|| not_found(path))
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let normalized = normalize(dir);
+        Ok(self
+            .files()
+            .keys()
+            .filter(|path| path.parent() == Some(&normalized))
+            .filter_map(|path| path.file_name().map(|name| dir.join(name)))
+            .collect())
+    }
+
+    /// The directories holding files, as nothing else marks one
+    fn list_subdirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let normalized = normalize(dir);
+        let mut names: Vec<_> = self
+            .files()
+            .keys()
+            .filter_map(|path| path.strip_prefix(&normalized).ok())
+            .filter(|relative| relative.components().count() > 1)
+            .filter_map(|relative| relative.components().next())
+            .map(|name| dir.join(name))
+            .collect();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn set_len(&self, path: &Path, len: u64) -> io::Result<()> {
+        let contents = self.contents(path)?;
+        lock(&contents).resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(lock(&self.contents(path)?).len() as u64)
+    }
+}
+
+/// An open [`MemStorage`] file
+struct MemFile {
+    contents: Contents,
+    position: u64,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let contents = lock(&self.contents);
+        let start = (self.position as usize).min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut contents = lock(&self.contents);
+        if self.append {
+            self.position = contents.len() as u64;
+        }
+        let start = self.position as usize;
+        if contents.len() < start + buf.len() {
+            contents.resize(start + buf.len(), 0);
+        }
+        contents[start..start + buf.len()].copy_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (lock(&self.contents).len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl StorageFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lock(contents: &Contents) -> std::sync::MutexGuard<'_, Vec<u8>> {
+    contents.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// `path` without `.` components, so `./a` and `a` name the same file
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemStorage, Storage};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_mem_storage_files() {
+        let storage = MemStorage::new();
+        let path = Path::new("dir/file");
+
+        let mut file = storage.open_append(path).unwrap();
+        file.write_all(b"hello").unwrap();
+        // Appends always go to the end
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b" world").unwrap();
+        assert_eq!(storage.read(path).unwrap(), b"hello world");
+
+        let mut file = storage.open(Path::new("./dir/file")).unwrap();
+        file.seek(SeekFrom::End(-5)).unwrap();
+        let mut tail = String::new();
+        file.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "world");
+
+        let mut file = storage.open_write(path).unwrap();
+        file.write_all(b"j").unwrap();
+        assert_eq!(storage.read(path).unwrap(), b"jello world");
+        storage.set_len(path, 4).unwrap();
+        assert_eq!(storage.file_len(path).unwrap(), 4);
+        storage.write(Path::new("dir/other"), b"x").unwrap();
+        storage.write(Path::new("dir/nested/deeper"), b"y").unwrap();
+        let mut listed = storage.list_dir(Path::new("dir")).unwrap();
+        listed.sort();
+        assert_eq!(listed, [PathBuf::from("dir/file"), PathBuf::from("dir/other")]);
+
+        storage.rename(path, Path::new("dir/other")).unwrap();
+        assert_eq!(storage.read(Path::new("dir/other")).unwrap(), b"jell");
+        assert!(!storage.exists(path));
+        storage.remove(Path::new("dir/other")).unwrap();
+        assert!(storage.open(Path::new("dir/other")).is_err());
+        assert!(storage.list_dir(Path::new("missing")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+//! The SSTables a column family holds open for point lookups.
+//!
+//! Readers are opened on first use and kept until more than
+//! [`Options::max_open_files`](crate::Options#structfield.max_open_files) are open, when
+//! the least recently used is closed, dropping its file handle along with the indexes
+//! read from it. A lookup still using an evicted reader keeps it alive until it is
+//! done, and scans open the tables they stream on their own, so neither is disturbed.
+
+use crate::sstable::SSTableReader;
+use crate::storage::Storage;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Open SSTable readers by path, up to a count
+pub(crate) struct TableCache {
+    capacity: usize,
+    storage: Arc<dyn Storage>,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Each reader by table path, with when it was last used
+    readers: HashMap<String, (Arc<SSTableReader>, u64)>,
+    /// Keys of `readers` by last use, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl TableCache {
+    /// A cache holding at most `capacity` readers, and always room for one
+    pub(crate) fn new(capacity: usize, storage: Arc<dyn Storage>) -> Self {
+        TableCache { capacity: capacity.max(1), storage, state: Mutex::default() }
+    }
+
+    /// The reader of the table at `path`, opening it if it is not open. Readers are
+    /// evicted before the table is opened, so no more than the capacity are ever open.
+    pub(crate) fn get(&self, path: &str) -> io::Result<Arc<SSTableReader>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.clock += 1;
+        if let Some((reader, used)) = state.readers.get_mut(path) {
+            state.recency.remove(used);
+            *used = state.clock;
+            state.recency.insert(state.clock, path.to_string());
+            return Ok(reader.clone());
+        }
+
+        while state.readers.len() >= self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else { break };
+            state.readers.remove(&oldest);
+        }
+        let reader = Arc::new(SSTableReader::open(&*self.storage, path)?);
+        state.recency.insert(state.clock, path.to_string());
+        state.readers.insert(path.to_string(), (reader.clone(), state.clock));
+        Ok(reader)
+    }
+
+    /// Close the table at `path`, once it is no longer read
+    pub(crate) fn forget(&self, path: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, used)) = state.readers.remove(path) {
+            state.recency.remove(&used);
+        }
+    }
+}
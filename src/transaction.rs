@@ -0,0 +1,151 @@
+use crate::batch::WriteBatch;
+use crate::db::Db;
+use crate::snapshot::Snapshot;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+/// An optimistic transaction started with [`Db::begin`].
+///
+/// Reads see the database as of `begin()` plus the transaction's own pending writes.
+/// Nothing is locked while the transaction runs; `commit` applies the writes as a single
+/// atomic batch, or fails with [`crate::error::EngineError::Conflict`] if another commit
+/// wrote any key this transaction read since it began. On conflict nothing is written and
+/// the caller can simply retry with a fresh transaction.
+pub struct Transaction {
+    db: Db,
+    /// Keeps the versions read from until the transaction ends
+    start: Snapshot,
+    reads: HashSet<Vec<u8>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(db: Db, start: Snapshot) -> Self {
+        Transaction {
+            db,
+            start,
+            reads: HashSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Option<String> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return value.as_deref().map(|v| String::from_utf8_lossy(v).into_owned());
+        }
+        self.reads.insert(key.to_vec());
+        self.start.get(key)
+    }
+
+    pub fn put<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    pub fn delete<K: Into<Vec<u8>>>(&mut self, key: K) {
+        self.writes.insert(key.into(), None);
+    }
+
+    /// Validate the read set and apply the writes atomically
+    pub fn commit(self) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        self.db.commit_if_unchanged(self.start.sequence(), &self.reads, &batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EngineError;
+    use std::fs;
+
+    #[test]
+    fn test_conflict_then_successful_retry() {
+        let dir = "test_transaction_conflict";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        db.put("counter", "1").unwrap();
+
+        let mut tx = db.begin();
+        let value: u32 = tx.get("counter").unwrap().parse().unwrap();
+        tx.put("counter", (value + 1).to_string());
+
+        // Another writer commits first
+        db.put("counter", "10").unwrap();
+
+        let err = tx.commit().unwrap_err();
+        assert_eq!(
+            EngineError::from_io(&err),
+            Some(&EngineError::Conflict { key: "counter".to_string() })
+        );
+        assert_eq!(db.get("counter"), Some("10".to_string()));
+
+        let mut retry = db.begin();
+        let value: u32 = retry.get("counter").unwrap().parse().unwrap();
+        retry.put("counter", (value + 1).to_string());
+        retry.commit().unwrap();
+        assert_eq!(db.get("counter"), Some("11".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_and_self_deleting_transactions() {
+        let dir = "test_transaction_edge_cases";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let before = db.last_sequence();
+        db.begin().commit().unwrap();
+        assert_eq!(db.last_sequence(), before);
+
+        let mut tx = db.begin();
+        tx.put("temp", "value");
+        assert_eq!(tx.get("temp"), Some("value".to_string()));
+        tx.delete("temp");
+        assert_eq!(tx.get("temp"), None);
+        tx.commit().unwrap();
+        assert_eq!(db.get("temp"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disjoint_transactions_both_commit() {
+        let dir = "test_transaction_disjoint";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open(dir).unwrap();
+        let mut tx1 = db.begin();
+        let mut tx2 = db.begin();
+
+        assert_eq!(tx1.get("a"), None);
+        tx1.put("a", "1");
+        assert_eq!(tx2.get("b"), None);
+        tx2.put("b", "2");
+
+        tx1.commit().unwrap();
+        tx2.commit().unwrap();
+        assert_eq!(db.get("a"), Some("1".to_string()));
+        assert_eq!(db.get("b"), Some("2".to_string()));
+
+        // Both survive recovery as batches
+        drop(db);
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get("a"), Some("1".to_string()));
+        assert_eq!(db.get("b"), Some("2".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
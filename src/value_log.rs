@@ -0,0 +1,242 @@
+use crate::storage::{Storage, StorageFile};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Where a separated value lives in the value log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValuePointer {
+    /// Number of the `vlog_{:06}.blob` file
+    pub file: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl ValuePointer {
+    /// Size of the encoded form stored in WAL records and SSTables
+    pub const ENCODED_LEN: usize = 16;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0u8; Self::ENCODED_LEN];
+        buf[..4].copy_from_slice(&self.file.to_le_bytes());
+        buf[4..12].copy_from_slice(&self.offset.to_le_bytes());
+        buf[12..].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        Some(ValuePointer {
+            file: u32::from_le_bytes(bytes[..4].try_into().ok()?),
+            offset: u64::from_le_bytes(bytes[4..12].try_into().ok()?),
+            len: u32::from_le_bytes(bytes[12..].try_into().ok()?),
+        })
+    }
+
+    /// Read the value this pointer refers to from the value log in `dir`
+    pub fn read(&self, storage: &dyn Storage, dir: &Path) -> io::Result<Vec<u8>> {
+        let mut file = self.open(storage, dir)?;
+        let mut value = vec![0u8; self.len as usize];
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    /// Open the value log file in `dir` this pointer refers to, positioned at the value
+    pub fn open(&self, storage: &dyn Storage, dir: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut file = storage.open(&blob_path(dir, self.file))?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file)
+    }
+}
+
+/// Append-only file of large values (WiscKey-style key/value separation).
+///
+/// Values are written here before the WAL record that points at them, so the WAL,
+/// memtable, and SSTables carry only a small [`ValuePointer`]. Space held by
+/// overwritten or deleted values is not reclaimed yet.
+pub struct ValueLog {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    file: Box<dyn StorageFile>,
+    number: u32,
+    offset: u64,
+}
+
+impl ValueLog {
+    /// Open the newest value log in `dir` for appending, creating one if there is none
+    pub fn open(storage: Arc<dyn Storage>, dir: &Path) -> io::Result<Self> {
+        let number = newest_blob_number(&*storage, dir)?.unwrap_or(0);
+        Self::open_file(storage, dir, number)
+    }
+
+    fn open_file(storage: Arc<dyn Storage>, dir: &Path, number: u32) -> io::Result<Self> {
+        let path = blob_path(dir, number);
+        let file = storage.open_append(&path)?;
+        let offset = storage.file_len(&path)?;
+        Ok(ValueLog {
+            storage,
+            dir: dir.to_path_buf(),
+            file,
+            number,
+            offset,
+        })
+    }
+
+    /// File currently being appended to
+    pub fn path(&self) -> PathBuf {
+        blob_path(&self.dir, self.number)
+    }
+
+    /// Continue in a new file so the current one never changes again. Does nothing
+    /// while the current file is empty.
+    pub fn seal(&mut self) -> io::Result<()> {
+        if self.offset > 0 {
+            *self = Self::open_file(self.storage.clone(), &self.dir, self.number + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Append and sync `value`, returning where it was written
+    pub fn append(&mut self, value: &[u8]) -> io::Result<ValuePointer> {
+        let len = u32::try_from(value.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "value too large for the value log")
+        })?;
+        self.file.write_all(value)?;
+        self.file.sync()?;
+
+        let pointer = ValuePointer {
+            file: self.number,
+            offset: self.offset,
+            len,
+        };
+        self.offset += value.len() as u64;
+        Ok(pointer)
+    }
+}
+
+fn blob_path(dir: &Path, number: u32) -> PathBuf {
+    dir.join(format!("vlog_{:06}.blob", number))
+}
+
+fn newest_blob_number(storage: &dyn Storage, dir: &Path) -> io::Result<Option<u32>> {
+    Ok(blob_numbers(storage, dir)?.into_iter().max())
+}
+
+/// Every value log file in `dir`, oldest first
+pub(crate) fn blob_files(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut numbers = blob_numbers(storage, dir)?;
+    numbers.sort_unstable();
+    Ok(numbers.into_iter().map(|n| blob_path(dir, n)).collect())
+}
+
+/// Whether `name` is a value log file name
+pub(crate) fn is_blob_file(name: &str) -> bool {
+    blob_number(name).is_some()
+}
+
+fn blob_number(name: &str) -> Option<u32> {
+    name.strip_prefix("vlog_")
+        .and_then(|rest| rest.strip_suffix(".blob"))
+        .and_then(|n| n.parse::<u32>().ok())
+}
+
+fn blob_numbers(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for path in storage.list_dir(dir)? {
+        if let Some(name) = path.file_name() {
+            numbers.extend(blob_number(&name.to_string_lossy()));
+        }
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use std::fs;
+
+    fn blob_options() -> Options {
+        Options {
+            value_log_threshold: Some(64),
+            ..Options::default()
+        }
+    }
+
+    fn large(seed: u8) -> Vec<u8> {
+        (0..1000u32).map(|i| seed.wrapping_add(i as u8)).collect()
+    }
+
+    #[test]
+    fn test_large_values_round_trip() {
+        let dir = "test_vlog_round_trip";
+        let _ = fs::remove_dir_all(dir);
+
+        {
+            let db = Db::open_with_options(dir, blob_options()).unwrap();
+            db.put("flushed", large(1)).unwrap();
+            db.flush().unwrap();
+            db.put("in_wal", large(2)).unwrap();
+            assert_eq!(db.get_bytes("flushed"), Some(large(1)));
+            assert_eq!(db.get_bytes("in_wal"), Some(large(2)));
+        }
+
+        // Pointers resolve without the threshold being set
+        let db = Db::open(dir).unwrap();
+        assert_eq!(db.get_bytes("flushed"), Some(large(1)));
+        assert_eq!(db.get_bytes("in_wal"), Some(large(2)));
+        assert_eq!(db.scan_bytes("a", "z").unwrap().len(), 2);
+
+        // Pointers are all the log and table hold
+        let table = fs::read(format!("{}/sstable_000000.sst", dir)).unwrap();
+        assert!(table.len() < 200);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_small_values_stay_inline() {
+        let dir = "test_vlog_inline";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open_with_options(dir, blob_options()).unwrap();
+        db.put("small", "tiny").unwrap();
+        db.flush().unwrap();
+        assert_eq!(fs::metadata(format!("{}/vlog_000000.blob", dir)).unwrap().len(), 0);
+        assert_eq!(db.get("small"), Some("tiny".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mixed_workload() {
+        let dir = "test_vlog_mixed";
+        let _ = fs::remove_dir_all(dir);
+
+        let db = Db::open_with_options(dir, blob_options()).unwrap();
+        for i in 0..150u8 {
+            let key = format!("key{:03}", i);
+            if i % 2 == 0 {
+                db.put(key, large(i)).unwrap();
+            } else {
+                db.put(key, vec![i]).unwrap();
+            }
+        }
+        db.put("key000", "replaced").unwrap();
+        db.delete("key002").unwrap();
+
+        assert_eq!(db.get("key000"), Some("replaced".to_string()));
+        assert_eq!(db.get_bytes("key002"), None);
+        assert_eq!(db.get_bytes("key004"), Some(large(4)));
+        assert_eq!(db.get_bytes("key005"), Some(vec![5]));
+
+        let items = db.scan_bytes("key", "kez").unwrap();
+        assert_eq!(items.len(), 149);
+        assert_eq!(items[3], (b"key004".to_vec(), large(4)));
+        assert_eq!(db.count_range("key", "kez").unwrap(), 149);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
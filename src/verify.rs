@@ -0,0 +1,419 @@
+//! Whole-database consistency checks, for [`Db::verify`](crate::Db::verify) and
+//! [`Options::verify_on_open`](crate::Options#structfield.verify_on_open)
+
+use crate::compaction;
+use crate::entry::RangeTombstone;
+use crate::error::EngineError;
+use crate::memtable::table_number;
+use crate::sstable::{SSTable, SSTableIterator, SSTableReader, TableHeader, DEFAULT_READ_AHEAD};
+use crate::storage::Storage;
+use crate::wal::{WalIterator, WalKeys};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// One of the checks [`Db::verify`](crate::Db::verify) runs on each column family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Every SSTable in use exists, reads back whole with its entries in order, and
+    /// opens for lookups
+    Tables,
+    /// No SSTable file is present that is neither in use nor waiting to be deleted
+    UnexpectedFiles,
+    /// Every table's entries and range tombstones fall within the sequence range its
+    /// header records
+    TableSequences,
+    /// The WAL parses to its end
+    Wal,
+    /// The next table number and the last sequence number are past every table and
+    /// WAL record on disk
+    Counters,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Check::Tables => "tables",
+            Check::UnexpectedFiles => "unexpected files",
+            Check::TableSequences => "table sequences",
+            Check::Wal => "wal",
+            Check::Counters => "counters",
+        })
+    }
+}
+
+/// The outcome of one check on one column family
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub column_family: String,
+    pub check: Check,
+    /// What the check found wrong, empty when it passed
+    pub violations: Vec<String>,
+}
+
+/// Every check [`Db::verify`](crate::Db::verify) ran, passed or not
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl VerifyReport {
+    /// Whether every check passed
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|result| result.violations.is_empty())
+    }
+
+    /// Every violation, each prefixed with its column family and check
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        for result in &self.checks {
+            for violation in &result.violations {
+                let family = &result.column_family;
+                violations.push(format!("{} {}: {}", family, result.check, violation));
+            }
+        }
+        violations
+    }
+}
+
+/// How much of an SSTable reads back
+pub(crate) struct TableCheck {
+    /// `None` when not even the header could be read
+    pub(crate) header: Option<TableHeader>,
+    /// Entries readable, and in order, before the damage
+    pub(crate) entries: u64,
+    /// The table's range tombstones, when they could be read
+    pub(crate) range_tombstones: Vec<RangeTombstone>,
+    /// Lowest and highest sequence number among what was read
+    pub(crate) sequences: Option<(u64, u64)>,
+    pub(crate) damage: Option<io::Error>,
+}
+
+/// Read the whole table at `path`: its entries in order, its range tombstones, and the
+/// index and filter a lookup needs. Damage is recorded in the result; only failures to
+/// read at all are returned as errors.
+pub(crate) fn check_table(storage: &dyn Storage, path: &str) -> io::Result<TableCheck> {
+    let mut check = TableCheck {
+        header: None,
+        entries: 0,
+        range_tombstones: Vec::new(),
+        sequences: None,
+        damage: None,
+    };
+    let mut table = match SSTableIterator::open_sequential(storage, path, DEFAULT_READ_AHEAD) {
+        Ok(table) => table,
+        Err(err) if is_damage(&err) => {
+            // A table that lost its footer still has the entries before the damage
+            if let Ok(mut table) = SSTableIterator::open_salvage(storage, path, DEFAULT_READ_AHEAD)
+            {
+                check.header = Some(table.header());
+                check.read_entries(&mut table)?;
+            }
+            check.damage = Some(err);
+            return Ok(check);
+        }
+        Err(err) => return Err(err),
+    };
+    check.header = Some(table.header());
+    if let Some(damage) = check.read_entries(&mut table)? {
+        check.damage = Some(damage);
+        return Ok(check);
+    }
+
+    match table.into_range_tombstones() {
+        Ok(range_tombstones) => {
+            for tombstone in &range_tombstones {
+                check.see_sequence(tombstone.seq);
+            }
+            check.range_tombstones = range_tombstones;
+        }
+        Err(err) if is_damage(&err) => {
+            check.damage = Some(err);
+            return Ok(check);
+        }
+        Err(err) => return Err(err),
+    }
+    match SSTableReader::open(storage, path) {
+        Ok(_) => {}
+        Err(err) if is_damage(&err) => check.damage = Some(err),
+        Err(err) => return Err(err),
+    }
+    Ok(check)
+}
+
+impl TableCheck {
+    /// Count the entries of `table` until it runs out, returning the damage that stopped
+    /// it short: an entry that fails to parse or is out of order
+    fn read_entries(&mut self, table: &mut SSTableIterator) -> io::Result<Option<io::Error>> {
+        let mut last: Option<(Vec<u8>, u64)> = None;
+        for item in table {
+            let (key, entry) = match item {
+                Ok(item) => item,
+                Err(err) if is_damage(&err) => return Ok(Some(err)),
+                Err(err) => return Err(err),
+            };
+            let in_order = last.as_ref().is_none_or(|(last_key, last_seq)| {
+                *last_key < key || (*last_key == key && *last_seq > entry.seq)
+            });
+            if !in_order {
+                let message = format!("entry {} is out of order", self.entries);
+                return Ok(Some(io::Error::new(io::ErrorKind::InvalidData, message)));
+            }
+            self.entries += 1;
+            self.see_sequence(entry.seq);
+            last = Some((key, entry.seq));
+        }
+        Ok(None)
+    }
+
+    fn see_sequence(&mut self, seq: u64) {
+        self.sequences = Some(self.sequences.map_or((seq, seq), |(min, max)| {
+            (min.min(seq), max.max(seq))
+        }));
+    }
+}
+
+/// Whether `err` is damage to the file being read, rather than a failure to read it or
+/// a file this build cannot read
+pub(crate) fn is_damage(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof)
+        && EngineError::from_io(err).is_none()
+}
+
+/// Run every check on the column family in `dir`, which has the SSTables `in_use`
+/// and the WAL at `wal_path`, and will next number a table `next_table` and a write
+/// `last_seq + 1`
+pub(crate) fn check_family(
+    storage: &dyn Storage,
+    dir: &Path,
+    in_use: &[String],
+    next_table: usize,
+    last_seq: u64,
+    wal_path: &str,
+    wal_keys: &WalKeys,
+) -> Vec<(Check, Vec<String>)> {
+    let (mut tables, mut sequences, mut unexpected, mut counters) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let past_last = |name: &str, max_seq: u64| {
+        (max_seq > last_seq).then(|| {
+            format!("{} holds sequence {}, past the last sequence {}", name, max_seq, last_seq)
+        })
+    };
+
+    for path in in_use {
+        let name = file_name(path);
+        let check = match check_table(storage, path) {
+            Ok(check) => check,
+            Err(err) => {
+                tables.push(format!("{}: {}", name, err));
+                continue;
+            }
+        };
+        if let Some(damage) = &check.damage {
+            tables.push(format!("{}: {}", name, damage));
+        }
+        let Some(header) = check.header else { continue };
+        counters.extend(past_last(&name, header.max_seq));
+        if let (Some((min, max)), true) = (check.sequences, header.version >= 2) {
+            // Tables before version 5 record no lowest sequence
+            let min_seq = if header.version >= 5 { header.min_seq } else { 0 };
+            if min < min_seq || max > header.max_seq {
+                sequences.push(format!(
+                    "{} holds sequences {} to {}, outside the {} to {} its header records",
+                    name, min, max, min_seq, header.max_seq
+                ));
+            }
+        }
+    }
+
+    let on_disk = storage.list_dir(dir).and_then(|paths| {
+        let obsolete = compaction::read_obsolete(storage, dir)?;
+        Ok((paths, obsolete))
+    });
+    match on_disk {
+        Ok((paths, obsolete)) => {
+            for path in paths {
+                let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let Some(number) = table_number(&name) else { continue };
+                if number >= next_table {
+                    counters.push(format!(
+                        "{} is numbered at or past the next table number {}",
+                        name, next_table
+                    ));
+                }
+                if in_use.iter().any(|table| file_name(table) == name) || obsolete.contains(&name) {
+                    continue;
+                }
+                unexpected.push(format!("{} is not in use", name));
+                if let Ok(header) = SSTable::header(storage, &path.to_string_lossy()) {
+                    counters.extend(past_last(&name, header.max_seq));
+                }
+            }
+        }
+        Err(err) => unexpected.push(format!("cannot list {}: {}", dir.display(), err)),
+    }
+
+    let mut wal = Vec::new();
+    let name = file_name(wal_path);
+    match WalIterator::with_keys(storage, wal_path, wal_keys.clone()) {
+        Ok(frames) => {
+            let mut last_record = 0;
+            for frame in frames {
+                match frame {
+                    Ok(frame) => {
+                        let records = frame.records.len() as u64;
+                        if let (Some(first), true) = (frame.sequence, records > 0) {
+                            last_record = first + records - 1;
+                        }
+                    }
+                    Err(err) => {
+                        wal.push(format!("{}: {}", name, err));
+                        break;
+                    }
+                }
+            }
+            counters.extend(past_last(&name, last_record));
+        }
+        Err(err) => wal.push(format!("{}: {}", name, err)),
+    }
+
+    vec![
+        (Check::Tables, tables),
+        (Check::UnexpectedFiles, unexpected),
+        (Check::TableSequences, sequences),
+        (Check::Wal, wal),
+        (Check::Counters, counters),
+    ]
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Entry;
+    use crate::sstable::SSTableWriter;
+    use crate::{Db, MemStorage, Options};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    const DIR: &str = "test_verify";
+
+    fn open(storage: &MemStorage, verify_on_open: bool) -> io::Result<Db> {
+        let options = Options {
+            storage: Some(Arc::new(storage.clone())),
+            verify_on_open,
+            ..Options::default()
+        };
+        Db::open_with_options(DIR, options)
+    }
+
+    /// A database of two flushed tables and a few WAL records
+    fn populated() -> MemStorage {
+        let storage = MemStorage::new();
+        let db = open(&storage, false).unwrap();
+        for table in 0..2 {
+            db.put(format!("key{}", table), "1").unwrap();
+            db.delete_range("a", "b").unwrap();
+            db.flush().unwrap();
+        }
+        db.put("wal", "1").unwrap();
+        storage
+    }
+
+    fn table(n: usize) -> String {
+        format!("{}/sstable_{:06}.sst", DIR, n)
+    }
+
+    /// Write a table holding one entry at `seq` whose header claims `max_seq`
+    fn write_table(storage: &MemStorage, path: &str, seq: u64, max_seq: u64) {
+        let mut writer = SSTableWriter::create(storage, path).unwrap();
+        writer.add(b"stray", &Entry::new(seq, crate::entry::Op::Delete)).unwrap();
+        writer.finish(&[], max_seq).unwrap();
+    }
+
+    /// The checks that failed, each with its violations
+    fn failures(db: &Db) -> Vec<(Check, Vec<String>)> {
+        let report = db.verify();
+        assert_eq!(report.checks.len(), 5);
+        let failed = report.checks.into_iter().filter(|result| !result.violations.is_empty());
+        failed.map(|result| (result.check, result.violations)).collect()
+    }
+
+    #[test]
+    fn test_verify_reports_each_violation() {
+        let storage = populated();
+        let db = open(&storage, true).unwrap();
+        assert!(db.verify().is_ok());
+        db.create_cf("events").unwrap().put("e", "1").unwrap();
+        let report = db.verify();
+        assert!(report.is_ok());
+        assert_eq!(report.checks.len(), 10);
+        assert_eq!(report.checks[5].column_family, "events");
+
+        // A table in use that is cut short, then one that is gone
+        let storage = populated();
+        let db = open(&storage, false).unwrap();
+        storage.set_len(Path::new(&table(1)), 30).unwrap();
+        let failed = failures(&db);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, Check::Tables);
+        assert!(failed[0].1[0].starts_with("sstable_000001.sst: "), "{:?}", failed);
+        storage.remove(Path::new(&table(0))).unwrap();
+        assert_eq!(failures(&db)[0].1.len(), 2);
+        drop(db);
+
+        // A table nothing uses, numbered and sequenced past the counters
+        let storage = populated();
+        let db = open(&storage, false).unwrap();
+        write_table(&storage, &table(7), 100, 100);
+        assert_eq!(
+            failures(&db),
+            [
+                (Check::UnexpectedFiles, vec!["sstable_000007.sst is not in use".to_string()]),
+                (
+                    Check::Counters,
+                    vec![
+                        "sstable_000007.sst is numbered at or past the next table number 2"
+                            .to_string(),
+                        "sstable_000007.sst holds sequence 100, past the last sequence 5"
+                            .to_string(),
+                    ]
+                ),
+            ]
+        );
+        drop(db);
+
+        // A table whose entries are newer than its header says
+        let storage = populated();
+        write_table(&storage, &table(2), 4, 1);
+        let db = open(&storage, false).unwrap();
+        let sequences = "sstable_000002.sst holds sequences 4 to 4, outside the 4 to 1 its \
+                         header records";
+        assert_eq!(failures(&db), [(Check::TableSequences, vec![sequences.to_string()])]);
+        drop(db);
+
+        // A WAL with garbage after its last record, which an open would cut off
+        let storage = populated();
+        let db = open(&storage, false).unwrap();
+        let wal = Path::new(DIR).join(crate::wal::WAL_FILE);
+        storage.open_append(&wal).unwrap().write_all(&[0xff; 5]).unwrap();
+        let failed = failures(&db);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, Check::Wal);
+        assert!(failed[0].1[0].starts_with("data.log: "), "{:?}", failed);
+        drop(db);
+
+        // An open that verifies refuses a damaged database
+        storage.set_len(Path::new(&table(0)), 30).unwrap();
+        let err = open(&storage, true).err().unwrap();
+        let Some(EngineError::VerificationFailed { violations }) = EngineError::from_io(&err)
+        else {
+            panic!("{}", err)
+        };
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("default tables: sstable_000000.sst: "), "{:?}", err);
+    }
+}
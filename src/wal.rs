@@ -1,95 +1,1463 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::batch::WriteBatch;
+use crate::compression;
+use crate::entry::{now_millis, Op};
+use crate::error::EngineError;
+use crate::options::{Compression, Options, SyncMode, SyncPolicy};
+use crate::quota::DiskUsage;
+use crate::stats::WalCounters;
+use crate::storage::{FileStorage, Storage, StorageFile};
+use crate::value_log::ValuePointer;
+use log::{debug, warn};
 
+/// Name of the WAL inside a database directory
+pub const WAL_FILE: &str = "data.log";
+
+/// Start of every binary log. The original text log has no header.
+const MAGIC: [u8; 4] = *b"WAL1";
+
+/// Start of a preallocated log, followed by its generation (u32). Its frames are
+/// checksummed together with the generation, so records left in a recycled file from
+/// its previous use never pass as current ones, and a zeroed frame header marks the
+/// end of the records.
+const MAGIC_PREALLOCATED: [u8; 4] = *b"WAL2";
+
+const PREALLOCATED_HEADER_LEN: u64 = 8;
+
+/// Appended to the path of a preallocated log once it is retired, until it is reused
+/// as the next log
+pub const RECYCLE_SUFFIX: &str = ".recycle";
+
+const RECORD_PUT: u8 = 0;
+const RECORD_DELETE: u8 = 1;
+const RECORD_MERGE: u8 = 2;
+/// A put followed by its expiry time (u64 Unix milliseconds)
+const RECORD_PUT_EXPIRING: u8 = 3;
+const RECORD_DELETE_RANGE: u8 = 4;
+/// A count followed by that many records, applied all-or-nothing
+const RECORD_BATCH: u8 = 5;
+/// A put whose value field is an encoded [`ValuePointer`]
+const RECORD_BLOB: u8 = 6;
+/// A blob put followed by its expiry time
+const RECORD_BLOB_EXPIRING: u8 = 7;
+/// The write time (u64 Unix milliseconds) followed by the record or batch it applies
+/// to. Every record is written this way; older logs have bare records.
+const RECORD_TIMESTAMPED: u8 = 8;
+/// The sequence number (u64) of the first record in the frame, followed by the
+/// timestamped record or batch. Stamped on the first record after
+/// [`WriteAheadLog::set_sequence`] so replay can number the records from there on.
+const RECORD_SEQUENCED: u8 = 9;
+/// The id (u32) of the key the rest of the payload is sealed with, followed by the
+/// nonce, the sequenced or timestamped record or batch encrypted, and its
+/// authentication tag. Every record is written this way while a key is configured.
+const RECORD_ENCRYPTED: u8 = 10;
+/// Set in the type of a put or merge whose value or operand field holds it compressed,
+/// as laid out in [`crate::compression`]
+const VALUE_COMPRESSED: u8 = 0x80;
+
+/// A logged mutation, as produced by replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalRecord {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Merge {
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    /// A put whose value lives in the value log
+    PutBlob {
+        key: Vec<u8>,
+        pointer: ValuePointer,
+        expires_at: Option<u64>,
+    },
+    /// Deletes every key in `[start, end)`
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+}
+
+impl WalRecord {
+    /// The key written, or `None` for a range deletion
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            WalRecord::Put { key, .. }
+            | WalRecord::Delete { key }
+            | WalRecord::Merge { key, .. }
+            | WalRecord::PutBlob { key, .. } => Some(key),
+            WalRecord::DeleteRange { .. } => None,
+        }
+    }
+}
+
+/// Append-only binary log.
+///
+/// The file starts with a magic header, followed by records framed as
+/// `[crc32][len][payload]` where the payload is a record type and its length-prefixed
+/// fields. Keys and values are raw bytes.
+///
+/// With [`Options::encryption`](crate::Options) set (under the `encryption` feature),
+/// each payload is sealed with XChaCha20-Poly1305 before it is framed, authenticated
+/// together with the key id and the frame's offset. A frame that passes its checksum
+/// but fails authentication at the end of the log, after earlier frames authenticated,
+/// is discarded like a torn one; anywhere else it fails replay with
+/// [`EngineError::DecryptionFailed`], as the key is most likely wrong.
 pub struct WriteAheadLog {
-    file: File,
+    storage: Arc<dyn Storage>,
+    file: Box<dyn StorageFile>,
     path: String,
+    /// Set for preallocated logs
+    generation: Option<u32>,
+    /// End of the last record
+    end: u64,
+    /// Size of the file, which is past `end` while preallocated space is left
+    allocated: u64,
+    /// Why appends are refused, once they are
+    closed: Option<&'static str>,
+    sync_policy: SyncPolicy,
+    sync_mode: SyncMode,
+    /// Appends written since the last sync
+    unsynced: u32,
+    last_sync: Instant,
+    /// Write time stamped on the latest record
+    written_at: u64,
+    /// Sequence number to stamp on the next record
+    sequence: Option<u64>,
+    /// Told about every append
+    disk_usage: Option<Arc<DiskUsage>>,
+    /// Told how many bytes each append writes
+    counters: Option<Arc<WalCounters>>,
+    /// How values are compressed, and the shortest compressed
+    compression: Option<(Compression, usize)>,
+    keys: WalKeys,
 }
 
 impl WriteAheadLog {
+    /// Open the log at `path`, syncing after every append
     pub fn new(path: &str) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?;
-        
+        Self::with_sync_policy(path, SyncPolicy::Always)
+    }
+
+    pub fn with_sync_policy(path: &str, sync_policy: SyncPolicy) -> io::Result<Self> {
+        Self::with_storage(Arc::new(FileStorage), path, sync_policy)
+    }
+
+    /// Open the log at `path` in `storage`
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        path: &str,
+        sync_policy: SyncPolicy,
+    ) -> io::Result<Self> {
+        Self::open_log(storage, path, sync_policy, None, WalKeys::default())
+    }
+
+    /// Open the log at `path` in `storage`, creating it preallocated to `size` bytes
+    /// if it is missing. The log retired to [`recycle_path`] is reused when there is
+    /// one, and otherwise a new file is made. Appends overwrite the preallocated space,
+    /// so the file only grows once it is full.
+    pub fn preallocated(
+        storage: Arc<dyn Storage>,
+        path: &str,
+        sync_policy: SyncPolicy,
+        size: u64,
+    ) -> io::Result<Self> {
+        Self::open_log(storage, path, sync_policy, Some(size), WalKeys::default())
+    }
+
+    /// Open the log at `path` in `storage`, preallocated to `preallocate` bytes if set,
+    /// with its records encrypted under `keys`
+    pub(crate) fn open_log(
+        storage: Arc<dyn Storage>,
+        path: &str,
+        sync_policy: SyncPolicy,
+        preallocate: Option<u64>,
+        keys: WalKeys,
+    ) -> io::Result<Self> {
+        let recycle = recycle_path(path);
+        if storage.exists(Path::new(&recycle)) {
+            match preallocate {
+                Some(size) if !storage.exists(Path::new(path)) => {
+                    reuse_log(&*storage, &recycle, path, size)?
+                }
+                _ => storage.remove(Path::new(&recycle))?,
+            }
+        }
+
+        let mut header = Vec::new();
+        if storage.exists(Path::new(path)) {
+            let mut reader = storage.open(Path::new(path))?.take(PREALLOCATED_HEADER_LEN);
+            reader.read_to_end(&mut header)?;
+        }
+        let (file, generation, end) = if header.starts_with(&MAGIC) {
+            let file = storage.open_append(Path::new(path))?;
+            (file, None, storage.file_len(Path::new(path))?)
+        } else if header.starts_with(&MAGIC_PREALLOCATED) && header.len() == 8 {
+            let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            // Appends go after the last record that checks out
+            let mut frames = WalIterator::with_keys(&*storage, path, keys.clone())?;
+            for _ in frames.by_ref() {}
+            let mut file = storage.open_write(Path::new(path))?;
+            file.seek(SeekFrom::Start(frames.offset()))?;
+            (file, Some(generation), frames.offset())
+        } else if !is_text_log(&header) {
+            // New file, or a crash while the header was being written
+            create_log(&*storage, path, preallocate)?
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is a legacy text WAL; convert it with `storage-engine migrate-wal` \
+                     or Db::migrate_wal",
+                    path
+                ),
+            ));
+        };
+        let allocated = storage.file_len(Path::new(path))?;
+
         Ok(WriteAheadLog {
+            storage,
             file,
             path: path.to_string(),
+            generation,
+            end,
+            allocated,
+            closed: None,
+            sync_policy,
+            sync_mode: SyncMode::default(),
+            unsynced: 0,
+            last_sync: Instant::now(),
+            written_at: 0,
+            sequence: None,
+            disk_usage: None,
+            counters: None,
+            compression: None,
+            keys,
         })
     }
 
-    pub fn log_put(&mut self, key: &str, value: &str) -> io::Result<()> {
-        let entry = format!("PUT,{},{}\n", key, value);
-        self.file.write_all(entry.as_bytes())?;
-        self.file.sync_all()?;
+    /// Use `sync_mode` for every sync from now on
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    /// Count every append from now on in `disk_usage`
+    pub(crate) fn track_usage(&mut self, disk_usage: Arc<DiskUsage>) {
+        self.disk_usage = Some(disk_usage);
+    }
+
+    /// Count the bytes of every append from now on in `counters`
+    pub(crate) fn count_writes(&mut self, counters: Arc<WalCounters>) {
+        self.counters = Some(counters);
+    }
+
+    /// Compress put values and merge operands of at least `threshold` bytes with
+    /// `compression` from now on, where that shrinks them. Fails if the algorithm is
+    /// not compiled in.
+    pub fn set_compression(
+        &mut self,
+        compression: Compression,
+        threshold: usize,
+    ) -> io::Result<()> {
+        compression.ensure_available()?;
+        self.compression = Some((compression, threshold));
         Ok(())
     }
 
-    pub fn log_delete(&mut self, key: &str) -> io::Result<()> {
-        let entry = format!("DELETE,{}\n", key);
-        self.file.write_all(entry.as_bytes())?;
-        self.file.sync_all()?;
+    /// Refuse all further appends, e.g. once the log's files have been deleted
+    pub(crate) fn close(&mut self, reason: &'static str) {
+        self.closed = Some(reason);
+    }
+
+    pub fn log_put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_PUT);
+        let kind = payload.len() - 1;
+        push_field(&mut payload, key);
+        self.push_value(&mut payload, kind, value);
+        self.append(&payload)
+    }
+
+    /// Log a put that stops being visible at `expires_at` (Unix milliseconds)
+    pub fn log_put_with_expiry(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_PUT_EXPIRING);
+        let kind = payload.len() - 1;
+        push_field(&mut payload, key);
+        self.push_value(&mut payload, kind, value);
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+        self.append(&payload)
+    }
+
+    /// Log a put whose value was already written to the value log
+    pub fn log_blob(
+        &mut self,
+        key: &[u8],
+        pointer: &ValuePointer,
+        expires_at: Option<u64>,
+    ) -> io::Result<()> {
+        let kind = if expires_at.is_some() { RECORD_BLOB_EXPIRING } else { RECORD_BLOB };
+        let mut payload = self.start_record(kind);
+        push_field(&mut payload, key);
+        push_field(&mut payload, &pointer.encode());
+        if let Some(expires_at) = expires_at {
+            payload.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        self.append(&payload)
+    }
+
+    pub fn log_delete(&mut self, key: &[u8]) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_DELETE);
+        push_field(&mut payload, key);
+        self.append(&payload)
+    }
+
+    pub fn log_merge(&mut self, key: &[u8], operand: &[u8]) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_MERGE);
+        let kind = payload.len() - 1;
+        push_field(&mut payload, key);
+        self.push_value(&mut payload, kind, operand);
+        self.append(&payload)
+    }
+
+    /// Log the deletion of every key in `[start, end)` as a single record
+    pub fn log_delete_range(&mut self, start: &[u8], end: &[u8]) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_DELETE_RANGE);
+        push_field(&mut payload, start);
+        push_field(&mut payload, end);
+        self.append(&payload)
+    }
+
+    /// Log a batch of operations that must be replayed all-or-nothing.
+    ///
+    /// The whole batch is one record with one checksum, so replay either sees all of
+    /// it or, if the write was torn, none of it.
+    pub fn log_batch(&mut self, batch: &WriteBatch) -> io::Result<()> {
+        let mut payload = self.start_record(RECORD_BATCH);
+        payload.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+        for (key, op) in batch.ops() {
+            let kind = payload.len();
+            match op {
+                Op::Put(value) => {
+                    payload.push(RECORD_PUT);
+                    push_field(&mut payload, key);
+                    self.push_value(&mut payload, kind, value);
+                }
+                Op::Delete => {
+                    payload.push(RECORD_DELETE);
+                    push_field(&mut payload, key);
+                }
+                Op::Merge(operand) => {
+                    payload.push(RECORD_MERGE);
+                    push_field(&mut payload, key);
+                    self.push_value(&mut payload, kind, operand);
+                }
+                Op::Blob(pointer) => {
+                    payload.push(RECORD_BLOB);
+                    push_field(&mut payload, key);
+                    push_field(&mut payload, &pointer.encode());
+                }
+            }
+        }
+        self.append(&payload)
+    }
+
+    /// Unix time in milliseconds stamped on the most recent record
+    pub fn last_write_time(&self) -> u64 {
+        self.written_at
+    }
+
+    /// Record that the next record logged takes sequence number `seq`, with each record
+    /// after it taking the next. Only the first record carries the number.
+    pub fn set_sequence(&mut self, seq: u64) {
+        self.sequence = Some(seq);
+    }
+
+    /// A payload of type `kind`, behind the current time and any pending sequence number
+    fn start_record(&mut self, kind: u8) -> Vec<u8> {
+        self.written_at = now_millis();
+        let mut payload = Vec::new();
+        if let Some(seq) = self.sequence.take() {
+            payload.push(RECORD_SEQUENCED);
+            payload.extend_from_slice(&seq.to_le_bytes());
+        }
+        payload.push(RECORD_TIMESTAMPED);
+        payload.extend_from_slice(&self.written_at.to_le_bytes());
+        payload.push(kind);
+        payload
+    }
+
+    /// Add `value` to `payload`, compressed if it is long enough and compresses, in
+    /// which case the record type at index `kind` is marked
+    fn push_value(&self, payload: &mut Vec<u8>, kind: usize, value: &[u8]) {
+        let compressed = self
+            .compression
+            .filter(|&(_, threshold)| value.len() >= threshold)
+            .and_then(|(compression, _)| compression::compress(compression, value));
+        let Some(compressed) = compressed else {
+            push_field(payload, value);
+            return;
+        };
+        payload[kind] |= VALUE_COMPRESSED;
+        if let Some(counters) = &self.counters {
+            let saved = (value.len() - compressed.len()) as u64;
+            counters.bytes_saved.fetch_add(saved, Ordering::Relaxed);
+        }
+        push_field(payload, &compressed);
+    }
+
+    /// Fail if the log has been closed
+    pub(crate) fn ensure_open(&self) -> io::Result<()> {
+        if let Some(reason) = self.closed {
+            return Err(io::Error::other(format!("cannot write {}: {}", self.path, reason)));
+        }
         Ok(())
     }
 
-    pub fn replay<F>(&self, mut callback: F) -> io::Result<()>
+    /// Frame `payload` with its checksum and length, then write it and sync as the
+    /// policy requires
+    fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.ensure_open()?;
+        let sealed = self.keys.seal(payload, self.end)?;
+        let payload = sealed.as_deref().unwrap_or(payload);
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.extend_from_slice(&checksum(self.generation, payload).to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(payload);
+        self.file.write_all(&record)?;
+        self.end += record.len() as u64;
+        if let Some(counters) = &self.counters {
+            counters.bytes_written.fetch_add(record.len() as u64, Ordering::Relaxed);
+        }
+        if self.end > self.allocated {
+            if let Some(disk_usage) = &self.disk_usage {
+                disk_usage.grow(self.end - self.allocated);
+            }
+            self.allocated = self.end;
+        }
+
+        self.unsynced += 1;
+        let due = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sync every append made so far; nothing is written when they already are
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.flush()?;
+            self.sync_mode.sync(&mut *self.file)?;
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Pass every logged record to `callback` in order, with the time it was written
+    /// (`None` for records logged before write times were kept).
+    ///
+    /// A record that is incomplete or fails its checksum (a write torn by a crash) ends
+    /// the log: it and anything after it are discarded, and the file is truncated there
+    /// so that new records follow the last good one. A preallocated log is zeroed from
+    /// there instead, keeping its size.
+    pub fn replay<F>(&self, callback: F) -> io::Result<()>
     where
-        F: FnMut(&str, Option<&str>),
+        F: FnMut(WalRecord, Option<u64>),
     {
-        let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
+        self.replay_after(0, callback)
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let parts: Vec<&str> = line.split(',').collect();
+    /// Like [`WriteAheadLog::replay`], but skip the records numbered `covered` or lower,
+    /// which a table already holds. Records logged before any sequence number was set
+    /// are never skipped.
+    pub fn replay_after<F>(&self, covered: u64, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(WalRecord, Option<u64>),
+    {
+        self.replay_numbered(covered, |_, record, written_at| callback(record, written_at))
+    }
 
-            match parts[0] {
-                "PUT" if parts.len() == 3 => {
-                    callback(parts[1], Some(parts[2]));
-                }
-                "DELETE" if parts.len() == 2 => {
-                    callback(parts[1], None);
+    /// Like [`WriteAheadLog::replay_after`], also passing each record the sequence
+    /// number the log gives it, if it gives one
+    pub(crate) fn replay_numbered<F>(&self, covered: u64, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(Option<u64>, WalRecord, Option<u64>),
+    {
+        let mut frames = WalIterator::with_keys(&*self.storage, &self.path, self.keys.clone())?;
+        let mut torn = false;
+        let mut next_seq = None;
+        let mut skipped = 0;
+        for frame in frames.by_ref() {
+            match frame {
+                Ok(frame) => {
+                    next_seq = frame.sequence.or(next_seq);
+                    for record in frame.records {
+                        let seq = next_seq;
+                        next_seq = next_seq.map(|seq| seq + 1);
+                        if seq.is_some_and(|seq| seq <= covered) {
+                            skipped += 1;
+                            continue;
+                        }
+                        callback(seq, record, frame.written_at);
+                    }
                 }
-                _ => {                 
+                Err(e) if is_torn(&e) => {
+                    torn = true;
+                    break;
                 }
+                Err(e) => return Err(e),
             }
         }
+        if skipped > 0 {
+            warn!(
+                "skipped {} records of {} already written to a table (through sequence {})",
+                skipped, self.path, covered
+            );
+        }
+        if !torn {
+            return Ok(());
+        }
 
+        let discarded = frames.file_len - frames.offset();
+        let path = Path::new(&self.path);
+        if self.generation.is_some() {
+            // Also what a recycled log holds past its records
+            debug!("clearing {} bytes after the last record of {}", discarded, self.path);
+            self.storage.set_len(path, frames.offset())?;
+            self.storage.set_len(path, frames.file_len)?;
+        } else {
+            warn!(
+                "discarding {} bytes of torn or corrupt records at the end of {}",
+                discarded, self.path
+            );
+            self.storage.set_len(path, frames.offset())?;
+        }
         Ok(())
     }
 }
 
+impl Drop for WriteAheadLog {
+    /// Closing the log syncs whatever the policy left unsynced
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            warn!("could not sync {} on close: {}", self.path, err);
+        }
+    }
+}
+
+/// One framed record of a log, as read by [`WalIterator`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalFrame {
+    /// Byte offset of the frame in the file
+    pub offset: u64,
+    /// Length of the payload, excluding the 8-byte checksum and length prefix
+    pub len: u32,
+    /// Whether the frame is a batch, applied all-or-nothing
+    pub batch: bool,
+    /// Unix time in milliseconds the frame was written, `None` in older logs
+    pub written_at: Option<u64>,
+    /// Sequence number of the frame's first record, if the frame carries one
+    pub sequence: Option<u64>,
+    pub records: Vec<WalRecord>,
+}
+
+/// Reads the frames of a log file in order without modifying it, so it works on the
+/// live log of a closed database as well as on a copied or archived one.
+///
+/// Iteration stops at the end of the file, at the unused space of a preallocated log,
+/// or at the first frame that is incomplete, fails its checksum, or does not decode.
+/// That frame is returned as an `InvalidData` error naming its offset; replay discards
+/// it and everything after it. Encrypted frames that cannot be decrypted end iteration
+/// with an [`EngineError`] instead.
+pub struct WalIterator {
+    reader: BufReader<Box<dyn StorageFile>>,
+    path: String,
+    /// Set for preallocated logs
+    generation: Option<u32>,
+    offset: u64,
+    file_len: u64,
+    done: bool,
+    keys: WalKeys,
+    /// Whether an encrypted frame has been decrypted
+    authenticated: bool,
+}
+
+impl WalIterator {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::with_storage(&FileStorage, path)
+    }
+
+    /// Read the log at `path` in `storage`
+    pub fn with_storage(storage: &dyn Storage, path: &str) -> io::Result<Self> {
+        Self::with_keys(storage, path, WalKeys::default())
+    }
+
+    /// Read the log at `path` in `storage`, decrypting its records with `keys`
+    pub(crate) fn with_keys(storage: &dyn Storage, path: &str, keys: WalKeys) -> io::Result<Self> {
+        let file_len = storage.file_len(Path::new(path))?;
+        let mut reader = BufReader::new(storage.open(Path::new(path))?);
+
+        let not_wal = || {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a binary WAL", path))
+        };
+        let mut header = [0u8; 4];
+        if reader.read_exact(&mut header).is_err() || ![MAGIC, MAGIC_PREALLOCATED].contains(&header)
+        {
+            return Err(not_wal());
+        }
+        let generation = if header == MAGIC_PREALLOCATED {
+            let mut generation = [0u8; 4];
+            reader.read_exact(&mut generation).map_err(|_| not_wal())?;
+            Some(u32::from_le_bytes(generation))
+        } else {
+            None
+        };
+        let offset = if generation.is_some() { PREALLOCATED_HEADER_LEN } else { 4 };
+
+        Ok(WalIterator {
+            reader,
+            path: path.to_string(),
+            generation,
+            offset,
+            file_len,
+            done: false,
+            keys,
+            authenticated: false,
+        })
+    }
+
+    /// End of the last frame read successfully
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next frame, or `None` at the unused space of a preallocated log
+    fn read_frame(&mut self) -> io::Result<Option<WalFrame>> {
+        let remaining = self.file_len - self.offset;
+        if remaining < 8 {
+            return Err(self.corrupt(format!("incomplete frame header ({} of 8 bytes)", remaining)));
+        }
+        let mut frame = [0u8; 8];
+        self.reader.read_exact(&mut frame)?;
+        if self.generation.is_some() && frame == [0; 8] {
+            return Ok(None);
+        }
+        let crc = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let len = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        if 8 + len as u64 > remaining {
+            let problem = format!("incomplete record ({} of {} bytes)", remaining - 8, len);
+            return Err(self.corrupt(problem));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        if checksum(self.generation, &payload) != crc {
+            return Err(self.corrupt("checksum mismatch".to_string()));
+        }
+        let end = self.offset + 8 + len as u64;
+        let payload = match payload.split_first() {
+            Some((&RECORD_ENCRYPTED, sealed)) => match self.keys.open(sealed, self.offset)? {
+                Some(payload) => {
+                    self.authenticated = true;
+                    payload
+                }
+                None => return Err(self.rejected(sealed, end)),
+            },
+            _ => payload,
+        };
+        let split = split_sequence(&payload).and_then(|(sequence, rest)| {
+            let (written_at, body) = split_write_time(rest)?;
+            Some((sequence, written_at, body))
+        });
+        let Some((sequence, written_at, body)) = split else {
+            return Err(self.corrupt("malformed record".to_string()));
+        };
+        let Some(records) = decode(body)? else {
+            return Err(self.corrupt("malformed record".to_string()));
+        };
+
+        Ok(Some(WalFrame {
+            offset: self.offset,
+            len,
+            batch: body[0] == RECORD_BATCH,
+            written_at,
+            sequence,
+            records,
+        }))
+    }
+
+    /// The error for the encrypted frame ending at `end` that failed authentication:
+    /// the end of the log if it is the last frame and an earlier one decrypted, so the
+    /// key is right, and otherwise [`EngineError::DecryptionFailed`]
+    fn rejected(&mut self, sealed: &[u8], end: u64) -> io::Error {
+        if self.authenticated && !self.frame_at(end) {
+            return self.corrupt("record fails authentication".to_string());
+        }
+        let key_id = sealed.first_chunk().map_or(0, |id| u32::from_le_bytes(*id));
+        EngineError::DecryptionFailed { path: self.path.clone(), key_id }.into()
+    }
+
+    /// Whether a frame that passes its checksum starts at `offset`, where the reader is
+    fn frame_at(&mut self, offset: u64) -> bool {
+        let mut frame = [0u8; 8];
+        if self.file_len - offset < 8 || self.reader.read_exact(&mut frame).is_err() {
+            return false;
+        }
+        let crc = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let len = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        let mut payload = vec![0u8; len as usize];
+        frame != [0; 8]
+            && 8 + len as u64 <= self.file_len - offset
+            && self.reader.read_exact(&mut payload).is_ok()
+            && checksum(self.generation, &payload) == crc
+    }
+
+    fn corrupt(&self, problem: String) -> io::Error {
+        let message = format!("{} at offset {}", problem, self.offset);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+impl Iterator for WalIterator {
+    type Item = io::Result<WalFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset == self.file_len {
+            return None;
+        }
+        let frame = self.read_frame();
+        match &frame {
+            Ok(Some(frame)) => self.offset += 8 + frame.len as u64,
+            Ok(None) | Err(_) => self.done = true,
+        }
+        frame.transpose()
+    }
+}
+
+/// Whether a log starting with `header` is in the original text format: it has some
+/// content, and that is not the start of a binary log's magic
+pub(crate) fn is_text_log(header: &[u8]) -> bool {
+    let prefix = &header[..header.len().min(MAGIC.len())];
+    !MAGIC.starts_with(prefix) && !MAGIC_PREALLOCATED.starts_with(prefix)
+}
+
+/// Whether `err`, from reading a log, marks a torn or corrupt frame that ends the log,
+/// rather than a failure to read it
+pub(crate) fn is_torn(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::InvalidData && EngineError::from_io(err).is_none()
+}
+
+/// Keys WAL records are encrypted with, from
+/// [`Options::encryption`](crate::Options) under the `encryption` feature. Without a
+/// key, records are written plain.
+#[derive(Clone, Default)]
+pub(crate) struct WalKeys {
+    #[cfg(feature = "encryption")]
+    keys: Option<Arc<dyn crate::KeyProvider>>,
+}
+
+impl WalKeys {
+    pub(crate) fn from_options(options: &Options) -> Self {
+        #[cfg(not(feature = "encryption"))]
+        let _ = options;
+        WalKeys {
+            #[cfg(feature = "encryption")]
+            keys: options.encryption.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl WalKeys {
+    /// `payload` sealed for the frame at `offset`, or `None` to write it as it is
+    fn seal(&self, payload: &[u8], offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let Some(keys) = &self.keys else { return Ok(None) };
+        let (key_id, key) = keys.current();
+        let aad = record_aad(key_id, offset);
+        let mut sealed = vec![RECORD_ENCRYPTED];
+        sealed.extend_from_slice(&key_id.to_le_bytes());
+        sealed.extend(crate::encryption::seal(&crate::encryption::cipher(&key), &aad, payload)?);
+        Ok(Some(sealed))
+    }
+
+    /// The payload sealed in the frame at `offset`, given what follows its type, or
+    /// `None` if it fails authentication
+    fn open(&self, sealed: &[u8], offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let Some((key_id, sealed)) = sealed.split_first_chunk() else { return Ok(None) };
+        let key_id = u32::from_le_bytes(*key_id);
+        let key = self.keys.as_ref().and_then(|keys| keys.key(key_id));
+        let key = key.ok_or(EngineError::UnknownEncryptionKey { key_id })?;
+        let cipher = crate::encryption::cipher(&key);
+        Ok(crate::encryption::open(&cipher, &record_aad(key_id, offset), sealed))
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl WalKeys {
+    fn seal(&self, _payload: &[u8], _offset: u64) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn open(&self, _sealed: &[u8], _offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let message = "WAL is encrypted; build with the `encryption` feature to read it";
+        Err(io::Error::new(io::ErrorKind::Unsupported, message))
+    }
+}
+
+/// Associated data of an encrypted frame, so it cannot be moved within the log
+#[cfg(feature = "encryption")]
+fn record_aad(key_id: u32, offset: u64) -> [u8; 12] {
+    let mut aad = [0u8; 12];
+    aad[..4].copy_from_slice(&key_id.to_le_bytes());
+    aad[4..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+/// Where the preallocated log at `path` is kept between being retired and reused
+pub fn recycle_path(path: &str) -> String {
+    format!("{}{}", path, RECYCLE_SUFFIX)
+}
+
+/// Create an empty log at `path`, preallocated to `preallocate` bytes if set. Returns
+/// the file positioned for appending, its generation, and the end of its header.
+fn create_log(
+    storage: &dyn Storage,
+    path: &str,
+    preallocate: Option<u64>,
+) -> io::Result<(Box<dyn StorageFile>, Option<u32>, u64)> {
+    let mut file = storage.create(Path::new(path))?;
+    let (generation, end) = match preallocate {
+        Some(size) => {
+            file.write_all(&MAGIC_PREALLOCATED)?;
+            file.write_all(&0u32.to_le_bytes())?;
+            storage.set_len(Path::new(path), size.max(PREALLOCATED_HEADER_LEN))?;
+            (Some(0), PREALLOCATED_HEADER_LEN)
+        }
+        None => {
+            file.write_all(&MAGIC)?;
+            (None, MAGIC.len() as u64)
+        }
+    };
+    file.sync()?;
+    let path = Path::new(path);
+    storage.sync_dir(path.parent().unwrap_or(Path::new("")))?;
+    Ok((file, generation, end))
+}
+
+/// Make the retired log at `recycle` the log at `path`, at least `size` bytes long. The
+/// next generation is made durable in its header before the rename, so its old records
+/// are never replayed.
+fn reuse_log(storage: &dyn Storage, recycle: &str, path: &str, size: u64) -> io::Result<()> {
+    let recycle = Path::new(recycle);
+    let mut header = Vec::new();
+    storage.open(recycle)?.take(PREALLOCATED_HEADER_LEN).read_to_end(&mut header)?;
+    let generation = match header.strip_prefix(&MAGIC_PREALLOCATED) {
+        Some(&[a, b, c, d]) => u32::from_le_bytes([a, b, c, d]).wrapping_add(1),
+        _ => 1,
+    };
+
+    let mut file = storage.open_write(recycle)?;
+    file.write_all(&MAGIC_PREALLOCATED)?;
+    file.write_all(&generation.to_le_bytes())?;
+    if storage.file_len(recycle)? < size {
+        storage.set_len(recycle, size)?;
+    }
+    file.sync()?;
+    storage.rename(recycle, Path::new(path))?;
+    let path = Path::new(path);
+    storage.sync_dir(path.parent().unwrap_or(Path::new("")))
+}
+
+/// Frame checksum: over the payload, preceded by the generation in preallocated logs
+fn checksum(generation: Option<u32>, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    if let Some(generation) = generation {
+        hasher.update(&generation.to_le_bytes());
+    }
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn push_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    buf.extend_from_slice(field);
+}
+
+/// Separate the sequence number from a payload that carries one. `None` if malformed.
+fn split_sequence(payload: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    match payload.split_first()? {
+        (&RECORD_SEQUENCED, rest) if rest.len() >= 8 => {
+            let (seq, rest) = rest.split_at(8);
+            Some((Some(u64::from_le_bytes(seq.try_into().ok()?)), rest))
+        }
+        (&RECORD_SEQUENCED, _) => None,
+        _ => Some((None, payload)),
+    }
+}
+
+/// Separate the write time from a payload that carries one. `None` if malformed.
+fn split_write_time(payload: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    match payload.split_first()? {
+        (&RECORD_TIMESTAMPED, rest) if rest.len() >= 8 => {
+            let (time, body) = rest.split_at(8);
+            Some((Some(u64::from_le_bytes(time.try_into().ok()?)), body))
+        }
+        (&RECORD_TIMESTAMPED, _) => None,
+        _ => Some((None, payload)),
+    }
+}
+
+/// Decode a record payload; a batch yields each of its records. `None` if malformed,
+/// and an error if it holds values compressed by an algorithm not compiled in.
+fn decode(payload: &[u8]) -> io::Result<Option<Vec<WalRecord>>> {
+    let mut decoder = Decoder { buf: payload, unsupported: None };
+    let records = decoder.records();
+    match decoder.unsupported {
+        Some(err) => Err(err),
+        None => Ok(records.filter(|_| decoder.buf.is_empty())),
+    }
+}
+
+/// Reads fields off the front of a record payload
+struct Decoder<'a> {
+    buf: &'a [u8],
+    /// Why a compressed value could not be read
+    unsupported: Option<io::Error>,
+}
+
+impl Decoder<'_> {
+    fn records(&mut self) -> Option<Vec<WalRecord>> {
+        match self.u8()? {
+            RECORD_BATCH => {
+                let count = self.u32()?;
+                let mut records = Vec::new();
+                for _ in 0..count {
+                    let kind = self.u8()?;
+                    records.push(self.record(kind)?);
+                }
+                Some(records)
+            }
+            kind => Some(vec![self.record(kind)?]),
+        }
+    }
+
+    fn record(&mut self, kind: u8) -> Option<WalRecord> {
+        if kind & VALUE_COMPRESSED != 0 {
+            return self.compressed_record(kind & !VALUE_COMPRESSED);
+        }
+        let record = match kind {
+            RECORD_PUT => WalRecord::Put {
+                key: self.field()?,
+                value: self.field()?,
+                expires_at: None,
+            },
+            RECORD_PUT_EXPIRING => WalRecord::Put {
+                key: self.field()?,
+                value: self.field()?,
+                expires_at: Some(self.u64()?),
+            },
+            RECORD_BLOB => WalRecord::PutBlob {
+                key: self.field()?,
+                pointer: ValuePointer::decode(&self.field()?)?,
+                expires_at: None,
+            },
+            RECORD_BLOB_EXPIRING => WalRecord::PutBlob {
+                key: self.field()?,
+                pointer: ValuePointer::decode(&self.field()?)?,
+                expires_at: Some(self.u64()?),
+            },
+            RECORD_DELETE => WalRecord::Delete { key: self.field()? },
+            RECORD_MERGE => WalRecord::Merge {
+                key: self.field()?,
+                operand: self.field()?,
+            },
+            RECORD_DELETE_RANGE => WalRecord::DeleteRange {
+                start: self.field()?,
+                end: self.field()?,
+            },
+            _ => return None,
+        };
+        Some(record)
+    }
+
+    /// A put or merge of type `kind` whose value is compressed
+    fn compressed_record(&mut self, kind: u8) -> Option<WalRecord> {
+        let record = match kind {
+            RECORD_PUT => WalRecord::Put {
+                key: self.field()?,
+                value: self.compressed_field()?,
+                expires_at: None,
+            },
+            RECORD_PUT_EXPIRING => WalRecord::Put {
+                key: self.field()?,
+                value: self.compressed_field()?,
+                expires_at: Some(self.u64()?),
+            },
+            RECORD_MERGE => WalRecord::Merge {
+                key: self.field()?,
+                operand: self.compressed_field()?,
+            },
+            _ => return None,
+        };
+        Some(record)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn field(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Some(self.take(len)?.to_vec())
+    }
+
+    fn compressed_field(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        match compression::decompress(self.take(len)?) {
+            Ok(value) => value,
+            Err(err) => {
+                self.unsupported = Some(err);
+                None
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::storage::MemStorage;
+
+    fn open(storage: &MemStorage, path: &str) -> WriteAheadLog {
+        WriteAheadLog::with_storage(Arc::new(storage.clone()), path, SyncPolicy::Always).unwrap()
+    }
+
+    fn put(key: &[u8], value: &[u8], expires_at: Option<u64>) -> WalRecord {
+        WalRecord::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at,
+        }
+    }
 
     #[test]
     fn test_wal_log_and_replay() {
         let wal_path = "test_wal.log";
-        
-        let _ = fs::remove_file(wal_path);
+        let storage = MemStorage::new();
+        let started = now_millis();
 
         {
-            let mut wal = WriteAheadLog::new(wal_path).unwrap();
-            wal.log_put("key1", "value1").unwrap();
-            wal.log_put("key2", "value2").unwrap();
-            wal.log_delete("key1").unwrap();
+            let mut wal = open(&storage, wal_path);
+            wal.log_put(b"key1", b"value1").unwrap();
+            wal.log_put(b"key2", b"value2").unwrap();
+            wal.log_delete(b"key1").unwrap();
+            wal.log_put_with_expiry(b"key3", b"value3", 1234).unwrap();
+            wal.log_delete_range(b"a", b"m").unwrap();
         }
+        // A record from before write times were logged
+        let mut bare = vec![RECORD_DELETE];
+        push_field(&mut bare, b"key2");
+        let mut file = storage.open_append(Path::new(wal_path)).unwrap();
+        file.write_all(&crc32fast::hash(&bare).to_le_bytes()).unwrap();
+        file.write_all(&(bare.len() as u32).to_le_bytes()).unwrap();
+        file.write_all(&bare).unwrap();
 
-        let wal = WriteAheadLog::new(wal_path).unwrap();
+        let wal = open(&storage, wal_path);
         let mut operations = Vec::new();
+        let mut times = Vec::new();
 
-        wal.replay(|key, value| {
-            operations.push((key.to_string(), value.map(|v| v.to_string())));
-        }).unwrap();
+        wal.replay(|record, written_at| {
+            operations.push(record);
+            times.push(written_at);
+        })
+        .unwrap();
 
-        assert_eq!(operations.len(), 3);
-        assert_eq!(operations[0], ("key1".to_string(), Some("value1".to_string())));
-        assert_eq!(operations[1], ("key2".to_string(), Some("value2".to_string())));
-        assert_eq!(operations[2], ("key1".to_string(), None));
+        assert_eq!(operations.len(), 6);
+        assert!(times[..5].iter().all(|t| t.is_some_and(|t| t >= started && t <= now_millis())));
+        assert_eq!(operations[5], WalRecord::Delete { key: b"key2".to_vec() });
+        assert_eq!(times[5], None);
+        assert_eq!(operations[0], put(b"key1", b"value1", None));
+        assert_eq!(operations[1], put(b"key2", b"value2", None));
+        assert_eq!(operations[2], WalRecord::Delete { key: b"key1".to_vec() });
+        assert_eq!(operations[3], put(b"key3", b"value3", Some(1234)));
+        assert_eq!(
+            operations[4],
+            WalRecord::DeleteRange { start: b"a".to_vec(), end: b"m".to_vec() }
+        );
+    }
 
-        fs::remove_file(wal_path).unwrap();
+    fn replayed(storage: &MemStorage, path: &str) -> Vec<WalRecord> {
+        let mut records = Vec::new();
+        open(storage, path).replay(|record, _| records.push(record)).unwrap();
+        records
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_preallocated_log_recovers_to_last_record() {
+        let path = "test_wal_preallocated.log";
+        let storage = MemStorage::new();
+        let preallocated = || {
+            let shared = Arc::new(storage.clone());
+            WriteAheadLog::preallocated(shared, path, SyncPolicy::Always, 4096).unwrap()
+        };
+
+        let mut wal = preallocated();
+        wal.log_put(b"a", b"1").unwrap();
+        wal.log_put(b"b", b"2").unwrap();
+        drop(wal);
+        assert_eq!(storage.file_len(Path::new(path)).unwrap(), 4096);
+
+        // Appends continue after the last record, whichever way the log is opened
+        let mut wal = preallocated();
+        wal.log_delete(b"a").unwrap();
+        drop(wal);
+        let mut wal = open(&storage, path);
+        wal.log_put(b"c", b"3").unwrap();
+        drop(wal);
+        let expected = vec![
+            put(b"a", b"1", None),
+            put(b"b", b"2", None),
+            WalRecord::Delete { key: b"a".to_vec() },
+            put(b"c", b"3", None),
+        ];
+        assert_eq!(replayed(&storage, path), expected);
+        assert_eq!(storage.file_len(Path::new(path)).unwrap(), 4096);
+
+        // A torn record is cleared without giving up the preallocated space
+        let mut frames = WalIterator::with_storage(&storage, path).unwrap();
+        for _ in frames.by_ref() {}
+        let mut file = storage.open_write(Path::new(path)).unwrap();
+        file.seek(SeekFrom::Start(frames.offset())).unwrap();
+        file.write_all(&[7; 20]).unwrap();
+        drop(file);
+        assert_eq!(replayed(&storage, path), expected);
+        let contents = storage.read(Path::new(path)).unwrap();
+        assert_eq!(contents.len(), 4096);
+        assert!(contents[frames.offset() as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_recycled_log_replays_only_new_records() {
+        let path = "test_wal_recycled.log";
+        let storage = MemStorage::new();
+        let preallocated = || {
+            let shared = Arc::new(storage.clone());
+            WriteAheadLog::preallocated(shared, path, SyncPolicy::Always, 256).unwrap()
+        };
+
+        let mut wal = preallocated();
+        for i in 0..10u8 {
+            wal.log_put(&[b'k', i], b"old value").unwrap();
+        }
+        drop(wal);
+        // Retire the log; its file is larger than asked for, and is reused as it is
+        storage.rename(Path::new(path), Path::new(&recycle_path(path))).unwrap();
+        let len = storage.file_len(Path::new(&recycle_path(path))).unwrap();
+        assert!(len > 256);
+
+        let mut wal = preallocated();
+        assert!(!storage.exists(Path::new(&recycle_path(path))));
+        assert_eq!(storage.file_len(Path::new(path)).unwrap(), len);
+        wal.log_put(b"new", b"1").unwrap();
+        drop(wal);
+        assert_eq!(replayed(&storage, path), vec![put(b"new", b"1", None)]);
+
+        // A second reuse moves on to the next generation
+        storage.rename(Path::new(path), Path::new(&recycle_path(path))).unwrap();
+        drop(preallocated());
+        assert!(replayed(&storage, path).is_empty());
+        assert_eq!(&storage.read(Path::new(path)).unwrap()[..8], b"WAL2\x02\0\0\0");
+
+        // Opening without preallocation drops a retired log instead
+        storage.rename(Path::new(path), Path::new(&recycle_path(path))).unwrap();
+        drop(open(&storage, path));
+        assert!(!storage.exists(Path::new(&recycle_path(path))));
+        assert_eq!(storage.file_len(Path::new(path)).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_batch_replayed_atomically() {
+        let wal_path = "test_wal_batch.log";
+        let storage = MemStorage::new();
+
+        {
+            let mut wal = open(&storage, wal_path);
+            let mut batch = WriteBatch::new();
+            batch.put("key1", "value1").delete("key2");
+            wal.log_batch(&batch).unwrap();
+
+            // A torn batch: the write stops partway through the record
+            let mut torn = WriteBatch::new();
+            torn.put("key3", "value3").delete("key4");
+            wal.log_batch(&torn).unwrap();
+        }
+        let len = storage.file_len(Path::new(wal_path)).unwrap();
+        storage.set_len(Path::new(wal_path), len - 5).unwrap();
+
+        let mut wal = open(&storage, wal_path);
+        let mut operations = Vec::new();
+        wal.replay(|record, _| operations.push(record)).unwrap();
+
+        assert_eq!(
+            operations,
+            vec![
+                put(b"key1", b"value1", None),
+                WalRecord::Delete { key: b"key2".to_vec() },
+            ]
+        );
+
+        // The torn tail was cut off, so later records are not lost behind it
+        wal.log_put(b"key5", b"value5").unwrap();
+        let mut operations = Vec::new();
+        wal.replay(|record, _| operations.push(record)).unwrap();
+        assert_eq!(operations.last(), Some(&put(b"key5", b"value5", None)));
+    }
+
+    #[test]
+    fn test_binary_values_and_checksum() {
+        let wal_path = "test_wal_binary.log";
+        let storage = MemStorage::new();
+
+        let value = vec![0x00, 0xff, 0xfe, b',', b'\n', 0x00];
+        {
+            let mut wal = open(&storage, wal_path);
+            wal.log_put(b"k\0ey", &value).unwrap();
+            wal.log_put(b"key2", b"value2").unwrap();
+        }
+
+        // Flip a byte in the last record's payload
+        let mut bytes = storage.read(Path::new(wal_path)).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        storage.write(Path::new(wal_path), &bytes).unwrap();
+
+        let wal = open(&storage, wal_path);
+        let mut operations = Vec::new();
+        wal.replay(|record, _| operations.push(record)).unwrap();
+        assert_eq!(operations, vec![put(b"k\0ey", &value, None)]);
+    }
+
+    #[test]
+    fn test_text_log_rejected() {
+        let storage = MemStorage::new();
+        let wal_path = "test_wal_text.log";
+        storage.write(Path::new(wal_path), b"PUT,key1,value1\n").unwrap();
+
+        let shared = Arc::new(storage.clone());
+        let err = WriteAheadLog::with_storage(shared, wal_path, SyncPolicy::Always).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(storage.read(Path::new(wal_path)).unwrap(), b"PUT,key1,value1\n");
+    }
+
+    #[test]
+    fn test_relaxed_sync_policy() {
+        let wal_path = "test_wal_sync_policy.log";
+        let storage = MemStorage::new();
+
+        {
+            let storage = Arc::new(storage.clone());
+            let mut wal =
+                WriteAheadLog::with_storage(storage, wal_path, SyncPolicy::EveryN(3)).unwrap();
+            for i in 0..4u8 {
+                wal.log_put(&[i], b"v").unwrap();
+            }
+            assert_eq!(wal.unsynced, 1);
+            wal.sync().unwrap();
+            assert_eq!(wal.unsynced, 0);
+            wal.log_delete(&[0]).unwrap();
+        }
+
+        // Unsynced appends are still in the file for the next open
+        let wal = open(&storage, wal_path);
+        let mut operations = Vec::new();
+        wal.replay(|record, _| operations.push(record)).unwrap();
+        assert_eq!(operations.len(), 5);
+    }
+
+    /// A JSON-like value that compresses well
+    #[cfg(feature = "lz4")]
+    fn document(i: u32) -> Vec<u8> {
+        format!("{{\"id\":{},\"tags\":[{}]}}", i, "\"tag\",".repeat(100)).into_bytes()
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compressed_and_plain_records_recover() {
+        let path = "test_wal_compressed.log";
+        let storage = MemStorage::new();
+        let counters = Arc::new(WalCounters::default());
+
+        let mut wal = open(&storage, path);
+        wal.log_put(b"before", &document(0)).unwrap();
+        let uncounted = storage.file_len(Path::new(path)).unwrap();
+        wal.set_compression(Compression::Lz4, 64).unwrap();
+        wal.count_writes(counters.clone());
+        wal.log_put(b"small", b"short value").unwrap();
+        wal.log_put(b"doc1", &document(1)).unwrap();
+        wal.log_put_with_expiry(b"doc2", &document(2), 1234).unwrap();
+        wal.log_merge(b"doc3", &document(3)).unwrap();
+        // Does not shrink, so it is kept as it is
+        let noise: Vec<u8> =
+            (0..200u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        wal.log_put(b"noise", &noise).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("doc4", document(4)).put("tiny", "1").merge("doc5", document(5));
+        wal.log_batch(&batch).unwrap();
+        drop(wal);
+
+        let merge = |key: &[u8], operand: Vec<u8>| WalRecord::Merge { key: key.to_vec(), operand };
+        let expected = vec![
+            put(b"before", &document(0), None),
+            put(b"small", b"short value", None),
+            put(b"doc1", &document(1), None),
+            put(b"doc2", &document(2), Some(1234)),
+            merge(b"doc3", document(3)),
+            put(b"noise", &noise, None),
+            put(b"doc4", &document(4), None),
+            put(b"tiny", b"1", None),
+            merge(b"doc5", document(5)),
+        ];
+        assert_eq!(replayed(&storage, path), expected);
+
+        let written = counters.bytes_written.load(Ordering::Relaxed);
+        assert_eq!(written, storage.file_len(Path::new(path)).unwrap() - uncounted);
+        let saved = counters.bytes_saved.load(Ordering::Relaxed);
+        assert!(saved > 5 * 500, "{}", saved);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_corrupt_compressed_value_is_a_corrupt_record() {
+        let path = "test_wal_corrupt_compressed.log";
+        let storage = MemStorage::new();
+        let mut wal = open(&storage, path);
+        wal.log_put(b"a", b"1").unwrap();
+        wal.set_compression(Compression::Lz4, 64).unwrap();
+        wal.log_put(b"k", &document(1)).unwrap();
+        drop(wal);
+        let original = storage.read(Path::new(path)).unwrap();
+        let frames: Vec<WalFrame> =
+            WalIterator::with_storage(&storage, path).unwrap().map(Result::unwrap).collect();
+        let at = frames[1].offset as usize;
+        let len = frames[1].len as usize;
+        // [timestamp record][kind]["k" field][value length], then the compressed value
+        let value = at + 8 + 9 + 1 + 5 + 4;
+        assert_eq!(original[at + 8 + 9] & VALUE_COMPRESSED, VALUE_COMPRESSED);
+
+        // A wrong decompressed length, then garbled compressed bytes, each with a
+        // checksum that matches so only decoding can catch them
+        for garble in [false, true] {
+            let mut contents = original.clone();
+            if garble {
+                contents[value + 5..at + 8 + len].fill(0xff);
+            } else {
+                contents[value + 1] ^= 1;
+            }
+            let crc = checksum(None, &contents[at + 8..at + 8 + len]);
+            contents[at..at + 4].copy_from_slice(&crc.to_le_bytes());
+            storage.write(Path::new(path), &contents).unwrap();
+
+            let mut frames = WalIterator::with_storage(&storage, path).unwrap();
+            assert!(frames.next().unwrap().is_ok());
+            let err = frames.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("malformed record"), "{}", err);
+            assert_eq!(replayed(&storage, path), vec![put(b"a", b"1", None)]);
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_record_failing_authentication() {
+        let path = "test_wal_encrypted.log";
+        let storage = MemStorage::new();
+        let keys = |byte| WalKeys {
+            keys: Some(Arc::new(crate::EncryptionKey { id: 1, key: [byte; 32] })),
+        };
+        let open_keyed = |byte| {
+            let shared = Arc::new(storage.clone());
+            WriteAheadLog::open_log(shared, path, SyncPolicy::Always, None, keys(byte)).unwrap()
+        };
+        let replayed_with = |byte| {
+            let mut records = Vec::new();
+            open_keyed(byte).replay(|record, _| records.push(record)).map(|()| records)
+        };
+
+        let mut wal = open_keyed(1);
+        for key in [b"a", b"b", b"c"] {
+            wal.log_put(key, b"secret").unwrap();
+        }
+        drop(wal);
+        let original = storage.read(Path::new(path)).unwrap();
+        assert!(!original.windows(6).any(|window| window == b"secret"));
+        let offsets: Vec<u64> = WalIterator::with_keys(&storage, path, keys(1))
+            .unwrap()
+            .map(|frame| frame.unwrap().offset)
+            .collect();
+        assert_eq!(offsets.len(), 3);
+
+        let rejected = |result: io::Result<Vec<WalRecord>>| {
+            let err = result.unwrap_err();
+            let failed = EngineError::DecryptionFailed { path: path.to_string(), key_id: 1 };
+            assert_eq!(EngineError::from_io(&err), Some(&failed));
+            assert_eq!(storage.read(Path::new(path)).unwrap().len(), original.len());
+        };
+        rejected(replayed_with(2));
+
+        // Flip a ciphertext bit of a frame and fix up its checksum, so only
+        // authentication catches it
+        let tamper = |offset: u64| {
+            let mut contents = original.clone();
+            let at = offset as usize;
+            let len = u32::from_le_bytes(contents[at + 4..at + 8].try_into().unwrap()) as usize;
+            contents[at + 8 + len - 1] ^= 1;
+            let crc = checksum(None, &contents[at + 8..at + 8 + len]);
+            contents[at..at + 4].copy_from_slice(&crc.to_le_bytes());
+            storage.write(Path::new(path), &contents).unwrap();
+        };
+        tamper(offsets[1]);
+        rejected(replayed_with(1));
+
+        // At the end of the log it is discarded like a torn record
+        tamper(offsets[2]);
+        let expected = vec![put(b"a", b"secret", None), put(b"b", b"secret", None)];
+        assert_eq!(replayed_with(1).unwrap(), expected);
+        assert_eq!(storage.file_len(Path::new(path)).unwrap(), offsets[2]);
+    }
+}
@@ -0,0 +1,227 @@
+//! Backpressure on writes when SSTables pile up faster than compaction merges them.
+//!
+//! Each column family counts its SSTables after every flush and compaction. At
+//! [`Options::slowdown_writes_sstables`](crate::Options#structfield.slowdown_writes_sstables)
+//! writes are delayed a little each, and at
+//! [`Options::stop_writes_sstables`](crate::Options#structfield.stop_writes_sstables) they
+//! wait until a compaction brings the count back under the limit.
+
+use crate::error::EngineError;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long each write is held back while writes are slowed
+pub const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Whether a column family is holding writes back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteStall {
+    /// Writes go straight through
+    #[default]
+    Normal,
+    /// Each write is delayed by [`SLOWDOWN_DELAY`]
+    Slowed,
+    /// Writes wait for a compaction, or fail with [`EngineError::WriteStopped`] when
+    /// they may not wait
+    Stopped,
+}
+
+/// Write stalls so far, as reported in [`crate::Stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct WriteStallStats {
+    pub(crate) slowed: u64,
+    pub(crate) stopped: u64,
+    pub(crate) waited_micros: u64,
+    pub(crate) current: WriteStall,
+}
+
+/// Decides, from a column family's SSTable count, whether its writes may proceed
+#[derive(Debug)]
+pub(crate) struct WriteController {
+    slowdown_at: usize,
+    stop_at: usize,
+    state: Mutex<StallState>,
+    released: Condvar,
+    slowed: AtomicU64,
+    stopped: AtomicU64,
+    waited_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StallState {
+    stall: WriteStall,
+    sstables: usize,
+}
+
+impl WriteController {
+    /// Slow writes from `slowdown_at` SSTables and stop them from `stop_at`; unset
+    /// limits never apply
+    pub(crate) fn new(slowdown_at: Option<usize>, stop_at: Option<usize>) -> Self {
+        WriteController {
+            slowdown_at: slowdown_at.unwrap_or(usize::MAX),
+            stop_at: stop_at.unwrap_or(usize::MAX),
+            state: Mutex::default(),
+            released: Condvar::new(),
+            slowed: AtomicU64::new(0),
+            stopped: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that the column family now has `sstables` tables, waking waiting writes
+    /// if that lifts the stop. Returns the previous and new condition when it changed.
+    pub(crate) fn update(&self, sstables: usize) -> Option<(WriteStall, WriteStall)> {
+        let stall = if sstables >= self.stop_at {
+            WriteStall::Stopped
+        } else if sstables >= self.slowdown_at {
+            WriteStall::Slowed
+        } else {
+            WriteStall::Normal
+        };
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = state.stall;
+        *state = StallState { stall, sstables };
+        if previous == stall {
+            return None;
+        }
+        if previous == WriteStall::Stopped {
+            self.released.notify_all();
+        }
+        Some((previous, stall))
+    }
+
+    /// Hold the calling write back as the current condition requires. While writes are
+    /// stopped it waits for them to resume, or with `wait` unset fails with
+    /// [`EngineError::WriteStopped`]. Must be called without the column family locked,
+    /// as only a compaction can release it.
+    pub(crate) fn admit(&self, wait: bool) -> io::Result<()> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.stall {
+            WriteStall::Normal => Ok(()),
+            WriteStall::Slowed => {
+                drop(state);
+                self.slowed.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(SLOWDOWN_DELAY);
+                self.add_wait(SLOWDOWN_DELAY);
+                Ok(())
+            }
+            WriteStall::Stopped if !wait => {
+                self.stopped.fetch_add(1, Ordering::Relaxed);
+                let sstables = state.sstables;
+                Err(EngineError::WriteStopped { sstables, limit: self.stop_at }.into())
+            }
+            WriteStall::Stopped => {
+                self.stopped.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                let state = self
+                    .released
+                    .wait_while(state, |state| state.stall == WriteStall::Stopped)
+                    .unwrap_or_else(PoisonError::into_inner);
+                drop(state);
+                self.add_wait(started.elapsed());
+                Ok(())
+            }
+        }
+    }
+
+    fn add_wait(&self, waited: Duration) {
+        self.waited_micros.fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            slowed: self.slowed.load(Ordering::Relaxed),
+            stopped: self.stopped.load(Ordering::Relaxed),
+            waited_micros: self.waited_micros.load(Ordering::Relaxed),
+            current: self.state.lock().unwrap_or_else(PoisonError::into_inner).stall,
+        }
+    }
+
+    /// Zero the counts, leaving the current condition
+    pub(crate) fn reset(&self) {
+        self.slowed.store(0, Ordering::Relaxed);
+        self.stopped.store(0, Ordering::Relaxed);
+        self.waited_micros.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteStall;
+    use crate::event::{EventListener, WriteStallInfo};
+    use crate::{Db, EngineError, MemStorage, Options};
+    use std::io;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<WriteStallInfo>>);
+
+    impl EventListener for Recorder {
+        fn on_write_stall_change(&self, info: &WriteStallInfo) {
+            self.0.lock().unwrap().push(*info);
+        }
+    }
+
+    #[test]
+    fn test_writes_stall_until_compaction_catches_up() {
+        let dir = "test_write_stall";
+
+        // Flushes and compactions on a throttled disk stand in for a slow backend
+        let recorder = Arc::new(Recorder::default());
+        let options = Options {
+            storage: Some(Arc::new(MemStorage::new())),
+            rate_limit_bytes_per_sec: Some(64 << 10),
+            slowdown_writes_sstables: Some(2),
+            stop_writes_sstables: Some(3),
+            event_listener: Some(recorder.clone()),
+            ..Options::default()
+        };
+        let db = Db::open_with_options(dir, options).unwrap();
+        for key in ["a", "b", "c"] {
+            db.put(key, "1").unwrap();
+            db.flush().unwrap();
+        }
+        let stats = db.stats().unwrap();
+        assert_eq!((stats.writes_slowed, stats.writes_stopped), (1, 0));
+        assert_eq!(stats.stopped_column_families, 1);
+
+        let err = db.try_put("d", "1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let stopped = EngineError::WriteStopped { sstables: 3, limit: 3 };
+        assert_eq!(EngineError::from_io(&err), Some(&stopped));
+
+        let (done, finished) = mpsc::channel();
+        let writer = db.clone();
+        let handle = thread::spawn(move || {
+            writer.put("d", "2").unwrap();
+            done.send(()).unwrap();
+        });
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(db.get("d"), None);
+
+        db.compact().unwrap();
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(db.get("d"), Some("2".to_string()));
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.writes_stopped, 2);
+        assert!(stats.write_stall_micros >= 200_000, "{}", stats.write_stall_micros);
+        assert_eq!(stats.stopped_column_families + stats.slowed_column_families, 0);
+        let change = |previous, current, sstables| WriteStallInfo { previous, current, sstables };
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                change(WriteStall::Normal, WriteStall::Slowed, 2),
+                change(WriteStall::Slowed, WriteStall::Stopped, 3),
+                change(WriteStall::Stopped, WriteStall::Normal, 1),
+            ]
+        );
+    }
+}
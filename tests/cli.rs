@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn storage_engine(dir: &str, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_storage-engine"))
+        .arg("--dir")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_put_get_del_scan() {
+    let dir = "test_cli_oneshot";
+    let _ = fs::remove_dir_all(dir);
+
+    assert!(storage_engine(dir, &["put", "user:1", "alice smith"], b"").status.success());
+    assert!(storage_engine(dir, &["put", "user:2", "bob"], b"").status.success());
+    assert!(storage_engine(dir, &["put", "other", "x"], b"").status.success());
+
+    let output = storage_engine(dir, &["get", "user:1"], b"");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"alice smith");
+
+    let output = storage_engine(dir, &["scan", "user:"], b"");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "user:1\talice smith\nuser:2\tbob\n");
+
+    assert!(storage_engine(dir, &["del", "user:1"], b"").status.success());
+    let output = storage_engine(dir, &["get", "user:1"], b"");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("not found"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_put_from_stdin_and_errors() {
+    let dir = "test_cli_stdin";
+    let _ = fs::remove_dir_all(dir);
+
+    let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    assert!(storage_engine(dir, &["put", "blob", "-"], &payload).status.success());
+    assert_eq!(storage_engine(dir, &["get", "blob"], b"").stdout, payload);
+
+    let output = storage_engine(dir, &["put", "only-key"], b"");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("wrong number of arguments"));
+    assert_eq!(storage_engine(dir, &["frobnicate"], b"").status.code(), Some(2));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_library_leaves_stdout_alone() {
+    let dir = "test_cli_quiet";
+    let _ = fs::remove_dir_all(dir);
+
+    // The flush and the flush on exit print only what the REPL itself writes
+    let output = storage_engine(dir, &["repl"], b"put a 1\nflush\nput b 2\nquit\n");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let replies: Vec<&str> = stdout.lines().skip(1).collect();
+    assert_eq!(replies, ["> OK", "> OK", "> OK", "> bye"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_compact() {
+    let dir = "test_cli_compact";
+    let _ = fs::remove_dir_all(dir);
+    let tables = || {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sst"))
+            .count()
+    };
+
+    {
+        let db = storage_engine::Db::open(dir).unwrap();
+        for i in 0..10 {
+            db.put(format!("key{}", i), format!("value{}", i)).unwrap();
+            db.put("shared", format!("round{}", i)).unwrap();
+            db.flush().unwrap();
+        }
+    }
+    assert_eq!(tables(), 10);
+
+    let output = storage_engine(dir, &["compact"], b"");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("default: 10 tables ("), "{}", stdout);
+    assert!(stdout.contains("-> 1 tables ("), "{}", stdout);
+    assert_eq!(tables(), 1);
+
+    assert_eq!(storage_engine(dir, &["get", "shared"], b"").stdout, b"round9");
+    let scanned = storage_engine(dir, &["scan", "key"], b"").stdout;
+    assert_eq!(String::from_utf8(scanned).unwrap().lines().count(), 10);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_stats_json() {
+    let dir = "test_cli_stats";
+    let _ = fs::remove_dir_all(dir);
+
+    {
+        let db = storage_engine::Db::open(dir).unwrap();
+        for i in 0..5 {
+            db.put(format!("a{}", i), "x").unwrap();
+        }
+        db.flush().unwrap();
+        db.put("b", "y").unwrap();
+        db.delete("a0").unwrap();
+        db.flush().unwrap();
+        db.put("c", "in wal").unwrap();
+        db.create_cf("events").unwrap().put("e", "1").unwrap();
+    }
+
+    let output = storage_engine(dir, &["stats", "--json"], b"");
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let default = &stats["column_families"][0];
+    assert_eq!(default["name"], "default");
+    assert_eq!(default["sstable_count"], 2);
+    assert_eq!(default["sstables"][0]["entries"], 5);
+    assert_eq!(default["sstables"][0]["first_key"], "a0");
+    assert_eq!(default["sstables"][0]["last_key"], "a4");
+    assert_eq!(default["sstables"][1]["entries"], 2);
+    assert_eq!(default["wal"]["records"], 1);
+    assert_eq!(default["memtable_entries"], 1);
+    assert_eq!(default["live_keys"], 6);
+    assert_eq!(default["next_sstable"], 2);
+    assert_eq!(default["last_sequence"], 8);
+    assert_eq!(stats["column_families"][1]["name"], "events");
+    assert_eq!(stats["column_families"][1]["live_keys"], 1);
+
+    let report = String::from_utf8(storage_engine(dir, &["stats"], b"").stdout).unwrap();
+    assert!(report.contains("  sstables: 2 ("), "{}", report);
+    assert!(report.contains("\"a0\" .. \"a4\""), "{}", report);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_stats_compaction_totals() {
+    let dir = "test_cli_stats_compaction";
+    let _ = fs::remove_dir_all(dir);
+
+    let (flushed, written) = {
+        let db = storage_engine::Db::open(dir).unwrap();
+        for i in 0..5 {
+            db.put(format!("a{}", i), "old").unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..3 {
+            db.put(format!("a{}", i), "new").unwrap();
+        }
+        db.delete("a4").unwrap();
+        db.flush().unwrap();
+        let size = |paths: Vec<std::path::PathBuf>| -> u64 {
+            paths.iter().map(|path| fs::metadata(path).unwrap().len()).sum()
+        };
+        let flushed = size(db.sstable_paths());
+        db.compact().unwrap();
+        (flushed, size(db.sstable_paths()))
+    };
+
+    // Counted in the process that compacted, read back by another
+    let output = storage_engine(dir, &["stats", "--json"], b"");
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let totals = &stats["column_families"][0]["compaction"];
+    assert_eq!(totals["compactions"], 1);
+    assert_eq!(totals["bytes_flushed"].as_u64(), Some(flushed));
+    assert_eq!(totals["bytes_read"].as_u64(), Some(flushed));
+    assert_eq!(totals["bytes_written"].as_u64(), Some(written));
+    assert_eq!(totals["keys"], 5);
+    // Three overwritten values and the one under a4's tombstone
+    assert_eq!(totals["versions_dropped"], 4);
+    assert_eq!(totals["tombstones_dropped"], 1);
+    let amplification = (flushed + written) as f64 / flushed as f64;
+    assert_eq!(totals["write_amplification"].as_f64(), Some(amplification));
+
+    let report = String::from_utf8(storage_engine(dir, &["stats"], b"").stdout).unwrap();
+    assert!(report.contains("compactions: 1 ("), "{}", report);
+    assert!(report.contains("4 versions and 1 tombstones dropped"), "{}", report);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_repair() {
+    let dir = "test_cli_repair";
+    let _ = fs::remove_dir_all(dir);
+
+    {
+        let db = storage_engine::Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+        db.put("b", "2").unwrap();
+        db.put("c", "3").unwrap();
+    }
+    // Tear the WAL's last record
+    let wal = format!("{}/data.log", dir);
+    let len = fs::metadata(&wal).unwrap().len();
+    fs::OpenOptions::new().write(true).open(&wal).unwrap().set_len(len - 2).unwrap();
+
+    let output = storage_engine(dir, &["repair"], b"");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines[0], "kept sstable_000000.sst");
+    assert!(lines[1].starts_with("salvaged data.log: 1 records kept ("), "{}", stdout);
+    assert_eq!(lines[2], "1 kept, 1 salvaged, 0 abandoned; last sequence 2");
+    assert!(lines[3].starts_with("originals of damaged files are in "), "{}", stdout);
+    assert_eq!(fs::metadata(format!("{}/lost/data.log", dir)).unwrap().len(), len - 2);
+
+    assert_eq!(storage_engine(dir, &["get", "b"], b"").stdout, b"2");
+    assert_eq!(storage_engine(dir, &["get", "c"], b"").status.code(), Some(3));
+    assert_eq!(storage_engine("test_cli_repair_missing", &["repair"], b"").status.code(), Some(1));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_config_file() {
+    let dir = "test_cli_config";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir(dir).unwrap();
+    let config = format!("{}/engine.toml", dir);
+    let data = format!("{}/data", dir);
+    fs::write(&config, format!("[storage]\ndir = '{}'\n[wal]\nsync_policy = 'never'\n", data))
+        .unwrap();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_storage-engine"))
+            .args(["--config", &config])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(run(&["put", "a", "1"]).status.success());
+    assert_eq!(storage_engine(&data, &["get", "a"], b"").stdout, b"1");
+    // --dir wins over the file's directory
+    let other = format!("{}/other", dir);
+    assert!(run(&["--dir", &other, "put", "b", "2"]).status.success());
+    assert_eq!(storage_engine(&other, &["get", "b"], b"").stdout, b"2");
+    assert_eq!(storage_engine(&data, &["get", "b"], b"").status.code(), Some(3));
+
+    fs::write(&config, "[storage]\nread_onyl = true\n").unwrap();
+    let output = run(&["get", "a"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unknown key storage.read_onyl; [storage] accepts dir,"), "{}", stderr);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_doctor() {
+    let dir = "test_cli_doctor";
+    let _ = fs::remove_dir_all(dir);
+
+    {
+        let db = storage_engine::Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+    }
+    let output = storage_engine(dir, &["doctor"], b"");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"0 findings, 0 fixed\n");
+
+    fs::copy(format!("{}/sstable_000000.sst", dir), format!("{}/old.sst", dir)).unwrap();
+    fs::write(format!("{}/copy.tmp", dir), "partial").unwrap();
+    let output = storage_engine(dir, &["doctor"], b"");
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "default info temporary file: copy.tmp is a leftover temporary file",
+            "  delete it (--fix does this)",
+            "default warning orphan table: old.sst is a whole SSTable of 1 entries up to \
+             sequence 1 that is not in use",
+            "  adopt it under the next table number (--fix does this)",
+            "2 findings, 0 fixed",
+        ]
+    );
+
+    let output = storage_engine(dir, &["doctor", "--fix"], b"");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("2 findings, 2 fixed\n"), "{}", stdout);
+    assert_eq!(storage_engine(dir, &["get", "a"], b"").stdout, b"1");
+    assert_eq!(storage_engine(dir, &["doctor"], b"").stdout, b"0 findings, 0 fixed\n");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_migrate_wal() {
+    let dir = "test_cli_migrate_wal";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir(dir).unwrap();
+    fs::write(format!("{}/data.log", dir), "PUT,a,1\nPUT,b,2\nDELETE,a\n").unwrap();
+
+    assert_eq!(storage_engine(dir, &["get", "b"], b"").status.code(), Some(1));
+    let output = storage_engine(dir, &["migrate-wal"], b"");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"converted 3 records; the text log is kept as data.log.v0\n");
+    assert_eq!(storage_engine(dir, &["get", "b"], b"").stdout, b"2");
+    assert_eq!(storage_engine(dir, &["get", "a"], b"").status.code(), Some(3));
+    let output = storage_engine(dir, &["migrate-wal"], b"");
+    assert_eq!(output.stdout, b"the WAL is already in the binary format\n");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_verify() {
+    let dir = "test_cli_verify";
+    let _ = fs::remove_dir_all(dir);
+
+    {
+        let db = storage_engine::Db::open(dir).unwrap();
+        db.put("a", "1").unwrap();
+        db.flush().unwrap();
+    }
+    let output = storage_engine(dir, &["verify"], b"");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "default tables: ok\ndefault unexpected files: ok\ndefault table sequences: ok\n\
+         default wal: ok\ndefault counters: ok\n"
+    );
+
+    let table = format!("{}/sstable_000000.sst", dir);
+    fs::OpenOptions::new().write(true).open(&table).unwrap().set_len(20).unwrap();
+    let output = storage_engine(dir, &["verify"], b"");
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let damaged = "default tables: 1 violations\n  sstable_000000.sst: ";
+    assert!(stdout.starts_with(damaged), "{}", stdout);
+
+    fs::remove_dir_all(dir).unwrap();
+}
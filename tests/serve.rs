@@ -0,0 +1,220 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::{self, Child, Command, Stdio};
+use std::time::Duration;
+
+/// Start a server command on a free port and return the process and its address
+fn start(dir: &str, command: &str) -> (Child, String) {
+    start_with(dir, &[command, "--listen", "127.0.0.1:0"])
+}
+
+/// Start the server command in `args` and return the process and the address it printed
+fn start_with(dir: &str, args: &[&str]) -> (Child, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_storage-engine"))
+        .args(["--dir", dir])
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap()).read_line(&mut line).unwrap();
+    let addr = line.trim().strip_prefix("listening on ").unwrap().to_string();
+    (child, addr)
+}
+
+/// Interrupt the server and return what it printed after starting
+fn interrupt(mut child: Child) -> String {
+    let status = Command::new("kill").arg("-INT").arg(child.id().to_string()).status();
+    assert!(status.unwrap().success());
+    assert!(child.wait().unwrap().success());
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    output
+}
+
+fn connect(addr: &str) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+}
+
+/// Send `args` as a RESP array and check the raw reply
+fn expect<S: Read + Write>(stream: &mut S, args: &[&str], reply: &str) {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut received = vec![0; reply.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(String::from_utf8_lossy(&received), reply, "reply to {:?}", args);
+}
+
+/// Writes, reads, and scans, leaving user:1 and order:1 set
+fn resp_conversation<S: Read + Write>(stream: &mut S) {
+    expect(stream, &["SET", "user:1", "alice"], "+OK\r\n");
+    expect(stream, &["set", "user:2", "bob"], "+OK\r\n");
+    expect(stream, &["SET", "order:1", "x y"], "+OK\r\n");
+    expect(stream, &["GET", "user:1"], "$5\r\nalice\r\n");
+    expect(stream, &["GET", "nobody"], "$-1\r\n");
+    expect(stream, &["EXISTS", "user:1", "user:2", "nobody"], ":2\r\n");
+    expect(
+        stream,
+        &["SCAN", "0", "MATCH", "user:*", "COUNT", "100"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+    );
+    // The cursor is the next key, user:2, in hex
+    expect(
+        stream,
+        &["SCAN", "0", "COUNT", "2"],
+        "*2\r\n$12\r\n757365723a32\r\n*2\r\n$7\r\norder:1\r\n$6\r\nuser:1\r\n",
+    );
+    expect(stream, &["SCAN", "757365723a32"], "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:2\r\n");
+    expect(stream, &["DEL", "user:2", "nobody"], ":1\r\n");
+    expect(stream, &["GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    expect(stream, &["FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n");
+
+    // Inline commands work too, as typed into telnet
+    stream.write_all(b"PING\r\n").unwrap();
+    let mut pong = [0; 7];
+    stream.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"+PONG\r\n");
+}
+
+#[test]
+fn test_resp_commands_and_restart() {
+    let dir = "test_serve_resp";
+    let _ = fs::remove_dir_all(dir);
+
+    let (server, addr) = start(dir, "serve");
+    let mut stream = connect(&addr);
+    resp_conversation(&mut stream);
+    assert!(interrupt(server).contains("flushed"));
+    drop(stream);
+
+    let (server, addr) = start(dir, "serve");
+    let mut stream = connect(&addr);
+    expect(&mut stream, &["GET", "user:1"], "$5\r\nalice\r\n");
+    expect(&mut stream, &["GET", "order:1"], "$3\r\nx y\r\n");
+    expect(&mut stream, &["EXISTS", "user:2"], ":0\r\n");
+    interrupt(server);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_resp_over_unix_socket() {
+    let dir = "test_serve_unix";
+    let _ = fs::remove_dir_all(dir);
+    let sockets = std::env::temp_dir().join(format!("storage-engine-serve-{}", process::id()));
+    let _ = fs::remove_dir_all(&sockets);
+    fs::create_dir_all(&sockets).unwrap();
+    let path = sockets.join("resp.sock");
+    // Left behind by a server that is gone
+    drop(UnixListener::bind(&path).unwrap());
+
+    let listen = format!("unix:{}", path.display());
+    let (server, addr) = start_with(dir, &["serve", "--listen", &listen, "--socket-mode", "600"]);
+    assert_eq!(addr, listen);
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // One still being served is not taken over
+    let other = "test_serve_unix_other";
+    let output = Command::new(env!("CARGO_BIN_EXE_storage-engine"))
+        .args(["--dir", other, "serve", "--listen", &listen])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("a server is already listening on"), "{}", stderr);
+    fs::remove_dir_all(other).unwrap();
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    resp_conversation(&mut stream);
+    assert!(interrupt(server).contains("flushed"));
+    assert!(!path.exists());
+
+    fs::remove_dir_all(&sockets).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Send one HTTP request on a fresh connection; returns the status, head, and body
+fn http(addr: &str, method: &str, target: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+    let mut stream = connect(addr);
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let status = head[9..12].parse().unwrap();
+    (status, head, response[split + 4..].to_vec())
+}
+
+#[test]
+fn test_http_routes() {
+    let dir = "test_serve_http";
+    let _ = fs::remove_dir_all(dir);
+
+    let (server, addr) = start(dir, "serve-http");
+    assert_eq!(http(&addr, "PUT", "/keys/user%3A1", b"alice").0, 204);
+    assert_eq!(http(&addr, "PUT", "/keys/user%3A2", b"bob").0, 204);
+    assert_eq!(http(&addr, "PUT", "/keys/a%20b%2Fc", b"\xff\x00raw").0, 204);
+    assert_eq!(http(&addr, "PUT", "/keys/other", b"x").0, 204);
+
+    let (status, head, body) = http(&addr, "GET", "/keys/user:1", b"");
+    assert_eq!((status, body.as_slice()), (200, &b"alice"[..]));
+    assert!(head.contains("Content-Type: text/plain"), "{}", head);
+    let (status, head, body) = http(&addr, "GET", "/keys/a%20b%2Fc", b"");
+    assert_eq!((status, body.as_slice()), (200, &b"\xff\x00raw"[..]));
+    assert!(head.contains("Content-Type: application/octet-stream"), "{}", head);
+    assert_eq!(http(&addr, "GET", "/keys/nobody", b"").0, 404);
+
+    let (status, _, body) = http(&addr, "GET", "/keys?prefix=user%3A&limit=10", b"");
+    assert_eq!(status, 200);
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        listing,
+        serde_json::json!({
+            "entries": [{"key": "user:1", "value": "alice"}, {"key": "user:2", "value": "bob"}],
+            "truncated": false,
+        })
+    );
+    let (_, _, body) = http(&addr, "GET", "/keys?limit=1", b"");
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listing["entries"][0]["key"], "a b/c");
+    assert_eq!(listing["entries"][0]["value_base64"], true);
+    assert_eq!(listing["truncated"], true);
+
+    assert_eq!(http(&addr, "DELETE", "/keys/user:2", b"").0, 204);
+    assert_eq!(http(&addr, "DELETE", "/keys/user:2", b"").0, 404);
+    assert_eq!(http(&addr, "GET", "/keys/user:2", b"").0, 404);
+    let (status, head, _) = http(&addr, "POST", "/keys/user:1", b"");
+    assert_eq!(status, 405);
+    assert!(head.contains("Allow: GET, PUT, DELETE"), "{}", head);
+    assert_eq!(http(&addr, "GET", "/nowhere", b"").0, 404);
+
+    let (status, head, body) = http(&addr, "GET", "/metrics", b"");
+    assert_eq!(status, 200);
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"), "{}", head);
+    let metrics = String::from_utf8(body).unwrap();
+    assert!(metrics.contains("# TYPE storage_engine_puts_total counter\n"), "{}", metrics);
+    assert!(metrics.contains("\nstorage_engine_puts_total 4\n"), "{}", metrics);
+    assert!(metrics.contains("\nstorage_engine_deletes_total 2\n"), "{}", metrics);
+    interrupt(server);
+
+    fs::remove_dir_all(dir).unwrap();
+}